@@ -0,0 +1,113 @@
+use crate::Palette;
+
+// How far apart two colors are when picking the nearest palette color
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Metric {
+    // Euclidean in sRGB
+    #[default]
+    Rgb,
+    // Euclidean in CIELAB (CIE76), closer to what the eye sees
+    Lab,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ColorId {
+    pub id: u8,
+    pub exact: bool,
+    // In the units of the metric it was matched with
+    pub distance: f64,
+}
+
+// The palette color closest to `rgb`; None only for an empty palette
+pub fn nearest(palette: &Palette, (r, g, b): (u8, u8, u8), metric: Metric) -> Option<ColorId> {
+    if let Some(id) = palette.id_of((r, g, b)) {
+        return Some(ColorId {
+            id,
+            exact: true,
+            distance: 0.0,
+        });
+    }
+    let (id, distance) = match metric {
+        Metric::Rgb => {
+            // Squared integers, so ties resolve the same everywhere
+            let (id, squared) = palette
+                .colors()
+                .iter()
+                .map(|&(r1, g1, b1)| {
+                    (r.abs_diff(r1) as u32).pow(2)
+                        + (g.abs_diff(g1) as u32).pow(2)
+                        + (b.abs_diff(b1) as u32).pow(2)
+                })
+                .enumerate()
+                .min_by_key(|&(_, squared)| squared)?;
+            (id, (squared as f64).sqrt())
+        }
+        Metric::Lab => {
+            let lab = to_lab((r, g, b));
+            palette
+                .colors()
+                .iter()
+                .map(|&color| {
+                    let other = to_lab(color);
+                    let (dl, da, db) = (lab[0] - other[0], lab[1] - other[1], lab[2] - other[2]);
+                    (dl * dl + da * da + db * db).sqrt()
+                })
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))?
+        }
+    };
+    Some(ColorId {
+        id: id as u8,
+        exact: false,
+        distance,
+    })
+}
+
+// sRGB to CIELAB under D65; only +, -, *, / and sqrt, which give the same bits on every target
+fn to_lab((r, g, b): (u8, u8, u8)) -> [f64; 3] {
+    let linear = |channel: u8| {
+        let c = channel as f64 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            // t^2.4 as t^2 * (t^2)^(1/5)
+            let t = (c + 0.055) / 1.055;
+            t * t * root(t * t, 5)
+        }
+    };
+    let (r, g, b) = (linear(r), linear(g), linear(b));
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f64| {
+        if t > 216.0 / 24389.0 {
+            root(t, 3)
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+// The positive `n`th root by Newton's method from above, stopping once it no longer shrinks
+fn root(x: f64, n: i32) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let power = |y: f64, n: i32| (1..n).fold(y, |product, _| product * y);
+    let mut y = x.max(1.0);
+    loop {
+        let next = y - (power(y, n) - x) / (n as f64 * power(y, n - 1));
+        if next >= y {
+            return y;
+        }
+        y = next;
+    }
+}
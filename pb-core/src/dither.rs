@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use crate::{nearest, ColorId, Metric, Palette};
+
+// Whether quantization errors are spread to the neighbours
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Dither {
+    // Every pixel on its own
+    #[default]
+    None,
+    FloydSteinberg,
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct QuantizeOptions {
+    pub metric: Metric,
+    pub dither: Dither,
+    // Pixels with less alpha are left out
+    pub alpha_threshold: u8,
+    // Source colors always painted as this palette id
+    pub overrides: HashMap<(u8, u8, u8), u8>,
+}
+
+// The palette color for one source color, overrides first
+pub fn resolve(palette: &Palette, rgb: (u8, u8, u8), options: &QuantizeOptions) -> Option<ColorId> {
+    match options.overrides.get(&rgb) {
+        Some(&id) => Some(ColorId {
+            id,
+            exact: true,
+            distance: 0.0,
+        }),
+        None => nearest(palette, rgb, options.metric),
+    }
+}
+
+// One row of RGBA bytes without dithering, so rows can go to different threads; None where left out
+pub fn quantize_row(
+    row: &[u8],
+    palette: &Palette,
+    options: &QuantizeOptions,
+) -> Vec<Option<ColorId>> {
+    row.chunks_exact(4)
+        .map(|pixel| {
+            let [r, g, b, a] = [pixel[0], pixel[1], pixel[2], pixel[3]];
+            if a < options.alpha_threshold {
+                return None;
+            }
+            resolve(palette, (r, g, b), options)
+        })
+        .collect()
+}
+
+// RGBA bytes of `width` pixels per row, one entry per pixel in the same order; None where left out
+pub fn quantize(
+    rgba: &[u8],
+    width: usize,
+    palette: &Palette,
+    options: &QuantizeOptions,
+) -> Vec<Option<ColorId>> {
+    if width == 0 {
+        return Vec::new();
+    }
+    match options.dither {
+        Dither::None => rgba
+            .chunks(width * 4)
+            .flat_map(|row| quantize_row(row, palette, options))
+            .collect(),
+        Dither::FloydSteinberg => floyd_steinberg(rgba, width, palette, options),
+    }
+}
+
+fn floyd_steinberg(
+    rgba: &[u8],
+    width: usize,
+    palette: &Palette,
+    options: &QuantizeOptions,
+) -> Vec<Option<ColorId>> {
+    let mut resolved = Vec::with_capacity(rgba.len() / 4);
+    // Error carried into this row and the next, with a spare column on each side
+    let mut current = vec![[0.0f64; 3]; width + 2];
+    let mut next = vec![[0.0f64; 3]; width + 2];
+    for row in rgba.chunks(width * 4) {
+        for (x, pixel) in row.chunks_exact(4).enumerate() {
+            let [r, g, b, a] = [pixel[0], pixel[1], pixel[2], pixel[3]];
+            if a < options.alpha_threshold {
+                resolved.push(None);
+                continue;
+            }
+            let carried = current[x + 1];
+            let wanted = [
+                r as f64 + carried[0],
+                g as f64 + carried[1],
+                b as f64 + carried[2],
+            ];
+            let channel = |value: f64| value.round().clamp(0.0, 255.0) as u8;
+            // Overridden colors stay themselves, only passing their error on
+            let source = if options.overrides.contains_key(&(r, g, b)) {
+                (r, g, b)
+            } else {
+                (channel(wanted[0]), channel(wanted[1]), channel(wanted[2]))
+            };
+            let color = resolve(palette, source, options);
+            if let Some((r1, g1, b1)) = color.and_then(|color| palette.rgb_of(color.id)) {
+                let error = [
+                    wanted[0] - r1 as f64,
+                    wanted[1] - g1 as f64,
+                    wanted[2] - b1 as f64,
+                ];
+                for (i, error) in error.into_iter().enumerate() {
+                    current[x + 2][i] += error * 7.0 / 16.0;
+                    next[x][i] += error * 3.0 / 16.0;
+                    next[x + 1][i] += error * 5.0 / 16.0;
+                    next[x + 2][i] += error / 16.0;
+                }
+            }
+            resolved.push(color);
+        }
+        current = std::mem::replace(&mut next, vec![[0.0; 3]; width + 2]);
+    }
+    resolved
+}
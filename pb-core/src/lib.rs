@@ -0,0 +1,14 @@
+//! How images turn into palette colors and in which order pixels are queued, without any I/O,
+//! so a preview built for wasm32 quantizes exactly like the bot does.
+//!
+//! Everything here works on plain slices: RGBA bytes row by row, `(x, y)` positions.
+
+mod color;
+mod dither;
+mod palette;
+mod traversal;
+
+pub use color::{nearest, ColorId, Metric};
+pub use dither::{quantize, quantize_row, resolve, Dither, QuantizeOptions};
+pub use palette::{parse_hex, Palette, DEFAULT_PALETTE};
+pub use traversal::block_major;
@@ -0,0 +1,91 @@
+pub const DEFAULT_PALETTE: [&str; 25] = [
+    "#FFFFFF", "#C2C2C2", "#858585", "#474747", "#000000", "#3AAFFF", "#71AAEB", "#4A76A8",
+    "#074BF3", "#5E30EB", "#FF6C5B", "#FE2500", "#FF218B", "#99244F", "#4D2C9C", "#FFCF4A",
+    "#FEB43F", "#FE8648", "#FF5B36", "#DA5100", "#94E044", "#5CBF0D", "#C3D117", "#FCC700",
+    "#D38301",
+];
+
+// Colors the server accepts, indexed by color id
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "Vec<(u8, u8, u8)>", into = "Vec<(u8, u8, u8)>")
+)]
+pub struct Palette {
+    colors: Vec<(u8, u8, u8)>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            colors: DEFAULT_PALETTE
+                .into_iter()
+                .map(|x| parse_hex(x).unwrap())
+                .collect(),
+        }
+    }
+}
+
+impl Palette {
+    // Color ids are a byte everywhere, so only this many colors can be told apart
+    pub const MAX_LEN: usize = 256;
+
+    // None when there are more colors than color ids
+    pub fn new(colors: Vec<(u8, u8, u8)>) -> Option<Self> {
+        (colors.len() <= Self::MAX_LEN).then_some(Self { colors })
+    }
+
+    pub fn rgb_of(&self, id: u8) -> Option<(u8, u8, u8)> {
+        self.colors.get(id as usize).copied()
+    }
+
+    pub fn id_of(&self, rgb: (u8, u8, u8)) -> Option<u8> {
+        self.colors
+            .iter()
+            .position(|&color| color == rgb)
+            .map(|id| id as u8)
+    }
+
+    pub fn colors(&self) -> &[(u8, u8, u8)] {
+        &self.colors
+    }
+
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+}
+
+impl TryFrom<Vec<(u8, u8, u8)>> for Palette {
+    type Error = String;
+
+    fn try_from(colors: Vec<(u8, u8, u8)>) -> Result<Self, Self::Error> {
+        let count = colors.len();
+        Self::new(colors).ok_or_else(|| {
+            format!(
+                "{count} colors are more than the {} supported",
+                Self::MAX_LEN
+            )
+        })
+    }
+}
+
+impl From<Palette> for Vec<(u8, u8, u8)> {
+    fn from(palette: Palette) -> Self {
+        palette.colors
+    }
+}
+
+// #RRGGBB, the # optional
+pub fn parse_hex(hex: &str) -> Option<(u8, u8, u8)> {
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    if digits.len() != 6 || !digits.is_ascii() {
+        return None;
+    }
+    let channel = |range| u8::from_str_radix(&digits[range], 16).ok();
+    Some((channel(0..2)?, channel(2..4)?, channel(4..6)?))
+}
@@ -0,0 +1,10 @@
+// Indices of `positions` block by block in rows of blocks, keeping the given order inside each
+pub fn block_major(positions: &[(u32, u32)], block_size: u32) -> Vec<usize> {
+    let size = block_size.max(1);
+    let mut order = (0..positions.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| {
+        let (x, y) = positions[i];
+        (y / size, x / size)
+    });
+    order
+}
@@ -0,0 +1,104 @@
+// Inputs and pinned outputs shared by pb-core's tests and the bot's, so both quantize alike
+
+pub const WIDTH: usize = 16;
+pub const HEIGHT: usize = 6;
+
+// RGBA rows sweeping the color space, with a transparent diagonal
+pub fn image() -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(WIDTH * HEIGHT * 4);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let alpha = if x == y * 2 { 0 } else { 255 };
+            rgba.extend([
+                (x * 16 + 7) as u8,
+                (y * 48 + 5) as u8,
+                (x * y * 29 % 256) as u8,
+                alpha,
+            ]);
+        }
+    }
+    rgba
+}
+
+// Palette ids of image() on the default palette with an alpha threshold of 128, - where left out
+pub const RGB: &str = "
+    - 4 4 4 4 4 13 13 13 13 13 11 11 11 11 11
+    4 4 - 3 14 14 14 9 9 19 13 13 13 12 12 12
+    3 3 3 7 - 3 3 2 9 24 13 2 2 1 18 10
+    21 3 7 21 2 7 - 2 2 24 2 1 24 17 1 24
+    21 7 5 21 5 21 6 21 - 22 1 1 15 1 15 1
+    21 5 21 5 20 6 20 6 20 20 - 20 1 15 0 15
+";
+
+pub const LAB: &str = "
+    - 4 4 4 13 13 13 13 19 19 19 19 19 11 11 11
+    3 3 - 3 14 14 14 9 9 19 10 13 13 12 12 12
+    21 3 3 7 - 3 3 7 14 24 17 13 13 14 19 10
+    21 21 7 21 2 7 - 2 7 22 2 2 24 17 1 24
+    21 21 6 21 1 21 1 21 - 20 1 6 15 1 15 1
+    21 20 21 20 21 1 20 0 20 20 - 20 0 22 0 15
+";
+
+pub const RGB_FLOYD_STEINBERG: &str = "
+    - 4 4 4 13 4 13 13 13 13 13 11 13 11 11 11
+    4 3 - 3 3 14 13 14 14 13 13 13 11 13 12 12
+    3 3 3 7 - 3 3 2 9 19 19 13 2 12 10 10
+    21 21 5 21 3 7 - 2 2 21 22 1 24 1 1 18
+    21 7 5 21 5 21 6 21 - 22 2 1 1 1 16 1
+    21 21 21 5 21 5 20 5 20 20 - 20 1 15 0 15
+";
+
+pub const LAB_FLOYD_STEINBERG: &str = "
+    - 4 4 13 4 13 13 13 13 19 13 19 19 11 11 11
+    3 3 - 3 3 14 14 14 14 13 19 13 13 13 12 12
+    21 3 3 7 - 3 3 3 14 19 13 13 12 9 10 10
+    21 3 2 21 2 2 - 2 1 22 2 2 15 17 13 16
+    21 21 5 21 6 21 1 21 - 21 15 6 15 1 0 1
+    21 20 21 0 21 0 21 0 20 21 - 20 0 20 0 22
+";
+
+// Palette id and distance
+type Match = (u8, f64);
+
+// Source color, then its match under Metric::Rgb and under Metric::Lab
+pub const NEAREST: [((u8, u8, u8), Match, Match); 8] = [
+    ((0, 0, 0), (4, 0.0), (4, 0.0)),
+    ((255, 255, 255), (0, 0.0), (0, 0.0)),
+    (
+        (128, 128, 128),
+        (2, 8.660254037844387),
+        (2, 1.9529920419445785),
+    ),
+    (
+        (200, 30, 40),
+        (13, 61.36774397026503),
+        (18, 22.277623950746904),
+    ),
+    (
+        (10, 200, 90),
+        (21, 112.84502647436439),
+        (21, 26.435809420928727),
+    ),
+    (
+        (60, 60, 200),
+        (14, 49.80963762164909),
+        (8, 16.496370578180198),
+    ),
+    (
+        (250, 200, 60),
+        (15, 16.431676725154983),
+        (15, 3.704514864975243),
+    ),
+    (
+        (130, 40, 120),
+        (13, 47.18050444834179),
+        (13, 29.86128284568867),
+    ),
+];
+
+pub fn parse(golden: &str) -> Vec<Option<u8>> {
+    golden
+        .split_whitespace()
+        .map(|id| id.parse().ok())
+        .collect()
+}
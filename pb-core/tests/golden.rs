@@ -0,0 +1,66 @@
+mod fixture;
+
+use pb_core::{nearest, quantize, quantize_row, Dither, Metric, Palette, QuantizeOptions};
+
+fn options(metric: Metric, dither: Dither) -> QuantizeOptions {
+    QuantizeOptions {
+        metric,
+        dither,
+        alpha_threshold: 128,
+        ..Default::default()
+    }
+}
+
+fn ids(colors: Vec<Option<pb_core::ColorId>>) -> Vec<Option<u8>> {
+    colors.into_iter().map(|color| Some(color?.id)).collect()
+}
+
+#[test]
+fn nearest_is_pinned() {
+    let palette = Palette::default();
+    for (rgb, by_rgb, by_lab) in fixture::NEAREST {
+        for (metric, (id, distance)) in [(Metric::Rgb, by_rgb), (Metric::Lab, by_lab)] {
+            let color = nearest(&palette, rgb, metric).unwrap();
+            assert_eq!(
+                (color.id, color.distance),
+                (id, distance),
+                "{rgb:?} by {metric:?}"
+            );
+            assert_eq!(color.exact, distance == 0.0);
+        }
+    }
+}
+
+#[test]
+fn quantize_is_pinned() {
+    let palette = Palette::default();
+    let image = fixture::image();
+    for (metric, dither, golden) in [
+        (Metric::Rgb, Dither::None, fixture::RGB),
+        (Metric::Lab, Dither::None, fixture::LAB),
+        (
+            Metric::Rgb,
+            Dither::FloydSteinberg,
+            fixture::RGB_FLOYD_STEINBERG,
+        ),
+        (
+            Metric::Lab,
+            Dither::FloydSteinberg,
+            fixture::LAB_FLOYD_STEINBERG,
+        ),
+    ] {
+        let colors = quantize(&image, fixture::WIDTH, &palette, &options(metric, dither));
+        assert_eq!(ids(colors), fixture::parse(golden), "{metric:?} {dither:?}");
+    }
+}
+
+#[test]
+fn rows_quantize_like_the_whole_image() {
+    let palette = Palette::default();
+    let options = options(Metric::Lab, Dither::None);
+    let rows = fixture::image()
+        .chunks(fixture::WIDTH * 4)
+        .flat_map(|row| quantize_row(row, &palette, &options))
+        .collect();
+    assert_eq!(ids(rows), fixture::parse(fixture::LAB));
+}
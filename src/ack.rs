@@ -0,0 +1,123 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+
+// What a broadcast has to share with one of our sends to count as its echo
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AckMatch {
+    // Same coordinates, whatever color the server reports
+    #[default]
+    Position,
+    // Same coordinates and color id
+    Exact,
+}
+
+// Which color ids our echoes come back with, to spot a server numbering its palette differently
+#[derive(Default)]
+pub struct RemapInference {
+    // Per color id we sent, how often each id was echoed for it
+    seen: HashMap<u8, HashMap<u8, u32>>,
+    echoes: u32,
+    noticed: bool,
+}
+
+impl RemapInference {
+    // Echoes looked at before calling anything consistent
+    const MIN_ECHOES: u32 = 20;
+    // Share of the echoes of a color that have to agree on one id
+    const AGREEMENT: f64 = 0.9;
+
+    // The inferred echoed-to-ours table the first time the echoes settle on one
+    pub fn record(&mut self, sent: u8, echoed: u8) -> Option<BTreeMap<u8, u8>> {
+        *self
+            .seen
+            .entry(sent)
+            .or_default()
+            .entry(echoed)
+            .or_default() += 1;
+        self.echoes += 1;
+        if self.noticed || self.echoes < Self::MIN_ECHOES {
+            return None;
+        }
+        let table = self.table()?;
+        self.noticed = true;
+        Some(table)
+    }
+
+    // None unless every color we sent comes back as a single other id, no two the same
+    fn table(&self) -> Option<BTreeMap<u8, u8>> {
+        let mut table = BTreeMap::new();
+        for (&sent, echoed) in &self.seen {
+            let total = echoed.values().sum::<u32>();
+            let (&id, &count) = echoed.iter().max_by_key(|&(_, count)| count)?;
+            if (count as f64) < total as f64 * Self::AGREEMENT {
+                return None;
+            }
+            if table.insert(id, sent).is_some() {
+                return None;
+            }
+        }
+        table.retain(|echoed, sent| echoed != sent);
+        (!table.is_empty()).then_some(table)
+    }
+}
+
+// The table as it goes into the config
+pub fn format_remap(table: &BTreeMap<u8, u8>) -> String {
+    let entries = table
+        .iter()
+        .map(|(echoed, ours)| format!("{echoed} = {ours}"))
+        .collect::<Vec<_>>();
+    format!("echo_remap = {{ {} }}", entries.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn infer(echoes: impl IntoIterator<Item = (u8, u8)>) -> Vec<BTreeMap<u8, u8>> {
+        let mut inference = RemapInference::default();
+        echoes
+            .into_iter()
+            .filter_map(|(sent, echoed)| inference.record(sent, echoed))
+            .collect()
+    }
+
+    #[test]
+    fn a_consistent_shift_is_reported_once() {
+        let echoes = (0..100).map(|i| (i % 4, i % 4 + 1));
+        let tables = infer(echoes);
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0], BTreeMap::from([(1, 0), (2, 1), (3, 2), (4, 3)]));
+    }
+
+    #[test]
+    fn nothing_is_reported_before_enough_echoes() {
+        assert!(infer((0..19).map(|_| (3, 7))).is_empty());
+        assert_eq!(infer((0..20).map(|_| (3, 7))).len(), 1);
+    }
+
+    #[test]
+    fn matching_ids_need_no_remap() {
+        assert!(infer((0..50).map(|i| (i % 5, i % 5))).is_empty());
+    }
+
+    #[test]
+    fn disagreeing_echoes_are_not_a_remap() {
+        // A foreign paint landing right after ours every few times
+        let echoes = (0..100).map(|i| (2, if i % 5 == 0 { 9 } else { 4 }));
+        assert!(infer(echoes).is_empty());
+    }
+
+    #[test]
+    fn two_colors_echoed_as_one_are_not_a_remap() {
+        assert!(infer((0..50).map(|i| (i % 2, 7))).is_empty());
+    }
+
+    #[test]
+    fn the_table_reads_as_config() {
+        let table = BTreeMap::from([(1, 0), (4, 3)]);
+        assert_eq!(format_remap(&table), "echo_remap = { 1 = 0, 4 = 3 }");
+    }
+}
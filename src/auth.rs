@@ -0,0 +1,198 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use url::Url;
+
+use crate::fetch;
+
+// For servers that drop sessions unless a fresh token is sent every so often
+#[derive(Deserialize, Clone)]
+pub struct AuthRefreshConfig {
+    // Fetched with GET; {token} becomes the token query parameter of the bot URL
+    url: String,
+    #[serde(default = "AuthRefreshConfig::default_interval")]
+    pub interval: u64,
+    // Text frame sent with {token} replaced by the fetched token
+    frame: String,
+    // Failed refreshes in a row before reconnecting; twice as many quarantine the bot
+    #[serde(default = "AuthRefreshConfig::default_max_failures")]
+    pub max_failures: u32,
+}
+
+impl AuthRefreshConfig {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn default_interval() -> u64 {
+        25 * 60
+    }
+
+    fn default_max_failures() -> u32 {
+        3
+    }
+
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.interval == 0 {
+            Err(anyhow!("auth_refresh.interval must be positive"))?
+        }
+        if self.max_failures == 0 {
+            Err(anyhow!("auth_refresh.max_failures must be at least 1"))?
+        }
+        let url = Url::parse(&self.url.replace("{token}", "token"))
+            .map_err(|why| anyhow!("auth_refresh.url is invalid: {why}"))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            Err(anyhow!("auth_refresh.url must be http or https"))?
+        }
+        Ok(())
+    }
+
+    // The refresh frame for the bot connected to `bot_url`
+    pub async fn frame(&self, bot_url: &Url) -> anyhow::Result<String> {
+        let current = bot_url
+            .query_pairs()
+            .find(|(key, _)| key == "token")
+            .map(|(_, token)| token.into_owned())
+            .unwrap_or_default();
+        let url = Url::parse(&self.url.replace("{token}", &current))?;
+        let body = tokio::time::timeout(Self::TIMEOUT, fetch::get(&url))
+            .await
+            .map_err(|_| anyhow!("token request timed out"))??;
+        let token = parse_token(&body).ok_or_else(|| anyhow!("token response is empty"))?;
+        Ok(self.frame.replace("{token}", &token))
+    }
+}
+
+// Either {"token": ...} or the bare token
+fn parse_token(body: &str) -> Option<String> {
+    let body = body.trim();
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(fields)) => fields.get("token")?.as_str().map(Into::into),
+        _ => (!body.is_empty()).then(|| body.trim_matches('"').into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_tungstenite::tungstenite::Message;
+    use futures::StreamExt;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::{parse_config, run};
+
+    fn config(url: &str) -> AuthRefreshConfig {
+        AuthRefreshConfig {
+            url: url.into(),
+            interval: 1,
+            frame: r#"{"type":"auth","token":"{token}"}"#.into(),
+            max_failures: 1,
+        }
+    }
+
+    // Answers every GET with a new token derived from the old one, reporting the request line
+    async fn tokens() -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/token?old={{token}}",
+            listener.local_addr().unwrap()
+        );
+        let (requests, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = BufReader::new(stream);
+                let mut request = String::new();
+                stream.read_line(&mut request).await.unwrap();
+                let old = request
+                    .split("old=")
+                    .nth(1)
+                    .unwrap()
+                    .split(' ')
+                    .next()
+                    .unwrap();
+                let body = format!(r#"{{"token": "fresh-{old}"}}"#);
+                let response = format!("HTTP/1.0 200 OK\r\n\r\n{body}");
+                stream
+                    .get_mut()
+                    .write_all(response.as_bytes())
+                    .await
+                    .unwrap();
+                drop(requests.send(request.trim().to_string()));
+            }
+        });
+        (url, received)
+    }
+
+    #[test]
+    fn tokens_come_bare_or_as_json() {
+        assert_eq!(parse_token(r#"{"token": "abc"}"#).as_deref(), Some("abc"));
+        assert_eq!(parse_token(" abc\n").as_deref(), Some("abc"));
+        assert_eq!(parse_token(r#""abc""#).as_deref(), Some("abc"));
+        assert_eq!(parse_token(r#"{"session": "abc"}"#), None);
+        assert_eq!(parse_token("  "), None);
+    }
+
+    #[test]
+    fn bad_settings_are_refused() {
+        assert!(config("https://auth.example/refresh?t={token}")
+            .check()
+            .is_ok());
+        let why = |config: AuthRefreshConfig| config.check().err().unwrap().to_string();
+        assert!(why(config("ftp://auth.example")).contains("http or https"));
+        assert!(why(config("not a url")).starts_with("auth_refresh.url is invalid"));
+        let never = AuthRefreshConfig {
+            interval: 0,
+            ..config("https://auth.example")
+        };
+        assert_eq!(why(never), "auth_refresh.interval must be positive");
+    }
+
+    #[tokio::test]
+    async fn workers_send_a_fresh_token_over_their_connection() {
+        let (token_url, mut requests) = tokens().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = format!("ws://{}/?token=first", listener.local_addr().unwrap());
+        let (frames, mut received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let frames = frames.clone();
+                tokio::spawn(async move {
+                    let mut connection = async_tungstenite::tokio::accept_async(stream).await?;
+                    while let Some(Ok(msg)) = connection.next().await {
+                        if let Message::Text(text) = msg {
+                            drop(frames.send(text));
+                        }
+                    }
+                    anyhow::Ok(())
+                });
+            }
+        });
+        let mut config = parse_config(&format!(
+            r##"{{
+                "brush": {{"rect": {{"width": 1, "height": 1, "color": "#FFFFFF"}}, "defend": true}},
+                "bots": ["{server}"],
+                "verify_first_paint": false,
+                "auth_refresh": {{"url": "{token_url}", "interval": 1, "frame": "auth {{token}}"}}
+            }}"##
+        ))
+        .unwrap();
+        config.canvas.auto = false;
+        config.assume_yes();
+        let (refreshed, stop) = tokio::sync::oneshot::channel();
+        let running = tokio::spawn(run(config, async {
+            drop(stop.await);
+        }));
+        let frame = tokio::time::timeout(Duration::from_secs(30), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        refreshed.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        assert_eq!(frame, "auth fresh-first");
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request, "GET /token?old=first HTTP/1.0");
+    }
+}
@@ -0,0 +1,117 @@
+use serde::Deserialize;
+
+// Probing one bot for the shortest cooldown the server really enforces
+#[derive(Deserialize, Clone, Copy)]
+pub struct CalibrationConfig {
+    // Paints the probe may spend, the first one included
+    #[serde(default = "CalibrationConfig::default_max_paints")]
+    pub max_paints: u32,
+    // Seconds added to the shortest accepted interval
+    #[serde(default = "CalibrationConfig::default_margin")]
+    pub margin: u64,
+    // A probe whose echo takes longer than this counts as rejected
+    #[serde(default = "CalibrationConfig::default_ack_timeout")]
+    pub ack_timeout: u64,
+}
+
+impl Default for CalibrationConfig {
+    fn default() -> Self {
+        Self {
+            max_paints: Self::default_max_paints(),
+            margin: Self::default_margin(),
+            ack_timeout: Self::default_ack_timeout(),
+        }
+    }
+}
+
+impl CalibrationConfig {
+    fn default_max_paints() -> u32 {
+        8
+    }
+
+    fn default_margin() -> u64 {
+        2
+    }
+
+    fn default_ack_timeout() -> u64 {
+        5
+    }
+
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.max_paints < 2 {
+            Err(anyhow::anyhow!(
+                "calibration.max_paints must be at least 2 to time anything"
+            ))?
+        }
+        if self.ack_timeout == 0 {
+            Err(anyhow::anyhow!("calibration.ack_timeout must be positive"))?
+        }
+        Ok(())
+    }
+}
+
+// Bisects whole seconds between a rejected and an accepted interval
+pub struct Search {
+    rejected: u64,
+    accepted: Option<u64>,
+    // Tried first, doubled while rejected
+    guess: u64,
+}
+
+impl Search {
+    pub fn new(guess: u64) -> Self {
+        Self {
+            rejected: 0,
+            accepted: None,
+            guess: guess.max(1),
+        }
+    }
+
+    // Seconds to wait after the last accepted paint, or None once converged
+    pub fn next(&self) -> Option<u64> {
+        match self.accepted {
+            None => Some(self.guess),
+            Some(accepted) if accepted - self.rejected > 1 => {
+                Some(self.rejected + (accepted - self.rejected) / 2)
+            }
+            Some(_) => None,
+        }
+    }
+
+    pub fn record(&mut self, interval: u64, accepted: bool) {
+        if accepted {
+            self.accepted = Some(self.accepted.map_or(interval, |a| a.min(interval)));
+        } else {
+            self.rejected = self.rejected.max(interval);
+            if self.accepted.is_none() {
+                self.guess = interval * 2;
+            }
+        }
+    }
+
+    // The shortest interval seen accepted
+    pub fn found(&self) -> Option<u64> {
+        self.accepted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_lands_on_the_enforced_cooldown() {
+        for cooldown in [1, 7, 60, 65, 300] {
+            for guess in [1, 30, 65, 600] {
+                let mut search = Search::new(guess);
+                let mut paints = 0;
+                while let Some(interval) = search.next() {
+                    search.record(interval, interval >= cooldown);
+                    paints += 1;
+                    assert!(paints < 32, "no convergence for {cooldown}s from {guess}s");
+                }
+                assert_eq!(search.found(), Some(cooldown), "from {guess}s");
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use image::RgbaImage;
+
+use pb_core::Palette;
+
+// Last known color of every pixel, one palette id per byte
+pub struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Box<[u8]>,
+    // One bit per tile changed since the last sweep
+    dirty: Box<[u64]>,
+}
+
+impl Canvas {
+    pub const UNKNOWN: u8 = u8::MAX;
+    const TILE: u32 = 64;
+
+    pub fn new(width: u32, height: u32) -> Self {
+        let tiles = (width.div_ceil(Self::TILE) * height.div_ceil(Self::TILE)) as usize;
+        Self {
+            width,
+            height,
+            pixels: vec![Self::UNKNOWN; (width * height) as usize].into_boxed_slice(),
+            dirty: vec![0; tiles.div_ceil(64)].into_boxed_slice(),
+        }
+    }
+
+    fn tiles_across(&self) -> u32 {
+        self.width.div_ceil(Self::TILE)
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> u8 {
+        if x >= self.width || y >= self.height {
+            return Self::UNKNOWN;
+        }
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, color_id: u8) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        self.pixels[(y * self.width + x) as usize] = color_id;
+        let tile = ((y / Self::TILE) * self.tiles_across() + x / Self::TILE) as usize;
+        self.dirty[tile / 64] |= 1 << (tile % 64);
+    }
+
+    // Maps every known pixel to another id, as after a palette change
+    pub fn recolor(&mut self, recolor: impl Fn(u8) -> u8) {
+        for color_id in self.pixels.iter_mut().filter(|id| **id != Self::UNKNOWN) {
+            *color_id = recolor(*color_id);
+        }
+    }
+
+    // Pixels never seen stay transparent
+    pub fn to_image(&self, palette: &Palette) -> RgbaImage {
+        RgbaImage::from_fn(self.width, self.height, |x, y| {
+            match palette.rgb_of(self.get(x, y)) {
+                Some((r, g, b)) => image::Rgba([r, g, b, 255]),
+                None => image::Rgba([0, 0, 0, 0]),
+            }
+        })
+    }
+
+    // Clears and yields the dirty tiles overlapping `area`, each clipped to it
+    pub fn sweep(&mut self, area: Area) -> Vec<Area> {
+        let mut swept = Vec::new();
+        if area.is_empty() {
+            return swept;
+        }
+        let across = self.tiles_across();
+        for tile_y in area.y0 / Self::TILE..=(area.y1 - 1) / Self::TILE {
+            for tile_x in area.x0 / Self::TILE..=(area.x1 - 1) / Self::TILE {
+                let tile = (tile_y * across + tile_x) as usize;
+                let Some(word) = self.dirty.get_mut(tile / 64) else {
+                    continue;
+                };
+                if *word & (1 << (tile % 64)) == 0 {
+                    continue;
+                }
+                *word &= !(1 << (tile % 64));
+                swept.push(Area {
+                    x0: (tile_x * Self::TILE).max(area.x0),
+                    y0: (tile_y * Self::TILE).max(area.y0),
+                    x1: ((tile_x + 1) * Self::TILE).min(area.x1),
+                    y1: ((tile_y + 1) * Self::TILE).min(area.y1),
+                });
+            }
+        }
+        swept
+    }
+}
+
+// Half-open rectangle of pixels
+#[derive(Clone, Copy, Default)]
+pub struct Area {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl Area {
+    pub fn is_empty(&self) -> bool {
+        self.x0 >= self.x1 || self.y0 >= self.y1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(x0: u32, y0: u32, x1: u32, y1: u32) -> Area {
+        Area { x0, y0, x1, y1 }
+    }
+
+    fn corners(areas: Vec<Area>) -> Vec<(u32, u32, u32, u32)> {
+        areas
+            .into_iter()
+            .map(|area| (area.x0, area.y0, area.x1, area.y1))
+            .collect()
+    }
+
+    #[test]
+    fn pixels_start_unknown_and_keep_what_is_set() {
+        let mut canvas = Canvas::new(100, 50);
+        assert_eq!(canvas.get(3, 4), Canvas::UNKNOWN);
+        canvas.set(3, 4, 7);
+        assert_eq!(canvas.get(3, 4), 7);
+        // Outside the canvas nothing is stored
+        canvas.set(100, 4, 7);
+        assert_eq!(canvas.get(100, 4), Canvas::UNKNOWN);
+    }
+
+    #[test]
+    fn sweeps_yield_changed_tiles_once_clipped_to_the_area() {
+        let mut canvas = Canvas::new(1590, 400);
+        canvas.set(10, 10, 1);
+        canvas.set(70, 10, 1);
+        canvas.set(1000, 300, 1);
+        assert_eq!(
+            corners(canvas.sweep(area(5, 0, 100, 40))),
+            [(5, 0, 64, 40), (64, 0, 100, 40)]
+        );
+        assert!(canvas.sweep(area(5, 0, 100, 40)).is_empty());
+        // Tiles outside the area stay dirty for a later, larger sweep
+        assert_eq!(
+            corners(canvas.sweep(area(0, 0, 1590, 400))),
+            [(960, 256, 1024, 320)]
+        );
+        assert!(canvas.sweep(area(0, 0, 0, 0)).is_empty());
+    }
+
+    #[test]
+    fn recoloring_leaves_unknown_pixels_alone() {
+        let mut canvas = Canvas::new(4, 1);
+        canvas.set(0, 0, 1);
+        canvas.set(1, 0, 2);
+        canvas.recolor(|id| id + 10);
+        let ids = (0..4).map(|x| canvas.get(x, 0)).collect::<Vec<_>>();
+        assert_eq!(ids, [11, 12, Canvas::UNKNOWN, Canvas::UNKNOWN]);
+    }
+
+    #[test]
+    fn unknown_pixels_are_transparent_in_images() {
+        let palette = Palette::default();
+        let mut canvas = Canvas::new(2, 1);
+        canvas.set(0, 0, 0);
+        let image = canvas.to_image(&palette);
+        let (r, g, b) = palette.rgb_of(0).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [r, g, b, 255]);
+        assert_eq!(image.get_pixel(1, 0).0, [0, 0, 0, 0]);
+    }
+}
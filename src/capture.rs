@@ -0,0 +1,592 @@
+use std::{
+    cmp,
+    collections::{BTreeMap, HashMap},
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use async_tungstenite::tungstenite::Message;
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, task::JoinHandle, time};
+
+use crate::canvas::Canvas;
+use crate::compress;
+use crate::{protocol, save_image, Context, PixelProvider};
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+// One websocket frame per line of the capture file
+#[derive(Serialize, Deserialize)]
+struct Record {
+    timestamp_ms: u64,
+    worker: i32,
+    direction: Direction,
+    opcode: String,
+    payload: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct Capture {
+    sender: mpsc::Sender<Record>,
+    dropped: Arc<AtomicU32>,
+}
+
+impl Capture {
+    const CAPACITY: usize = 1024;
+
+    pub fn start(path: &PathBuf) -> anyhow::Result<(Self, JoinHandle<()>)> {
+        let mut file = compress::create(path, compress::wanted(path))?;
+        let (sender, mut receiver) = mpsc::channel::<Record>(Self::CAPACITY);
+        let path = path.clone();
+        let runtime = tokio::runtime::Handle::current();
+        let writer = tokio::task::spawn_blocking(move || {
+            let mut flushed = Instant::now();
+            let mut unflushed = false;
+            loop {
+                if unflushed && flushed.elapsed() >= compress::FLUSH_INTERVAL {
+                    if let Err(why) = file.flush() {
+                        error!("Cannot write capture to {}: {why}", path.display());
+                        return;
+                    }
+                    (flushed, unflushed) = (Instant::now(), false);
+                }
+                let record = if unflushed {
+                    // A quiet spell still gets what came before it onto disk in time
+                    let due = flushed + compress::FLUSH_INTERVAL;
+                    match runtime.block_on(time::timeout_at(due.into(), receiver.recv())) {
+                        Ok(record) => record,
+                        Err(_) => continue,
+                    }
+                } else {
+                    receiver.blocking_recv()
+                };
+                let Some(record) = record else {
+                    break;
+                };
+                let written = serde_json::to_writer(&mut file, &record)
+                    .map_err(io::Error::from)
+                    .and_then(|()| writeln!(file));
+                if let Err(why) = written {
+                    error!("Cannot write capture to {}: {why}", path.display());
+                    return;
+                }
+                unflushed = true;
+            }
+            drop(file.flush());
+        });
+        let capture = Self {
+            sender,
+            dropped: Arc::new(AtomicU32::new(0)),
+        };
+        Ok((capture, writer))
+    }
+
+    pub fn record(&self, worker: i32, direction: Direction, msg: &Message) {
+        let opcode = match msg {
+            Message::Text(_) => "text",
+            Message::Binary(_) => "binary",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::Close(_) => "close",
+            Message::Frame(_) => "frame",
+        };
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let record = Record {
+            timestamp_ms,
+            worker,
+            direction,
+            opcode: opcode.into(),
+            payload: msg.clone().into_data(),
+        };
+        // Never hold up a worker for the capture
+        if self.sender.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+// Captures do not record the run's config, so these read them with the default codec and palette
+pub fn dump(path: &PathBuf) -> anyhow::Result<()> {
+    let context = Context::default();
+    for line in compress::open(path)?.lines() {
+        let record: Record = serde_json::from_str(&line?)?;
+        let arrow = match record.direction {
+            Direction::Inbound => "<-",
+            Direction::Outbound => "->",
+        };
+        println!(
+            "{} #{} {arrow} {} {}",
+            record.timestamp_ms,
+            record.worker,
+            record.opcode,
+            protocol::hexdump(&record.payload)
+        );
+        if record.opcode == "binary" {
+            for pixel in context.decode(&record.payload) {
+                println!("    {{{}:{}}} color {}", pixel.x, pixel.y, pixel.color_id);
+            }
+        } else if record.opcode == "text" {
+            println!("    {}", String::from_utf8_lossy(&record.payload));
+        }
+    }
+    Ok(())
+}
+
+// Feeds the recorded inbound frames through the decoder and a canvas, as fast as they load
+pub fn replay(path: &PathBuf, out: Option<PathBuf>) -> anyhow::Result<()> {
+    let context = Context::default();
+    let (
+        canvas,
+        Replayed {
+            decoded,
+            unknown,
+            applied,
+        },
+    ) = rebuild(path, &context)?;
+    println!("{decoded} frames decoded, {unknown} unknown, {applied} pixels applied");
+    if let Some(out) = out {
+        save_image(&canvas.to_image(&context.palette()), &out)?;
+        println!("Saved the reconstructed canvas to {}", out.display());
+    }
+    Ok(())
+}
+
+#[derive(Default, PartialEq, Debug)]
+struct Replayed {
+    decoded: u32,
+    unknown: u32,
+    applied: u32,
+}
+
+fn rebuild(path: &PathBuf, context: &Context) -> anyhow::Result<(Canvas, Replayed)> {
+    let mut canvas = Canvas::new(PixelProvider::MAX_WIDTH, PixelProvider::MAX_HEIGHT);
+    let mut replayed = Replayed::default();
+    for line in compress::open(path)?.lines() {
+        let record: Record = serde_json::from_str(&line?)?;
+        if !matches!(record.direction, Direction::Inbound) || record.opcode != "binary" {
+            continue;
+        }
+        let pixels = context.decode_updates(&record.payload);
+        if pixels.is_empty() {
+            replayed.unknown += 1;
+            continue;
+        }
+        replayed.decoded += 1;
+        for pixel in pixels {
+            canvas.set(pixel.x, pixel.y, pixel.color_id);
+            replayed.applied += 1;
+        }
+    }
+    Ok((canvas, replayed))
+}
+
+// A paint counts as failed when no broadcast shows it within this long
+const ECHO_WINDOW_MS: u64 = 30_000;
+const MOST_REPAINTED: usize = 10;
+
+#[derive(Serialize, Default)]
+struct Stats {
+    records: u64,
+    first_ms: u64,
+    last_ms: u64,
+    paints: u64,
+    failed: u64,
+    workers: Vec<WorkerStats>,
+    hours: Vec<HourStats>,
+    most_repainted: Vec<Repainted>,
+    // The last line was cut off, most likely by a crash, and left out
+    truncated: bool,
+}
+
+#[derive(Serialize, Clone, Copy, Default)]
+struct WorkerStats {
+    worker: i32,
+    paints: u64,
+    failed: u64,
+}
+
+#[derive(Serialize)]
+struct HourStats {
+    hour: String,
+    paints: u64,
+}
+
+#[derive(Serialize)]
+struct Repainted {
+    x: u32,
+    y: u32,
+    paints: u32,
+}
+
+pub fn stats(path: &PathBuf, json: bool) -> anyhow::Result<()> {
+    let stats = summarize(path)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else {
+        print_stats(&stats);
+    }
+    Ok(())
+}
+
+// Reads one line at a time, so memory stays bounded by the canvas and the run length
+fn summarize(path: &PathBuf) -> anyhow::Result<Stats> {
+    let context = Context::default();
+    let mut stats = Stats::default();
+    let mut workers = HashMap::<i32, WorkerStats>::new();
+    let mut hours = BTreeMap::<u64, u64>::new();
+    let mut paints_at = HashMap::<(u32, u32), u32>::new();
+    // Sent but not seen broadcast yet, by position and color
+    let mut pending = HashMap::<(u32, u32, u8), (u64, i32)>::new();
+    let mut broken = None;
+    for (number, line) in compress::open(path)?.lines().enumerate() {
+        if let Some((number, why)) = broken.take() {
+            Err(anyhow!("{} line {number}: {why}", path.display()))?
+        }
+        let record: Record = match serde_json::from_str(&line?) {
+            Ok(record) => record,
+            Err(why) => {
+                broken = Some((number + 1, why));
+                continue;
+            }
+        };
+        stats.records += 1;
+        if stats.first_ms == 0 {
+            stats.first_ms = record.timestamp_ms;
+        }
+        stats.last_ms = stats.last_ms.max(record.timestamp_ms);
+        if record.opcode != "binary" {
+            continue;
+        }
+        match record.direction {
+            Direction::Outbound => {
+                let mut failed = Vec::new();
+                let worker = workers.entry(record.worker).or_insert(WorkerStats {
+                    worker: record.worker,
+                    ..WorkerStats::default()
+                });
+                for pixel in context.decode(&record.payload) {
+                    worker.paints += 1;
+                    *hours.entry(record.timestamp_ms / HOUR_MS).or_default() += 1;
+                    *paints_at.entry((pixel.x, pixel.y)).or_default() += 1;
+                    let earlier = pending.insert(
+                        (pixel.x, pixel.y, pixel.color_id),
+                        (record.timestamp_ms, record.worker),
+                    );
+                    // A resend within the window shares the echo with the first send
+                    if let Some((sent, worker)) = earlier {
+                        if record.timestamp_ms.saturating_sub(sent) > ECHO_WINDOW_MS {
+                            failed.push(worker);
+                        }
+                    }
+                }
+                for worker in failed {
+                    workers.entry(worker).or_default().failed += 1;
+                }
+            }
+            Direction::Inbound => {
+                for pixel in context.decode_updates(&record.payload) {
+                    pending.remove(&(pixel.x, pixel.y, pixel.color_id));
+                }
+            }
+        }
+        if stats.records % 1024 == 0 {
+            let now = record.timestamp_ms;
+            pending.retain(|_, &mut (sent, worker)| {
+                let expired = now.saturating_sub(sent) > ECHO_WINDOW_MS;
+                if expired {
+                    workers.entry(worker).or_default().failed += 1;
+                }
+                !expired
+            });
+        }
+    }
+    if broken.is_some() {
+        stats.truncated = true;
+    }
+    // Whatever is left never came back before the capture ended
+    for (_, (sent, worker)) in pending {
+        if stats.last_ms.saturating_sub(sent) > ECHO_WINDOW_MS {
+            workers.entry(worker).or_default().failed += 1;
+        }
+    }
+    stats.workers = workers.into_values().collect();
+    stats.workers.sort_by_key(|worker| worker.worker);
+    stats.paints = stats.workers.iter().map(|w| w.paints).sum();
+    stats.failed = stats.workers.iter().map(|w| w.failed).sum();
+    stats.hours = hours
+        .into_iter()
+        .map(|(hour, paints)| HourStats {
+            hour: utc_hour(hour),
+            paints,
+        })
+        .collect();
+    let mut repainted = paints_at
+        .into_iter()
+        .filter(|&(_, paints)| paints > 1)
+        .collect::<Vec<_>>();
+    repainted.sort_by_key(|&((x, y), paints)| (cmp::Reverse(paints), y, x));
+    stats.most_repainted = repainted
+        .into_iter()
+        .take(MOST_REPAINTED)
+        .map(|((x, y), paints)| Repainted { x, y, paints })
+        .collect();
+    Ok(stats)
+}
+
+fn print_stats(stats: &Stats) {
+    if stats.truncated {
+        println!("The last line is cut off and was left out.");
+    }
+    println!(
+        "{} records over {:.1}h, {} paints, {} never echoed ({})",
+        stats.records,
+        stats.last_ms.saturating_sub(stats.first_ms) as f64 / HOUR_MS as f64,
+        stats.paints,
+        stats.failed,
+        percent(stats.failed, stats.paints)
+    );
+    println!("Paints per worker:");
+    for worker in &stats.workers {
+        println!(
+            "  #{:<4} {:>8}  {:>6} failed ({})",
+            worker.worker,
+            worker.paints,
+            worker.failed,
+            percent(worker.failed, worker.paints)
+        );
+    }
+    println!("Paints per hour (UTC):");
+    let busiest = stats.hours.iter().map(|h| h.paints).max().unwrap_or(1);
+    for hour in &stats.hours {
+        let bar = "#".repeat((hour.paints * 40).div_ceil(busiest) as usize);
+        println!("  {} {:>8} {bar}", hour.hour, hour.paints);
+    }
+    if !stats.most_repainted.is_empty() {
+        println!("Most repainted:");
+        for pixel in &stats.most_repainted {
+            println!("  {{{}:{}}} {} times", pixel.x, pixel.y, pixel.paints);
+        }
+    }
+}
+
+fn percent(part: u64, whole: u64) -> String {
+    if whole == 0 {
+        return "-".into();
+    }
+    format!("{:.1}%", part as f64 / whole as f64 * 100.0)
+}
+
+const HOUR_MS: u64 = 60 * 60 * 1000;
+
+// Like 2024-04-01 18:00, from hours since the epoch
+fn utc_hour(hour: u64) -> String {
+    let days = (hour / 24) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02} {:02}:00", hour % 24)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::tests::scratch;
+    use crate::PixelInfo;
+
+    // 2024-04-01 18:00 UTC
+    const EVENING_MS: u64 = 1_711_994_400_000;
+
+    fn paint(timestamp_ms: u64, worker: i32, direction: Direction, pixels: &[(u32, u8)]) -> Record {
+        let context = Context::default();
+        let payload = pixels
+            .iter()
+            .flat_map(|&(x, color_id)| context.pack(PixelInfo { x, y: 0, color_id }).unwrap())
+            .collect();
+        Record {
+            timestamp_ms,
+            worker,
+            direction,
+            opcode: "binary".into(),
+            payload,
+        }
+    }
+
+    fn text(timestamp_ms: u64, direction: Direction) -> Record {
+        Record {
+            timestamp_ms,
+            worker: 0,
+            direction,
+            opcode: "text".into(),
+            payload: b"{}".to_vec(),
+        }
+    }
+
+    fn write(name: &str, records: &[Record]) -> PathBuf {
+        let path = scratch(name);
+        let lines = records
+            .iter()
+            .map(|record| serde_json::to_string(record).unwrap() + "\n")
+            .collect::<String>();
+        fs::write(&path, lines).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn every_frame_becomes_a_line() {
+        let path = scratch("capture.jsonl");
+        let (capture, writer) = Capture::start(&path).unwrap();
+        capture.record(2, Direction::Outbound, &Message::Binary(vec![1, 2, 3]));
+        capture.record(2, Direction::Inbound, &Message::Text("hello".into()));
+        capture.record(5, Direction::Inbound, &Message::Ping(Vec::new()));
+        assert_eq!(capture.dropped(), 0);
+        drop(capture);
+        writer.await.unwrap();
+        let records = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Record>(line).unwrap())
+            .collect::<Vec<_>>();
+        let summary = records
+            .iter()
+            .map(|r| (r.worker, r.opcode.as_str(), r.payload.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (2, "binary", vec![1, 2, 3]),
+                (2, "text", b"hello".to_vec()),
+                (5, "ping", Vec::new()),
+            ]
+        );
+        assert!(matches!(records[0].direction, Direction::Outbound));
+        assert!(records.iter().all(|record| record.timestamp_ms > 0));
+    }
+
+    #[test]
+    fn replay_applies_only_received_updates() {
+        let path = write(
+            "replay.jsonl",
+            &[
+                paint(0, 0, Direction::Outbound, &[(7, 3)]),
+                paint(1, 0, Direction::Inbound, &[(1, 2), (2, 4)]),
+                text(2, Direction::Inbound),
+                Record {
+                    payload: vec![0xff; 3],
+                    ..paint(3, 0, Direction::Inbound, &[])
+                },
+            ],
+        );
+        let (canvas, replayed) = rebuild(&path, &Context::default()).unwrap();
+        assert_eq!(
+            replayed,
+            Replayed {
+                decoded: 1,
+                unknown: 1,
+                applied: 2,
+            }
+        );
+        assert_eq!((canvas.get(1, 0), canvas.get(2, 0)), (2, 4));
+        assert_eq!(canvas.get(7, 0), Canvas::UNKNOWN);
+    }
+
+    #[test]
+    fn paints_never_echoed_count_as_failed() {
+        let at = |secs: u64| EVENING_MS + secs * 1000;
+        let path = write(
+            "stats.jsonl",
+            &[
+                paint(at(0), 0, Direction::Outbound, &[(1, 2)]),
+                paint(at(0), 1, Direction::Outbound, &[(2, 2)]),
+                paint(at(1), 0, Direction::Inbound, &[(1, 2)]),
+                paint(at(2), 0, Direction::Outbound, &[(1, 3)]),
+                paint(at(3), 0, Direction::Inbound, &[(1, 3)]),
+                text(at(40), Direction::Inbound),
+            ],
+        );
+        let stats = summarize(&path).unwrap();
+        assert_eq!((stats.records, stats.paints, stats.failed), (6, 3, 1));
+        let workers = stats
+            .workers
+            .iter()
+            .map(|w| (w.worker, w.paints, w.failed))
+            .collect::<Vec<_>>();
+        assert_eq!(workers, [(0, 2, 0), (1, 1, 1)]);
+        let hours = stats
+            .hours
+            .iter()
+            .map(|h| (h.hour.as_str(), h.paints))
+            .collect::<Vec<_>>();
+        assert_eq!(hours, [("2024-04-01 18:00", 3)]);
+        let repainted = stats
+            .most_repainted
+            .iter()
+            .map(|p| (p.x, p.y, p.paints))
+            .collect::<Vec<_>>();
+        assert_eq!(repainted, [(1, 0, 2)]);
+        assert!(!stats.truncated);
+    }
+
+    #[test]
+    fn only_the_last_line_may_be_cut_off() {
+        let path = write("torn.jsonl", &[paint(0, 0, Direction::Outbound, &[(1, 2)])]);
+        let mut lines = fs::read_to_string(&path).unwrap();
+        lines.push_str(r#"{"timestamp_ms":1,"wor"#);
+        fs::write(&path, &lines).unwrap();
+        let stats = summarize(&path).unwrap();
+        assert!(stats.truncated);
+        assert_eq!((stats.records, stats.paints), (1, 1));
+        lines.push('\n');
+        lines.push_str(&serde_json::to_string(&text(2, Direction::Inbound)).unwrap());
+        fs::write(&path, &lines).unwrap();
+        let why = summarize(&path).err().unwrap().to_string();
+        assert!(
+            why.starts_with(&format!("{} line 2: ", path.display())),
+            "{why}"
+        );
+    }
+
+    #[test]
+    fn hours_read_as_utc_dates() {
+        assert_eq!(utc_hour(0), "1970-01-01 00:00");
+        assert_eq!(utc_hour(EVENING_MS / HOUR_MS), "2024-04-01 18:00");
+        // Leap day, and the first hour of the following year
+        assert_eq!(
+            utc_hour(EVENING_MS / HOUR_MS - 32 * 24 - 18),
+            "2024-02-29 00:00"
+        );
+        assert_eq!(utc_hour(473_352), "2024-01-01 00:00");
+        assert_eq!(percent(1, 3), "33.3%");
+        assert_eq!(percent(0, 0), "-");
+    }
+}
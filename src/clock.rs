@@ -0,0 +1,114 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use log::*;
+use tokio::sync::watch;
+
+// Where schedule windows and the deadline read the time of day; cooldowns use Instant instead
+pub trait WallClock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl WallClock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// Notices the wall clock being stepped by comparing how far it moved with how far Instant did
+pub struct JumpDetector<C> {
+    clock: C,
+    threshold: Duration,
+    wall: SystemTime,
+    monotonic: Instant,
+}
+
+impl<C: WallClock> JumpDetector<C> {
+    pub fn new(clock: C, threshold: Duration) -> Self {
+        Self {
+            wall: clock.now(),
+            monotonic: Instant::now(),
+            clock,
+            threshold,
+        }
+    }
+
+    // Seconds the wall clock moved beyond the elapsed time since the last check, if over the threshold
+    pub fn check(&mut self) -> Option<f64> {
+        let (wall, monotonic) = (self.clock.now(), Instant::now());
+        let elapsed = monotonic.duration_since(self.monotonic).as_secs_f64();
+        let moved = match wall.duration_since(self.wall) {
+            Ok(forward) => forward.as_secs_f64(),
+            Err(backward) => -backward.duration().as_secs_f64(),
+        };
+        (self.wall, self.monotonic) = (wall, monotonic);
+        let jump = moved - elapsed;
+        (jump.abs() > self.threshold.as_secs_f64()).then_some(jump)
+    }
+}
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Wakes everything waiting on a wall-clock time so it recomputes after a jump
+pub async fn watch_jumps(threshold: Duration, jumped: watch::Sender<()>) {
+    let mut detector = JumpDetector::new(SystemClock, threshold);
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Some(jump) = detector.check() {
+            let direction = if jump > 0.0 { "forward" } else { "back" };
+            warn!(
+                "System clock jumped {direction} by {:.1}s; recomputing schedule windows and the deadline",
+                jump.abs()
+            );
+            jumped.send_replace(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct Manual(Arc<Mutex<SystemTime>>);
+
+    impl Manual {
+        fn step(&self, by: f64) {
+            let mut now = self.0.lock().unwrap();
+            *now = if by >= 0.0 {
+                *now + Duration::from_secs_f64(by)
+            } else {
+                *now - Duration::from_secs_f64(-by)
+            };
+        }
+    }
+
+    impl WallClock for Manual {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn steps_past_the_threshold_are_reported_with_their_direction() {
+        let clock = Manual(Arc::new(Mutex::new(SystemTime::now())));
+        let mut detector = JumpDetector::new(clock.clone(), Duration::from_secs(30));
+        assert_eq!(detector.check(), None);
+        clock.step(10.0);
+        assert_eq!(detector.check(), None);
+        clock.step(3600.0);
+        assert!(detector
+            .check()
+            .is_some_and(|jump| (jump - 3600.0).abs() < 1.0));
+        // Measured from where the clock is now, so the same jump is not reported again
+        assert_eq!(detector.check(), None);
+        clock.step(-120.0);
+        assert!(detector
+            .check()
+            .is_some_and(|jump| (jump + 120.0).abs() < 1.0));
+    }
+}
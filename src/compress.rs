@@ -0,0 +1,275 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, Write},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+
+// Long runs flush captures this often, so a crash loses at most this much
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+const MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+// Files named like this are written through zstd
+pub fn wanted(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .extension()
+        .is_some_and(|extension| extension == "zst")
+}
+
+pub fn check(path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    if wanted(path) && !cfg!(feature = "compression") {
+        Err(anyhow!(
+            "{} ends in .zst but pb was built without the compression feature",
+            path.display()
+        ))?
+    }
+    Ok(())
+}
+
+// Flushing ends a zstd frame, so everything flushed stays readable after a crash
+pub fn create(path: impl AsRef<Path>, compressed: bool) -> io::Result<Box<dyn Write + Send>> {
+    let path = path.as_ref();
+    let file = io::BufWriter::new(File::create(path)?);
+    if !compressed {
+        return Ok(Box::new(file));
+    }
+    #[cfg(feature = "compression")]
+    return Ok(Box::new(zstd_frames::Writer::new(file)?));
+    #[cfg(not(feature = "compression"))]
+    Err(unsupported(path))
+}
+
+// Plain or zstd, told apart by the magic bytes rather than the name
+pub fn open(path: impl AsRef<Path>) -> io::Result<Box<dyn BufRead + Send>> {
+    let path = path.as_ref();
+    let mut file = io::BufReader::new(File::open(path)?);
+    if !file.fill_buf()?.starts_with(&MAGIC) {
+        return Ok(Box::new(file));
+    }
+    #[cfg(feature = "compression")]
+    return Ok(Box::new(io::BufReader::new(zstd_frames::Reader::new(
+        file, path,
+    ))));
+    #[cfg(not(feature = "compression"))]
+    Err(unsupported(path))
+}
+
+#[cfg(not(feature = "compression"))]
+fn unsupported(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} is zstd compressed but pb was built without the compression feature",
+            path.display()
+        ),
+    )
+}
+
+#[cfg(feature = "compression")]
+mod zstd_frames {
+    use std::{
+        fs::File,
+        io::{self, BufRead, Read, Write},
+        path::{Path, PathBuf},
+    };
+
+    use log::*;
+    use zstd::stream::{read, write::Encoder};
+
+    // Frames end every flush, a couple of seconds of captures, so anything far bigger is corrupt
+    const MAX_FRAME: u64 = 64 << 20;
+
+    pub struct Writer {
+        encoder: Option<Encoder<'static, io::BufWriter<File>>>,
+        // Whether the open frame holds anything
+        written: bool,
+    }
+
+    impl Writer {
+        pub fn new(file: io::BufWriter<File>) -> io::Result<Self> {
+            Ok(Self {
+                encoder: Some(Encoder::new(file, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+                written: false,
+            })
+        }
+
+        fn encoder(&mut self) -> io::Result<&mut Encoder<'static, io::BufWriter<File>>> {
+            self.encoder.as_mut().ok_or_else(broken)
+        }
+    }
+
+    fn broken() -> io::Error {
+        io::Error::new(io::ErrorKind::BrokenPipe, "an earlier zstd frame failed")
+    }
+
+    impl Write for Writer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let written = self.encoder()?.write(buf)?;
+            self.written |= written > 0;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            if !self.written {
+                return self.encoder()?.get_mut().flush();
+            }
+            let Some(encoder) = self.encoder.take() else {
+                return Err(broken());
+            };
+            let mut file = encoder.finish()?;
+            file.flush()?;
+            self.encoder = Some(Encoder::new(file, zstd::DEFAULT_COMPRESSION_LEVEL)?);
+            self.written = false;
+            Ok(())
+        }
+    }
+
+    impl Drop for Writer {
+        fn drop(&mut self) {
+            drop(self.flush());
+        }
+    }
+
+    // Decodes a frame at a time and only hands out whole frames, so a torn one is dropped entirely
+    pub struct Reader {
+        input: io::BufReader<File>,
+        frame: io::Cursor<Vec<u8>>,
+        path: PathBuf,
+        done: bool,
+    }
+
+    impl Reader {
+        pub fn new(input: io::BufReader<File>, path: &Path) -> Self {
+            Self {
+                input,
+                frame: io::Cursor::default(),
+                path: path.to_path_buf(),
+                done: false,
+            }
+        }
+
+        fn next_frame(&mut self) -> io::Result<()> {
+            if self.input.fill_buf()?.is_empty() {
+                self.done = true;
+                return Ok(());
+            }
+            let mut frame = Vec::new();
+            let decoded = read::Decoder::with_buffer(&mut self.input)
+                .and_then(|decoder| {
+                    decoder
+                        .single_frame()
+                        .take(MAX_FRAME + 1)
+                        .read_to_end(&mut frame)
+                })
+                .and_then(|read| {
+                    if read as u64 > MAX_FRAME {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("frame inflates past {MAX_FRAME} bytes"),
+                        ));
+                    }
+                    Ok(read)
+                });
+            match decoded {
+                Ok(_) => self.frame = io::Cursor::new(frame),
+                Err(why) => {
+                    warn!(
+                        "{}: ignoring a damaged zstd frame and everything after it, likely left by a crash: {why}",
+                        self.path.display()
+                    );
+                    self.done = true;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Read for Reader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                let read = self.frame.read(buf)?;
+                if read > 0 || self.done || buf.is_empty() {
+                    return Ok(read);
+                }
+                self.next_frame()?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+
+    use super::*;
+    use crate::tests::scratch;
+
+    fn read(path: &Path) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        open(path)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    #[test]
+    fn plain_files_round_trip() {
+        let path = scratch("compress-plain.bin");
+        let mut writer = create(&path, wanted(&path)).unwrap();
+        writer.write_all(b"plain").unwrap();
+        drop(writer);
+        assert_eq!(read(&path).unwrap(), b"plain");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn only_zst_names_are_compressed() {
+        assert!(wanted("capture.bin.zst"));
+        assert!(!wanted("capture.bin"));
+        assert!(!wanted("zst"));
+        assert_eq!(check("state.zst").is_ok(), cfg!(feature = "compression"));
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn compressed_files_need_the_feature() {
+        let path = scratch("compress-unsupported.zst");
+        fs::write(&path, MAGIC).unwrap();
+        assert_eq!(read(&path).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_files_round_trip_frame_by_frame() {
+        let path = scratch("compress-frames.zst");
+        let mut writer = create(&path, wanted(&path)).unwrap();
+        writer.write_all(b"first ").unwrap();
+        writer.flush().unwrap();
+        writer.flush().unwrap();
+        writer.write_all(b"second").unwrap();
+        drop(writer);
+        assert!(fs::read(&path).unwrap().starts_with(&MAGIC));
+        assert_eq!(read(&path).unwrap(), b"first second");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn a_torn_last_frame_is_dropped() {
+        let path = scratch("compress-torn.zst");
+        let mut writer = create(&path, true).unwrap();
+        writer.write_all(b"kept").unwrap();
+        writer.flush().unwrap();
+        let whole = fs::metadata(&path).unwrap().len();
+        writer.write_all(&[7; 4096]).unwrap();
+        drop(writer);
+        // As if the process died halfway through writing the second frame
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(whole + 8).unwrap();
+        assert_eq!(read(&path).unwrap(), b"kept");
+        fs::remove_file(path).unwrap();
+    }
+}
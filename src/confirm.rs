@@ -0,0 +1,130 @@
+use std::{io::IsTerminal, sync::Arc, time::Duration};
+
+use log::*;
+use tokio::sync::watch;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Decision {
+    Pending,
+    Confirmed,
+    TimedOut,
+}
+
+// Holds the first paint of a big run back until an operator types yes or sends /confirm
+#[derive(Clone)]
+pub struct Gate {
+    decision: Arc<watch::Sender<Decision>>,
+}
+
+impl Gate {
+    // Gives up after `timeout`; a terminal on stdin is read for a yes until then
+    pub fn start(timeout: Duration) -> Self {
+        let (decision, _) = watch::channel(Decision::Pending);
+        let gate = Self {
+            decision: Arc::new(decision),
+        };
+        // A blocking read would hold up runtime shutdown, so stdin gets a thread of its own
+        if std::io::stdin().is_terminal() {
+            let gate = gate.clone();
+            std::thread::spawn(move || {
+                for line in std::io::stdin().lines() {
+                    let Ok(line) = line else {
+                        return;
+                    };
+                    if line.trim().eq_ignore_ascii_case("yes") {
+                        gate.confirm("on stdin");
+                        return;
+                    }
+                    if gate.is_decided() {
+                        return;
+                    }
+                    info!("Type yes to start painting.");
+                }
+            });
+        }
+        tokio::spawn({
+            let gate = gate.clone();
+            async move {
+                tokio::time::sleep(timeout).await;
+                gate.decision.send_if_modified(|decision| {
+                    let pending = *decision == Decision::Pending;
+                    if pending {
+                        *decision = Decision::TimedOut;
+                    }
+                    pending
+                });
+            }
+        });
+        gate
+    }
+
+    // False if it was already decided
+    pub fn confirm(&self, how: &str) -> bool {
+        let confirmed = self.decision.send_if_modified(|decision| {
+            let pending = *decision == Decision::Pending;
+            if pending {
+                *decision = Decision::Confirmed;
+            }
+            pending
+        });
+        if confirmed {
+            info!("Painting confirmed {how}");
+        }
+        confirmed
+    }
+
+    fn is_decided(&self) -> bool {
+        *self.decision.borrow() != Decision::Pending
+    }
+
+    // True once confirmed, false once timed out
+    pub async fn decided(&self) -> bool {
+        let mut decision = self.decision.subscribe();
+        loop {
+            match *decision.borrow_and_update() {
+                Decision::Pending => {}
+                decided => return decided == Decision::Confirmed,
+            }
+            if decision.changed().await.is_err() {
+                return false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(300);
+
+    #[tokio::test(start_paused = true)]
+    async fn a_confirmation_opens_the_gate_once() {
+        let gate = Gate::start(TIMEOUT);
+        assert!(!gate.is_decided());
+        assert!(gate.confirm("in a test"));
+        assert!(!gate.confirm("in a test"));
+        assert!(gate.decided().await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn nobody_confirming_times_out() {
+        let gate = Gate::start(TIMEOUT);
+        let started = tokio::time::Instant::now();
+        assert!(!gate.decided().await);
+        assert_eq!(started.elapsed(), TIMEOUT);
+        assert!(!gate.confirm("too late"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiters_hear_of_a_later_confirmation() {
+        let gate = Gate::start(TIMEOUT);
+        let waiter = tokio::spawn({
+            let gate = gate.clone();
+            async move { gate.decided().await }
+        });
+        tokio::time::sleep(TIMEOUT / 2).await;
+        gate.confirm("in a test");
+        assert!(waiter.await.unwrap());
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::distributions::uniform::{UniformDuration, UniformSampler};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use tokio::time::Instant;
+
+// What a bot knows about its last paint when picking the next wait
+pub struct CooldownContext {
+    pub succeeded: bool,
+    pub server_wait: Option<Duration>,
+}
+
+pub trait Cooldown {
+    fn next_duration(&mut self, ctx: &CooldownContext) -> Duration;
+}
+
+pub struct UniformCooldown {
+    rng: StdRng,
+    uniform: UniformDuration,
+}
+
+impl UniformCooldown {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            rng: StdRng::from_entropy(),
+            uniform: UniformDuration::new_inclusive(min, max),
+        }
+    }
+}
+
+impl Cooldown for UniformCooldown {
+    fn next_duration(&mut self, ctx: &CooldownContext) -> Duration {
+        let sampled = self.uniform.sample(&mut self.rng);
+        ctx.server_wait.map_or(sampled, |wait| wait.max(sampled))
+    }
+}
+
+pub struct FixedCooldown(pub Duration);
+
+impl Cooldown for FixedCooldown {
+    fn next_duration(&mut self, ctx: &CooldownContext) -> Duration {
+        ctx.server_wait.map_or(self.0, |wait| wait.max(self.0))
+    }
+}
+
+pub fn from_range(min: Duration, max: Duration) -> Box<dyn Cooldown + Send> {
+    if min == max {
+        Box::new(FixedCooldown(min))
+    } else {
+        Box::new(UniformCooldown::new(min, max))
+    }
+}
+
+// Cooldowns stretched by a factor easing linearly from `start` to 1 over `duration`
+pub struct RampUp {
+    started: Instant,
+    duration: Duration,
+    start: f64,
+}
+
+impl RampUp {
+    pub fn new(duration: Duration, start: f64) -> Self {
+        Self {
+            started: Instant::now(),
+            duration,
+            start,
+        }
+    }
+
+    pub fn factor(&self) -> f64 {
+        let progress = self.started.elapsed().as_secs_f64() / self.duration.as_secs_f64();
+        if progress >= 1.0 || !progress.is_finite() {
+            return 1.0;
+        }
+        self.start + (1.0 - self.start) * progress
+    }
+}
+
+struct Ramped {
+    inner: Box<dyn Cooldown + Send>,
+    ramp: Arc<RampUp>,
+}
+
+impl Cooldown for Ramped {
+    fn next_duration(&mut self, ctx: &CooldownContext) -> Duration {
+        self.inner.next_duration(ctx).mul_f64(self.ramp.factor())
+    }
+}
+
+pub fn ramped(
+    inner: Box<dyn Cooldown + Send>,
+    ramp: Option<Arc<RampUp>>,
+) -> Box<dyn Cooldown + Send> {
+    match ramp {
+        Some(ramp) => Box::new(Ramped { inner, ramp }),
+        None => inner,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAINTED: CooldownContext = CooldownContext {
+        succeeded: true,
+        server_wait: None,
+    };
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn equal_bounds_give_a_fixed_cooldown() {
+        let mut cooldown = from_range(secs(65), secs(65));
+        assert!((0..10).all(|_| cooldown.next_duration(&PAINTED) == secs(65)));
+    }
+
+    #[test]
+    fn uniform_cooldowns_stay_within_bounds() {
+        let mut cooldown = from_range(secs(65), secs(180));
+        for _ in 0..1000 {
+            let wait = cooldown.next_duration(&PAINTED);
+            assert!((secs(65)..=secs(180)).contains(&wait), "{wait:?}");
+        }
+    }
+
+    #[test]
+    fn a_longer_server_wait_wins() {
+        for mut cooldown in [
+            from_range(secs(65), secs(65)),
+            from_range(secs(60), secs(70)),
+        ] {
+            let told = |secs| CooldownContext {
+                succeeded: false,
+                server_wait: Some(Duration::from_secs(secs)),
+            };
+            assert_eq!(cooldown.next_duration(&told(600)), secs(600));
+            assert!(cooldown.next_duration(&told(1)) >= secs(60));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ramp_up_eases_to_the_normal_cooldown() {
+        let ramp = Arc::new(RampUp::new(secs(600), 3.0));
+        let mut cooldown = ramped(Box::new(FixedCooldown(secs(60))), Some(ramp.clone()));
+        assert_eq!(ramp.factor(), 3.0);
+        assert_eq!(cooldown.next_duration(&PAINTED), secs(180));
+        tokio::time::advance(secs(300)).await;
+        assert_eq!(ramp.factor(), 2.0);
+        assert_eq!(cooldown.next_duration(&PAINTED), secs(120));
+        tokio::time::advance(secs(300)).await;
+        assert_eq!(ramp.factor(), 1.0);
+        assert_eq!(cooldown.next_duration(&PAINTED), secs(60));
+    }
+}
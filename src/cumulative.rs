@@ -0,0 +1,122 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+// Counters summed over every run that shared the stats file
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+pub struct Totals {
+    pub runs: u32,
+    pub painted: u64,
+    pub repaints: u64,
+    pub reconnects: u64,
+    pub uptime_secs: u64,
+}
+
+pub struct Cumulative {
+    path: PathBuf,
+    // What earlier runs left in the file
+    baseline: Totals,
+    started: Instant,
+}
+
+impl Cumulative {
+    pub fn load(path: &Path) -> Self {
+        let baseline = match fs::read_to_string(path) {
+            Ok(text) => match serde_json::from_str(&text) {
+                Ok(totals) => totals,
+                Err(why) => {
+                    let backup = path.with_extension("corrupt");
+                    warn!(
+                        "{} is unreadable ({why}); moving it to {} and starting over",
+                        path.display(),
+                        backup.display()
+                    );
+                    if let Err(why) = fs::rename(path, &backup) {
+                        warn!("Cannot back up {}: {why}", path.display());
+                    }
+                    Totals::default()
+                }
+            },
+            Err(why) if why.kind() == io::ErrorKind::NotFound => Totals::default(),
+            Err(why) => {
+                warn!("Cannot read {}: {why}; starting over", path.display());
+                Totals::default()
+            }
+        };
+        Self {
+            path: path.into(),
+            baseline,
+            started: Instant::now(),
+        }
+    }
+
+    // Earlier runs plus this one so far
+    pub fn totals(&self, painted: u32, repaints: u64, reconnects: u64) -> Totals {
+        Totals {
+            runs: self.baseline.runs + 1,
+            painted: self.baseline.painted + painted as u64,
+            repaints: self.baseline.repaints + repaints,
+            reconnects: self.baseline.reconnects + reconnects,
+            uptime_secs: self.baseline.uptime_secs + self.started.elapsed().as_secs(),
+        }
+    }
+
+    pub fn save(&self, totals: &Totals) {
+        // Write aside and rename so a crash never leaves a torn file
+        let partial = self.path.with_extension("partial");
+        let saved = serde_json::to_vec_pretty(totals)
+            .map_err(io::Error::from)
+            .and_then(|json| fs::write(&partial, json))
+            .and_then(|()| fs::rename(&partial, &self.path));
+        if let Err(why) = saved {
+            warn!(
+                "Cannot save cumulative stats to {}: {why}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch;
+
+    #[test]
+    fn runs_add_up() {
+        let path = scratch("cumulative.json");
+        let first = Cumulative::load(&path);
+        let totals = first.totals(100, 7, 2);
+        assert_eq!((totals.runs, totals.painted, totals.repaints), (1, 100, 7));
+        first.save(&totals);
+        let second = Cumulative::load(&path);
+        let totals = second.totals(50, 3, 1);
+        assert_eq!(
+            (
+                totals.runs,
+                totals.painted,
+                totals.repaints,
+                totals.reconnects
+            ),
+            (2, 150, 10, 3)
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unreadable_files_are_set_aside() {
+        let path = scratch("cumulative-corrupt.json");
+        fs::write(&path, "{ not json").unwrap();
+        let cumulative = Cumulative::load(&path);
+        assert_eq!(cumulative.totals(0, 0, 0).runs, 1);
+        let backup = path.with_extension("corrupt");
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "{ not json");
+        assert!(!path.exists());
+        fs::remove_file(backup).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use pb_core::Palette;
+use serde::Deserialize;
+
+use crate::clock::{SystemClock, WallClock};
+use crate::PixelInfo;
+
+// When the event ends; nothing painted after it counts
+#[derive(Deserialize, Clone)]
+#[serde(try_from = "String")]
+pub struct Deadline {
+    pub at: SystemTime,
+    text: String,
+}
+
+impl TryFrom<String> for Deadline {
+    type Error = anyhow::Error;
+
+    fn try_from(text: String) -> anyhow::Result<Self> {
+        Ok(Self {
+            at: parse_rfc3339(&text)?,
+            text,
+        })
+    }
+}
+
+impl fmt::Display for Deadline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Deadline {
+    // Zero once passed
+    pub fn left(&self) -> Duration {
+        self.left_on(&SystemClock)
+    }
+
+    pub fn left_on(&self, clock: &dyn WallClock) -> Duration {
+        self.at.duration_since(clock.now()).unwrap_or_default()
+    }
+}
+
+// Which pixels are kept when not all of them fit before the deadline
+#[derive(Deserialize, Default, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadlinePriority {
+    // As queued: frames and higher priority brushes first
+    #[default]
+    Queue,
+    // Edges of shapes and color areas first, so the art stays readable
+    Outline,
+    // Listed colors first, in that order
+    Colors(Vec<String>),
+}
+
+impl DeadlinePriority {
+    pub fn check(&self) -> anyhow::Result<()> {
+        if let Self::Colors(colors) = self {
+            for color in colors {
+                crate::parse_hex(color)?;
+            }
+        }
+        Ok(())
+    }
+
+    // `queued` most important first; stable, so queue order breaks ties
+    pub fn rank(
+        &self,
+        queued: &mut [PixelInfo],
+        palette: &Palette,
+        target: impl Fn(u32, u32) -> Option<u8>,
+    ) {
+        match self {
+            Self::Queue => {}
+            Self::Outline => queued.sort_by_key(|pixel| {
+                let edge = [(-1, 0), (1, 0), (0, -1), (0, 1)].iter().any(|(dx, dy)| {
+                    let (x, y) = (pixel.x as i64 + dx, pixel.y as i64 + dy);
+                    x < 0 || y < 0 || target(x as u32, y as u32) != Some(pixel.color_id)
+                });
+                !edge
+            }),
+            Self::Colors(colors) => {
+                let order = colors
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, hex)| {
+                        let id = palette.id_of(crate::parse_hex(hex).ok()?)?;
+                        Some((id, i))
+                    })
+                    .collect::<HashMap<_, _>>();
+                queued.sort_by_key(|pixel| {
+                    order.get(&pixel.color_id).copied().unwrap_or(colors.len())
+                });
+            }
+        }
+    }
+}
+
+const DAY: i64 = 24 * 60 * 60;
+
+// Like 2024-04-01T18:00:00Z or 2024-04-01T20:00:00.5+02:00
+pub fn parse_rfc3339(text: &str) -> anyhow::Result<SystemTime> {
+    let bad = || anyhow!("{text} is not an RFC3339 timestamp like 2024-04-01T18:00:00Z");
+    let (date, rest) = text.split_once(['T', 't', ' ']).ok_or_else(bad)?;
+    let number = |part: &str, digits: usize| {
+        if part.len() != digits || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(bad());
+        }
+        part.parse::<i64>().map_err(|_| bad())
+    };
+    let [year, month, day] = split3(date, '-').ok_or_else(bad)?;
+    let (year, month, day) = (number(year, 4)?, number(month, 2)?, number(day, 2)?);
+    let zone = rest.find(['Z', 'z', '+', '-']).ok_or_else(bad)?;
+    let (time, zone) = rest.split_at(zone);
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let [hour, minute, second] = split3(time, ':').ok_or_else(bad)?;
+    let (hour, minute, second) = (number(hour, 2)?, number(minute, 2)?, number(second, 2)?);
+    let offset = match zone {
+        "Z" | "z" => 0,
+        _ => {
+            let (hours, minutes) = zone[1..].split_once(':').ok_or_else(bad)?;
+            let offset = number(hours, 2)? * 3600 + number(minutes, 2)? * 60;
+            if zone.starts_with('-') {
+                -offset
+            } else {
+                offset
+            }
+        }
+    };
+    if !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        // 60 is a leap second
+        || second > 60
+    {
+        Err(bad())?
+    }
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            Err(bad())?
+        }
+        format!("{fraction:0<9}")[..9].parse::<u32>()?
+    };
+    let secs =
+        days_from_civil(year, month, day) * DAY + hour * 3600 + minute * 60 + second - offset;
+    let secs = u64::try_from(secs).map_err(|_| anyhow!("{text} is before 1970"))?;
+    Ok(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+fn split3(text: &str, separator: char) -> Option<[&str; 3]> {
+    let mut parts = text.splitn(3, separator);
+    Some([parts.next()?, parts.next()?, parts.next()?])
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unix(text: &str) -> u64 {
+        parse_rfc3339(text)
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn pixel(x: u32, y: u32, color_id: u8) -> PixelInfo {
+        PixelInfo { x, y, color_id }
+    }
+
+    #[test]
+    fn timestamps_are_read_in_utc() {
+        assert_eq!(unix("1970-01-01T00:00:00Z"), 0);
+        assert_eq!(unix("2024-04-01T18:00:00Z"), 1711994400);
+        assert_eq!(unix("2024-04-01T20:00:00+02:00"), 1711994400);
+        assert_eq!(unix("2024-04-01t13:30:00-04:30"), 1711994400);
+        assert_eq!(unix("2024-02-29 00:00:00Z"), 1709164800);
+        let fraction = parse_rfc3339("2024-04-01T18:00:00.25Z").unwrap();
+        assert_eq!(
+            fraction.duration_since(UNIX_EPOCH).unwrap(),
+            Duration::new(1711994400, 250_000_000)
+        );
+    }
+
+    #[test]
+    fn malformed_timestamps_are_refused() {
+        for text in [
+            "2024-04-01",
+            "2024-04-01T18:00:00",
+            "2024-4-01T18:00:00Z",
+            "2023-02-29T00:00:00Z",
+            "2024-04-31T00:00:00Z",
+            "2024-04-01T24:00:00Z",
+            "2024-04-01T18:00:00.x5Z",
+            "2024-04-01T18:00:00+0200",
+            "1969-12-31T23:59:59Z",
+        ] {
+            assert!(parse_rfc3339(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn time_left_runs_out_at_the_deadline() {
+        struct At(SystemTime);
+        impl WallClock for At {
+            fn now(&self) -> SystemTime {
+                self.0
+            }
+        }
+        let deadline = Deadline::try_from("2024-04-01T18:00:00Z".to_string()).unwrap();
+        let before = deadline.at - Duration::from_secs(90);
+        assert_eq!(deadline.left_on(&At(before)), Duration::from_secs(90));
+        let after = deadline.at + Duration::from_secs(90);
+        assert_eq!(deadline.left_on(&At(after)), Duration::ZERO);
+        assert_eq!(deadline.to_string(), "2024-04-01T18:00:00Z");
+    }
+
+    #[test]
+    fn outlines_go_before_the_inside() {
+        // A 3x3 square of color 1: only its center is not on an edge
+        let mut queued = (0..3)
+            .flat_map(|y| (0..3).map(move |x| pixel(x + 5, y + 5, 1)))
+            .collect::<Vec<_>>();
+        queued.reverse();
+        let target = |x, y| ((5..8).contains(&x) && (5..8).contains(&y)).then_some(1);
+        DeadlinePriority::Outline.rank(&mut queued, &Palette::default(), target);
+        assert_eq!(queued.last(), Some(&pixel(6, 6, 1)));
+        assert!(queued[..8]
+            .iter()
+            .all(|pixel| *pixel != self::pixel(6, 6, 1)));
+    }
+
+    #[test]
+    fn listed_colors_go_first_in_their_order() {
+        let palette = Palette::default();
+        let hex = |id| {
+            let (r, g, b) = palette.rgb_of(id).unwrap();
+            format!("#{r:02X}{g:02X}{b:02X}")
+        };
+        let priority = DeadlinePriority::Colors(vec![hex(3), hex(1)]);
+        assert!(priority.check().is_ok());
+        let mut queued = [0, 1, 2, 3, 1, 0]
+            .map(|id| pixel(id as u32, 0, id))
+            .to_vec();
+        priority.rank(&mut queued, &palette, |_, _| None);
+        let ids = queued
+            .iter()
+            .map(|pixel| pixel.color_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [3, 1, 1, 0, 2, 0]);
+        assert!(DeadlinePriority::Colors(vec!["blue".into()])
+            .check()
+            .is_err());
+    }
+}
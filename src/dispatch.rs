@@ -0,0 +1,697 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use serde::Deserialize;
+
+use crate::traversal::{BlockMajor, Sequential, TraversalCtx, TraversalStrategy};
+use crate::PixelInfo;
+
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Locality {
+    #[default]
+    None,
+    Clustered,
+    // Next to pixels already showing the template first, so the art grows in one piece
+    Growth,
+}
+
+impl Locality {
+    // Growth keeps the built order as its priority
+    fn strategy(self) -> Box<dyn TraversalStrategy> {
+        match self {
+            Self::None | Self::Growth => Box::new(Sequential),
+            Self::Clustered => Box::new(BlockMajor),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Fairness {
+    #[default]
+    None,
+    // Workers too far ahead of the average wait for the rest to catch up
+    Strict,
+}
+
+// Where a pixel goes after a failed send
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryPlacement {
+    // Next in line
+    #[default]
+    Front,
+    // Behind everything queued
+    Back,
+    // A queue of its own, served between fresh pixels
+    Lane,
+}
+
+// Frame pixels go out before anything else, whatever the locality
+pub struct Queue {
+    frame: VecDeque<PixelInfo>,
+    framed: HashSet<(u32, u32)>,
+    order: Order,
+    placement: RetryPlacement,
+    retries: VecDeque<PixelInfo>,
+    // One retry from the lane after this many fresh pixels
+    lane_ratio: u32,
+    fresh_served: u32,
+}
+
+enum Order {
+    Sequential(VecDeque<PixelInfo>),
+    Clustered(Clusters),
+    Growth(Growth),
+}
+
+impl Queue {
+    pub fn new(pixels: Vec<PixelInfo>, framed: HashSet<(u32, u32)>, locality: Locality) -> Self {
+        let (frame, pixels) = pixels
+            .into_iter()
+            .partition::<Vec<_>, _>(|pixel| framed.contains(&(pixel.x, pixel.y)));
+        let ctx = TraversalCtx {
+            block_size: Clusters::BLOCK_SIZE,
+        };
+        let pixels = locality.strategy().order(&pixels, &ctx);
+        let order = match locality {
+            Locality::None => Order::Sequential(pixels.into()),
+            Locality::Clustered => Order::Clustered(Clusters::new(pixels)),
+            Locality::Growth => Order::Growth(Growth::new(pixels)),
+        };
+        Self {
+            frame: frame.into(),
+            framed,
+            order,
+            placement: RetryPlacement::default(),
+            retries: VecDeque::new(),
+            lane_ratio: 1,
+            fresh_served: 0,
+        }
+    }
+
+    pub fn set_retries(&mut self, placement: RetryPlacement, lane_ratio: u32) {
+        self.placement = placement;
+        self.lane_ratio = lane_ratio.max(1);
+    }
+
+    pub fn pop(&mut self, worker: i32) -> Option<PixelInfo> {
+        if let Some(pixel) = self.frame.pop_front() {
+            return Some(pixel);
+        }
+        if !self.retries.is_empty() && self.fresh_served >= self.lane_ratio {
+            self.fresh_served = 0;
+            return self.retries.pop_front();
+        }
+        let fresh = match &mut self.order {
+            Order::Sequential(queue) => queue.pop_front(),
+            Order::Clustered(clusters) => clusters.pop(worker),
+            Order::Growth(growth) => growth.pop(),
+        };
+        match fresh {
+            Some(pixel) => {
+                self.fresh_served = self.fresh_served.saturating_add(1);
+                Some(pixel)
+            }
+            None => {
+                self.fresh_served = 0;
+                self.retries.pop_front()
+            }
+        }
+    }
+
+    // After a failed send, wherever the placement puts it
+    pub fn requeue(&mut self, pixel: PixelInfo) {
+        match self.placement {
+            RetryPlacement::Front => self.push_front(pixel),
+            RetryPlacement::Back => self.push_back(pixel),
+            RetryPlacement::Lane => self.retries.push_back(pixel),
+        }
+    }
+
+    // For pixels that arrive while painting, behind everything already queued
+    pub fn push_back(&mut self, pixel: PixelInfo) {
+        if self.framed.contains(&(pixel.x, pixel.y)) {
+            self.frame.push_back(pixel);
+            return;
+        }
+        match &mut self.order {
+            Order::Sequential(queue) => queue.push_back(pixel),
+            Order::Clustered(clusters) => clusters
+                .blocks
+                .entry(Clusters::block_of(&pixel))
+                .or_default()
+                .push_back(pixel),
+            Order::Growth(growth) => growth.push_back(pixel),
+        }
+    }
+
+    pub fn push_front(&mut self, pixel: PixelInfo) {
+        if self.framed.contains(&(pixel.x, pixel.y)) {
+            self.frame.push_front(pixel);
+            return;
+        }
+        match &mut self.order {
+            Order::Sequential(queue) => queue.push_front(pixel),
+            Order::Clustered(clusters) => clusters
+                .blocks
+                .entry(Clusters::block_of(&pixel))
+                .or_default()
+                .push_front(pixel),
+            Order::Growth(growth) => growth.push_front(pixel),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frame.is_empty()
+            && self.retries.is_empty()
+            && match &self.order {
+                Order::Sequential(queue) => queue.is_empty(),
+                Order::Clustered(clusters) => clusters.blocks.values().all(VecDeque::is_empty),
+                Order::Growth(growth) => growth.remaining.is_empty(),
+            }
+    }
+
+    // Frame first, the rest in no particular order when clustered, the retry lane last
+    pub fn iter(&self) -> impl Iterator<Item = &PixelInfo> {
+        let (sequential, clustered, growth) = match &self.order {
+            Order::Sequential(queue) => (Some(queue), None, None),
+            Order::Clustered(clusters) => (None, Some(clusters), None),
+            Order::Growth(growth) => (None, None, Some(growth)),
+        };
+        self.frame
+            .iter()
+            .chain(sequential.into_iter().flatten())
+            .chain(
+                clustered
+                    .into_iter()
+                    .flat_map(|clusters| clusters.blocks.values().flatten()),
+            )
+            .chain(
+                growth
+                    .into_iter()
+                    .flat_map(|growth| growth.remaining.values()),
+            )
+            .chain(&self.retries)
+    }
+
+    pub fn retarget(&mut self, mut retarget: impl FnMut(&mut PixelInfo)) {
+        self.frame.iter_mut().for_each(&mut retarget);
+        self.retries.iter_mut().for_each(&mut retarget);
+        match &mut self.order {
+            Order::Sequential(queue) => queue.iter_mut().for_each(retarget),
+            Order::Clustered(clusters) => clusters.blocks.values_mut().flatten().for_each(retarget),
+            Order::Growth(growth) => growth.remaining.values_mut().for_each(retarget),
+        }
+    }
+
+    // The canvas shows the template at this position now
+    pub fn grow(&mut self, x: u32, y: u32) {
+        if let Order::Growth(growth) = &mut self.order {
+            growth.grow(x, y);
+        }
+    }
+
+    pub fn framed(&self) -> &HashSet<(u32, u32)> {
+        &self.framed
+    }
+
+    // Randomly permuted starting block per worker; empty unless clustered
+    pub fn shuffle(&mut self, workers: usize, seed: Option<u64>) -> Vec<(i32, (u32, u32))> {
+        match &mut self.order {
+            Order::Sequential(_) | Order::Growth(_) => Vec::new(),
+            Order::Clustered(clusters) => clusters.shuffle(workers, seed),
+        }
+    }
+
+    pub fn leave(&mut self, worker: i32) {
+        if let Order::Clustered(clusters) = &mut self.order {
+            clusters.owners.remove(&worker);
+        }
+    }
+}
+
+// Work split into square blocks, each painted by one worker at a time
+pub struct Clusters {
+    blocks: HashMap<(u32, u32), VecDeque<PixelInfo>>,
+    owners: HashMap<i32, (u32, u32)>,
+    // Where each worker starts looking for its first block
+    homes: HashMap<i32, (u32, u32)>,
+}
+
+impl Clusters {
+    pub const BLOCK_SIZE: u32 = 16;
+
+    fn new(pixels: Vec<PixelInfo>) -> Self {
+        let mut blocks = HashMap::<_, VecDeque<_>>::new();
+        for pixel in pixels {
+            blocks
+                .entry(Self::block_of(&pixel))
+                .or_default()
+                .push_back(pixel);
+        }
+        Self {
+            blocks,
+            owners: HashMap::new(),
+            homes: HashMap::new(),
+        }
+    }
+
+    fn shuffle(&mut self, workers: usize, seed: Option<u64>) -> Vec<(i32, (u32, u32))> {
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let mut blocks = self.blocks.keys().copied().collect::<Vec<_>>();
+        blocks.sort_unstable_by_key(|&(x, y)| (y, x));
+        let mut ids = (0..workers as i32).collect::<Vec<_>>();
+        ids.shuffle(&mut rng);
+        // One region per worker, or per block when there are fewer blocks
+        let regions = workers.min(blocks.len());
+        self.homes = ids
+            .into_iter()
+            .take(regions)
+            .enumerate()
+            .map(|(region, id)| (id, blocks[region * blocks.len() / regions]))
+            .collect();
+        let mut table = self
+            .homes
+            .iter()
+            .map(|(&id, &home)| (id, home))
+            .collect::<Vec<_>>();
+        table.sort_unstable();
+        table
+    }
+
+    fn block_of(pixel: &PixelInfo) -> (u32, u32) {
+        (pixel.x / Self::BLOCK_SIZE, pixel.y / Self::BLOCK_SIZE)
+    }
+
+    fn pop(&mut self, worker: i32) -> Option<PixelInfo> {
+        if let Some(block) = self.owners.get(&worker) {
+            if let Some(pixel) = self.blocks.get_mut(block).and_then(VecDeque::pop_front) {
+                return Some(pixel);
+            }
+        }
+        let previous = self
+            .owners
+            .remove(&worker)
+            .or_else(|| self.homes.remove(&worker));
+        self.blocks.retain(|_, pixels| !pixels.is_empty());
+        let claimed = self.owners.values().collect::<HashSet<_>>();
+        let next = *self
+            .blocks
+            .keys()
+            .filter(|block| !claimed.contains(block))
+            .min_by_key(|&&(x, y)| {
+                let distance =
+                    previous.map_or(0, |(px, py)| x.abs_diff(px).pow(2) + y.abs_diff(py).pow(2));
+                (distance, y, x)
+            })?;
+        self.owners.insert(worker, next);
+        self.blocks.get_mut(&next)?.pop_front()
+    }
+}
+
+// Work in queue order, but pixels touching correct ones go first
+pub struct Growth {
+    // By priority; pixels put back get keys below every original one, or above when sent to the back
+    remaining: BTreeMap<i64, PixelInfo>,
+    keys: HashMap<(u32, u32), i64>,
+    // Remaining pixels 4-adjacent to a correct one
+    frontier: BTreeSet<i64>,
+    correct: HashSet<(u32, u32)>,
+    next_front: i64,
+    next_back: i64,
+    // Where growth starts while nothing is correct yet
+    center: (u32, u32),
+}
+
+impl Growth {
+    fn new(pixels: Vec<PixelInfo>) -> Self {
+        let (mut x0, mut y0, mut x1, mut y1) = (u32::MAX, u32::MAX, 0, 0);
+        for pixel in &pixels {
+            x0 = x0.min(pixel.x);
+            y0 = y0.min(pixel.y);
+            x1 = x1.max(pixel.x);
+            y1 = y1.max(pixel.y);
+        }
+        let keys = pixels
+            .iter()
+            .enumerate()
+            .map(|(key, pixel)| ((pixel.x, pixel.y), key as i64))
+            .collect();
+        let next_back = pixels.len() as i64;
+        Self {
+            remaining: pixels
+                .into_iter()
+                .enumerate()
+                .map(|(key, pixel)| (key as i64, pixel))
+                .collect(),
+            keys,
+            frontier: BTreeSet::new(),
+            correct: HashSet::new(),
+            next_front: -1,
+            next_back,
+            center: (x0.saturating_add(x1) / 2, y0.saturating_add(y1) / 2),
+        }
+    }
+
+    fn neighbours(x: u32, y: u32) -> impl Iterator<Item = (u32, u32)> {
+        [
+            x.checked_sub(1).map(|x| (x, y)),
+            Some((x + 1, y)),
+            y.checked_sub(1).map(|y| (x, y)),
+            Some((x, y + 1)),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    fn pop(&mut self) -> Option<PixelInfo> {
+        let key = match self.frontier.pop_first() {
+            Some(key) => key,
+            None if self.correct.is_empty() => {
+                let (cx, cy) = self.center;
+                *self
+                    .remaining
+                    .iter()
+                    .min_by_key(|(_, pixel)| {
+                        pixel.x.abs_diff(cx).pow(2) + pixel.y.abs_diff(cy).pow(2)
+                    })?
+                    .0
+            }
+            None => *self.remaining.keys().next()?,
+        };
+        let pixel = self.remaining.remove(&key)?;
+        self.keys.remove(&(pixel.x, pixel.y));
+        Some(pixel)
+    }
+
+    fn push_front(&mut self, pixel: PixelInfo) {
+        let key = self.next_front;
+        self.next_front -= 1;
+        self.insert(key, pixel);
+    }
+
+    fn push_back(&mut self, pixel: PixelInfo) {
+        let key = self.next_back;
+        self.next_back += 1;
+        self.insert(key, pixel);
+    }
+
+    fn insert(&mut self, key: i64, pixel: PixelInfo) {
+        if let Some(old) = self.keys.insert((pixel.x, pixel.y), key) {
+            self.remaining.remove(&old);
+            self.frontier.remove(&old);
+        }
+        if Self::neighbours(pixel.x, pixel.y).any(|position| self.correct.contains(&position)) {
+            self.frontier.insert(key);
+        }
+        self.remaining.insert(key, pixel);
+    }
+
+    fn grow(&mut self, x: u32, y: u32) {
+        if !self.correct.insert((x, y)) {
+            return;
+        }
+        for position in Self::neighbours(x, y) {
+            if let Some(&key) = self.keys.get(&position) {
+                self.frontier.insert(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const LOCALITIES: [Locality; 3] = [Locality::None, Locality::Clustered, Locality::Growth];
+
+    // Pixels with whether each one is part of a frame
+    fn pixels() -> impl Strategy<Value = Vec<(PixelInfo, bool)>> {
+        prop::collection::hash_map((0..200u32, 0..120u32), any::<bool>(), 0..200).prop_map(
+            |positions| {
+                positions
+                    .into_iter()
+                    .map(|((x, y), framed)| (PixelInfo { x, y, color_id: 0 }, framed))
+                    .collect()
+            },
+        )
+    }
+
+    fn queue(pixels: &[(PixelInfo, bool)], locality: Locality) -> Queue {
+        let framed = pixels
+            .iter()
+            .filter(|(_, framed)| *framed)
+            .map(|(pixel, _)| (pixel.x, pixel.y))
+            .collect();
+        let pixels = pixels.iter().map(|(pixel, _)| pixel.clone()).collect();
+        Queue::new(pixels, framed, locality)
+    }
+
+    fn positions<'a>(pixels: impl IntoIterator<Item = &'a PixelInfo>) -> Vec<(u32, u32)> {
+        let mut positions = pixels
+            .into_iter()
+            .map(|pixel| (pixel.x, pixel.y))
+            .collect::<Vec<_>>();
+        positions.sort_unstable();
+        positions
+    }
+
+    proptest! {
+        #[test]
+        fn every_pixel_is_handed_out_once_frame_first(
+            pixels in pixels(),
+            workers in 1..8usize,
+            seed in any::<u64>(),
+        ) {
+            for locality in LOCALITIES {
+                let mut queue = queue(&pixels, locality);
+                queue.shuffle(workers, Some(seed));
+                // Clustered workers get nothing while the others own every block left
+                let (mut popped, mut idle) = (Vec::new(), 0);
+                for worker in (0..workers as i32).cycle() {
+                    match queue.pop(worker) {
+                        Some(pixel) => {
+                            popped.push(pixel);
+                            idle = 0;
+                        }
+                        None if idle + 1 == workers => break,
+                        None => idle += 1,
+                    }
+                }
+                prop_assert!(queue.is_empty());
+                prop_assert_eq!(positions(&popped), positions(pixels.iter().map(|(p, _)| p)));
+                let framed = pixels.iter().filter(|(_, framed)| *framed).count();
+                prop_assert!(popped[..framed].iter().all(|p| queue.framed().contains(&(p.x, p.y))));
+            }
+        }
+
+        #[test]
+        fn failed_pixels_come_back_once_whatever_the_placement(
+            pixels in pixels(),
+            failures in prop::collection::vec(any::<bool>(), 200),
+            lane_ratio in 0..5u32,
+        ) {
+            for locality in LOCALITIES {
+                for placement in [RetryPlacement::Front, RetryPlacement::Back, RetryPlacement::Lane] {
+                    let mut queue = queue(&pixels, locality);
+                    queue.set_retries(placement, lane_ratio);
+                    let (mut failed, mut painted) = (HashSet::new(), Vec::new());
+                    while let Some(pixel) = queue.pop(0) {
+                        let i = painted.len() + failed.len();
+                        if failures[i % failures.len()] && failed.insert((pixel.x, pixel.y)) {
+                            queue.requeue(pixel);
+                        } else {
+                            painted.push(pixel);
+                        }
+                    }
+                    prop_assert_eq!(positions(&painted), positions(pixels.iter().map(|(p, _)| p)));
+                }
+            }
+        }
+
+        #[test]
+        fn shuffles_repeat_under_a_seed(
+            pixels in pixels(),
+            workers in 1..8usize,
+            seed in any::<u64>(),
+        ) {
+            let run = || {
+                let mut queue = queue(&pixels, Locality::Clustered);
+                let homes = queue.shuffle(workers, Some(seed));
+                let order = (0..pixels.len())
+                    .map_while(|i| queue.pop((i % workers) as i32))
+                    .collect::<Vec<_>>();
+                (homes, order)
+            };
+            prop_assert_eq!(run(), run());
+        }
+
+        #[test]
+        fn shuffled_homes_are_distinct_blocks_one_per_region(
+            pixels in pixels(),
+            workers in 1..40usize,
+            seed in any::<u64>(),
+        ) {
+            let mut queue = queue(&pixels, Locality::Clustered);
+            let homes = queue.shuffle(workers, Some(seed));
+            // Frame pixels go out first, outside any block
+            let blocks = pixels
+                .iter()
+                .filter(|(_, framed)| !framed)
+                .map(|(pixel, _)| Clusters::block_of(pixel))
+                .collect::<HashSet<_>>();
+            prop_assert_eq!(homes.len(), workers.min(blocks.len()));
+            prop_assert!(homes.iter().all(|(id, home)| (0..workers as i32).contains(id) && blocks.contains(home)));
+            let ids = homes.iter().map(|(id, _)| id).collect::<HashSet<_>>();
+            let regions = homes.iter().map(|(_, home)| home).collect::<HashSet<_>>();
+            prop_assert_eq!((ids.len(), regions.len()), (homes.len(), homes.len()));
+        }
+    }
+
+    fn row(len: u32) -> Queue {
+        let pixels = (0..len)
+            .map(|x| PixelInfo {
+                x,
+                y: 0,
+                color_id: 0,
+            })
+            .collect();
+        Queue::new(pixels, HashSet::new(), Locality::None)
+    }
+
+    fn drain(queue: &mut Queue) -> Vec<u32> {
+        std::iter::from_fn(|| queue.pop(0))
+            .map(|pixel| pixel.x)
+            .collect()
+    }
+
+    #[test]
+    fn front_retries_go_next() {
+        let mut queue = row(4);
+        queue.set_retries(RetryPlacement::Front, 1);
+        let failed = queue.pop(0).unwrap();
+        queue.requeue(failed);
+        assert_eq!(drain(&mut queue), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn back_retries_go_last() {
+        let mut queue = row(4);
+        queue.set_retries(RetryPlacement::Back, 1);
+        let failed = queue.pop(0).unwrap();
+        queue.requeue(failed);
+        assert_eq!(drain(&mut queue), [1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn lane_retries_go_one_per_ratio_of_fresh_pixels() {
+        let mut queue = row(10);
+        queue.set_retries(RetryPlacement::Lane, 3);
+        for _ in 0..2 {
+            let failed = queue.pop(0).unwrap();
+            queue.requeue(failed);
+        }
+        assert_eq!(drain(&mut queue), [2, 0, 3, 4, 5, 1, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn growth_spreads_from_correct_pixels() {
+        let grid = |x0: u32, size: u32| {
+            (0..size)
+                .flat_map(move |y| (x0..x0 + size).map(move |x| PixelInfo { x, y, color_id: 0 }))
+        };
+        let touches = |painted: &HashSet<(u32, u32)>, pixel: &PixelInfo| {
+            Growth::neighbours(pixel.x, pixel.y).any(|position| painted.contains(&position))
+        };
+        // Nothing is correct yet, so it starts in the middle
+        let mut queue = Queue::new(grid(0, 9).collect(), HashSet::new(), Locality::Growth);
+        let mut painted = HashSet::new();
+        while let Some(pixel) = queue.pop(0) {
+            assert!(
+                painted.is_empty() && (pixel.x, pixel.y) == (4, 4) || touches(&painted, &pixel)
+            );
+            painted.insert((pixel.x, pixel.y));
+            queue.grow(pixel.x, pixel.y);
+        }
+        assert_eq!(painted.len(), 81);
+        // A pixel already correct on the canvas is the seed instead
+        let mut queue = Queue::new(grid(0, 9).collect(), HashSet::new(), Locality::Growth);
+        queue.grow(0, 8);
+        let first = queue.pop(0).unwrap();
+        assert_eq!((first.x, first.y), (0, 7));
+        // Neighbours go in queue order; once one island is done the next starts where the queue says
+        let islands = grid(20, 2).chain(grid(0, 2)).collect::<Vec<_>>();
+        let mut queue = Queue::new(islands, HashSet::new(), Locality::Growth);
+        queue.grow(1, 2);
+        let order = std::iter::from_fn(|| {
+            let pixel = queue.pop(0)?;
+            queue.grow(pixel.x, pixel.y);
+            Some((pixel.x, pixel.y))
+        })
+        .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            [
+                (1, 1),
+                (1, 0),
+                (0, 0),
+                (0, 1),
+                (20, 0),
+                (21, 0),
+                (20, 1),
+                (21, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn clustered_workers_finish_their_block_before_moving_on() {
+        // Eight blocks of 16x16 in a 4x2 grid
+        let pixels = (0..32)
+            .flat_map(|y| (0..64).map(move |x| PixelInfo { x, y, color_id: 0 }))
+            .collect();
+        let mut queue = Queue::new(pixels, HashSet::new(), Locality::Clustered);
+        let mut taken = HashMap::<i32, Vec<PixelInfo>>::new();
+        let (mut owner, mut idle) = (HashMap::new(), 0);
+        for worker in (0..3).cycle() {
+            let Some(pixel) = queue.pop(worker) else {
+                // The others may still own the last blocks
+                idle += 1;
+                if idle == 3 {
+                    break;
+                }
+                continue;
+            };
+            idle = 0;
+            // Blocks are claimed by one worker only
+            let block = Clusters::block_of(&pixel);
+            assert_eq!(*owner.entry(block).or_insert(worker), worker);
+            taken.entry(worker).or_default().push(pixel);
+        }
+        assert!(queue.is_empty());
+        assert_eq!(owner.len(), 8);
+        assert_eq!(taken.values().map(Vec::len).sum::<usize>(), 64 * 32);
+        for pixels in taken.values() {
+            let blocks = pixels.iter().map(Clusters::block_of).collect::<Vec<_>>();
+            let mut moves = 0;
+            for (pair, block) in pixels.windows(2).zip(blocks.windows(2)) {
+                if block[0] == block[1] {
+                    assert!(pair[0].x.abs_diff(pair[1].x) < Clusters::BLOCK_SIZE);
+                    assert!(pair[0].y.abs_diff(pair[1].y) < Clusters::BLOCK_SIZE);
+                } else {
+                    moves += 1;
+                }
+            }
+            // Each block it took was left only once it ran out
+            let distinct = blocks.iter().collect::<HashSet<_>>().len();
+            assert_eq!(moves, distinct - 1);
+        }
+    }
+}
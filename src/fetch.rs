@@ -0,0 +1,61 @@
+use anyhow::anyhow;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_native_tls::native_tls;
+use url::Url;
+
+// The body of a 2xx answer
+pub async fn get(url: &Url) -> anyhow::Result<String> {
+    request(url, "GET", "Accept: application/json, text/plain\r\n", "").await
+}
+
+pub async fn post_json(url: &Url, body: &str) -> anyhow::Result<()> {
+    let headers = format!(
+        "Content-Type: application/json\r\nContent-Length: {}\r\n",
+        body.len()
+    );
+    request(url, "POST", &headers, body).await.map(drop)
+}
+
+async fn request(url: &Url, method: &str, headers: &str, body: &str) -> anyhow::Result<String> {
+    let host = url.host_str().ok_or_else(|| anyhow!("{url} has no host"))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let stream = TcpStream::connect((host, port)).await?;
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+    // HTTP/1.0 keeps the answer unchunked and the connection one-shot
+    let request = format!("{method} {target} HTTP/1.0\r\nHost: {host}\r\n{headers}\r\n{body}");
+    let response = if url.scheme() == "https" {
+        let connector = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
+        exchange(connector.connect(host, stream).await?, &request).await?
+    } else {
+        exchange(stream, &request).await?
+    };
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow!("{host} sent a malformed response"))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("{host} sent a malformed status line"))?;
+    if !(200..300).contains(&status) {
+        Err(anyhow!("{host} answered the {method} with {status}"))?
+    }
+    Ok(body.into())
+}
+
+async fn exchange(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    request: &str,
+) -> anyhow::Result<String> {
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
@@ -0,0 +1,181 @@
+use std::{collections::VecDeque, time::Duration};
+
+use serde::Deserialize;
+use tokio::time::Instant;
+
+// Stops painting when most of the target suddenly mismatches, as after an admin wipes or moves the canvas
+#[derive(Deserialize, Clone, Copy)]
+pub struct ResetGuardConfig {
+    // Share of the known target pixels that have to mismatch
+    #[serde(default = "ResetGuardConfig::default_threshold")]
+    pub threshold: f64,
+    // Seconds within which the share has to climb past the threshold
+    #[serde(default = "ResetGuardConfig::default_window")]
+    pub window: u64,
+    // Seconds after which painting continues without a /resume
+    pub auto_resume_after: Option<u64>,
+    // Reconnect every worker on a suspected reset, so the server sends the board again
+    #[serde(default)]
+    pub refetch_board: bool,
+}
+
+impl ResetGuardConfig {
+    fn default_threshold() -> f64 {
+        0.9
+    }
+
+    fn default_window() -> u64 {
+        60
+    }
+
+    pub fn check(&self) -> anyhow::Result<()> {
+        if !(self.threshold > 0.0 && self.threshold <= 1.0) {
+            Err(anyhow::anyhow!(
+                "defend.reset_guard.threshold must be above 0 and at most 1"
+            ))?
+        }
+        Ok(())
+    }
+}
+
+pub struct ResetGuard {
+    pub config: ResetGuardConfig,
+    // Mismatching shares measured within the window, oldest first
+    history: VecDeque<(Instant, f64)>,
+    paused: Option<Instant>,
+}
+
+// A sudden climb of the mismatching share
+pub struct Suspicion {
+    pub from: f64,
+    pub to: f64,
+}
+
+impl ResetGuard {
+    pub fn new(config: ResetGuardConfig) -> Self {
+        Self {
+            config,
+            history: VecDeque::new(),
+            paused: None,
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    // Pauses on a climb from below the threshold to above it within the window
+    pub fn observe(&mut self, mismatch: f64, now: Instant) -> Option<Suspicion> {
+        let window = Duration::from_secs(self.config.window);
+        while self
+            .history
+            .front()
+            .is_some_and(|&(at, _)| now.duration_since(at) > window)
+        {
+            self.history.pop_front();
+        }
+        let lowest = self
+            .history
+            .iter()
+            .map(|&(_, share)| share)
+            .min_by(f64::total_cmp);
+        self.history.push_back((now, mismatch));
+        if self.paused() || mismatch < self.config.threshold {
+            return None;
+        }
+        let from = lowest.filter(|&share| share < self.config.threshold)?;
+        self.paused = Some(now);
+        Some(Suspicion { from, to: mismatch })
+    }
+
+    // Whether auto_resume_after has passed since the pause
+    pub fn resume_due(&self, now: Instant) -> bool {
+        self.paused
+            .zip(self.config.auto_resume_after)
+            .is_some_and(|(since, after)| now.duration_since(since) >= Duration::from_secs(after))
+    }
+
+    // The current mismatch is accepted as the starting point, so it does not pause again
+    pub fn resume(&mut self) -> bool {
+        self.history.clear();
+        self.paused.take().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(auto_resume_after: Option<u64>) -> ResetGuard {
+        ResetGuard::new(ResetGuardConfig {
+            threshold: 0.9,
+            window: 60,
+            auto_resume_after,
+            refetch_board: false,
+        })
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn a_sudden_climb_pauses() {
+        let (mut guard, start) = (guard(None), Instant::now());
+        assert!(guard.observe(0.05, start).is_none());
+        assert!(guard.observe(0.3, start + secs(10)).is_none());
+        let suspicion = guard.observe(0.95, start + secs(20)).unwrap();
+        assert_eq!((suspicion.from, suspicion.to), (0.05, 0.95));
+        assert!(guard.paused());
+        // Reported once per pause
+        assert!(guard.observe(0.99, start + secs(25)).is_none());
+    }
+
+    #[test]
+    fn a_slow_climb_does_not() {
+        let (mut guard, start) = (guard(None), Instant::now());
+        assert!(guard.observe(0.05, start).is_none());
+        assert!(guard.observe(0.95, start + secs(61)).is_none());
+        assert!(!guard.paused());
+    }
+
+    #[test]
+    fn a_canvas_wrong_from_the_start_does_not() {
+        let (mut guard, start) = (guard(None), Instant::now());
+        assert!(guard.observe(1.0, start).is_none());
+        assert!(guard.observe(0.97, start + secs(5)).is_none());
+        assert!(!guard.paused());
+    }
+
+    #[test]
+    fn resuming_accepts_the_current_mismatch() {
+        let (mut guard, start) = (guard(None), Instant::now());
+        guard.observe(0.0, start);
+        guard.observe(1.0, start + secs(1)).unwrap();
+        assert!(guard.resume());
+        assert!(!guard.resume());
+        assert!(guard.observe(1.0, start + secs(2)).is_none());
+        assert!(!guard.paused());
+    }
+
+    #[test]
+    fn auto_resume_comes_due_after_the_configured_time() {
+        let (mut guard, start) = (guard(Some(300)), Instant::now());
+        assert!(!guard.resume_due(start));
+        guard.observe(0.0, start);
+        guard.observe(1.0, start + secs(1)).unwrap();
+        assert!(!guard.resume_due(start + secs(300)));
+        assert!(guard.resume_due(start + secs(301)));
+    }
+
+    #[test]
+    fn thresholds_outside_zero_to_one_are_refused() {
+        for (threshold, valid) in [(0.0, false), (0.5, true), (1.0, true), (1.5, false)] {
+            let config = ResetGuardConfig {
+                threshold,
+                ..guard(None).config
+            };
+            assert_eq!(config.check().is_ok(), valid, "{threshold}");
+        }
+    }
+}
@@ -0,0 +1,8535 @@
+mod ack;
+mod auth;
+mod calibrate;
+mod canvas;
+mod capture;
+mod clock;
+mod compress;
+mod confirm;
+mod cooldown;
+mod cumulative;
+mod deadline;
+mod dispatch;
+mod fetch;
+mod guard;
+mod logging;
+mod metrics;
+mod observe;
+mod planner;
+mod protocol;
+mod proxy;
+mod ratelimit;
+mod resume;
+mod retry;
+mod schedule;
+mod secrets;
+mod simulate;
+mod stats;
+mod status;
+#[cfg(all(unix, feature = "systemd"))]
+mod systemd;
+mod text;
+mod traversal;
+mod verify;
+mod webhook;
+
+// pb-core's golden inputs and outputs, which the bot has to reproduce
+#[cfg(test)]
+#[path = "../pb-core/tests/fixture/mod.rs"]
+mod fixture;
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use std::{
+    cmp,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    env,
+    fs::{self, File},
+    io::{self, IsTerminal, Read, Write},
+    mem,
+    ops::Range as IndexRange,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use async_tungstenite::tungstenite;
+use async_tungstenite::tungstenite::{
+    http,
+    protocol::{CloseFrame, WebSocketConfig},
+};
+
+use async_tungstenite::{
+    stream::Stream,
+    tokio::{
+        client_async_tls_with_connector_and_config, connect_async_with_tls_connector_and_config,
+        TokioAdapter,
+    },
+    WebSocketStream,
+};
+use futures::{stream::FuturesUnordered, SinkExt, StreamExt};
+use image::RgbaImage;
+use log::*;
+
+use rand::distributions::uniform::{UniformDuration, UniformSampler};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::time::Instant;
+use tokio_native_tls::native_tls;
+use url::Url;
+
+use pb_core::{Dither, Metric, QuantizeOptions};
+
+use crate::ack::{AckMatch, RemapInference};
+use crate::auth::AuthRefreshConfig;
+use crate::calibrate::{CalibrationConfig, Search};
+use crate::canvas::{Area, Canvas};
+use crate::capture::{Capture, Direction};
+use crate::confirm::Gate;
+use crate::cooldown::RampUp;
+use crate::cumulative::{Cumulative, Totals};
+use crate::deadline::{Deadline, DeadlinePriority};
+use crate::dispatch::{Clusters, Fairness, Locality, Queue, RetryPlacement};
+use crate::guard::{ResetGuard, ResetGuardConfig};
+use crate::logging::PaintLevel;
+use crate::observe::SnapshotConfig;
+use crate::planner::{PlannerConfig, Report};
+use crate::protocol::{Codec, Metadata, UpdateOffset, Wire};
+use crate::proxy::{Stage, StageFailed};
+use crate::ratelimit::{Bucket, LogThrottle, ReconnectLimit};
+use crate::resume::StateFile;
+use crate::retry::{Backoff, Category, ConnectRetries, HandshakeFailed, RetryConfig};
+use crate::schedule::Schedule;
+use crate::secrets::Variables;
+use crate::stats::{BotStats, State, StatsRegistry};
+use crate::status::StatusConfig;
+use crate::text::TextBrush;
+use crate::verify::Verification;
+use crate::webhook::{Event, Webhook};
+
+pub fn parse_slice(slice: &str) -> Result<IndexRange<usize>, String> {
+    let (start, end) = slice
+        .split_once("..")
+        .ok_or_else(|| format!("{slice} is not a START..END range"))?;
+    let start = start.parse::<usize>().map_err(|e| e.to_string())?;
+    let end = end.parse::<usize>().map_err(|e| e.to_string())?;
+    if start > end {
+        return Err(format!("{slice} is an empty range"));
+    }
+    Ok(start..end)
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    brush: Brush,
+    // Painted alongside `brush`, into the same queue
+    #[serde(default)]
+    brushes: Vec<Brush>,
+    // Contested pixels go to the brush with the highest priority instead of failing
+    #[serde(default)]
+    allow_overlap: bool,
+    // Left alone even when they differ from the template, e.g. after a manual fix
+    #[serde(default)]
+    pinned: Vec<Pin>,
+    #[serde(default)]
+    bots: Vec<BotEntry>,
+    // Listen-only connections; with no bots the run just watches the canvas
+    #[serde(default)]
+    scouts: Vec<BotEntry>,
+    snapshot: Option<SnapshotConfig>,
+    summary: Option<PathBuf>,
+    #[serde(default)]
+    canvas: CanvasConfig,
+    #[serde(default = "Config::default_max_connections")]
+    max_connections_per_bot: u32,
+    #[serde(default = "Config::default_min_bots")]
+    min_bots: u32,
+    #[serde(default)]
+    stall: StallConfig,
+    #[serde(default)]
+    color_overrides: HashMap<String, ColorTarget>,
+    #[serde(default)]
+    humanize: HumanizeConfig,
+    #[serde(default)]
+    griefing: GriefingConfig,
+    #[serde(default)]
+    defend: DefendConfig,
+    schedule: Option<Schedule>,
+    // HTTP /healthz and /status for process supervisors
+    status: Option<StatusConfig>,
+    // Stalls and finished jobs are POSTed here as JSON events
+    webhook: Option<Url>,
+    // Painted pixels are remembered here between runs; a .zst name compresses it
+    state_file: Option<PathBuf>,
+    // Counters carried over from earlier runs and written back
+    cumulative_stats: Option<PathBuf>,
+    // Overrides CloseAction::default_for per close code
+    #[serde(default)]
+    close_codes: HashMap<u16, CloseAction>,
+    #[serde(default)]
+    log: LogConfig,
+    // Pixels packed into every frame, for servers that accept several per cooldown
+    #[serde(default = "Config::default_paints_per_cycle")]
+    paints_per_cycle: usize,
+    #[serde(default)]
+    allow_duplicate_bots: bool,
+    #[serde(default = "Config::default_connect_concurrency")]
+    connect_concurrency: usize,
+    // Failed sends in a row before a bot is benched
+    #[serde(default = "Config::default_failure_streak")]
+    failure_streak: u32,
+    #[serde(default)]
+    retry_policy: RetryConfig,
+    // Every websocket frame is appended here when set
+    pub capture_path: Option<PathBuf>,
+    #[serde(default = "Config::default_cooldown")]
+    cooldown: Range,
+    // Cooldowns start rampup_factor times longer and ease to normal over this many minutes
+    rampup_minutes: Option<u64>,
+    #[serde(default = "Config::default_rampup_factor")]
+    rampup_factor: f64,
+    #[serde(default, alias = "traversal")]
+    locality: Locality,
+    // Where pixels go back to after a failed send
+    #[serde(default)]
+    retry_placement: RetryPlacement,
+    // Fresh pixels served per retry when the placement is a lane
+    #[serde(default = "Config::default_retry_lane_ratio")]
+    retry_lane_ratio: u32,
+    // Failed sends one pixel may be retried after, before it counts as failed for good
+    max_pixel_retries: Option<u32>,
+    #[serde(default)]
+    fairness: Fairness,
+    // Sends a worker may be ahead of the average under strict fairness
+    #[serde(default = "Config::default_fairness_margin")]
+    fairness_margin: u32,
+    // Past this a bot ignores broadcasts for the rest of the hour
+    max_rx_bytes_per_hour: Option<u64>,
+    // 3xx answers to the websocket upgrade followed before giving up
+    #[serde(default = "Config::default_max_redirects")]
+    max_redirects: u32,
+    // Bytes; the board snapshot some servers send on connect is far above tungstenite's defaults
+    #[serde(default = "Config::default_max_message_size")]
+    max_message_size: usize,
+    #[serde(default = "Config::default_max_frame_size")]
+    max_frame_size: usize,
+    auth_refresh: Option<AuthRefreshConfig>,
+    // Probe with the first bot for the real minimum cooldown and raise or lower the floor to it
+    #[serde(default)]
+    calibrate: bool,
+    #[serde(default)]
+    calibration: CalibrationConfig,
+    // Reconnect attempts of all bots together, so a server restart is not met by a stampede
+    #[serde(default)]
+    reconnect_limit: ReconnectLimit,
+    // Start every worker in a different random region on each run
+    #[serde(default)]
+    shuffle_assignments: bool,
+    shuffle_seed: Option<u64>,
+    #[serde(default = "Config::default_max_inexact_ratio")]
+    max_inexact_ratio: f64,
+    // Pixels farther than this from every palette color are left unpainted, in color_metric units
+    max_color_distance: Option<f64>,
+    #[serde(default)]
+    color_metric: Metric,
+    // Spread the error of inexact matches to the neighbours, as the preview does
+    #[serde(default)]
+    dither: Dither,
+    // Match brush pixels on every core; dithered output then differs slightly at band edges
+    #[serde(default)]
+    parallel_quantize: bool,
+    // RFC3339; past it the run stops, and before it only what fits is painted
+    deadline: Option<Deadline>,
+    #[serde(default)]
+    deadline_priority: DeadlinePriority,
+    // One pixel has to come back as sent before the other bots start, so a wrong wire format stops the run
+    #[serde(default = "Config::default_verify_first_paint")]
+    verify_first_paint: bool,
+    // Seconds to wait for that echo
+    #[serde(default = "Config::default_first_paint_timeout")]
+    first_paint_timeout: u64,
+    // Seconds the wall clock may drift from the monotonic one between checks before schedule
+    // windows and the deadline are worked out again
+    #[serde(default = "Config::default_clock_jump_threshold")]
+    clock_jump_threshold: u64,
+    #[serde(default)]
+    ack_match: AckMatch,
+    // Color ids the server echoes, to the ids we paint with, for servers numbering them differently
+    #[serde(default)]
+    echo_remap: HashMap<u8, u8>,
+    // Runs queueing more pixels wait for a yes on stdin or /confirm before the first paint
+    confirm_above_pixels: Option<usize>,
+    // Seconds to wait for that before giving up
+    #[serde(default = "Config::default_confirm_timeout")]
+    confirm_timeout: u64,
+}
+
+#[derive(Deserialize, Default)]
+struct HumanizeConfig {
+    reaction_delay_ms: Option<Range>,
+    #[serde(default)]
+    skip_probability: f64,
+}
+
+#[derive(Deserialize)]
+struct DefendConfig {
+    // Keep running after the queue drains and repaint overwritten pixels
+    #[serde(default)]
+    enabled: bool,
+    // Written on SIGUSR1
+    heatmap: Option<PathBuf>,
+    // Seconds with nothing to repaint before workers only wake on damage
+    #[serde(default = "DefendConfig::default_idle_after")]
+    idle_after: u64,
+    // Seconds between wakes while watching, in case an update was missed
+    #[serde(default = "DefendConfig::default_idle_wake")]
+    idle_wake: u64,
+    // Seconds after our repaint within which an overwrite counts as a revert
+    #[serde(default = "DefendConfig::default_revert_window")]
+    revert_window: u64,
+    // Reverts after which a pixel is only repainted when nothing else is pending
+    #[serde(default = "DefendConfig::default_demote_after")]
+    demote_after: u32,
+    // Reverts after which it is not repainted at all
+    give_up_after: Option<u32>,
+    reset_guard: Option<ResetGuardConfig>,
+}
+
+impl Default for DefendConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            heatmap: None,
+            idle_after: Self::default_idle_after(),
+            idle_wake: Self::default_idle_wake(),
+            revert_window: Self::default_revert_window(),
+            demote_after: Self::default_demote_after(),
+            give_up_after: None,
+            reset_guard: None,
+        }
+    }
+}
+
+impl DefendConfig {
+    fn default_revert_window() -> u64 {
+        10
+    }
+
+    fn default_demote_after() -> u32 {
+        3
+    }
+
+    fn reverts(&self) -> RevertPolicy {
+        RevertPolicy {
+            window: Duration::from_secs(self.revert_window),
+            demote_after: self.demote_after,
+            give_up_after: self.give_up_after,
+        }
+    }
+
+    fn default_idle_after() -> u64 {
+        5 * 60
+    }
+
+    fn default_idle_wake() -> u64 {
+        10 * 60
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct GriefingConfig {
+    // Overwrites of our painted pixels per minute considered an attack
+    threshold: Option<u32>,
+    #[serde(default)]
+    disable_skip: bool,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum CloseAction {
+    Reconnect,
+    // A number in the close reason takes precedence
+    ReconnectAfter(u64),
+    Quarantine,
+}
+
+impl CloseAction {
+    fn default_for(code: u16) -> Self {
+        match code {
+            // Policy violation, unauthorized, forbidden: retrying won't help
+            1008 | 4001 | 4003 => Self::Quarantine,
+            // Service restart
+            1012 => Self::ReconnectAfter(10),
+            // Gateway overload
+            1013 | 4029 | 4429 => Self::ReconnectAfter(30),
+            _ => Self::Reconnect,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+struct Range {
+    min: u64,
+    max: u64,
+}
+
+impl Range {
+    fn check(&self, name: &str) -> anyhow::Result<()> {
+        if self.min > self.max {
+            Err(anyhow!("{name}.min must not exceed {name}.max"))?
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorTarget {
+    Index(u8),
+    Hex(String),
+}
+
+impl ColorTarget {
+    fn resolve(&self, palette: &Palette) -> anyhow::Result<u8> {
+        match self {
+            Self::Index(id) if palette.rgb_of(*id).is_some() => Ok(*id),
+            Self::Index(id) => Err(anyhow!("Palette has no color with id {id}")),
+            Self::Hex(hex) => {
+                let rgb = parse_hex(hex)?;
+                palette
+                    .id_of(rgb)
+                    .ok_or_else(|| anyhow!("{hex} is not a palette color"))
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Turns every bot into a scout, so the run only watches the canvas.
+    pub fn observe_only(&mut self) {
+        self.scouts.append(&mut self.bots);
+    }
+
+    /// Starts painting without asking, however many pixels are queued.
+    pub fn assume_yes(&mut self) {
+        self.confirm_above_pixels = None;
+    }
+
+    fn bot_configs(&self) -> Vec<BotConfig> {
+        self.bots.iter().cloned().map(BotConfig::from).collect()
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            max_redirects: self.max_redirects,
+            max_message_size: self.max_message_size,
+            max_frame_size: self.max_frame_size,
+        }
+    }
+
+    fn default_max_connections() -> u32 {
+        3
+    }
+
+    fn all_brushes(&self) -> impl Iterator<Item = &Brush> {
+        std::iter::once(&self.brush).chain(&self.brushes)
+    }
+
+    // One per brush, in config order
+    fn jobs(&self) -> Vec<Job> {
+        self.all_brushes()
+            .enumerate()
+            .map(|(i, brush)| {
+                Job::new(
+                    brush.name.clone().unwrap_or_else(|| brush_name(i)),
+                    brush.priority,
+                    brush.defend.unwrap_or(self.defend.enabled),
+                )
+            })
+            .collect()
+    }
+
+    fn default_min_bots() -> u32 {
+        1
+    }
+
+    fn default_paints_per_cycle() -> usize {
+        1
+    }
+
+    fn default_fairness_margin() -> u32 {
+        10
+    }
+
+    fn default_rampup_factor() -> f64 {
+        3.0
+    }
+
+    fn default_max_redirects() -> u32 {
+        3
+    }
+
+    fn default_max_message_size() -> usize {
+        256 << 20
+    }
+
+    fn default_max_frame_size() -> usize {
+        128 << 20
+    }
+
+    fn default_cooldown() -> Range {
+        Range { min: 65, max: 180 }
+    }
+
+    fn default_max_inexact_ratio() -> f64 {
+        1.0
+    }
+
+    fn default_retry_lane_ratio() -> u32 {
+        4
+    }
+
+    fn default_verify_first_paint() -> bool {
+        true
+    }
+
+    fn default_first_paint_timeout() -> u64 {
+        10
+    }
+
+    fn default_clock_jump_threshold() -> u64 {
+        5
+    }
+
+    fn default_confirm_timeout() -> u64 {
+        600
+    }
+
+    fn default_failure_streak() -> u32 {
+        5
+    }
+
+    fn default_connect_concurrency() -> usize {
+        8
+    }
+}
+
+#[derive(Deserialize)]
+struct StallConfig {
+    #[serde(default = "StallConfig::default_timeout")]
+    timeout: u64,
+    #[serde(default = "StallConfig::default_max_reconnects")]
+    max_reconnects: u32,
+}
+
+impl StallConfig {
+    fn default_timeout() -> u64 {
+        600
+    }
+
+    fn default_max_reconnects() -> u32 {
+        3
+    }
+}
+
+impl Default for StallConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Self::default_timeout(),
+            max_reconnects: Self::default_max_reconnects(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+enum BotEntry {
+    Url(Url),
+    Config(BotConfig),
+}
+
+#[derive(Deserialize, Clone)]
+struct BotConfig {
+    url: Url,
+    name: Option<String>,
+    #[serde(default = "BotConfig::default_connections")]
+    connections: u32,
+    cooldown: Option<Range>,
+    cooldown_scale: Option<f64>,
+    #[serde(default)]
+    allow_insecure: bool,
+    // SOCKS5 proxies in order; the next is tried only when one is down or refuses us
+    #[serde(default)]
+    proxies: Vec<Url>,
+}
+
+// Where a bot connects and through which proxies
+#[derive(Clone)]
+struct Endpoint {
+    url: Url,
+    insecure: bool,
+    proxies: Vec<Url>,
+    limits: Limits,
+}
+
+// Redirects followed and the largest message and frame accepted, in bytes
+#[derive(Clone, Copy)]
+struct Limits {
+    max_redirects: u32,
+    max_message_size: usize,
+    max_frame_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_redirects: Config::default_max_redirects(),
+            max_message_size: Config::default_max_message_size(),
+            max_frame_size: Config::default_max_frame_size(),
+        }
+    }
+}
+
+impl Limits {
+    fn websocket(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: Some(self.max_message_size),
+            max_frame_size: Some(self.max_frame_size),
+            ..WebSocketConfig::default()
+        }
+    }
+}
+
+impl BotConfig {
+    fn endpoint(&self, limits: Limits) -> Endpoint {
+        Endpoint {
+            url: self.url.clone(),
+            insecure: self.allow_insecure,
+            proxies: self.proxies.clone(),
+            limits,
+        }
+    }
+
+    fn default_connections() -> u32 {
+        1
+    }
+
+    fn cooldown(&self, default: Range) -> (Duration, Duration) {
+        let Range { min, max } = self.cooldown.unwrap_or(default);
+        let scale = self.cooldown_scale.unwrap_or(1.0);
+        (
+            Duration::from_secs(min).mul_f64(scale),
+            Duration::from_secs(max).mul_f64(scale),
+        )
+    }
+}
+
+// Pairs of bots with the same URL or the same token query parameter
+fn find_duplicate_bots(bots: &[BotConfig]) -> Vec<String> {
+    let label = |bot: &BotConfig| bot.name.clone().unwrap_or_else(|| redact(&bot.url));
+    let mut seen = HashMap::new();
+    let mut duplicates = Vec::new();
+    for bot in bots {
+        let token = bot
+            .url
+            .query_pairs()
+            .find(|(key, _)| key == "token")
+            .map(|(_, token)| token.into_owned());
+        let account = token.unwrap_or_else(|| bot.url.to_string());
+        match seen.get(&account) {
+            Some(first) => duplicates.push(format!("{first} and {}", label(bot))),
+            None => {
+                seen.insert(account, label(bot));
+            }
+        }
+    }
+    duplicates
+}
+
+impl BotEntry {
+    fn endpoint(&self) -> (&Url, bool) {
+        match self {
+            Self::Url(url) => (url, false),
+            Self::Config(config) => (&config.url, config.allow_insecure),
+        }
+    }
+}
+
+impl From<BotEntry> for BotConfig {
+    fn from(entry: BotEntry) -> Self {
+        match entry {
+            BotEntry::Url(url) => Self {
+                url,
+                name: None,
+                connections: Self::default_connections(),
+                cooldown: None,
+                cooldown_scale: None,
+                allow_insecure: false,
+                proxies: Vec::new(),
+            },
+            BotEntry::Config(config) => config,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Brush {
+    #[serde(flatten)]
+    source: BrushSource,
+    #[serde(default)]
+    offset_x: u32,
+    #[serde(default)]
+    offset_y: u32,
+    #[serde(default, deserialize_with = "deserialize_slice")]
+    slice: Option<IndexRange<usize>>,
+    // Painted around the artwork before the artwork itself
+    frame: Option<FrameConfig>,
+    // Wins pixels shared with other brushes when allow_overlap is set, and is painted first
+    #[serde(default)]
+    priority: i32,
+    // Shown in logs and reports in place of brush or brushes[N]
+    name: Option<String>,
+    // Overrides defend.enabled for this brush's pixels
+    defend: Option<bool>,
+}
+
+// How config errors and reports refer to the brush at this index of all_brushes
+fn brush_name(i: usize) -> String {
+    match i {
+        0 => "brush".to_string(),
+        i => format!("brushes[{}]", i - 1),
+    }
+}
+
+// A single pixel or a rect, checked to lie on the canvas
+#[derive(Deserialize)]
+#[serde(try_from = "PinSpec")]
+struct Pin {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PinSpec {
+    Pixel([u32; 2]),
+    Rect {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+}
+
+impl TryFrom<PinSpec> for Pin {
+    type Error = anyhow::Error;
+
+    fn try_from(spec: PinSpec) -> anyhow::Result<Self> {
+        let (x, y, width, height) = match spec {
+            PinSpec::Pixel([x, y]) => (x, y, 1, 1),
+            PinSpec::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => (x, y, width, height),
+        };
+        let fits = |start: u32, len: u32, max| start.checked_add(len).is_some_and(|end| end <= max);
+        if !fits(x, width, PixelProvider::MAX_WIDTH) || !fits(y, height, PixelProvider::MAX_HEIGHT)
+        {
+            Err(anyhow!(
+                "pinned {width}x{height} at {{{x}:{y}}} is outside the canvas"
+            ))?
+        }
+        Ok(Self {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+impl Pin {
+    fn positions(&self) -> impl Iterator<Item = (u32, u32)> {
+        let Self {
+            x,
+            y,
+            width,
+            height,
+        } = *self;
+        (y..y.saturating_add(height))
+            .flat_map(move |y| (x..x.saturating_add(width)).map(move |x| (x, y)))
+    }
+}
+
+#[derive(Deserialize)]
+struct FrameConfig {
+    color: ColorTarget,
+    #[serde(default = "FrameConfig::default_thickness")]
+    thickness: u32,
+}
+
+impl FrameConfig {
+    fn default_thickness() -> u32 {
+        1
+    }
+
+    // The band of `thickness` around the bounding box of `pixels`, clipped to the canvas
+    fn around(&self, pixels: &[PixelInfo], palette: &Palette) -> anyhow::Result<Vec<PixelInfo>> {
+        let color_id = self.color.resolve(palette)?;
+        let (Some(x0), Some(y0)) = (
+            pixels.iter().map(|p| p.x).min(),
+            pixels.iter().map(|p| p.y).min(),
+        ) else {
+            return Ok(Vec::new());
+        };
+        let x1 = pixels.iter().map(|p| p.x + 1).max().unwrap_or_default();
+        let y1 = pixels.iter().map(|p| p.y + 1).max().unwrap_or_default();
+        let t = self.thickness;
+        let mut frame = Vec::new();
+        for y in y0.saturating_sub(t)..(y1 + t).min(PixelProvider::MAX_HEIGHT) {
+            for x in x0.saturating_sub(t)..(x1 + t).min(PixelProvider::MAX_WIDTH) {
+                if (x0..x1).contains(&x) && (y0..y1).contains(&y) {
+                    continue;
+                }
+                frame.push(PixelInfo { x, y, color_id });
+            }
+        }
+        Ok(frame)
+    }
+}
+
+fn deserialize_slice<'de, D>(deserializer: D) -> Result<Option<IndexRange<usize>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|slice| parse_slice(&slice).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BrushSource {
+    Image(PathBuf),
+    Text(TextBrush),
+    Rect(RectBrush),
+    PlanFile(PathBuf),
+    // Pixels claimed one at a time from a planner instead of read from a file
+    Remote(PlannerConfig),
+}
+
+#[derive(Deserialize)]
+struct RectBrush {
+    width: u32,
+    height: u32,
+    color: String,
+}
+
+impl RectBrush {
+    fn render(&self, palette: &Palette) -> anyhow::Result<RgbaImage> {
+        if self.width == 0 || self.height == 0 {
+            Err(anyhow!("Rect brush must not be empty"))?
+        }
+        let (r, g, b) = parse_hex(&self.color)?;
+        if palette.id_of((r, g, b)).is_none() {
+            warn!("Rect color {} is not in the palette", self.color);
+        }
+        Ok(RgbaImage::from_pixel(
+            self.width,
+            self.height,
+            image::Rgba([r, g, b, 255]),
+        ))
+    }
+}
+
+impl BrushSource {
+    fn describe(&self) -> String {
+        match self {
+            Self::Image(path) => format!("image {}", path.display()),
+            Self::Text(text) => format!("text {:?}", text.content()),
+            Self::Rect(rect) => format!("rect {}", rect.color),
+            Self::PlanFile(path) => format!("plan {}", path.display()),
+            Self::Remote(planner) => format!("planner at {}", planner.address),
+        }
+    }
+
+    // Only the part up to `visible` is kept, the rest would be clipped anyway
+    fn load(&self, visible: (u32, u32), palette: &Palette) -> anyhow::Result<RgbaImage> {
+        match self {
+            Self::Image(path) => {
+                let image = open_image(path)?;
+                let (width, height) = (image.width(), image.height());
+                let image = image
+                    .crop_imm(0, 0, width.min(visible.0), height.min(visible.1))
+                    .to_rgba8();
+                info!(
+                    "Brush {} is {width}x{height}, {}x{} of it on the canvas",
+                    path.display(),
+                    image.width(),
+                    image.height()
+                );
+                Ok(image)
+            }
+            Self::Text(text) => text.render(),
+            Self::Rect(rect) => rect.render(palette),
+            Self::PlanFile(path) => Err(anyhow!("{} is a plan, not an image", path.display())),
+            Self::Remote(planner) => Err(anyhow!("{} is a planner, not an image", planner.address)),
+        }
+    }
+}
+
+// Extensions lie, e.g. chat apps save WebP as .png, so fall back to sniffing the contents
+fn open_image(path: &PathBuf) -> anyhow::Result<::image::DynamicImage> {
+    use ::image::ImageFormat;
+    let by_extension = match ::image::open(path) {
+        Ok(image) => return Ok(image),
+        Err(why) => why,
+    };
+    let bytes = fs::read(path).map_err(|why| anyhow!("Cannot read {}: {why}", path.display()))?;
+    let guessed = ::image::guess_format(&bytes);
+    if let Some(&format) = guessed.as_ref().ok().filter(|&&format| !readable(format)) {
+        Err(anyhow!(
+            "{} is {format:?}, which this build cannot read; rebuild with --features {}",
+            path.display(),
+            format_feature(format)
+        ))?
+    }
+    if let Ok(format) = guessed {
+        if let Ok(image) = ::image::load_from_memory_with_format(&bytes, format) {
+            warn!(
+                "{} is actually {format:?}, not what its extension says",
+                path.display()
+            );
+            return Ok(image);
+        }
+    }
+    let supported = [
+        ImageFormat::Png,
+        ImageFormat::Jpeg,
+        ImageFormat::Gif,
+        ImageFormat::WebP,
+        ImageFormat::Pnm,
+        ImageFormat::Tiff,
+        ImageFormat::Tga,
+        ImageFormat::Dds,
+        ImageFormat::Bmp,
+        ImageFormat::Ico,
+        ImageFormat::Hdr,
+        ImageFormat::OpenExr,
+        ImageFormat::Farbfeld,
+        ImageFormat::Avif,
+        ImageFormat::Qoi,
+    ]
+    .into_iter()
+    .filter(|&format| readable(format))
+    .map(|format| format!("{format:?}"))
+    .collect::<Vec<_>>();
+    Err(anyhow!(
+        "Cannot decode {} ({by_extension}); it starts with {}{}, supported formats are {}",
+        path.display(),
+        protocol::hexdump(&bytes),
+        guessed.map_or(String::new(), |format| format!(
+            " which looks like {format:?}"
+        )),
+        supported.join(", ")
+    ))
+}
+
+// The crate feature that brings in `format`
+fn format_feature(format: ::image::ImageFormat) -> &'static str {
+    use ::image::ImageFormat;
+    match format {
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif => "formats-basic",
+        _ => "formats-extra",
+    }
+}
+
+// ImageFormat::can_read knows nothing of the features this build left out
+fn readable(format: ::image::ImageFormat) -> bool {
+    match format_feature(format) {
+        "formats-basic" => cfg!(feature = "formats-basic"),
+        _ => cfg!(feature = "formats-extra") && format.can_read(),
+    }
+}
+
+// Saves in the format the extension names, explaining which feature a missing encoder needs
+fn save_image(image: &RgbaImage, path: &Path) -> anyhow::Result<()> {
+    match image.save(path) {
+        Err(::image::ImageError::Unsupported(why)) => {
+            let feature =
+                ::image::ImageFormat::from_path(path).map_or("formats-basic", format_feature);
+            Err(anyhow!(
+                "Cannot save {}: {why}; rebuild with --features {feature}",
+                path.display()
+            ))
+        }
+        saved => Ok(saved?),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Plan {
+    canvas: CanvasSpec,
+    palette_hash: u64,
+    pixels: Vec<PixelInfo>,
+}
+
+impl Plan {
+    fn load(path: &PathBuf, canvas: CanvasSpec, context: &Context) -> anyhow::Result<Self> {
+        let plan: Self = bincode::deserialize_from(compress::open(path)?)?;
+        if plan.canvas != canvas {
+            Err(anyhow!(
+                "{} was planned for a different canvas spec",
+                path.display()
+            ))?
+        }
+        if plan.palette_hash != context.palette_hash() {
+            Err(anyhow!(
+                "{} was planned for a different palette",
+                path.display()
+            ))?
+        }
+        let palette = context.palette();
+        let valid = |pixel: &PixelInfo| {
+            pixel.x < PixelProvider::MAX_WIDTH
+                && pixel.y < PixelProvider::MAX_HEIGHT
+                && palette.rgb_of(pixel.color_id).is_some()
+        };
+        if !plan.pixels.iter().all(valid) {
+            Err(anyhow!(
+                "{} contains pixels outside the canvas or palette",
+                path.display()
+            ))?
+        }
+        Ok(plan)
+    }
+
+    fn save(&self, path: &PathBuf) -> anyhow::Result<()> {
+        let mut file = compress::create(path, compress::wanted(path))?;
+        bincode::serialize_into(&mut file, self)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct CanvasConfig {
+    #[serde(flatten)]
+    spec: CanvasSpec,
+    // Take the palette from the server's metadata frame
+    #[serde(default)]
+    auto: bool,
+    // When the palette changes mid-run, repaint finished pixels a new color matches better
+    #[serde(default)]
+    requantize_completed: bool,
+    #[serde(default)]
+    codec: Codec,
+    #[serde(default)]
+    update_offset: UpdateOffset,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct CanvasSpec {
+    #[serde(default)]
+    pub origin: Origin,
+    #[serde(default)]
+    pub swap_axes: bool,
+    #[serde(default = "CanvasSpec::default_max_color_id")]
+    pub max_color_id: u32,
+}
+
+impl Default for CanvasSpec {
+    fn default() -> Self {
+        Self {
+            origin: Origin::default(),
+            swap_axes: false,
+            max_color_id: Self::default_max_color_id(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Origin {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl CanvasSpec {
+    fn default_max_color_id() -> u32 {
+        PixelProvider::MAX_COLOR_ID
+    }
+
+    fn check(&self, palette: &Palette, codec: Codec) -> anyhow::Result<()> {
+        if self.max_color_id as usize > Palette::MAX_LEN {
+            Err(anyhow!(
+                "canvas.max_color_id of {} is more than the {} colors supported",
+                self.max_color_id,
+                Palette::MAX_LEN
+            ))?
+        }
+        if palette.len() as u32 > self.max_color_id {
+            Err(anyhow!(
+                "Palette has {} colors but canvas.max_color_id is {}",
+                palette.len(),
+                self.max_color_id
+            ))?
+        }
+        if self.max_color_id as u64 > codec.max_color_id() {
+            Err(anyhow!(
+                "canvas.max_color_id of {} does not fit the {codec:?} pixel encoding, which goes up to {}",
+                self.max_color_id,
+                codec.max_color_id()
+            ))?
+        }
+        Ok(())
+    }
+
+    fn transform(&self, info: PixelInfo) -> PixelInfo {
+        let (width, height) = (PixelProvider::MAX_WIDTH, PixelProvider::MAX_HEIGHT);
+        let PixelInfo { x, y, color_id } = info;
+        let (x, y) = match self.origin {
+            Origin::TopLeft => (x, y),
+            Origin::TopRight => (width - 1 - x, y),
+            Origin::BottomLeft => (x, height - 1 - y),
+            Origin::BottomRight => (width - 1 - x, height - 1 - y),
+        };
+        if !self.swap_axes {
+            return PixelInfo { x, y, color_id };
+        }
+        // Packed column by column, as y + x * height; the codec packs rows, so spell that as a row position
+        let position = y + x * height;
+        PixelInfo {
+            x: position % width,
+            y: position / width,
+            color_id,
+        }
+    }
+
+    fn untransform(&self, info: PixelInfo) -> PixelInfo {
+        let (width, height) = (PixelProvider::MAX_WIDTH, PixelProvider::MAX_HEIGHT);
+        let PixelInfo { x, y, color_id } = info;
+        let (x, y) = if self.swap_axes {
+            let position = x + y * width;
+            (position / height, position % height)
+        } else {
+            (x, y)
+        };
+        // Mirroring is its own inverse
+        let mirrored = Self {
+            swap_axes: false,
+            ..*self
+        };
+        mirrored.transform(PixelInfo { x, y, color_id })
+    }
+}
+
+fn build_queue(config: &Config, context: &Context) -> anyhow::Result<Vec<PixelInfo>> {
+    let Work {
+        mut frame, pixels, ..
+    } = build_work(config, context)?;
+    frame.extend(pixels);
+    Ok(frame)
+}
+
+// The frame and the artwork, kept apart so the frame can go first
+#[derive(Default)]
+struct Work {
+    frame: Vec<PixelInfo>,
+    pixels: Vec<PixelInfo>,
+    // Source colors of the pixels without an exact palette match
+    inexact: Sources,
+    // Set on all brushes together only
+    coverage: Coverage,
+    // Index in all_brushes of the brush each position was kept for
+    owners: HashMap<(u32, u32), usize>,
+}
+
+// Where on the canvas a brush paints, after clipping, overlaps and pins
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct BoundingBox {
+    pub min_x: u32,
+    pub min_y: u32,
+    pub max_x: u32,
+    pub max_y: u32,
+    pub pixels: u32,
+}
+
+impl BoundingBox {
+    fn of<'a>(pixels: impl Iterator<Item = &'a PixelInfo>) -> Option<Self> {
+        pixels.fold(None, |bounds: Option<Self>, pixel| {
+            let point = Self {
+                min_x: pixel.x,
+                min_y: pixel.y,
+                max_x: pixel.x,
+                max_y: pixel.y,
+                pixels: 1,
+            };
+            Some(bounds.map_or(point, |bounds| bounds.union(point)))
+        })
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self {
+            min_x: self.min_x.min(other.min_x),
+            min_y: self.min_y.min(other.min_y),
+            max_x: self.max_x.max(other.max_x),
+            max_y: self.max_y.max(other.max_y),
+            pixels: self.pixels + other.pixels,
+        }
+    }
+}
+
+impl std::fmt::Display for BoundingBox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{{}:{}}} to {{{}:{}}}, {} pixels",
+            self.min_x, self.min_y, self.max_x, self.max_y, self.pixels
+        )
+    }
+}
+
+// One box per brush, in config order, and one around them all
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct Coverage {
+    pub brushes: Vec<Option<BoundingBox>>,
+    pub union: Option<BoundingBox>,
+}
+
+type Sources = HashMap<(u32, u32), (u8, u8, u8)>;
+
+// All brushes together
+fn build_work(config: &Config, context: &Context) -> anyhow::Result<Work> {
+    config
+        .canvas
+        .spec
+        .check(&context.palette(), context.wire.codec)?;
+    let works = config
+        .all_brushes()
+        .map(|brush| brush_work(config, brush, context))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let pinned = config
+        .pinned
+        .iter()
+        .flat_map(Pin::positions)
+        .collect::<HashSet<_>>();
+    let mut all = Work::default();
+    let mut works = resolve_overlaps(config, works)?
+        .into_iter()
+        .enumerate()
+        .collect::<Vec<_>>();
+    for (i, work) in &works {
+        let bounds = BoundingBox::of(
+            work.frame
+                .iter()
+                .chain(&work.pixels)
+                .filter(|pixel| !pinned.contains(&(pixel.x, pixel.y))),
+        );
+        all.coverage.brushes.push(bounds);
+        all.coverage.union = match (all.coverage.union, bounds) {
+            (Some(union), Some(bounds)) => Some(union.union(bounds)),
+            (union, bounds) => union.or(bounds),
+        };
+        for pixel in work.frame.iter().chain(&work.pixels) {
+            all.owners.insert((pixel.x, pixel.y), *i);
+        }
+    }
+    // Higher priority brushes are queued first, ties in config order
+    let brushes = config.all_brushes().collect::<Vec<_>>();
+    works.sort_by_key(|(i, _)| cmp::Reverse(brushes[*i].priority));
+    for (_, work) in works {
+        all.frame.extend(work.frame);
+        all.pixels.extend(work.pixels);
+        all.inexact.extend(work.inexact);
+    }
+    Ok(all)
+}
+
+fn brush_work(config: &Config, brush: &Brush, context: &Context) -> anyhow::Result<Work> {
+    let palette = context.palette();
+    let (pixels, inexact) = match &brush.source {
+        BrushSource::PlanFile(path) => (
+            Plan::load(path, config.canvas.spec, context)?.pixels,
+            HashMap::new(),
+        ),
+        BrushSource::Remote(_) => (Vec::new(), HashMap::new()),
+        source => {
+            let options = QuantizeOptions {
+                metric: config.color_metric,
+                dither: config.dither,
+                alpha_threshold: PixelProvider::ALPHA_THRESHOLD,
+                overrides: config
+                    .color_overrides
+                    .iter()
+                    .map(|(source, target)| Ok((parse_hex(source)?, target.resolve(&palette)?)))
+                    .collect::<anyhow::Result<HashMap<_, _>>>()?,
+            };
+            if !(0.0..=1.0).contains(&config.max_inexact_ratio) {
+                Err(anyhow!("max_inexact_ratio must be between 0 and 1"))?
+            }
+            if config
+                .max_color_distance
+                .is_some_and(|d| d.is_nan() || d < 0.0)
+            {
+                Err(anyhow!("max_color_distance must not be negative"))?
+            }
+            let visible = (
+                PixelProvider::MAX_WIDTH.saturating_sub(brush.offset_x),
+                PixelProvider::MAX_HEIGHT.saturating_sub(brush.offset_y),
+            );
+            PixelProvider::quantize(
+                source.load(visible, &palette)?,
+                &palette,
+                (brush.offset_x, brush.offset_y),
+                &options,
+                config.max_inexact_ratio,
+                config.max_color_distance,
+                config.parallel_quantize,
+            )?
+        }
+    };
+    let frame = match &brush.frame {
+        Some(frame) => frame.around(&pixels, &palette)?,
+        None => Vec::new(),
+    };
+    let pixels = match &brush.slice {
+        Some(slice) => slice_queue(pixels, slice.clone())?,
+        None => pixels,
+    };
+    Ok(Work {
+        frame,
+        pixels,
+        inexact,
+        coverage: Coverage::default(),
+        owners: HashMap::new(),
+    })
+}
+
+// Brushes sharing a pixel would repaint each other's work forever
+fn resolve_overlaps(config: &Config, mut works: Vec<Work>) -> anyhow::Result<Vec<Work>> {
+    let bounds = works
+        .iter()
+        .map(|Work { frame, pixels, .. }| {
+            let all = || frame.iter().chain(pixels);
+            Some((
+                all().map(|p| p.x).min()?,
+                all().map(|p| p.y).min()?,
+                all().map(|p| p.x).max()?,
+                all().map(|p| p.y).max()?,
+            ))
+        })
+        .collect::<Vec<_>>();
+    let intersect = |a: (u32, u32, u32, u32), b: (u32, u32, u32, u32)| {
+        a.0 <= b.2 && b.0 <= a.2 && a.1 <= b.3 && b.1 <= a.3
+    };
+    let touching = (0..bounds.len()).any(|i| {
+        (i + 1..bounds.len()).any(|j| match (bounds[i], bounds[j]) {
+            (Some(a), Some(b)) => intersect(a, b),
+            _ => false,
+        })
+    });
+    if !touching {
+        return Ok(works);
+    }
+    let brushes = config.all_brushes().collect::<Vec<_>>();
+    // Highest priority first, ties going to the brush listed first
+    let mut order = (0..works.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| std::cmp::Reverse(brushes[i].priority));
+    let mut owners = HashMap::new();
+    let mut contested = BTreeMap::<(usize, usize), u32>::new();
+    for &i in &order {
+        let Work { frame, pixels, .. } = &works[i];
+        for pixel in frame.iter().chain(pixels) {
+            let owner = *owners.entry(pixel.position()).or_insert(i);
+            if owner != i {
+                *contested.entry((owner, i)).or_default() += 1;
+            }
+        }
+    }
+    if contested.is_empty() {
+        return Ok(works);
+    }
+    let name = brush_name;
+    let total = contested.values().sum::<u32>();
+    let pairs = contested
+        .iter()
+        .map(|(&(owner, loser), count)| format!("{} over {} on {count}", name(owner), name(loser)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if !config.allow_overlap {
+        Err(anyhow!(
+            "Brushes overlap on {total} pixels ({pairs}); set allow_overlap to give each to the brush with the highest priority"
+        ))?
+    }
+    warn!("Brushes overlap on {total} pixels; {pairs}");
+    for (i, Work { frame, pixels, .. }) in works.iter_mut().enumerate() {
+        frame.retain(|pixel| owners[&pixel.position()] == i);
+        pixels.retain(|pixel| owners[&pixel.position()] == i);
+    }
+    Ok(works)
+}
+
+fn slice_queue(pixels: Vec<PixelInfo>, slice: IndexRange<usize>) -> anyhow::Result<Vec<PixelInfo>> {
+    let len = pixels.len();
+    pixels
+        .get(slice.clone())
+        .map(<[_]>::to_vec)
+        .ok_or_else(|| anyhow!("Slice {slice:?} is out of the queue of {len} pixels"))
+}
+
+pub fn plan(config: Config, out: PathBuf, slice: Option<IndexRange<usize>>) -> anyhow::Result<()> {
+    let context = Context::new(&config);
+    let mut pixels = build_queue(&config, &context)?;
+    if let Some(slice) = slice {
+        pixels = slice_queue(pixels, slice)?;
+    }
+    info!(
+        "Saving a plan of {} pixels to {}",
+        pixels.len(),
+        out.display()
+    );
+    Plan {
+        canvas: config.canvas.spec,
+        palette_hash: context.palette_hash(),
+        pixels,
+    }
+    .save(&out)
+}
+
+#[derive(Serialize)]
+struct OverlayEntry {
+    x: u32,
+    y: u32,
+    color: String,
+}
+
+/// Saves the pixels the bots would paint as `{x, y, color}` entries for overlay userscripts.
+pub fn export_overlay(
+    config: Config,
+    out: PathBuf,
+    remaining: bool,
+    pretty: bool,
+) -> anyhow::Result<()> {
+    let context = Context::new(&config);
+    let mut pixels = build_queue(&config, &context)?;
+    if remaining {
+        let path = config
+            .state_file
+            .as_ref()
+            .ok_or_else(|| anyhow!("--remaining needs a state_file in the config"))?;
+        pixels = StateFile::resume(path, pixels, config.canvas.spec)?.1;
+    }
+    let palette = context.palette();
+    let entries = pixels
+        .iter()
+        .map(|pixel| {
+            let (r, g, b) = palette.rgb_of(pixel.color_id).unwrap_or_default();
+            OverlayEntry {
+                x: pixel.x,
+                y: pixel.y,
+                color: format!("#{r:02X}{g:02X}{b:02X}"),
+            }
+        })
+        .collect::<Vec<_>>();
+    let file = io::BufWriter::new(File::create(&out)?);
+    if pretty {
+        serde_json::to_writer_pretty(file, &entries)?;
+    } else {
+        serde_json::to_writer(file, &entries)?;
+    }
+    info!(
+        "Saved {} overlay pixels to {}",
+        entries.len(),
+        out.display()
+    );
+    Ok(())
+}
+
+/// Prints how the brush pixels spread over the palette and the worst inexact source colors.
+pub fn palette_report(config: Config) -> anyhow::Result<()> {
+    let context = Context::new(&config);
+    let usage = PaletteUsage::of(build_work(&config, &context)?, &context);
+    let palette = context.palette();
+    let color = io::stdout().is_terminal()
+        && env::var_os("NO_COLOR").is_none()
+        && env::var("COLORTERM").is_ok_and(|term| term == "truecolor" || term == "24bit");
+    println!("Palette coverage of {} pixels:", usage.total);
+    for (id, (&rgb, &count)) in palette.colors().iter().zip(&usage.counts).enumerate() {
+        println!(
+            "  {} {id:>3}  {count:>8}  {:5.1}%",
+            swatch(rgb, color),
+            usage.share(count)
+        );
+    }
+    if usage.inexact.is_empty() {
+        return Ok(());
+    }
+    println!("Inexact source colors:");
+    for (source, count, id) in usage.inexact {
+        let target = palette.rgb_of(id).unwrap_or_default();
+        println!(
+            "  {} -> {} (id {id})  {count} px",
+            swatch(source, color),
+            swatch(target, color)
+        );
+    }
+    Ok(())
+}
+
+// Brush pixels per palette id, and the commonest inexact source colors with the id each became
+#[derive(PartialEq, Debug)]
+struct PaletteUsage {
+    counts: Vec<u32>,
+    total: usize,
+    inexact: Vec<((u8, u8, u8), u32, u8)>,
+}
+
+impl PaletteUsage {
+    const INEXACT_REPORTED: usize = 10;
+
+    fn of(work: Work, context: &Context) -> Self {
+        let mut counts = vec![0u32; context.palette().len()];
+        for pixel in work.frame.iter().chain(&work.pixels) {
+            if let Some(count) = counts.get_mut(pixel.color_id as usize) {
+                *count += 1;
+            }
+        }
+        let mut sources = HashMap::<_, u32>::new();
+        for rgb in work.inexact.into_values() {
+            *sources.entry(rgb).or_default() += 1;
+        }
+        let mut sources = sources.into_iter().collect::<Vec<_>>();
+        sources.sort_by_key(|&(rgb, count)| (cmp::Reverse(count), rgb));
+        let inexact = sources
+            .into_iter()
+            .take(Self::INEXACT_REPORTED)
+            .map(|((r, g, b), count)| ((r, g, b), count, context.resolve_color_id(r, g, b).id))
+            .collect();
+        Self {
+            counts,
+            total: work.frame.len() + work.pixels.len(),
+            inexact,
+        }
+    }
+
+    // Percent of all brush pixels
+    fn share(&self, count: u32) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            count as f64 / self.total as f64 * 100.0
+        }
+    }
+}
+
+// The hex code, after a block in that color when the terminal takes truecolor escapes
+fn swatch((r, g, b): (u8, u8, u8), color: bool) -> String {
+    if color {
+        format!("\x1b[48;2;{r};{g};{b}m    \x1b[0m #{r:02X}{g:02X}{b:02X}")
+    } else {
+        format!("#{r:02X}{g:02X}{b:02X}")
+    }
+}
+
+/// Prints the startup summary of a run with `config` without connecting anywhere.
+pub fn print_plan(config: Config) -> anyhow::Result<()> {
+    validate(&config)?;
+    let Work {
+        mut frame,
+        pixels,
+        coverage,
+        ..
+    } = build_work(&config, &Context::new(&config))?;
+    frame.extend(pixels);
+    let version = template_version(&frame);
+    println!("{}", describe(&config, &frame, &version, &coverage));
+    Ok(())
+}
+
+fn validate(config: &Config) -> anyhow::Result<()> {
+    for path in config.state_file.iter().chain(&config.capture_path) {
+        compress::check(path)?;
+    }
+    if !(0.0..=1.0).contains(&config.humanize.skip_probability) {
+        Err(anyhow!("humanize.skip_probability must be between 0 and 1"))?
+    }
+    if let Some(range) = &config.humanize.reaction_delay_ms {
+        range.check("humanize.reaction_delay_ms")?;
+    }
+    config.cooldown.check("cooldown")?;
+    if config.rampup_minutes == Some(0)
+        || config.rampup_factor.is_nan()
+        || config.rampup_factor < 1.0
+    {
+        Err(anyhow!(
+            "rampup_minutes must be positive and rampup_factor at least 1"
+        ))?
+    }
+    if let Some(schedule) = &config.schedule {
+        schedule.check()?;
+    }
+    if config.connect_concurrency == 0 {
+        Err(anyhow!("connect_concurrency must be at least 1"))?
+    }
+    if config.failure_streak == 0 {
+        Err(anyhow!("failure_streak must be at least 1"))?
+    }
+    if config.paints_per_cycle == 0 {
+        Err(anyhow!("paints_per_cycle must be at least 1"))?
+    }
+    if config.retry_lane_ratio == 0 {
+        Err(anyhow!("retry_lane_ratio must be at least 1"))?
+    }
+    if config.max_frame_size == 0 || config.max_frame_size > config.max_message_size {
+        Err(anyhow!(
+            "max_frame_size must be positive and at most max_message_size"
+        ))?
+    }
+    config.retry_policy.check()?;
+    if let Some(webhook) = &config.webhook {
+        if !matches!(webhook.scheme(), "http" | "https") {
+            Err(anyhow!("webhook must be http or https"))?
+        }
+    }
+    if let Some(auth) = &config.auth_refresh {
+        auth.check()?;
+    }
+    if config.calibrate {
+        config.calibration.check()?;
+    }
+    if let Some(deadline) = &config.deadline {
+        if deadline.left().is_zero() {
+            Err(anyhow!("deadline {deadline} has already passed"))?
+        }
+    }
+    config.deadline_priority.check()?;
+    if let Some(reset_guard) = &config.defend.reset_guard {
+        reset_guard.check()?;
+    }
+    if config.defend.demote_after == 0 || config.defend.give_up_after == Some(0) {
+        Err(anyhow!(
+            "defend.demote_after and defend.give_up_after must be at least 1"
+        ))?
+    }
+    if config.reconnect_limit.attempts == 0 || config.reconnect_limit.per == 0 {
+        Err(anyhow!(
+            "reconnect_limit needs attempts and per of at least 1"
+        ))?
+    }
+    if config
+        .all_brushes()
+        .filter_map(|brush| brush.frame.as_ref())
+        .any(|frame| frame.thickness == 0)
+    {
+        Err(anyhow!("brush.frame.thickness must be at least 1"))?
+    }
+    if config.all_brushes().enumerate().any(|(i, brush)| {
+        matches!(brush.source, BrushSource::Remote(_))
+            && (i > 0 || !config.brushes.is_empty() || brush.frame.is_some())
+    }) {
+        Err(anyhow!(
+            "a remote brush must be the only brush, and without a frame"
+        ))?
+    }
+    let bots = config.bot_configs();
+    for bot_config in &bots {
+        if !matches!(bot_config.url.scheme(), "ws" | "wss") {
+            Err(anyhow!(
+                "Bot {} has unsupported scheme {}, expected ws or wss",
+                bot_config
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| redact(&bot_config.url)),
+                bot_config.url.scheme()
+            ))?
+        }
+        if let Some(cooldown) = &bot_config.cooldown {
+            cooldown.check("bots.cooldown")?;
+        }
+        if !bot_config.cooldown_scale.is_none_or(|scale| scale > 0.0) {
+            Err(anyhow!("bots.cooldown_scale must be positive"))?
+        }
+        for proxy in &bot_config.proxies {
+            proxy::check(proxy)?;
+        }
+    }
+    let duplicates = find_duplicate_bots(&bots);
+    if !duplicates.is_empty() && !config.allow_duplicate_bots {
+        Err(anyhow!(
+            "Bots share an account: {}; set allow_duplicate_bots to run them anyway",
+            duplicates.join(", ")
+        ))?
+    }
+    Ok(())
+}
+
+// Multi-line summary of what the run is about to do
+fn describe(config: &Config, pixels: &[PixelInfo], version: &str, coverage: &Coverage) -> String {
+    let extent = |coordinate: fn(&PixelInfo) -> u32| {
+        let min = pixels.iter().map(coordinate).min().unwrap_or_default();
+        let max = pixels.iter().map(coordinate).max().map_or(0, |max| max + 1);
+        max - min
+    };
+    let spec = &config.canvas.spec;
+    let bots = config.bot_configs();
+    let mut lines = vec![
+        "Run plan:".to_string(),
+        format!(
+            "  brush: {}, {}x{} at {{{}:{}}}{}",
+            config.brush.source.describe(),
+            extent(|pixel| pixel.x),
+            extent(|pixel| pixel.y),
+            config.brush.offset_x,
+            config.brush.offset_y,
+            match &config.brush.frame {
+                Some(frame) => format!(", framed {}px first", frame.thickness),
+                None => String::new(),
+            }
+        ),
+    ];
+    for brush in &config.brushes {
+        lines.push(format!(
+            "    and {} at {{{}:{}}}, priority {}",
+            brush.source.describe(),
+            brush.offset_x,
+            brush.offset_y,
+            brush.priority
+        ));
+    }
+    let covers = |bounds: Option<BoundingBox>| {
+        bounds.map_or_else(|| "nothing".to_string(), |bounds| bounds.to_string())
+    };
+    lines.push(format!("  covers: {}", covers(coverage.union)));
+    if coverage.brushes.len() > 1 {
+        for (i, &bounds) in coverage.brushes.iter().enumerate() {
+            lines.push(format!("    {}: {}", brush_name(i), covers(bounds)));
+        }
+    }
+    lines.extend([
+        format!(
+            "  canvas: origin {:?}, {}, color ids up to {}",
+            spec.origin,
+            if spec.swap_axes {
+                "axes swapped"
+            } else {
+                "axes as is"
+            },
+            spec.max_color_id
+        ),
+        format!("  template: version {version}"),
+        format!("  traversal: {:?}", config.locality),
+        format!("  pixels: {}", pixels.len()),
+        format!(
+            "  bots: {} with {} connections",
+            bots.len(),
+            bots.iter().map(|bot| bot.connections).sum::<u32>()
+        ),
+    ]);
+    for bot in &bots {
+        let (min, max) = bot.cooldown(config.cooldown);
+        let cooldown = if min == max {
+            format!("fixed {:.1}s", min.as_secs_f64())
+        } else {
+            format!("uniform {:.1}-{:.1}s", min.as_secs_f64(), max.as_secs_f64())
+        };
+        lines.push(format!(
+            "    {} x{}: cooldown {cooldown}",
+            bot.name.clone().unwrap_or_else(|| redact(&bot.url)),
+            bot.connections
+        ));
+    }
+    lines.push(format!(
+        "  estimated completion: {}",
+        completion(pixels.len(), paint_rate(config))
+    ));
+    if let Some(deadline) = &config.deadline {
+        lines.push(format!(
+            "  deadline: {deadline}, room for about {} paints",
+            paints_before(deadline, paint_rate(config))
+        ));
+    }
+    lines.join("\n")
+}
+
+// Paints per second of all connections together
+fn paint_rate(config: &Config) -> f64 {
+    config
+        .bot_configs()
+        .iter()
+        .map(|bot| {
+            let (min, max) = bot.cooldown(config.cooldown);
+            (bot.connections as usize * config.paints_per_cycle) as f64
+                / ((min + max) / 2).as_secs_f64()
+        })
+        .sum()
+}
+
+fn paints_before(deadline: &Deadline, rate: f64) -> usize {
+    (rate * deadline.left().as_secs_f64()) as usize
+}
+
+fn completion(pixels: usize, rate: f64) -> String {
+    match pixels as f64 / rate {
+        secs if secs.is_finite() => {
+            let secs = secs.ceil() as u64;
+            format!("{}h {:02}m {:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+        }
+        _ => "as fast as the server allows".into(),
+    }
+}
+
+// Passwords and query values are cut to their first and last 4 characters
+fn redact(url: &Url) -> String {
+    let mask = |secret: &str| {
+        let chars = secret.chars().collect::<Vec<_>>();
+        if chars.len() <= 8 {
+            "****".to_string()
+        } else {
+            let (head, tail) = (&chars[..4], &chars[chars.len() - 4..]);
+            format!("{}...{}", String::from_iter(head), String::from_iter(tail))
+        }
+    };
+    let mut url = url.clone();
+    if let Some(password) = url.password().map(mask) {
+        url.set_password(Some(&password)).ok();
+    }
+    let pairs = url
+        .query_pairs()
+        .map(|(key, value)| (key.into_owned(), mask(&value)))
+        .collect::<Vec<_>>();
+    if !pairs.is_empty() {
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    url.to_string()
+}
+
+pub use crate::capture::{dump as dump_capture, replay as replay_capture, stats as audit_stats};
+pub use crate::cooldown::{Cooldown, CooldownContext, FixedCooldown, UniformCooldown};
+pub use crate::logging::{init as init_logging, LogConfig, LogFormat, Logging};
+pub use pb_core::{self, ColorId, Palette};
+
+/// Paints the configured brush until the queue is drained or `shutdown_signal` resolves.
+pub async fn run(
+    mut config: Config,
+    shutdown_signal: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<RunReport> {
+    validate(&config)?;
+    let limits = config.limits();
+    let context = Arc::new(Context::new(&config));
+    if config.canvas.auto {
+        match config.bots.first().or(config.scouts.first()) {
+            Some(entry) => discover_palette(entry, limits, &context).await?,
+            None => warn!("canvas.auto needs a bot to ask the server; using the built-in palette"),
+        }
+    }
+    if config.bots.is_empty() {
+        if config.scouts.is_empty() {
+            Err(anyhow!(
+                "No bots to paint with; to only watch the canvas, list at least one scout or run with --observe"
+            ))?
+        }
+        let scouts = config
+            .scouts
+            .iter()
+            .map(|entry| {
+                let (url, insecure) = entry.endpoint();
+                Endpoint {
+                    url: url.clone(),
+                    insecure,
+                    proxies: Vec::new(),
+                    limits,
+                }
+            })
+            .collect();
+        observe::run(
+            scouts,
+            context,
+            config.canvas.spec,
+            config.snapshot,
+            config.capture_path,
+            config.retry_policy,
+            shutdown_signal,
+        )
+        .await?;
+        return Ok(RunReport::default());
+    }
+    let Work {
+        frame,
+        pixels,
+        inexact,
+        coverage,
+        owners,
+    } = build_work(&config, &context)?;
+    let frame_positions = frame.iter().map(|p| (p.x, p.y)).collect::<HashSet<_>>();
+    let queue = frame.into_iter().chain(pixels).collect::<Vec<_>>();
+    let version = template_version(&queue);
+    let loaded = SystemTime::now();
+    let (state, queue, resumed) = match &config.state_file {
+        Some(path) => {
+            let planned = queue.iter().map(|p| (p.x, p.y)).collect::<HashSet<_>>();
+            let (state, queue) = StateFile::resume(path, queue, config.canvas.spec)?;
+            let remaining = queue.iter().map(|p| (p.x, p.y)).collect::<HashSet<_>>();
+            let resumed = planned.difference(&remaining).copied().collect::<Vec<_>>();
+            (Some(Arc::new(std::sync::Mutex::new(state))), queue, resumed)
+        }
+        None => (None, queue, Vec::new()),
+    };
+    info!("{}", describe(&config, &queue, &version, &coverage));
+    let confirm_timeout = Duration::from_secs(config.confirm_timeout);
+    let gate = config
+        .confirm_above_pixels
+        .filter(|&max| queue.len() > max)
+        .map(|max| {
+            warn!(
+                "{} pixels to paint, more than confirm_above_pixels of {max}. Type yes to start, or POST /confirm to the status server; giving up in {}s.",
+                queue.len(),
+                confirm_timeout.as_secs()
+            );
+            Gate::start(confirm_timeout)
+        });
+    let (webhook, delivery) = config.webhook.clone().map(Webhook::start).unzip();
+    if let Some(webhook) = &webhook {
+        webhook.send(Event::RunStart {
+            coverage: coverage.clone(),
+        });
+    }
+    let mut provider = PixelProvider::new(
+        queue,
+        frame_positions,
+        config.locality,
+        config.defend.enabled,
+        context.clone(),
+    );
+    provider.pinned = config.pinned.iter().flat_map(Pin::positions).collect();
+    provider.sources = inexact;
+    provider.coverage = coverage;
+    provider.assign_jobs(config.jobs(), &owners);
+    let planner = match &config.brush.source {
+        BrushSource::Remote(planner) => {
+            let (reports, receiver) = mpsc::unbounded_channel();
+            provider.planner = Some(reports);
+            Some((planner.clone(), receiver))
+        }
+        _ => None,
+    };
+    provider.reverts = config.defend.reverts();
+    provider.reset_guard = config.defend.reset_guard.map(ResetGuard::new);
+    provider.unconfirmed = gate.clone();
+    provider.webhook = webhook.clone();
+    provider.ack_match = config.ack_match;
+    provider.echo_remap = config.echo_remap.clone();
+    provider
+        .queue
+        .set_retries(config.retry_placement, config.retry_lane_ratio);
+    provider.max_retries = config.max_pixel_retries;
+    // Pixels painted in earlier runs seed growth
+    for (x, y) in resumed {
+        provider.queue.grow(x, y);
+    }
+    if let Some(state) = &state {
+        provider.pinned.extend(state.lock().unwrap().pinned());
+    }
+    if !provider.pinned.is_empty() {
+        info!(
+            "{} pixels are pinned and will be left alone",
+            provider.pinned.len()
+        );
+    }
+    let pixel = Arc::new(Mutex::new(provider));
+    let mut planner = planner.map(|(planner, reports)| {
+        let prefetch = config
+            .bot_configs()
+            .iter()
+            .map(|bot| bot.connections as usize * config.paints_per_cycle)
+            .sum();
+        tokio::spawn(planner::feed(planner, prefetch, pixel.clone(), reports))
+    });
+    let probe = config
+        .bot_configs()
+        .first()
+        .filter(|_| config.verify_first_paint)
+        .map(|bot| bot.endpoint(limits));
+    // Behind a gate the probe goes out once confirmed, with the workers already connected
+    if let Some(endpoint) = probe.as_ref().filter(|_| gate.is_none()) {
+        check_first_paint(
+            endpoint,
+            &pixel,
+            config.canvas.spec,
+            Duration::from_secs(config.first_paint_timeout),
+        )
+        .await?;
+    }
+    let (capture, capture_writer) = match &config.capture_path {
+        Some(path) => {
+            let (capture, writer) = Capture::start(path)?;
+            (Some(capture), Some(writer))
+        }
+        None => (None, None),
+    };
+    let stats = StatsRegistry::default();
+    let schedule = config.schedule.take().map(Arc::new);
+    // Up before calibration waits on the gate, so /confirm can reach it
+    let status = config.status.take().map(|status| {
+        tokio::spawn(status::serve(
+            status,
+            stats.clone(),
+            pixel.clone(),
+            Duration::from_secs(config.stall.timeout),
+            schedule.clone(),
+        ))
+    });
+    if let Some(bot) = config.bot_configs().first().filter(|_| config.calibrate) {
+        // Calibration paints, and the workers need its floor before they start
+        if let Some(gate) = &gate {
+            if !gate.decided().await {
+                if let Some(status) = &status {
+                    status.abort();
+                }
+                Err(unconfirmed(confirm_timeout))?
+            }
+        }
+        let calibrated = calibrate_cooldown(
+            &bot.endpoint(limits),
+            &pixel,
+            config.canvas.spec,
+            config.calibration,
+            config.cooldown.max,
+        )
+        .await;
+        match calibrated {
+            Ok(Some(found)) => {
+                let floor = found + config.calibration.margin;
+                info!(
+                    "Calibration: the server took paints {found}s apart; cooldown floor set to {floor}s, was {}s",
+                    config.cooldown.min
+                );
+                config.cooldown.min = floor;
+                config.cooldown.max = config.cooldown.max.max(floor);
+            }
+            Ok(None) => warn!("Calibration saw no paint accepted; keeping the configured cooldown"),
+            Err(why) => warn!("Calibration failed: {why}; keeping the configured cooldown"),
+        }
+    }
+    // With the calibrated floor, for what still fits before the deadline
+    let rate = paint_rate(&config);
+    let sleep = SleepPerformer::new(&config.humanize);
+    let (reconnect, _) = watch::channel(());
+    let (shutdown, _) = watch::channel(());
+    let shutdown = Arc::new(shutdown);
+    let (damaged, damage) = watch::channel(());
+    let (jumped, clock_jump) = watch::channel(());
+    let clock_watch = tokio::spawn(clock::watch_jumps(
+        Duration::from_secs(config.clock_jump_threshold),
+        jumped,
+    ));
+    let shared = Shared {
+        pixel: pixel.clone(),
+        context: context.clone(),
+        canvas: config.canvas.spec,
+        reconnect: reconnect.subscribe(),
+        shutdown: shutdown.subscribe(),
+        damage,
+        damaged: Arc::new(damaged),
+        clock_jump: clock_jump.clone(),
+        idle_after: Duration::from_secs(config.defend.idle_after),
+        idle_wake: Duration::from_secs(config.defend.idle_wake),
+        schedule: schedule.clone(),
+        capture: capture.clone(),
+        failure_streak: config.failure_streak,
+        retry: config.retry_policy,
+        stats: stats.clone(),
+        close_codes: Arc::new(config.close_codes),
+        paints_per_cycle: config.paints_per_cycle,
+        log: config.log,
+        fairness: config.fairness,
+        fairness_margin: config.fairness_margin,
+        max_rx_bytes_per_hour: config.max_rx_bytes_per_hour,
+        auth_refresh: config.auth_refresh.map(Arc::new),
+        reconnect_bucket: Arc::new(Bucket::new(config.reconnect_limit)),
+        requantize: config
+            .canvas
+            .auto
+            .then_some(config.canvas.requantize_completed),
+    };
+    let ramp = config.rampup_minutes.map(|minutes| {
+        Arc::new(RampUp::new(
+            Duration::from_secs(minutes * 60),
+            config.rampup_factor,
+        ))
+    });
+    if let Some(ramp) = &ramp {
+        shared.stats.set_ramp(ramp.clone());
+    }
+    let handles = FuturesUnordered::new();
+    let never_connected = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut connected = 0;
+    let mut id = 0;
+    let mut workers = Vec::new();
+    for bot_config in config.bots.into_iter().map(BotConfig::from) {
+        if bot_config.connections > config.max_connections_per_bot {
+            warn!(
+                "{} requests {} connections, more than the safety cap of {}",
+                redact(&bot_config.url),
+                bot_config.connections,
+                config.max_connections_per_bot
+            );
+        }
+        let (min, max) = bot_config.cooldown(config.cooldown);
+        for n in 1..=bot_config.connections {
+            let name = match &bot_config.name {
+                Some(name) if bot_config.connections > 1 => format!("{name}/{n}"),
+                Some(name) => name.clone(),
+                None => format!("#{id}"),
+            };
+            let connect = {
+                let (name, endpoint) = (name.clone(), bot_config.endpoint(limits));
+                let (sleep, shared, ramp) = (sleep.clone(), shared.clone(), ramp.clone());
+                move || {
+                    Bot::new(
+                        id,
+                        name.clone(),
+                        endpoint.clone(),
+                        sleep.clone(),
+                        cooldown::ramped(cooldown::from_range(min, max), ramp.clone()),
+                        shared.clone(),
+                    )
+                }
+            };
+            shared.stats.register(name.clone(), redact(&bot_config.url));
+            workers.push((id, name, bot_config.url.clone(), connect));
+            id += 1;
+        }
+    }
+    if config.shuffle_assignments {
+        let table = pixel
+            .lock()
+            .await
+            .queue
+            .shuffle(workers.len(), config.shuffle_seed);
+        if table.is_empty() {
+            warn!("shuffle_assignments has no effect unless locality is clustered");
+        }
+        for (id, (x, y)) in table {
+            let size = Clusters::BLOCK_SIZE;
+            info!(
+                "Worker {} starts at block {},{} (pixel {},{})",
+                workers[id as usize].1,
+                x,
+                y,
+                x * size,
+                y * size
+            );
+        }
+    }
+    let started = Instant::now();
+    // Worker ids were assigned above, so completion order does not matter
+    let attempts = futures::stream::iter(workers)
+        .map(|(id, name, url, connect)| async move { (connect().await, id, name, url, connect) })
+        .buffer_unordered(config.connect_concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    info!(
+        "Initial connects took {:.1}s.",
+        started.elapsed().as_secs_f64()
+    );
+    for (bot, id, name, url, connect) in attempts {
+        match bot {
+            Ok(bot) => {
+                connected += 1;
+                handles.push(tokio::spawn(bot.run()));
+            }
+            Err(why) => {
+                warn!(
+                    "Worker {name} cannot connect to {}: {why}; retrying in background.",
+                    redact(&url)
+                );
+                shared.stats.bot(id).set(State::Disconnected);
+                never_connected.lock().unwrap().push(name.clone());
+                handles.push(tokio::spawn(retry_bot(
+                    name,
+                    why,
+                    connect,
+                    shared.clone(),
+                    never_connected.clone(),
+                )));
+            }
+        }
+    }
+    if connected < config.min_bots {
+        // Nothing painted yet, so nothing has a send to finish
+        for handle in handles.iter() {
+            handle.abort();
+        }
+        clock_watch.abort();
+        if let Some(status) = &status {
+            status.abort();
+        }
+        if let Some(feed) = &planner {
+            feed.abort();
+        }
+        Err(anyhow!(
+            "Only {connected} of {id} workers connected, at least {} required",
+            config.min_bots
+        ))?
+    }
+    #[cfg(all(unix, feature = "systemd"))]
+    systemd::notify("READY=1");
+    // Pings stop once no bot is connected or painting stalls, so systemd restarts us
+    #[cfg(all(unix, feature = "systemd"))]
+    let watchdog = systemd::watchdog_interval().map(|interval| {
+        let (stats, pixel, schedule) = (shared.stats.clone(), pixel.clone(), schedule.clone());
+        let stall = Duration::from_secs(config.stall.timeout);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                match status::health(&stats, &pixel, 1, stall, schedule.as_deref()).await {
+                    None => systemd::notify("WATCHDOG=1"),
+                    Some(problem) => debug!("Skipping the watchdog ping: {problem}"),
+                }
+            }
+        })
+    });
+    let progress = tokio::spawn({
+        let (pixel, stats) = (pixel.clone(), shared.stats.clone());
+        async move {
+            let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let report = pixel.lock().await.report();
+                let pool = stats.snapshot();
+                match &pool.online {
+                    Some(online) if online.age_secs < PROGRESS_INTERVAL.as_secs() => {
+                        info!("Progress: {report}; {} users online", online.count)
+                    }
+                    Some(online) => info!(
+                        "Progress: {report}; {} users online {}s ago",
+                        online.count, online.age_secs
+                    ),
+                    None => info!("Progress: {report}"),
+                }
+                if logging::progress() {
+                    info!("Workers:\n{}", status::table(&pool));
+                }
+            }
+        }
+    });
+    let gatekeeper = gate.map(|gate| {
+        let (pixel, shutdown, probe) = (pixel.clone(), shutdown.clone(), probe.clone());
+        let (spec, timeout) = (
+            config.canvas.spec,
+            Duration::from_secs(config.first_paint_timeout),
+        );
+        tokio::spawn(async move {
+            let checked = if !gate.decided().await {
+                Err(unconfirmed(confirm_timeout))
+            } else if let Some(endpoint) = probe {
+                check_first_paint(&endpoint, &pixel, spec, timeout).await
+            } else {
+                Ok(())
+            };
+            if let Err(why) = &checked {
+                error!("{why}; shutting down");
+                shutdown.send_replace(());
+            } else {
+                pixel.lock().await.unconfirmed = None;
+            }
+            checked
+        })
+    });
+    let saver = state
+        .as_ref()
+        .zip(config.state_file.clone())
+        .map(|(state, path)| {
+            let (state, pixel) = (state.clone(), pixel.clone());
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(STATE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    save_state(&state, &pixel, &path).await;
+                }
+            })
+        });
+    let cumulative = config
+        .cumulative_stats
+        .as_deref()
+        .map(|path| Arc::new(Cumulative::load(path)));
+    let flusher = cumulative.clone().map(|cumulative| {
+        let (pixel, stats) = (pixel.clone(), stats.clone());
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(STATE_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                cumulative.save(&cumulative_totals(&cumulative, &pixel, &stats).await);
+            }
+        })
+    });
+    let reconnect = Arc::new(reconnect);
+    let reset_guard = config.defend.reset_guard.map(|guard| {
+        let reconnect = guard.refetch_board.then(|| reconnect.clone());
+        tokio::spawn(watch_resets(pixel.clone(), reconnect, webhook.clone()))
+    });
+    let stall = tokio::spawn({
+        let watched = watch_stalls(
+            pixel.clone(),
+            reconnect,
+            config.stall,
+            schedule,
+            webhook.clone(),
+        );
+        let shutdown = shutdown.clone();
+        async move {
+            watched.await;
+            shutdown.send_replace(());
+        }
+    });
+    let griefing = config.griefing.threshold.map(|threshold| {
+        let urgent = config.griefing.disable_skip.then(|| sleep.urgent.clone());
+        tokio::spawn(watch_griefing(
+            pixel.clone(),
+            threshold,
+            urgent,
+            webhook.clone(),
+        ))
+    });
+    #[cfg(unix)]
+    let heatmap = config.defend.heatmap.clone().map(|path| {
+        let pixel = pixel.clone();
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let Ok(mut signal) = signal(SignalKind::user_defined1()) else {
+                return;
+            };
+            while signal.recv().await.is_some() {
+                let heatmap = pixel.lock().await.heatmap();
+                match heatmap.map(|heatmap| save_image(&heatmap, &path)) {
+                    Some(Ok(())) => info!("Saved repaint heatmap to {}", path.display()),
+                    Some(Err(why)) => warn!("Cannot save heatmap to {}: {why}", path.display()),
+                    None => warn!("Nothing to draw a heatmap of"),
+                }
+            }
+        })
+    });
+    let trimmer = config.deadline.clone().map(|deadline| {
+        let (pixel, priority) = (pixel.clone(), config.deadline_priority.clone());
+        let mut clock_jump = clock_jump.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(DEADLINE_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    Ok(()) = clock_jump.changed() => {}
+                }
+                let budget = paints_before(&deadline, rate);
+                pixel.lock().await.trim(budget, &priority);
+            }
+        })
+    });
+    let deadline = config.deadline.clone();
+    // True when the deadline and not the signal ended the run
+    let mut clock_jump = clock_jump;
+    let interrupt = tokio::spawn(async move {
+        tokio::pin!(shutdown_signal);
+        let reached = loop {
+            let left = deadline.as_ref().map_or(Duration::MAX, Deadline::left);
+            tokio::select! {
+                _ = &mut shutdown_signal => break false,
+                _ = tokio::time::sleep(left), if deadline.is_some() => break true,
+                Ok(()) = clock_jump.changed(), if deadline.is_some() => {
+                    let left = deadline.as_ref().map_or(Duration::MAX, Deadline::left);
+                    info!("Deadline is now {}s away by the corrected clock.", left.as_secs());
+                }
+            }
+        };
+        match &deadline {
+            Some(deadline) if reached => {
+                info!("Deadline {deadline} reached; waiting for workers to finish their sends.")
+            }
+            _ => info!("Shutting down; waiting for workers to finish their sends."),
+        }
+        #[cfg(all(unix, feature = "systemd"))]
+        systemd::notify("STOPPING=1");
+        shutdown.send_replace(());
+        reached
+    });
+    handles.collect::<Vec<_>>().await;
+    drop(shared);
+    // Letting go of the sender tells the feed to send its last reports and hang up
+    if let Some(feed) = &mut planner {
+        pixel.lock().await.planner = None;
+        if tokio::time::timeout(PLANNER_GOODBYE, &mut *feed)
+            .await
+            .is_err()
+        {
+            warn!("The planner did not take the last reports in time");
+            feed.abort();
+        }
+    }
+    if let (Some(capture), Some(writer)) = (capture, capture_writer) {
+        let dropped = capture.dropped();
+        drop(capture);
+        drop(writer.await);
+        if dropped > 0 {
+            warn!("Capture dropped {dropped} frames while the writer was behind.");
+        }
+    }
+    interrupt.abort();
+    let deadline_reached = matches!(interrupt.await, Ok(true));
+    clock_watch.abort();
+    if let Some(trimmer) = trimmer {
+        trimmer.abort();
+    }
+    progress.abort();
+    stall.abort();
+    let stalled = stall.await.is_ok();
+    pixel.lock().await.webhook = None;
+    drop(webhook);
+    if let Some(delivery) = delivery {
+        if tokio::time::timeout(WEBHOOK_GOODBYE, delivery)
+            .await
+            .is_err()
+        {
+            warn!("The webhook did not take the last events in time");
+        }
+    }
+    if let Some(status) = status {
+        status.abort();
+    }
+    if let (Some(saver), Some(state), Some(path)) = (saver, &state, &config.state_file) {
+        saver.abort();
+        save_state(state, &pixel, path).await;
+    }
+    if let Some(griefing) = griefing {
+        griefing.abort();
+    }
+    if let Some(reset_guard) = reset_guard {
+        reset_guard.abort();
+    }
+    #[cfg(unix)]
+    if let Some(heatmap) = heatmap {
+        heatmap.abort();
+    }
+    #[cfg(all(unix, feature = "systemd"))]
+    if let Some(watchdog) = watchdog {
+        watchdog.abort();
+    }
+    if let Some(flusher) = flusher {
+        flusher.abort();
+    }
+    let totals = match &cumulative {
+        Some(cumulative) => {
+            let totals = cumulative_totals(cumulative, &pixel, &stats).await;
+            cumulative.save(&totals);
+            Some(totals)
+        }
+        None => None,
+    };
+    if let Some(gatekeeper) = gatekeeper {
+        gatekeeper.abort();
+        if let Ok(Err(why)) = gatekeeper.await {
+            return Err(why);
+        }
+    }
+    let mut report = pixel.lock().await.report();
+    report.never_connected = never_connected.lock().unwrap().clone();
+    report.cumulative = totals;
+    // The template is loaded once per run, so this run painted a single version
+    report.template_versions = vec![TemplateVersion {
+        version,
+        active_from: unix_secs(loaded),
+        active_until: unix_secs(SystemTime::now()),
+    }];
+    let dropped = pixel.lock().await.dropped.len() as u32;
+    report.deadline = config.deadline.as_ref().map(|deadline| DeadlineReport {
+        at: deadline.to_string(),
+        reached: deadline_reached,
+        dropped,
+    });
+    report.stalled = stalled;
+    info!("Finished: {report}");
+    if !report.never_connected.is_empty() {
+        warn!(
+            "Workers that never connected: {}",
+            report.never_connected.join(", ")
+        );
+    }
+    if let Some(path) = config.summary {
+        serde_json::to_writer_pretty(File::create(path)?, &report)?;
+    }
+    Ok(report)
+}
+
+/// Runs against an in-process server on a paused clock, so `duration` passes as fast as the bots
+/// can paint, and saves the final canvas to `out`.
+pub fn simulate(
+    config: Config,
+    griefs_per_minute: f64,
+    duration: Duration,
+    out: PathBuf,
+) -> anyhow::Result<RunReport> {
+    // Time only auto-advances on a paused single-threaded runtime, so the rehearsal gets its own
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()?
+            .block_on(rehearse(config, griefs_per_minute, duration, out))
+    })
+    .join()
+    .map_err(|_| anyhow!("The simulation panicked"))?
+}
+
+async fn rehearse(
+    mut config: Config,
+    griefs_per_minute: f64,
+    duration: Duration,
+    out: PathBuf,
+) -> anyhow::Result<RunReport> {
+    let cooldown = Duration::from_secs(config.cooldown.min);
+    let (server, url) = simulate::Server::start(cooldown, Context::new(&config)).await?;
+    let url = Url::parse(&url)?;
+    config.bots = mem::take(&mut config.bots)
+        .into_iter()
+        .map(|entry| {
+            let bot = BotConfig::from(entry);
+            BotEntry::Config(BotConfig {
+                url: url.clone(),
+                allow_insecure: false,
+                proxies: Vec::new(),
+                ..bot
+            })
+        })
+        .collect();
+    config.allow_duplicate_bots = true;
+    config.canvas.auto = false;
+    // Nothing outside the process runs on the paused clock: a prompt or a request would time out at once
+    config.assume_yes();
+    config.webhook = None;
+    config.auth_refresh = None;
+    let targets = build_queue(&config, &Context::new(&config))?
+        .into_iter()
+        .map(|pixel| config.canvas.spec.transform(pixel))
+        .collect();
+    let griefer = tokio::spawn(server.grief(targets, griefs_per_minute / 60.0));
+    let started = std::time::Instant::now();
+    let report = run(config, tokio::time::sleep(duration)).await;
+    griefer.abort();
+    save_image(&server.snapshot().await, &out)?;
+    info!(
+        "Simulated {}s in {:.1}s and saved the canvas to {}; the server rejected {} paints sent during cooldown.",
+        duration.as_secs(),
+        started.elapsed().as_secs_f64(),
+        out.display(),
+        server.rejected.load(Ordering::Relaxed)
+    );
+    report
+}
+
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn discover_palette(
+    entry: &BotEntry,
+    limits: Limits,
+    context: &Context,
+) -> anyhow::Result<()> {
+    let (url, insecure) = entry.endpoint();
+    let metadata = tokio::time::timeout(DISCOVERY_TIMEOUT, async {
+        let mut connection = Bot::connect(url, insecure, limits).await?;
+        while let Some(msg) = connection.next().await {
+            if let tungstenite::Message::Text(text) = msg? {
+                if let Some(metadata) = protocol::parse_metadata(&text) {
+                    drop(connection.close(None).await);
+                    return Ok(Some(metadata));
+                }
+            }
+        }
+        anyhow::Ok(None)
+    })
+    .await;
+    let metadata = match metadata {
+        Ok(Ok(Some(metadata))) => metadata,
+        Ok(Err(why)) => {
+            warn!("Canvas discovery failed: {why}; using the built-in palette");
+            return Ok(());
+        }
+        _ => {
+            warn!("Server sent no canvas metadata; using the built-in palette");
+            return Ok(());
+        }
+    };
+    let (width, height) = (PixelProvider::MAX_WIDTH, PixelProvider::MAX_HEIGHT);
+    if metadata.width.is_some_and(|w| w != width) || metadata.height.is_some_and(|h| h != height) {
+        warn!(
+            "!!! Server canvas is {}x{} but this build paints a {width}x{height} canvas !!!",
+            metadata.width.unwrap_or(width),
+            metadata.height.unwrap_or(height)
+        );
+    }
+    let colors = metadata
+        .palette
+        .iter()
+        .map(|hex| parse_hex(hex))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if colors.is_empty() {
+        Err(anyhow!("Server advertised an empty palette"))?
+    }
+    let count = colors.len();
+    let Some(colors) = Palette::new(colors) else {
+        Err(anyhow!(
+            "Server advertised {count} colors but at most {} are supported",
+            Palette::MAX_LEN
+        ))?
+    };
+    if colors != Palette::default() {
+        warn!(
+            "!!! Server palette of {} colors differs from the built-in one; using the server's !!!",
+            colors.len()
+        );
+    }
+    context.set_palette(colors);
+    Ok(())
+}
+
+/// Listens to the canvas for `listen` and reports the share of the template intact on it.
+pub async fn verify(
+    config: Config,
+    listen: Duration,
+    diff: Option<PathBuf>,
+) -> anyhow::Result<f64> {
+    validate(&config)?;
+    let entry = config
+        .bots
+        .first()
+        .or(config.scouts.first())
+        .ok_or_else(|| anyhow!("verify needs a bot or scout to watch the canvas with"))?;
+    let context = Context::new(&config);
+    if config.canvas.auto {
+        discover_palette(entry, config.limits(), &context).await?;
+    }
+    let pixels = build_queue(&config, &context)?;
+    let canvas = watch_canvas(&config, entry, listen, &context).await?;
+    let verification = Verification::compare(&canvas, &pixels, context.palette());
+    println!("{verification}");
+    if let Some(diff) = diff {
+        save_image(
+            &Verification::diff(&canvas, &pixels, &context.palette()),
+            &diff,
+        )?;
+        println!("Saved the differences to {}", diff.display());
+    }
+    Ok(verification.ratio())
+}
+
+// The canvas as far as `listen` worth of updates through `entry` shows it
+async fn watch_canvas(
+    config: &Config,
+    entry: &BotEntry,
+    listen: Duration,
+    context: &Context,
+) -> anyhow::Result<Canvas> {
+    let (url, insecure) = entry.endpoint();
+    let mut connection = Bot::connect(url, insecure, config.limits()).await?;
+    let mut canvas = Canvas::new(PixelProvider::MAX_WIDTH, PixelProvider::MAX_HEIGHT);
+    info!(
+        "Watching the canvas through {} for {}s",
+        redact(url),
+        listen.as_secs()
+    );
+    let deadline = tokio::time::sleep(listen);
+    tokio::pin!(deadline);
+    loop {
+        let msg = tokio::select! {
+            msg = connection.next() => msg,
+            () = &mut deadline => break,
+        };
+        match msg {
+            Some(Ok(tungstenite::Message::Binary(frame))) => {
+                for pixel in context.decode_updates(&frame) {
+                    let pixel = config.canvas.spec.untransform(pixel);
+                    canvas.set(pixel.x, pixel.y, pixel.color_id);
+                }
+            }
+            Some(Ok(_)) => {}
+            Some(Err(tungstenite::Error::Utf8)) => protocol::parse_failed(),
+            Some(Err(why)) => Err(why)?,
+            None => break,
+        }
+    }
+    drop(connection.close(None).await);
+    Ok(canvas)
+}
+
+/// Compares placing the brush at each of `offsets`, diffing against the live canvas when `listen` is set.
+pub async fn estimate(
+    mut config: Config,
+    offsets: Vec<(u32, u32)>,
+    listen: Option<Duration>,
+) -> anyhow::Result<()> {
+    validate(&config)?;
+    if matches!(config.brush.source, BrushSource::PlanFile(_)) {
+        Err(anyhow!("estimate cannot move a plan file brush"))?
+    }
+    let context = Context::new(&config);
+    let canvas = match listen {
+        Some(listen) => {
+            let entry = config
+                .bots
+                .first()
+                .or(config.scouts.first())
+                .ok_or_else(|| anyhow!("--listen needs a bot or scout to watch the canvas with"))?;
+            if config.canvas.auto {
+                discover_palette(entry, config.limits(), &context).await?;
+            }
+            Some(watch_canvas(&config, entry, listen, &context).await?)
+        }
+        None => None,
+    };
+    // Quantized once at the origin; each candidate only shifts and clips it
+    (config.brush.offset_x, config.brush.offset_y) = (0, 0);
+    let frame = config.brush.frame.take();
+    let template = brush_work(&config, &config.brush, &context)?.pixels;
+    let rate = paint_rate(&config);
+    println!(
+        "{:<12} {:>8} {:>8} {:>9} {:>9}  estimate",
+        "offset", "pixels", "clipped", "matching", "to paint"
+    );
+    for (x, y) in offsets {
+        let Candidate {
+            pixels,
+            clipped,
+            matching,
+            to_paint,
+        } = Candidate::at(&template, (x, y), frame.as_ref(), canvas.as_ref(), &context)?;
+        println!(
+            "{:<12} {pixels:>8} {clipped:>8} {:>9} {to_paint:>9}  {}",
+            format!("{x},{y}"),
+            matching.map_or("-".into(), |matching| matching.to_string()),
+            completion(to_paint, rate)
+        );
+    }
+    Ok(())
+}
+
+// One offset of `pb estimate`, in pixels
+#[derive(PartialEq, Debug)]
+struct Candidate {
+    pixels: usize,
+    clipped: usize,
+    // None without a board to compare with
+    matching: Option<usize>,
+    to_paint: usize,
+}
+
+impl Candidate {
+    // `template` is quantized at the origin, so moving it is only a shift and a clip
+    fn at(
+        template: &[PixelInfo],
+        (x, y): (u32, u32),
+        frame: Option<&FrameConfig>,
+        canvas: Option<&Canvas>,
+        context: &Context,
+    ) -> anyhow::Result<Self> {
+        let mut pixels = template
+            .iter()
+            .filter(|p| p.x + x < PixelProvider::MAX_WIDTH && p.y + y < PixelProvider::MAX_HEIGHT)
+            .map(|p| p.clone().with_offset(x, y))
+            .collect::<Vec<_>>();
+        let clipped = template.len() - pixels.len();
+        if let Some(frame) = frame {
+            pixels.extend(frame.around(&pixels, &context.palette())?);
+        }
+        let matching = canvas.map(|canvas| {
+            pixels
+                .iter()
+                .filter(|p| canvas.get(p.x, p.y) == p.color_id)
+                .count()
+        });
+        Ok(Self {
+            pixels: pixels.len(),
+            clipped,
+            matching,
+            to_paint: pixels.len() - matching.unwrap_or_default(),
+        })
+    }
+}
+
+const PAINT_ONE_WATCH: Duration = Duration::from_secs(30);
+// Per bot when validating: the whole connect, then the wait for a first frame
+const VALIDATE_CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const VALIDATE_FIRST_FRAME: Duration = Duration::from_secs(3);
+
+struct Validation {
+    name: String,
+    latency: Option<Duration>,
+    handshake: String,
+    first_frame: String,
+    passed: bool,
+}
+
+/// Connects each configured bot once without painting and prints whether it works; errors if any fails.
+pub async fn validate_bots(config: Config) -> anyhow::Result<()> {
+    validate(&config)?;
+    let bots = config.bot_configs();
+    if bots.is_empty() {
+        Err(anyhow!("No bots configured to validate"))?
+    }
+    let results = futures::stream::iter(&bots)
+        .map(|bot| validate_bot(bot, config.limits()))
+        .buffered(config.connect_concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    let width = results
+        .iter()
+        .map(|r| r.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(3);
+    println!(
+        "{:<width$} {:>9}  {:<9} {:<24} verdict",
+        "bot", "latency", "handshake", "first frame"
+    );
+    for result in &results {
+        let latency = match result.latency {
+            Some(latency) => format!("{}ms", latency.as_millis()),
+            None => "-".into(),
+        };
+        println!(
+            "{:<width$} {latency:>9}  {:<9} {:<24} {}",
+            result.name,
+            if result.latency.is_some() {
+                "ok"
+            } else {
+                "failed"
+            },
+            result.first_frame,
+            if result.passed { "pass" } else { "FAIL" }
+        );
+        if result.latency.is_none() {
+            println!("{:<width$}   {}", "", result.handshake);
+        }
+    }
+    let passed = results.iter().filter(|r| r.passed).count();
+    if passed < results.len() {
+        Err(anyhow!(
+            "{} of {} bots failed validation",
+            results.len() - passed,
+            results.len()
+        ))?
+    }
+    if passed < config.min_bots as usize {
+        Err(anyhow!(
+            "Only {passed} bots passed, min_bots needs {}",
+            config.min_bots
+        ))?
+    }
+    Ok(())
+}
+
+async fn validate_bot(bot: &BotConfig, limits: Limits) -> Validation {
+    let name = bot.name.clone().unwrap_or_else(|| redact(&bot.url));
+    let started = Instant::now();
+    let connected = tokio::time::timeout(
+        VALIDATE_CONNECT_TIMEOUT,
+        Bot::connect_through(&bot.endpoint(limits)),
+    )
+    .await;
+    let mut connection = match connected {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(why)) => {
+            return Validation {
+                name,
+                latency: None,
+                handshake: why.to_string(),
+                first_frame: "-".into(),
+                passed: false,
+            }
+        }
+        Err(_) => {
+            return Validation {
+                name,
+                latency: None,
+                handshake: format!("no answer in {}s", VALIDATE_CONNECT_TIMEOUT.as_secs()),
+                first_frame: "-".into(),
+                passed: false,
+            }
+        }
+    };
+    let latency = started.elapsed();
+    // A server that dislikes the token often accepts the upgrade and closes right after
+    let (first_frame, passed) =
+        match tokio::time::timeout(VALIDATE_FIRST_FRAME, connection.next()).await {
+            Err(_) => ("none".into(), true),
+            Ok(Some(Ok(tungstenite::Message::Binary(frame)))) => {
+                (format!("binary, {} bytes", frame.len()), true)
+            }
+            Ok(Some(Ok(tungstenite::Message::Text(text)))) => {
+                (format!("text, {} bytes", text.len()), true)
+            }
+            Ok(Some(Ok(tungstenite::Message::Ping(_) | tungstenite::Message::Pong(_)))) => {
+                ("ping".into(), true)
+            }
+            Ok(Some(Ok(tungstenite::Message::Close(frame)))) => (
+                match frame {
+                    Some(frame) => format!("close {}", u16::from(frame.code)),
+                    None => "close".into(),
+                },
+                false,
+            ),
+            Ok(Some(Ok(tungstenite::Message::Frame(_)))) => ("frame".into(), true),
+            Ok(Some(Err(why))) => (format!("error: {why}"), false),
+            Ok(None) => ("closed".into(), false),
+        };
+    drop(connection.close(None).await);
+    Validation {
+        name,
+        latency: Some(latency),
+        handshake: "ok".into(),
+        first_frame,
+        passed,
+    }
+}
+
+/// Sends a single pixel over a fresh connection and reports whether its echo came back.
+pub async fn paint_one(
+    config: &Config,
+    url: Url,
+    x: u32,
+    y: u32,
+    color: &str,
+    insecure: bool,
+) -> anyhow::Result<bool> {
+    let color = match color.parse::<u8>() {
+        Ok(id) => ColorTarget::Index(id),
+        Err(_) => ColorTarget::Hex(color.into()),
+    };
+    if x >= PixelProvider::MAX_WIDTH || y >= PixelProvider::MAX_HEIGHT {
+        Err(anyhow!("Pixel {{{x}:{y}}} is outside the canvas"))?
+    }
+    // Speaks the configured codec and palette, but paints nothing of the brush
+    let context = Context::new(config);
+    let pixel = PixelInfo {
+        x,
+        y,
+        color_id: color.resolve(&context.palette())?,
+    };
+    let mut connection = Bot::connect(&url, insecure, config.limits()).await?;
+    let packed = context.pack(pixel.clone())?;
+    info!("Sent {}", protocol::hex(&packed));
+    connection.send(packed.into()).await?;
+    let mut echoed = false;
+    let deadline = tokio::time::sleep(PAINT_ONE_WATCH);
+    tokio::pin!(deadline);
+    loop {
+        let msg = tokio::select! {
+            msg = connection.next() => msg,
+            _ = &mut deadline => break,
+        };
+        match msg {
+            Some(Ok(tungstenite::Message::Binary(frame))) => {
+                info!("Received {}", protocol::hex(&frame));
+                echoed |= context
+                    .decode_updates(&frame)
+                    .iter()
+                    .any(|p| (p.x, p.y, p.color_id) == (pixel.x, pixel.y, pixel.color_id));
+            }
+            Some(Ok(msg)) => info!("Received {msg:?}"),
+            Some(Err(why)) => {
+                warn!("Connection failed: {why}");
+                break;
+            }
+            None => break,
+        }
+    }
+    drop(connection.close(None).await);
+    Ok(echoed)
+}
+
+// Calibration and the first paint check claim their pixels like a worker of their own
+const PROBE_WORKER: i32 = -1;
+
+// Bisects the shortest interval after an accepted paint that the server accepts the next one
+async fn calibrate_cooldown(
+    endpoint: &Endpoint,
+    pixel: &Mutex<PixelProvider>,
+    spec: CanvasSpec,
+    calibration: CalibrationConfig,
+    guess: u64,
+) -> anyhow::Result<Option<u64>> {
+    let mut connection = Bot::connect_through(endpoint).await?;
+    let context = pixel.lock().await.context.clone();
+    let ack_timeout = Duration::from_secs(calibration.ack_timeout);
+    let mut search = Search::new(guess);
+    let mut last_accepted = None::<Instant>;
+    for _ in 0..calibration.max_paints {
+        if let Some(last) = last_accepted {
+            let Some(interval) = search.next() else {
+                break;
+            };
+            let due = last + Duration::from_secs(interval);
+            tokio::time::sleep(due.saturating_duration_since(Instant::now())).await;
+        }
+        let Some(probe) = pixel.lock().await.get_pixel(PROBE_WORKER) else {
+            break;
+        };
+        let sent = Instant::now();
+        // Past the planned interval when waiting out a rejected probe's echo took longer
+        let interval = last_accepted.map_or(0, |last| (sent - last).as_secs());
+        let accepted = echoed(
+            &mut connection,
+            spec,
+            &context,
+            &probe,
+            ack_timeout,
+            &mut Vec::new(),
+        )
+        .await;
+        let mut provider = pixel.lock().await;
+        match accepted {
+            Ok(true) => provider.painted(&probe),
+            _ => provider.release(probe),
+        }
+        drop(provider);
+        let accepted = accepted?;
+        if last_accepted.is_none() && !accepted {
+            break;
+        }
+        if last_accepted.is_some() {
+            search.record(interval, accepted);
+            debug!(
+                "Calibration probe {interval}s after the last paint was {}",
+                if accepted { "accepted" } else { "rejected" }
+            );
+        }
+        if accepted {
+            last_accepted = Some(sent);
+        }
+    }
+    pixel.lock().await.queue.leave(PROBE_WORKER);
+    drop(connection.close(None).await);
+    Ok(search.found())
+}
+
+fn unconfirmed(timeout: Duration) -> anyhow::Error {
+    anyhow!(
+        "Nobody confirmed painting within {}s; pass --yes or raise confirm_above_pixels to start without asking",
+        timeout.as_secs()
+    )
+}
+
+// Sends one pixel from the queue and fails the run unless the server broadcasts it back unchanged
+async fn check_first_paint(
+    endpoint: &Endpoint,
+    pixel: &Mutex<PixelProvider>,
+    spec: CanvasSpec,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let (probe, context) = {
+        let mut provider = pixel.lock().await;
+        (provider.get_pixel(PROBE_WORKER), provider.context.clone())
+    };
+    let Some(probe) = probe else {
+        return Ok(());
+    };
+    let mut connection = Bot::connect_through(endpoint).await?;
+    let mut seen = Vec::new();
+    let echo = echoed(&mut connection, spec, &context, &probe, timeout, &mut seen).await;
+    drop(connection.close(None).await);
+    let mut provider = pixel.lock().await;
+    match echo {
+        Ok(true) => provider.painted(&probe),
+        _ => provider.release(probe.clone()),
+    }
+    provider.queue.leave(PROBE_WORKER);
+    drop(provider);
+    if echo? {
+        info!(
+            "First paint {{{}:{}}} came back as sent; starting the other bots.",
+            probe.x, probe.y
+        );
+        return Ok(());
+    }
+    let wire = spec.transform(probe.clone());
+    let mut why = format!(
+        "first paint {{{}:{}}} color {} was sent as {{{}:{}}} color {} ({}) but did not come back within {}s",
+        probe.x,
+        probe.y,
+        probe.color_id,
+        wire.x,
+        wire.y,
+        wire.color_id,
+        protocol::hex(&context.pack(wire.clone())?),
+        timeout.as_secs()
+    );
+    if seen.is_empty() {
+        why.push_str("; no canvas updates arrived at all");
+    } else {
+        let changes = seen
+            .iter()
+            .take(5)
+            .map(|p| format!("{{{}:{}}} color {}", p.x, p.y, p.color_id))
+            .collect::<Vec<_>>();
+        why.push_str(&format!(
+            "; the canvas changed at {}{}",
+            changes.join(", "),
+            if seen.len() > 5 { ", ..." } else { "" }
+        ));
+    }
+    Err(anyhow!(
+        "{why}. Check canvas.codec, canvas.update_offset and the canvas spec, or set verify_first_paint to false"
+    ))
+}
+
+// Sends `pixel` and waits for the server to broadcast it back, collecting the other updates into `seen`
+async fn echoed(
+    connection: &mut WStream,
+    spec: CanvasSpec,
+    context: &Context,
+    pixel: &PixelInfo,
+    timeout: Duration,
+    seen: &mut Vec<PixelInfo>,
+) -> anyhow::Result<bool> {
+    let sent = spec.transform(pixel.clone());
+    connection.send(context.pack(sent.clone())?.into()).await?;
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    loop {
+        let msg = tokio::select! {
+            msg = connection.next() => msg,
+            () = &mut deadline => return Ok(false),
+        };
+        match msg {
+            Some(Ok(tungstenite::Message::Binary(frame))) => {
+                let updates = context.decode_updates(&frame);
+                if updates
+                    .iter()
+                    .any(|p| (p.x, p.y, p.color_id) == (sent.x, sent.y, sent.color_id))
+                {
+                    return Ok(true);
+                }
+                seen.extend(updates);
+            }
+            Some(Ok(_)) => {}
+            Some(Err(tungstenite::Error::Utf8)) => protocol::parse_failed(),
+            Some(Err(why)) => Err(why)?,
+            None => Err(anyhow!("connection closed while waiting for the echo"))?,
+        }
+    }
+}
+
+async fn retry_bot<F, Fut>(
+    name: String,
+    mut why: anyhow::Error,
+    connect: F,
+    mut shared: Shared,
+    never_connected: Arc<std::sync::Mutex<Vec<String>>>,
+) where
+    F: Fn() -> Fut,
+    Fut: Future<Output = anyhow::Result<Bot>>,
+{
+    let mut retries = ConnectRetries::new(&shared.retry);
+    loop {
+        let wait = match why.downcast_ref::<Throttled>() {
+            Some(throttled) => {
+                let wait = Bot::throttle_wait(throttled);
+                warn!(
+                    "Worker {name} cannot connect: {why}; next attempt in {}s.",
+                    wait.as_secs()
+                );
+                wait
+            }
+            None => {
+                let backoff = retries.pick(&why);
+                let Some(wait) = backoff.next() else {
+                    error!("Worker {name} cannot connect: {why}; giving up ({backoff}).");
+                    return;
+                };
+                warn!(
+                    "Worker {name} cannot connect: {why}; next attempt in {}s ({backoff}).",
+                    wait.as_secs()
+                );
+                wait
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shared.shutdown.changed() => return,
+        }
+        if shared.pixel.lock().await.is_done() {
+            return;
+        }
+        tokio::select! {
+            () = shared.reconnect_bucket.acquire() => {}
+            _ = shared.shutdown.changed() => return,
+        }
+        match connect().await {
+            Ok(bot) => {
+                never_connected.lock().unwrap().retain(|n| *n != name);
+                info!("Worker {name} connected after retrying.");
+                return bot.run().await;
+            }
+            Err(error) => why = error,
+        }
+    }
+}
+
+pub fn load_config(path: Option<PathBuf>) -> anyhow::Result<Config> {
+    read_config(path, io::stdin().lock())
+}
+
+// The config `load_config` finds, taking `stdin` for a path of -
+fn read_config(path: Option<PathBuf>, mut stdin: impl Read) -> anyhow::Result<Config> {
+    if let Ok(document) = env::var("PB_CONFIG_INLINE") {
+        return parse_config(&document);
+    }
+    let path = match path {
+        Some(path) => path.to_string_lossy().into_owned(),
+        None => env::var("PB_CONFIG").unwrap_or_else(|_| {
+            info!("PB_CONFIG var were not present, using default path (pb.json)");
+            "pb.json".into()
+        }),
+    };
+    let document = if path == "-" {
+        let mut document = String::new();
+        stdin.read_to_string(&mut document)?;
+        document
+    } else {
+        fs::read_to_string(path)?
+    };
+    parse_config(&document)
+}
+
+/// Reads only the `log` section of the config `load_config` would load, so logging can be set
+/// up first. None when there is no document to read without consuming stdin.
+pub fn log_config(path: Option<&PathBuf>) -> anyhow::Result<Option<LogConfig>> {
+    #[derive(Deserialize)]
+    struct Document {
+        #[serde(default)]
+        log: LogConfig,
+    }
+    let document = match env::var("PB_CONFIG_INLINE") {
+        Ok(document) => document,
+        Err(_) => {
+            let path = path
+                .cloned()
+                .or_else(|| env::var_os("PB_CONFIG").map(PathBuf::from))
+                .unwrap_or_else(|| "pb.json".into());
+            match fs::read_to_string(&path) {
+                Ok(document) if path != Path::new("-") => document,
+                _ => return Ok(None),
+            }
+        }
+    };
+    Ok(Some(parse_document::<Document>(&document)?.log))
+}
+
+fn parse_config(document: &str) -> anyhow::Result<Config> {
+    let variables = Variables::load(parse_document(document)?)?;
+    let config: Config = parse_document(&variables.expand(document)?)?;
+    Ok(config)
+}
+
+fn parse_document<T: DeserializeOwned>(document: &str) -> anyhow::Result<T> {
+    match document.trim_start().chars().next() {
+        Some('{') => Ok(serde_json::from_str(document)?),
+        _ => Ok(toml::from_str(document)?),
+    }
+}
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(60);
+const STATE_INTERVAL: Duration = Duration::from_secs(30);
+// How often the queue is cut down again to what still fits before the deadline
+const DEADLINE_INTERVAL: Duration = Duration::from_secs(300);
+const GRIEFING_WINDOW: Duration = Duration::from_secs(60);
+const RESET_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// How long the planner gets to take the last reports after the bots stop
+const PLANNER_GOODBYE: Duration = Duration::from_secs(5);
+// How long the webhook gets to take the events left when the run ends
+const WEBHOOK_GOODBYE: Duration = Duration::from_secs(10);
+
+async fn save_state(
+    state: &std::sync::Mutex<StateFile>,
+    pixel: &Mutex<PixelProvider>,
+    path: &PathBuf,
+) {
+    let pixel = pixel.lock().await;
+    let mut state = state.lock().unwrap();
+    state.record(&pixel);
+    if let Err(why) = state.save(path) {
+        warn!("Cannot save state to {}: {why}", path.display());
+    }
+}
+
+async fn cumulative_totals(
+    cumulative: &Cumulative,
+    pixel: &Mutex<PixelProvider>,
+    stats: &StatsRegistry,
+) -> Totals {
+    let (painted, repaints) = {
+        let pixel = pixel.lock().await;
+        (pixel.report().painted, pixel.repaints())
+    };
+    cumulative.totals(painted, repaints, stats.snapshot().reconnects())
+}
+
+// Returns once reconnecting did not help, for the run to end
+async fn watch_stalls(
+    pixel: Arc<Mutex<PixelProvider>>,
+    reconnect: Arc<watch::Sender<()>>,
+    config: StallConfig,
+    schedule: Option<Arc<Schedule>>,
+    webhook: Option<Webhook>,
+) {
+    let timeout = Duration::from_secs(config.timeout);
+    let mut interval = tokio::time::interval(timeout / 10);
+    let mut stalls = 0;
+    loop {
+        interval.tick().await;
+        let mut pixel = pixel.lock().await;
+        // Nobody paints off-hours or while paused, so the clock restarts once painting can
+        if pixel.paused()
+            || schedule
+                .as_ref()
+                .is_some_and(|schedule| !schedule.is_active())
+        {
+            pixel.stall_excused = Instant::now();
+            continue;
+        }
+        let (last_echo, last_reconnect) = (pixel.last_echo, pixel.stall_excused);
+        if last_echo > last_reconnect {
+            stalls = 0;
+        }
+        if pixel.progressing(timeout) {
+            continue;
+        }
+        stalls += 1;
+        if stalls > config.max_reconnects {
+            error!(
+                "Painting is still stalled after {} reconnects; shutting down.",
+                stalls - 1
+            );
+            return;
+        }
+        let idle_secs = last_echo.elapsed().as_secs();
+        error!(
+            "No paint was echoed for {idle_secs}s; reconnecting all workers ({stalls}/{}).",
+            config.max_reconnects
+        );
+        if let Some(webhook) = &webhook {
+            webhook.send(Event::Stall {
+                idle_secs,
+                reconnect: stalls,
+                max_reconnects: config.max_reconnects,
+            });
+        }
+        reconnect.send_replace(());
+        pixel.stall_excused = Instant::now();
+    }
+}
+
+async fn watch_griefing(
+    pixel: Arc<Mutex<PixelProvider>>,
+    threshold: u32,
+    urgent: Option<Arc<AtomicBool>>,
+    webhook: Option<Webhook>,
+) {
+    let mut interval = tokio::time::interval(GRIEFING_WINDOW);
+    interval.tick().await;
+    let mut last_total = 0;
+    let mut under_attack = false;
+    loop {
+        interval.tick().await;
+        let total = pixel.lock().await.overwritten;
+        let rate = total - last_total;
+        last_total = total;
+        if rate > threshold && !under_attack {
+            warn!("Under attack: {rate} of our pixels were overwritten in the last minute.");
+            if let Some(webhook) = &webhook {
+                webhook.send(Event::UnderAttack { rate, threshold });
+            }
+        } else if rate <= threshold && under_attack {
+            info!("Attack subsided: {rate} of our pixels were overwritten in the last minute.");
+        }
+        under_attack = rate > threshold;
+        if let Some(urgent) = &urgent {
+            urgent.store(under_attack, Ordering::Relaxed);
+        }
+    }
+}
+
+// Pauses painting when most of the target suddenly mismatches, and resumes after auto_resume_after
+async fn watch_resets(
+    pixel: Arc<Mutex<PixelProvider>>,
+    reconnect: Option<Arc<watch::Sender<()>>>,
+    webhook: Option<Webhook>,
+) {
+    let mut interval = tokio::time::interval(RESET_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let mut pixel = pixel.lock().await;
+        let Some(mismatch) = pixel.mismatch() else {
+            continue;
+        };
+        let Some(guard) = &mut pixel.reset_guard else {
+            return;
+        };
+        let now = Instant::now();
+        if guard.resume_due(now) {
+            guard.resume();
+            info!("No /resume after the suspected canvas reset; painting again as auto_resume_after allows");
+            continue;
+        }
+        let Some(suspicion) = guard.observe(mismatch, now) else {
+            continue;
+        };
+        let resume = match guard.config.auto_resume_after {
+            Some(after) => format!("a /resume or {after}s"),
+            None => "a /resume".into(),
+        };
+        warn!(
+            "canvas_reset_suspected: {:.0}% of the target mismatches, up from {:.0}% within {}s; painting paused until {resume}",
+            suspicion.to * 100.0,
+            suspicion.from * 100.0,
+            guard.config.window
+        );
+        if let Some(webhook) = &webhook {
+            webhook.send(Event::CanvasResetSuspected {
+                from: suspicion.from,
+                to: suspicion.to,
+                window: guard.config.window,
+            });
+        }
+        drop(pixel);
+        if let Some(reconnect) = &reconnect {
+            info!("Reconnecting every worker to get the board again");
+            reconnect.send_replace(());
+        }
+    }
+}
+
+#[derive(Clone)]
+struct SleepPerformer {
+    rng: Arc<Mutex<StdRng>>,
+    reaction: Option<UniformDuration>,
+    skip_probability: f64,
+    // Set while under attack to stop skipping paints on purpose
+    urgent: Arc<AtomicBool>,
+}
+
+impl SleepPerformer {
+    fn new(humanize: &HumanizeConfig) -> Self {
+        Self {
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+            reaction: humanize.reaction_delay_ms.map(|Range { min, max }| {
+                UniformDuration::new_inclusive(
+                    Duration::from_millis(min),
+                    Duration::from_millis(max),
+                )
+            }),
+            skip_probability: humanize.skip_probability,
+            urgent: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Passing up a chance to paint leaves the cooldown spent, so the next one comes a moment later
+    const SKIP_DELAY: (Duration, Duration) = (Duration::from_millis(500), Duration::from_secs(3));
+
+    // How long to hold off when this chance to paint is let pass
+    async fn skip(&self) -> Option<Duration> {
+        if self.skip_probability <= 0.0 || self.urgent.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut rng = self.rng.lock().await;
+        if !rng.gen_bool(self.skip_probability) {
+            return None;
+        }
+        let (min, max) = Self::SKIP_DELAY;
+        let delay = match &self.reaction {
+            Some(reaction) => reaction.sample(&mut *rng),
+            None => UniformDuration::new_inclusive(min, max).sample(&mut *rng),
+        };
+        Some(delay)
+    }
+
+    async fn react(&self) {
+        if let Some(reaction) = &self.reaction {
+            let duration = reaction.sample(&mut *self.rng.lock().await);
+            tokio::time::sleep(duration).await;
+        }
+    }
+}
+
+type WStream = WebSocketStream<
+    Stream<
+        TokioAdapter<tokio::net::TcpStream>,
+        TokioAdapter<tokio_native_tls::TlsStream<tokio::net::TcpStream>>,
+    >,
+>;
+
+#[derive(Clone)]
+struct Shared {
+    pixel: Arc<Mutex<PixelProvider>>,
+    context: Arc<Context>,
+    canvas: CanvasSpec,
+    reconnect: watch::Receiver<()>,
+    shutdown: watch::Receiver<()>,
+    // Changed whenever a sweep requeues damaged pixels
+    damage: watch::Receiver<()>,
+    damaged: Arc<watch::Sender<()>>,
+    // Changed whenever the wall clock was stepped
+    clock_jump: watch::Receiver<()>,
+    idle_after: Duration,
+    idle_wake: Duration,
+    schedule: Option<Arc<Schedule>>,
+    capture: Option<Capture>,
+    failure_streak: u32,
+    retry: RetryConfig,
+    stats: StatsRegistry,
+    close_codes: Arc<HashMap<u16, CloseAction>>,
+    paints_per_cycle: usize,
+    log: LogConfig,
+    fairness: Fairness,
+    fairness_margin: u32,
+    max_rx_bytes_per_hour: Option<u64>,
+    auth_refresh: Option<Arc<AuthRefreshConfig>>,
+    // Follow palette announcements, also repainting finished pixels if true
+    requantize: Option<bool>,
+    // Taken before every reconnect; first connects go without
+    reconnect_bucket: Arc<Bucket>,
+}
+
+struct Bot {
+    id: i32,
+    name: String,
+    endpoint: Endpoint,
+    sleep: SleepPerformer,
+    cooldown: Box<dyn Cooldown + Send>,
+    shared: Shared,
+    connection: WStream,
+    failures: u32,
+    bench: Backoff,
+    paint_log: LogThrottle,
+    send_log: LogThrottle,
+    updates_log: LogThrottle,
+    // Broadcast updates waiting to be applied, so a flood of frames takes the provider lock once per flush
+    observed: Vec<PixelInfo>,
+    stats: Arc<BotStats>,
+    // Start of the current hour and the bytes received in it
+    rx_hour: (Instant, u64),
+    refresh_failures: u32,
+    // Nothing to repaint, so waking only on damage
+    watching: bool,
+    // Waiting for the next schedule window, which moves when the wall clock does
+    off_hours: bool,
+}
+
+impl Bot {
+    async fn new(
+        id: i32,
+        name: String,
+        endpoint: Endpoint,
+        sleep: SleepPerformer,
+        cooldown: Box<dyn Cooldown + Send>,
+        shared: Shared,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            id,
+            name,
+            connection: Self::connect_through(&endpoint).await?,
+            endpoint,
+            sleep,
+            cooldown,
+            failures: 0,
+            bench: Backoff::new(shared.retry.policy(Category::Bench)),
+            paint_log: LogThrottle::new(Duration::from_secs(shared.log.paint_interval)),
+            send_log: LogThrottle::new(Duration::from_secs(shared.log.paint_interval)),
+            updates_log: LogThrottle::new(Duration::from_secs(shared.log.paint_interval)),
+            observed: Vec::new(),
+            stats: shared.stats.bot(id),
+            rx_hour: (Instant::now(), 0),
+            refresh_failures: 0,
+            watching: false,
+            off_hours: false,
+            shared,
+        })
+    }
+
+    const RECONNECT_STAGGER: Duration = Duration::from_secs(2);
+    const IDLE_RECONNECT_LEAD: Duration = Duration::from_secs(60);
+    const PING_INTERVAL: Duration = Duration::from_secs(30);
+    const OBSERVE_FLUSH: Duration = Duration::from_millis(100);
+    const CLAIM_RETRY: Duration = Duration::from_secs(1);
+    // How often a paused worker looks whether painting was resumed
+    const PAUSE_POLL: Duration = Duration::from_secs(2);
+    // Claims in a row found already correct before a cycle gives up and waits
+    const MAX_CORRECT_SKIPS: u32 = 3;
+    const RX_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+    async fn connect(url: &Url, insecure: bool, limits: Limits) -> anyhow::Result<WStream> {
+        Self::connect_through(&Endpoint {
+            url: url.clone(),
+            insecure,
+            proxies: Vec::new(),
+            limits,
+        })
+        .await
+    }
+
+    // Follows redirects of the upgrade, refusing loops and wss to ws downgrades
+    async fn connect_through(endpoint: &Endpoint) -> anyhow::Result<WStream> {
+        let max_redirects = endpoint.limits.max_redirects;
+        let mut visited = vec![endpoint.url.clone()];
+        loop {
+            let url = visited.last().unwrap();
+            let why = match Self::upgrade_via(url, endpoint).await {
+                Ok(connection) => return Ok(connection),
+                Err(why) => why,
+            };
+            let Some(Redirected(location)) = why.downcast_ref::<Redirected>() else {
+                return Err(why);
+            };
+            let mut next = url.join(location).map_err(|e| {
+                anyhow!(
+                    "{} redirected to bad location {location:?}: {e}",
+                    redact(url)
+                )
+            })?;
+            // Gateways may point at the http(s) form of the websocket URL
+            let scheme = match next.scheme() {
+                "http" => Some("ws"),
+                "https" => Some("wss"),
+                _ => None,
+            };
+            if let Some(scheme) = scheme {
+                next.set_scheme(scheme).ok();
+            }
+            if !matches!(next.scheme(), "ws" | "wss") {
+                Err(anyhow!(
+                    "{} redirected to unsupported {}",
+                    redact(url),
+                    redact(&next)
+                ))?
+            }
+            if url.scheme() == "wss" && next.scheme() == "ws" {
+                Err(anyhow!(
+                    "{} redirected to insecure {}; refusing",
+                    redact(url),
+                    redact(&next)
+                ))?
+            }
+            if visited.contains(&next) {
+                Err(anyhow!(
+                    "{} redirected back to {}; giving up on the loop",
+                    redact(url),
+                    redact(&next)
+                ))?
+            }
+            if visited.len() > max_redirects as usize {
+                Err(anyhow!(
+                    "{} redirected more than {max_redirects} times",
+                    redact(&visited[0])
+                ))?
+            }
+            info!("{} redirected to {}", redact(url), redact(&next));
+            visited.push(next);
+        }
+    }
+
+    // Falls back to the next proxy only when the current one is at fault
+    async fn upgrade_via(url: &Url, endpoint: &Endpoint) -> anyhow::Result<WStream> {
+        let mut proxies = endpoint.proxies.iter().peekable();
+        while let Some(proxy) = proxies.next() {
+            let upgraded = Self::upgrade(url, endpoint, Some(proxy)).await;
+            match (&upgraded, proxies.peek()) {
+                (Err(why), Some(next))
+                    if why
+                        .downcast_ref::<StageFailed>()
+                        .is_some_and(|failed| failed.stage.is_proxy()) =>
+                {
+                    warn!("{why}; falling back to {}", redact(next));
+                }
+                _ => return upgraded,
+            }
+        }
+        Self::upgrade(url, endpoint, None).await
+    }
+
+    async fn upgrade(
+        url: &Url,
+        endpoint: &Endpoint,
+        proxy: Option<&Url>,
+    ) -> anyhow::Result<WStream> {
+        let connector = if endpoint.insecure {
+            let connector = native_tls::TlsConnector::builder()
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true)
+                .build()?;
+            Some(connector.into())
+        } else {
+            None
+        };
+        let upgraded = match proxy {
+            Some(proxy) => {
+                let stream = proxy::tunnel(proxy, url).await?;
+                client_async_tls_with_connector_and_config(
+                    url.as_str(),
+                    stream,
+                    connector,
+                    Some(endpoint.limits.websocket()),
+                )
+                .await
+            }
+            None => {
+                connect_async_with_tls_connector_and_config(
+                    url,
+                    connector,
+                    Some(endpoint.limits.websocket()),
+                )
+                .await
+            }
+        };
+        match upgraded {
+            Ok((connection, _)) => Ok(connection),
+            Err(why @ tungstenite::Error::Tls(_)) => match proxy {
+                Some(proxy) => Err(StageFailed::new(Stage::TlsHandshake, proxy, why).into()),
+                None => Err(HandshakeFailed {
+                    host: url.host_str().unwrap_or_default().into(),
+                    why: why.to_string(),
+                }
+                .into()),
+            },
+            Err(tungstenite::Error::Http(response))
+                if response.status() == http::StatusCode::TOO_MANY_REQUESTS =>
+            {
+                let retry_after = response
+                    .headers()
+                    .get(http::header::RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+                    .map(Duration::from_secs);
+                Err(Throttled { retry_after }.into())
+            }
+            Err(tungstenite::Error::Http(response)) if response.status().is_redirection() => {
+                match response
+                    .headers()
+                    .get(http::header::LOCATION)
+                    .and_then(|value| value.to_str().ok())
+                {
+                    Some(location) => Err(Redirected(location.into()).into()),
+                    None => Err(anyhow!(
+                        "{} answered {} without a Location",
+                        redact(url),
+                        response.status()
+                    )),
+                }
+            }
+            Err(why) => match proxy {
+                Some(proxy) => Err(StageFailed::new(Stage::WsUpgrade, proxy, why).into()),
+                None => Err(why.into()),
+            },
+        }
+    }
+
+    const DEFAULT_THROTTLE: Duration = Duration::from_secs(30);
+    const THROTTLE_JITTER: Duration = Duration::from_secs(5);
+
+    fn close_action(&self, code: u16) -> CloseAction {
+        self.shared
+            .close_codes
+            .get(&code)
+            .copied()
+            .unwrap_or_else(|| CloseAction::default_for(code))
+    }
+
+    fn retry_hint(frame: &CloseFrame) -> Option<Duration> {
+        frame
+            .reason
+            .split(|c: char| !c.is_ascii_digit())
+            .find(|digits| !digits.is_empty())
+            .and_then(|digits| digits.parse().ok())
+            .map(Duration::from_secs)
+    }
+
+    fn throttle_wait(throttled: &Throttled) -> Duration {
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=Self::THROTTLE_JITTER);
+        throttled.retry_after.unwrap_or(Self::DEFAULT_THROTTLE) + jitter
+    }
+
+    // Keeps trying until connected; false on shutdown or once the retry policy gives up
+    async fn reconnect(&mut self) -> bool {
+        self.stats.set(State::Reconnecting);
+        let mut retries = ConnectRetries::new(&self.shared.retry);
+        loop {
+            tokio::select! {
+                () = self.shared.reconnect_bucket.acquire() => {}
+                Ok(()) = self.shared.shutdown.changed() => return false,
+            }
+            let why = match Self::connect_through(&self.endpoint).await {
+                Ok(connection) => {
+                    self.connection = connection;
+                    self.stats.set(State::Connected);
+                    self.stats.reconnected();
+                    info!("Worker {} reconnected.", self.name);
+                    return true;
+                }
+                Err(why) => why,
+            };
+            let wait = match why.downcast_ref::<Throttled>() {
+                Some(throttled) => {
+                    let wait = Self::throttle_wait(throttled);
+                    warn!(
+                        "Worker {} cannot reconnect: {why}; retrying in {}s.",
+                        self.name,
+                        wait.as_secs()
+                    );
+                    wait
+                }
+                None => {
+                    let backoff = retries.pick(&why);
+                    let Some(wait) = backoff.next() else {
+                        error!(
+                            "Worker {} cannot reconnect: {why}; giving up ({backoff}).",
+                            self.name
+                        );
+                        self.stats.set(State::Disconnected);
+                        return false;
+                    };
+                    warn!(
+                        "Worker {} cannot reconnect: {why}; retrying in {}s ({backoff}).",
+                        self.name,
+                        wait.as_secs()
+                    );
+                    wait
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                Ok(()) = self.shared.shutdown.changed() => return false,
+            }
+        }
+    }
+
+    // Sleeps disconnected until shortly before the next window; false on shutdown
+    async fn idle(&mut self, schedule: &Schedule) -> bool {
+        drop(self.connection.close(None).await);
+        self.stats.set(State::Idle);
+        loop {
+            let until = schedule.until_active();
+            info!(
+                "Worker {} disconnecting until the next active window in {}s.",
+                self.name,
+                until.as_secs()
+            );
+            let wait = until.saturating_sub(Self::IDLE_RECONNECT_LEAD)
+                + Self::RECONNECT_STAGGER * self.id as u32;
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => break,
+                Ok(()) = self.shared.clock_jump.changed() => {}
+                Ok(()) = self.shared.shutdown.changed() => return false,
+            }
+        }
+        self.reconnect().await
+    }
+
+    // Benched bots neither claim pixels nor reconnect until the cooloff ends
+    async fn bench(&mut self) -> bool {
+        let Some(cooloff) = self.bench.next() else {
+            error!(
+                "Worker {} gives up after {} more failed sends in a row ({}).",
+                self.name, self.failures, self.bench
+            );
+            return false;
+        };
+        warn!(
+            "Worker {} benched for {}s after {} failed sends in a row ({}).",
+            self.name,
+            cooloff.as_secs(),
+            self.failures,
+            self.bench
+        );
+        self.failures = 0;
+        self.stats.benched();
+        let resumed = self.rest(cooloff, State::Benched).await;
+        if resumed {
+            info!("Worker {} is back from the bench.", self.name);
+        }
+        resumed
+    }
+
+    // Drops the connection and reconnects after `wait`; false on shutdown
+    async fn rest(&mut self, wait: Duration, state: State) -> bool {
+        drop(self.connection.close(None).await);
+        self.stats.set(state);
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            Ok(()) = self.shared.shutdown.changed() => return false,
+        }
+        self.reconnect().await
+    }
+
+    fn log_paint(&mut self, pixel: &PixelInfo) {
+        let line = format!(
+            "Worker {} painting {{{}:{}}} to {}",
+            self.name, pixel.x, pixel.y, pixel.color_id
+        );
+        match self.shared.log.paint_level {
+            PaintLevel::Info => match self.paint_log.admit() {
+                Some(0) => info!("{line}"),
+                Some(n) => info!("{line}; painted {n} px since last report"),
+                None => debug!("{line}"),
+            },
+            PaintLevel::Debug => debug!("{line}"),
+            PaintLevel::Off => {}
+        }
+    }
+
+    // Counts the frame's bytes and hands it to the capture, if any
+    fn record(&mut self, direction: Direction, msg: &tungstenite::Message) {
+        match direction {
+            Direction::Inbound => {
+                self.stats.received(msg.len());
+                self.meter(msg.len());
+            }
+            Direction::Outbound => self.stats.transmitted(msg.len()),
+        }
+        if let Some(capture) = &self.shared.capture {
+            capture.record(self.id, direction, msg);
+        }
+    }
+
+    fn meter(&mut self, bytes: usize) {
+        let Some(cap) = self.shared.max_rx_bytes_per_hour else {
+            return;
+        };
+        let (start, received) = &mut self.rx_hour;
+        if start.elapsed() >= Self::RX_WINDOW {
+            (*start, *received) = (Instant::now(), 0);
+            if self.stats.reduced() {
+                self.stats.set_reduced(false);
+                info!("Worker {} is handling broadcasts again.", self.name);
+            }
+        }
+        *received += bytes as u64;
+        if *received > cap && !self.stats.reduced() {
+            self.stats.set_reduced(true);
+            warn!(
+                "Worker {} received {received} bytes within the hour, over max_rx_bytes_per_hour of {cap}; ignoring broadcasts until the hour is over.",
+                self.name
+            );
+        }
+    }
+
+    async fn run(mut self) {
+        self.stats.set(State::Connected);
+        self.paint().await;
+        self.stats.finish();
+        self.shared.pixel.lock().await.queue.leave(self.id);
+    }
+
+    async fn paint(&mut self) {
+        info!("Worker {} started.", self.name);
+        let ping = tokio::time::sleep(Duration::ZERO);
+        let cooldown = tokio::time::sleep(Duration::ZERO);
+        let refresh_interval = Duration::from_secs(
+            self.shared
+                .auth_refresh
+                .as_ref()
+                .map_or(u64::MAX >> 2, |auth| auth.interval),
+        );
+        let refresh = tokio::time::sleep(refresh_interval);
+        let flush = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(ping, cooldown, refresh, flush);
+        loop {
+            let msg = tokio::select! {
+                msg = self.connection.next() => msg,
+                () = &mut ping => {
+                    let ping_msg = tungstenite::Message::Ping("ping".into());
+                    self.record(Direction::Outbound, &ping_msg);
+                    ping.as_mut().reset(tokio::time::Instant::now() + Self::PING_INTERVAL);
+                    if let Err(why) = self.connection.send(ping_msg).await {
+                        info!(
+                            "Worker {} could not send a ping: {why}; trying to reconnect.",
+                            self.name
+                        );
+                        if !self.reconnect().await {
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                () = &mut refresh, if self.shared.auth_refresh.is_some() => {
+                    refresh.as_mut().reset(tokio::time::Instant::now() + refresh_interval);
+                    if !self.refresh_auth().await {
+                        return;
+                    }
+                    continue;
+                }
+                () = &mut flush, if !self.observed.is_empty() => {
+                    self.apply_observed().await;
+                    continue;
+                }
+                () = &mut cooldown => {
+                    // Claim against the canvas as last broadcast
+                    self.apply_observed().await;
+                    let Some(wait) = self.cycle().await else {
+                        return;
+                    };
+                    self.stats.scheduled(wait);
+                    cooldown.as_mut().reset(tokio::time::Instant::now() + wait);
+                    continue;
+                }
+                Ok(()) = self.shared.damage.changed(), if self.watching => {
+                    cooldown.as_mut().reset(tokio::time::Instant::now());
+                    continue;
+                }
+                Ok(()) = self.shared.clock_jump.changed(), if self.off_hours => {
+                    cooldown.as_mut().reset(tokio::time::Instant::now());
+                    continue;
+                }
+                Ok(()) = self.shared.shutdown.changed() => {
+                    info!("Worker {} shutting down.", self.name);
+                    drop(self.connection.close(None).await);
+                    return;
+                }
+                Ok(()) = self.shared.reconnect.changed() => {
+                    tokio::time::sleep(Self::RECONNECT_STAGGER * self.id as u32).await;
+                    info!("Worker {} reconnecting due to stall.", self.name);
+                    drop(self.connection.close(None).await);
+                    if !self.reconnect().await {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let Some(msg) = msg else {
+                break;
+            };
+            if let Ok(msg) = &msg {
+                self.record(Direction::Inbound, msg);
+            }
+            match msg {
+                Ok(tungstenite::Message::Close(Some(frame))) => {
+                    let code = u16::from(frame.code);
+                    match self.close_action(code) {
+                        CloseAction::Reconnect => {
+                            info!(
+                                "Worker {} connection was closed with {code} {:?}; trying to reconnect.",
+                                self.name, frame.reason
+                            );
+                            if !self.reconnect().await {
+                                return;
+                            }
+                        }
+                        CloseAction::ReconnectAfter(secs) => {
+                            let retry_after =
+                                Self::retry_hint(&frame).unwrap_or(Duration::from_secs(secs));
+                            let wait = Self::throttle_wait(&Throttled {
+                                retry_after: Some(retry_after),
+                            });
+                            warn!(
+                                "Worker {} connection was closed with {code} {:?}; reconnecting in {}s.",
+                                self.name,
+                                frame.reason,
+                                wait.as_secs()
+                            );
+                            if !self.rest(wait, State::Throttled).await {
+                                return;
+                            }
+                        }
+                        CloseAction::Quarantine => {
+                            error!(
+                                "Worker {} connection was closed with {code} {:?}; quarantined.",
+                                self.name, frame.reason
+                            );
+                            self.stats.set(State::Quarantined);
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                // A server going down mid-frame resets rather than closing
+                Ok(tungstenite::Message::Close(..))
+                | Err(
+                    tungstenite::Error::ConnectionClosed
+                    | tungstenite::Error::AlreadyClosed
+                    | tungstenite::Error::Io(_)
+                    | tungstenite::Error::Protocol(
+                        tungstenite::error::ProtocolError::ResetWithoutClosingHandshake,
+                    ),
+                ) => {
+                    info!(
+                        "Worker {} connection was closed; trying to reconnect.",
+                        self.name,
+                    );
+                    if !self.reconnect().await {
+                        return;
+                    }
+                    continue;
+                }
+                Ok(tungstenite::Message::Binary(_) | tungstenite::Message::Text(_))
+                    if self.stats.reduced() => {}
+                Ok(tungstenite::Message::Binary(frame)) => {
+                    let updates = self
+                        .shared
+                        .context
+                        .decode_updates(&frame)
+                        .into_iter()
+                        .map(|pixel| self.shared.canvas.untransform(pixel))
+                        .collect::<Vec<_>>();
+                    if self.observed.is_empty() {
+                        flush
+                            .as_mut()
+                            .reset(tokio::time::Instant::now() + Self::OBSERVE_FLUSH);
+                    }
+                    let count = updates.len();
+                    self.observed.extend(updates);
+                    if let Some(frames) = self.updates_log.admit() {
+                        debug!(
+                            "Worker {} received {} canvas updates ({frames} more frames since the last report)",
+                            self.name, count
+                        );
+                    }
+                }
+                Ok(tungstenite::Message::Text(text)) => {
+                    if let Some(count) = protocol::parse_online(&text) {
+                        self.shared.stats.set_online(count);
+                    }
+                    if let Some(metadata) = protocol::parse_metadata(&text) {
+                        self.announced(metadata).await;
+                    }
+                    info!("Message {text}");
+                }
+                // Only produced when writing raw frames, never read
+                Ok(tungstenite::Message::Frame(_)) => {}
+                Ok(msg) => info!("Message {msg}"),
+                // Relayed chat can carry invalid UTF-8; the stream is fused after any error, so start over
+                Err(tungstenite::Error::Utf8) => {
+                    protocol::parse_failed();
+                    warn!(
+                        "Worker {} received a text frame that is not UTF-8; reconnecting.",
+                        self.name
+                    );
+                    if !self.reconnect().await {
+                        return;
+                    }
+                }
+                Err(tungstenite::Error::Capacity(why)) => {
+                    warn!(
+                        "Worker {} received a message over the limit ({why}); raise max_message_size or max_frame_size. Reconnecting.",
+                        self.name
+                    );
+                    if !self.reconnect().await {
+                        return;
+                    }
+                }
+                // Idk how to deal. C'mon, just ignore
+                Err(why) => {
+                    error!(
+                        "Worker {} received unexpected error: {}; exiting.",
+                        self.name, why
+                    );
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn apply_observed(&mut self) {
+        if self.observed.is_empty() {
+            return;
+        }
+        let mut provider = self.shared.pixel.lock().await;
+        provider.update_batches += 1;
+        for update in self.observed.drain(..) {
+            provider.observe(&update);
+        }
+        if provider.sweep_damage() {
+            self.shared.damaged.send_replace(());
+        }
+    }
+
+    async fn announced(&mut self, metadata: Metadata) {
+        let Some(requantize_completed) = self.shared.requantize else {
+            return;
+        };
+        let colors = match metadata
+            .palette
+            .iter()
+            .map(|hex| parse_hex(hex))
+            .collect::<anyhow::Result<Vec<_>>>()
+        {
+            Ok(colors) if !colors.is_empty() => colors,
+            Ok(_) => return,
+            Err(why) => {
+                warn!("Worker {} got an unusable palette: {why}", self.name);
+                return;
+            }
+        };
+        let count = colors.len();
+        let Some(colors) = Palette::new(colors) else {
+            warn!(
+                "Worker {} got a palette of {count} colors, more than the {} supported",
+                self.name,
+                Palette::MAX_LEN
+            );
+            return;
+        };
+        let mut pixel = self.shared.pixel.lock().await;
+        pixel.repalette(colors, requantize_completed);
+    }
+
+    // Sends a fresh session token; false once the worker is quarantined or gone
+    async fn refresh_auth(&mut self) -> bool {
+        let Some(auth) = self.shared.auth_refresh.clone() else {
+            return true;
+        };
+        let why = match auth.frame(&self.endpoint.url).await {
+            Ok(frame) => {
+                let frame = tungstenite::Message::Text(frame);
+                self.record(Direction::Outbound, &frame);
+                match self.connection.send(frame).await {
+                    Ok(()) => {
+                        debug!("Worker {} refreshed its session.", self.name);
+                        self.refresh_failures = 0;
+                        self.stats.refreshed(true);
+                        return true;
+                    }
+                    Err(why) => anyhow::Error::from(why),
+                }
+            }
+            Err(why) => why,
+        };
+        self.refresh_failures += 1;
+        self.stats.refreshed(false);
+        if self.refresh_failures >= 2 * auth.max_failures {
+            error!(
+                "Worker {} failed to refresh its session {} times in a row: {why}; quarantined.",
+                self.name, self.refresh_failures
+            );
+            self.stats.set(State::Quarantined);
+            drop(self.connection.close(None).await);
+            return false;
+        }
+        if self.refresh_failures == auth.max_failures {
+            warn!(
+                "Worker {} failed to refresh its session {} times in a row: {why}; reconnecting.",
+                self.name, self.refresh_failures
+            );
+            drop(self.connection.close(None).await);
+            return self.reconnect().await;
+        }
+        warn!("Worker {} failed to refresh its session: {why}.", self.name);
+        true
+    }
+
+    fn next_wait(&mut self, succeeded: bool) -> Duration {
+        let ctx = CooldownContext {
+            succeeded,
+            server_wait: None,
+        };
+        self.cooldown.next_duration(&ctx)
+    }
+
+    // One chance to paint; the wait until the next, or None once the worker should stop
+    async fn cycle(&mut self) -> Option<Duration> {
+        self.off_hours = false;
+        if let Some(schedule) = self.shared.schedule.clone() {
+            if !schedule.is_active() {
+                // Stay connected once the window is about to open
+                if schedule.disconnect_when_idle
+                    && schedule.until_active() > Self::IDLE_RECONNECT_LEAD
+                    && !self.idle(&schedule).await
+                {
+                    return None;
+                }
+                self.off_hours = true;
+                return Some(schedule.until_active());
+            }
+        }
+        if self.shared.pixel.lock().await.paused() {
+            self.stats.set(State::Paused);
+            return Some(Self::PAUSE_POLL);
+        }
+        if self.stats.state() == State::Paused {
+            self.stats.set(State::Connected);
+        }
+        if self.shared.fairness == Fairness::Strict {
+            if let Some(lead) = self
+                .shared
+                .stats
+                .snapshot()
+                .lead(self.id)
+                .filter(|&lead| lead > self.shared.fairness_margin as f64)
+            {
+                debug!(
+                    "Worker {} is {lead:.0} sends ahead of the average; letting the others catch up.",
+                    self.name
+                );
+                return Some(Self::CLAIM_RETRY);
+            }
+        }
+        if let Some(delay) = self.sleep.skip().await {
+            debug!(
+                "Worker {} lets this chance to paint pass; next in {}ms.",
+                self.name,
+                delay.as_millis()
+            );
+            return Some(delay);
+        }
+        self.sleep.react().await;
+        let mut claims = Vec::new();
+        let mut skips = 0;
+        while claims.len() < self.shared.paints_per_cycle && skips < Self::MAX_CORRECT_SKIPS {
+            let Some(mut claim) = Claim::take(&self.shared.pixel, self.id).await else {
+                break;
+            };
+            // Someone else may have painted it right since it was queued; no cooldown spent then
+            let pixel = claim.pixel().clone();
+            if claim.settle_if_correct().await {
+                debug!(
+                    "Worker {} skips {{{}:{}}}, already correct on the canvas.",
+                    self.name, pixel.x, pixel.y
+                );
+                skips += 1;
+                continue;
+            }
+            skips = 0;
+            claims.push(claim);
+        }
+        if claims.is_empty() {
+            let mut provider = self.shared.pixel.lock().await;
+            if provider.is_done() {
+                return None;
+            }
+            if provider.watching(self.shared.idle_after) {
+                drop(provider);
+                self.watching = true;
+                self.stats.set(State::Watching);
+                return Some(self.shared.idle_wake);
+            }
+            // Everything left is being painted by other workers
+            return Some(Self::CLAIM_RETRY);
+        }
+        if self.watching {
+            self.watching = false;
+            self.stats.set(State::Connected);
+        }
+        // Several records in one frame count as a single paint for the server
+        let mut packed = Vec::new();
+        for claim in &claims {
+            let pixel = claim.pixel().clone();
+            self.log_paint(&pixel);
+            match self
+                .shared
+                .context
+                .pack(self.shared.canvas.transform(pixel))
+            {
+                Ok(record) => packed.extend(record),
+                Err(why) => {
+                    error!(
+                        "Worker {} cannot encode a pixel: {why}; exiting.",
+                        self.name
+                    );
+                    return None;
+                }
+            }
+        }
+        let packed = tungstenite::Message::Binary(packed);
+        self.record(Direction::Outbound, &packed);
+        match self.connection.send(packed).await {
+            Ok(()) => {
+                for claim in claims {
+                    claim.confirm().await;
+                }
+                self.stats.sent(true);
+                self.failures = 0;
+                self.bench.reset();
+                Some(self.next_wait(true))
+            }
+            Err(why) => {
+                let line = format!(
+                    "Worker {} failed to send {} pixels: {}; returning them to the queue.",
+                    self.name,
+                    claims.len(),
+                    why
+                );
+                match self.send_log.admit() {
+                    Some(0) => warn!("{line}"),
+                    Some(n) => warn!("{line} ({n} more failures since the last report)"),
+                    None => debug!("{line}"),
+                }
+                for claim in claims {
+                    claim.release().await;
+                }
+                self.stats.sent(false);
+                self.failures += 1;
+                if self.failures >= self.shared.failure_streak && !self.bench().await {
+                    return None;
+                }
+                Some(self.next_wait(false))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Throttled {
+    retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for Throttled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.retry_after {
+            Some(retry_after) => write!(f, "throttled, retry after {}s", retry_after.as_secs()),
+            None => write!(f, "throttled"),
+        }
+    }
+}
+
+impl std::error::Error for Throttled {}
+
+#[derive(Debug)]
+struct Redirected(String);
+
+impl std::fmt::Display for Redirected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "redirected to {}", self.0)
+    }
+}
+
+impl std::error::Error for Redirected {}
+
+// A pixel taken out of the queue; it goes back unless the send is confirmed
+struct Claim {
+    provider: Arc<Mutex<PixelProvider>>,
+    pixel: Option<PixelInfo>,
+}
+
+impl Claim {
+    async fn take(provider: &Arc<Mutex<PixelProvider>>, worker: i32) -> Option<Self> {
+        let pixel = provider.lock().await.get_pixel(worker)?;
+        Some(Self {
+            provider: provider.clone(),
+            pixel: Some(pixel),
+        })
+    }
+
+    fn pixel(&self) -> &PixelInfo {
+        self.pixel.as_ref().unwrap()
+    }
+
+    // Done without a send when the canvas already shows the pixel
+    async fn settle_if_correct(&mut self) -> bool {
+        if !self.provider.lock().await.settle_if_correct(self.pixel()) {
+            return false;
+        }
+        // Nothing to put back on drop
+        self.pixel = None;
+        true
+    }
+
+    async fn confirm(mut self) {
+        let pixel = self.pixel.take().unwrap();
+        self.provider.lock().await.painted(&pixel);
+    }
+
+    // Back to the queue after a failed send
+    async fn release(mut self) {
+        let pixel = self.pixel.take().unwrap();
+        self.provider.lock().await.failed(pixel);
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let Some(pixel) = self.pixel.take() else {
+            return;
+        };
+        if let Ok(mut provider) = self.provider.try_lock() {
+            provider.release(pixel);
+        } else if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let provider = self.provider.clone();
+            runtime.spawn(async move { provider.lock().await.release(pixel) });
+        } else {
+            error!(
+                "Pixel {{{}:{}}} was lost while shutting down",
+                pixel.x, pixel.y
+            );
+        }
+    }
+}
+
+// Colors and wire format of one run, built from its config; only canvas discovery
+// and a palette announced mid-run replace the palette afterwards
+struct Context {
+    palette: RwLock<Arc<Palette>>,
+    metric: Metric,
+    wire: Wire,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self {
+            palette: RwLock::default(),
+            metric: Metric::Rgb,
+            wire: Wire::default(),
+        }
+    }
+}
+
+impl Context {
+    fn new(config: &Config) -> Self {
+        Self {
+            palette: RwLock::default(),
+            metric: config.color_metric,
+            wire: Wire {
+                codec: config.canvas.codec,
+                update_offset: config.canvas.update_offset,
+            },
+        }
+    }
+
+    // The built-in palette unless the server advertised another
+    fn palette(&self) -> Arc<Palette> {
+        self.palette.read().unwrap().clone()
+    }
+
+    fn set_palette(&self, palette: Palette) {
+        *self.palette.write().unwrap() = Arc::new(palette);
+    }
+
+    fn palette_hash(&self) -> u64 {
+        fnv1a(
+            self.palette()
+                .colors()
+                .iter()
+                .flat_map(|&(r, g, b)| [r, g, b]),
+        )
+    }
+
+    fn resolve_color_id(&self, r: u8, g: u8, b: u8) -> ColorId {
+        pb_core::nearest(&self.palette(), (r, g, b), self.metric).unwrap()
+    }
+
+    fn pack(&self, info: PixelInfo) -> anyhow::Result<Vec<u8>> {
+        self.wire.codec.encode(&info)
+    }
+
+    fn decode(&self, frame: &[u8]) -> Vec<PixelInfo> {
+        self.wire.decode(frame, self.palette().len())
+    }
+
+    fn decode_updates(&self, frame: &[u8]) -> Vec<PixelInfo> {
+        self.wire.decode_updates(frame, self.palette().len())
+    }
+}
+
+// Content hash naming the template in logs, summaries and the state file
+fn template_version(pixels: &[PixelInfo]) -> String {
+    let hash = fnv1a(pixels.iter().flat_map(|pixel| {
+        pixel
+            .x
+            .to_le_bytes()
+            .into_iter()
+            .chain(pixel.y.to_le_bytes())
+            .chain([pixel.color_id])
+    }));
+    format!("{hash:016x}")
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Stable across builds unlike the std hasher
+fn fnv1a(bytes: impl Iterator<Item = u8>) -> u64 {
+    bytes.fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn parse_hex(hex: &str) -> anyhow::Result<(u8, u8, u8)> {
+    pb_core::parse_hex(hex).ok_or_else(|| anyhow!("{hex} is not a #RRGGBB color"))
+}
+
+struct PixelProvider {
+    queue: Queue,
+    context: Arc<Context>,
+    stats: Vec<ColorStats>,
+    // When the server last echoed one of our paints; a send alone proves nothing was painted
+    last_echo: Instant,
+    // Stalls are timed from here at the earliest: the last reconnect, or when painting last could not happen
+    stall_excused: Instant,
+    target: HashMap<(u32, u32), TargetPixel>,
+    // Times one of our painted pixels was overwritten with a foreign color
+    overwritten: u32,
+    defend: bool,
+    canvas: Canvas,
+    // Bounding box of the target
+    area: Area,
+    // Our sends whose broadcast echo has not arrived yet, with the color sent
+    pending: HashMap<(u32, u32), (u8, Instant)>,
+    ack_match: AckMatch,
+    echo_remap: HashMap<u8, u8>,
+    remap_inference: RemapInference,
+    echo_latencies: Vec<Duration>,
+    echo_timeouts: u32,
+    // Times workers applied their buffered broadcast updates, each under one lock
+    update_batches: u64,
+    // Never painted nor defended
+    pinned: HashSet<(u32, u32)>,
+    // Claimed pixels, by the worker holding them and since when
+    leases: HashMap<(u32, u32), (i32, Instant)>,
+    // Latest failed sends, oldest first
+    failures: VecDeque<((u32, u32), Instant)>,
+    // What the inexact pixels looked like in the brush, to match them again on a new palette
+    sources: Sources,
+    // Since when the queue is empty, and whether workers went to watching since
+    drained: Option<Instant>,
+    watching: bool,
+    // Left out because they would not be painted before the deadline anyway
+    dropped: HashSet<(u32, u32)>,
+    // Claims found already painted right by someone else, so never sent
+    already_correct: u32,
+    reverts: RevertPolicy,
+    // Pauses painting when the canvas looks wiped
+    reset_guard: Option<ResetGuard>,
+    // Holds painting back until an operator confirms a big run
+    unconfirmed: Option<Gate>,
+    // Told when a job completes
+    webhook: Option<Webhook>,
+    // Reverted too often; served only once the queue has nothing else, or never again
+    demoted: HashSet<(u32, u32)>,
+    low_priority: VecDeque<PixelInfo>,
+    given_up: HashSet<(u32, u32)>,
+    // Failed sends per pixel since it was last painted
+    send_failures: HashMap<(u32, u32), u32>,
+    max_retries: Option<u32>,
+    // Failed more often than retried, so no longer queued
+    abandoned: HashSet<(u32, u32)>,
+    coverage: Coverage,
+    // The brushes, each painted and defended on its own terms
+    jobs: Vec<Job>,
+    // Where pixels claimed from a planner are reported back, and whether it has more
+    planner: Option<mpsc::UnboundedSender<Report>>,
+    planner_finished: bool,
+}
+
+// One brush of the run
+struct Job {
+    name: String,
+    priority: i32,
+    defend: bool,
+    // Queued this run, and first paints of those
+    queued: u32,
+    painted: u32,
+    completed: Option<SystemTime>,
+}
+
+impl Job {
+    fn new(name: String, priority: i32, defend: bool) -> Self {
+        Self {
+            name,
+            priority,
+            defend,
+            queued: 0,
+            painted: 0,
+            completed: None,
+        }
+    }
+
+    fn state(&self) -> JobState {
+        if self.painted == 0 && self.queued > 0 {
+            JobState::Queued
+        } else if self.painted < self.queued {
+            JobState::Painting
+        } else if self.defend {
+            JobState::Defending
+        } else {
+            JobState::Complete
+        }
+    }
+
+    fn report(&self) -> JobReport {
+        JobReport {
+            name: self.name.clone(),
+            priority: self.priority,
+            state: self.state(),
+            queued: self.queued,
+            painted: self.painted,
+            completed_at: self.completed.map(unix_secs),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Painting,
+    Complete,
+    // Complete, and repainted when damaged
+    Defending,
+}
+
+// When repainting a pixel others keep reverting stops being worth a cooldown
+#[derive(Clone, Copy)]
+struct RevertPolicy {
+    window: Duration,
+    demote_after: u32,
+    give_up_after: Option<u32>,
+}
+
+struct TargetPixel {
+    color_id: u8,
+    intact: bool,
+    painted: bool,
+    // Waiting in the queue or claimed by a worker
+    queued: bool,
+    repaints: u32,
+    // Our last paint of it, and how often it was overwritten shortly after one
+    painted_at: Option<Instant>,
+    reverts: u32,
+    // Index of its brush among the jobs
+    job: usize,
+}
+
+impl PixelProvider {
+    const MAX_COLOR_ID: u32 = 25;
+    const MAX_HEIGHT: u32 = 400;
+    const MAX_WIDTH: u32 = 1590;
+    const SIZE: u32 = 636000;
+    const ALPHA_THRESHOLD: u8 = 128;
+
+    const CONTESTED_REPORTED: usize = 10;
+    const ECHO_TIMEOUT: Duration = Duration::from_secs(30);
+    const FAILURES_KEPT: usize = 1000;
+
+    fn new(
+        pixels: Vec<PixelInfo>,
+        frame: HashSet<(u32, u32)>,
+        locality: Locality,
+        defend: bool,
+        context: Arc<Context>,
+    ) -> Self {
+        let mut stats = vec![ColorStats::default(); context.palette().len()];
+        let mut target = HashMap::with_capacity(pixels.len());
+        let mut area = Area {
+            x0: u32::MAX,
+            y0: u32::MAX,
+            ..Area::default()
+        };
+        for pixel in &pixels {
+            area.x0 = area.x0.min(pixel.x);
+            area.y0 = area.y0.min(pixel.y);
+            area.x1 = area.x1.max(pixel.x + 1);
+            area.y1 = area.y1.max(pixel.y + 1);
+            stats[pixel.color_id as usize].queued += 1;
+            target.insert(
+                (pixel.x, pixel.y),
+                TargetPixel {
+                    color_id: pixel.color_id,
+                    intact: false,
+                    painted: false,
+                    queued: true,
+                    repaints: 0,
+                    painted_at: None,
+                    reverts: 0,
+                    job: 0,
+                },
+            );
+        }
+        Self {
+            queue: Queue::new(pixels, frame, locality),
+            context,
+            stats,
+            last_echo: Instant::now(),
+            stall_excused: Instant::now(),
+            target,
+            overwritten: 0,
+            defend,
+            canvas: Canvas::new(Self::MAX_WIDTH, Self::MAX_HEIGHT),
+            area,
+            pending: HashMap::new(),
+            ack_match: AckMatch::default(),
+            echo_remap: HashMap::new(),
+            remap_inference: RemapInference::default(),
+            echo_latencies: Vec::new(),
+            echo_timeouts: 0,
+            update_batches: 0,
+            pinned: HashSet::new(),
+            leases: HashMap::new(),
+            failures: VecDeque::new(),
+            sources: HashMap::new(),
+            drained: None,
+            watching: false,
+            dropped: HashSet::new(),
+            already_correct: 0,
+            reverts: DefendConfig::default().reverts(),
+            reset_guard: None,
+            unconfirmed: None,
+            webhook: None,
+            demoted: HashSet::new(),
+            low_priority: VecDeque::new(),
+            given_up: HashSet::new(),
+            send_failures: HashMap::new(),
+            max_retries: None,
+            abandoned: HashSet::new(),
+            coverage: Coverage::default(),
+            jobs: Vec::new(),
+            planner: None,
+            planner_finished: false,
+        }
+    }
+
+    // Queues a pixel the planner handed out
+    fn add_remote(&mut self, pixel: PixelInfo) -> anyhow::Result<()> {
+        if pixel.x >= Self::MAX_WIDTH || pixel.y >= Self::MAX_HEIGHT {
+            Err(anyhow!("outside the canvas"))?
+        }
+        if let Some(old) = self.target.get(&(pixel.x, pixel.y)) {
+            if old.queued {
+                Err(anyhow!("already queued"))?
+            }
+            let stats = &mut self.stats[old.color_id as usize];
+            stats.queued -= 1;
+            if old.painted {
+                stats.painted -= 1;
+            }
+        }
+        self.stats[pixel.color_id as usize].queued += 1;
+        self.target.insert(
+            (pixel.x, pixel.y),
+            TargetPixel {
+                color_id: pixel.color_id,
+                intact: false,
+                painted: false,
+                queued: true,
+                repaints: 0,
+                painted_at: None,
+                reverts: 0,
+                job: 0,
+            },
+        );
+        self.area.x0 = self.area.x0.min(pixel.x);
+        self.area.y0 = self.area.y0.min(pixel.y);
+        self.area.x1 = self.area.x1.max(pixel.x + 1);
+        self.area.y1 = self.area.y1.max(pixel.y + 1);
+        self.queue.push_back(pixel);
+        Ok(())
+    }
+
+    // Handed out by the planner and neither painted nor failed yet
+    fn outstanding(&self) -> usize {
+        self.queue.iter().count() + self.leases.len()
+    }
+
+    fn report_to_planner(&self, report: Report) {
+        if let Some(planner) = &self.planner {
+            drop(planner.send(report));
+        }
+    }
+
+    fn assign_jobs(&mut self, mut jobs: Vec<Job>, owners: &HashMap<(u32, u32), usize>) {
+        for (position, target) in &mut self.target {
+            target.job = owners.get(position).copied().unwrap_or_default();
+            if let Some(job) = jobs.get_mut(target.job) {
+                job.queued += 1;
+            }
+        }
+        self.defend = jobs.iter().any(|job| job.defend);
+        self.jobs = jobs;
+    }
+
+    // A pixel of `job` is on the canvas for the first time
+    fn job_painted(&mut self, job: usize) {
+        let Some(job) = self.jobs.get_mut(job) else {
+            return;
+        };
+        job.painted += 1;
+        if job.painted == 1 && job.queued > 1 {
+            info!("Job {} started painting", job.name);
+        }
+        if job.painted == job.queued {
+            job.completed = Some(SystemTime::now());
+            if let Some(webhook) = &self.webhook {
+                webhook.send(Event::JobComplete {
+                    job: job.name.clone(),
+                    priority: job.priority,
+                    painted: job.painted,
+                    defending: job.defend,
+                });
+            }
+            info!(
+                "Job {} complete: {} pixels painted{}",
+                job.name,
+                job.queued,
+                if job.defend {
+                    "; defending it from now on"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+
+    // Switches to `new` and retargets every pixel at its nearest color there
+    fn repalette(&mut self, new: Palette, requantize_completed: bool) {
+        let old = self.context.palette();
+        if *old == new {
+            return;
+        }
+        self.context.set_palette(new);
+        let new = self.context.palette();
+        let (mut changed, mut requeued) = (0, 0);
+        let mut stats = vec![ColorStats::default(); new.len()];
+        let mut repaint = Vec::new();
+        for (&(x, y), target) in &mut self.target {
+            let painted_as = old.rgb_of(target.color_id);
+            let (r, g, b) = self
+                .sources
+                .get(&(x, y))
+                .copied()
+                .or(painted_as)
+                .unwrap_or_default();
+            let id = self.context.resolve_color_id(r, g, b).id;
+            // The color it had, under its id in the new palette if still there
+            let kept = painted_as.and_then(|rgb| new.id_of(rgb));
+            if kept != Some(id) {
+                changed += 1;
+            }
+            let redo = kept != Some(id)
+                && target.painted
+                && !target.queued
+                && (requantize_completed || kept.is_none());
+            match kept {
+                Some(kept) if target.painted && !target.queued && !redo => target.color_id = kept,
+                _ => target.color_id = id,
+            }
+            if redo {
+                target.queued = true;
+                target.intact = false;
+                requeued += 1;
+                repaint.push(PixelInfo { x, y, color_id: id });
+            }
+            stats[target.color_id as usize].queued += 1;
+            if target.painted && !redo {
+                stats[target.color_id as usize].painted += 1;
+            }
+        }
+        let target = &self.target;
+        let retarget = |pixel: &mut PixelInfo| {
+            if let Some(target) = target.get(&(pixel.x, pixel.y)) {
+                pixel.color_id = target.color_id;
+            }
+        };
+        self.queue.retarget(retarget);
+        self.low_priority.iter_mut().for_each(retarget);
+        for pixel in repaint {
+            self.queue.push_front(pixel);
+        }
+        self.stats = stats;
+        self.canvas.recolor(|color_id| {
+            old.rgb_of(color_id)
+                .and_then(|rgb| new.id_of(rgb))
+                .unwrap_or(Canvas::UNKNOWN)
+        });
+        self.pending.clear();
+        warn!(
+            "Palette changed from {} to {} colors; {changed} pixels changed target color, {requeued} finished ones requeued",
+            old.len(),
+            new.len()
+        );
+    }
+
+    fn quantize(
+        image: RgbaImage,
+        palette: &Palette,
+        (x, y): (u32, u32),
+        options: &QuantizeOptions,
+        max_inexact_ratio: f64,
+        max_color_distance: Option<f64>,
+        parallel: bool,
+    ) -> anyhow::Result<(Vec<PixelInfo>, Sources)> {
+        if x >= Self::MAX_WIDTH {
+            Err(anyhow!("X axis is out of range"))?
+        }
+        if y >= Self::MAX_HEIGHT {
+            Err(anyhow!("Y axis is out of range"))?
+        }
+        let (width, height) = image.dimensions();
+        let (columns, rows) = (
+            width.min(Self::MAX_WIDTH - x),
+            height.min(Self::MAX_HEIGHT - y),
+        );
+        let started = Instant::now();
+        let image = image::imageops::crop_imm(&image, 0, 0, columns, rows).to_image();
+        let width = columns as usize;
+        let rows_of = |colors: Vec<Option<ColorId>>| {
+            colors.chunks(width).map(<[_]>::to_vec).collect::<Vec<_>>()
+        };
+        // The warnings below go out in image order either way
+        let colors = match (options.dither, parallel) {
+            (_, false) => rows_of(pb_core::quantize(&image, width, palette, options)),
+            // Undithered rows do not depend on each other, so this matches the serial result
+            (Dither::None, true) => image
+                .par_chunks(width * 4)
+                .map(|row| pb_core::quantize_row(row, palette, options))
+                .collect(),
+            // Each row takes error from the one above, so bands are dithered apart and drop it at their edges
+            (Dither::FloydSteinberg, true) => {
+                let band = (rows as usize).div_ceil(rayon::current_num_threads());
+                image
+                    .par_chunks(band.max(1) * width * 4)
+                    .map(|band| rows_of(pb_core::quantize(band, width, palette, options)))
+                    .collect::<Vec<_>>()
+                    .concat()
+            }
+        };
+        let resolved = colors
+            .into_iter()
+            .zip(image.rows())
+            .map(|(colors, sources)| {
+                (0..columns)
+                    .zip(colors)
+                    .zip(sources)
+                    .filter_map(|((dx, color), source)| {
+                        let [r, g, b, _] = source.0;
+                        Some((dx, (r, g, b), color?))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        info!(
+            "Matched {}x{} brush pixels to the palette in {:.0?}{}",
+            columns,
+            rows,
+            started.elapsed(),
+            if parallel { " on all cores" } else { "" }
+        );
+        let mut pixels = Vec::new();
+        let mut inexact = HashMap::<_, u32>::new();
+        let mut sources = HashMap::new();
+        let mut skipped = 0;
+        for (dy, row) in (0..rows).zip(resolved) {
+            for (dx, (r, g, b), color) in row {
+                let ColorId {
+                    id,
+                    exact,
+                    distance,
+                } = color;
+                if max_color_distance.is_some_and(|max| distance > max) {
+                    debug!("Pixel {{{dx}:{dy}}} has no close enough palette color; skipped");
+                    skipped += 1;
+                    continue;
+                }
+                if !exact {
+                    warn!("Pixel {{{dx}:{dy}}} is not exactly match allowed colors. Converted to {id:x}");
+                    *inexact.entry((r, g, b)).or_default() += 1;
+                    sources.insert((dx + x, dy + y), (r, g, b));
+                }
+                pixels.push(
+                    PixelInfo {
+                        x: dx,
+                        y: dy,
+                        color_id: id,
+                    }
+                    .with_offset(x, y),
+                );
+            }
+        }
+        let total = inexact.values().sum::<u32>();
+        let ratio = if pixels.is_empty() {
+            0.0
+        } else {
+            total as f64 / pixels.len() as f64
+        };
+        info!(
+            "{total} of {} pixels ({:.1}%) are inexact palette matches",
+            pixels.len(),
+            ratio * 100.0
+        );
+        if skipped > 0 {
+            warn!("{skipped} pixels are farther than max_color_distance from the palette and will not be painted");
+        }
+        if ratio > max_inexact_ratio {
+            let mut offenders = inexact.iter().collect::<Vec<_>>();
+            offenders.sort_by_key(|&(_, count)| cmp::Reverse(count));
+            let offenders = offenders
+                .iter()
+                .take(5)
+                .map(|((r, g, b), count)| format!("#{r:02X}{g:02X}{b:02X} ({count})"))
+                .collect::<Vec<_>>();
+            Err(anyhow!(
+                "{:.1}% of pixels are inexact, more than max_inexact_ratio allows; \
+                 worst source colors: {}. Consider dithering the image to the palette first",
+                ratio * 100.0,
+                offenders.join(", ")
+            ))?
+        }
+        Ok((pixels, sources))
+    }
+
+    fn get_pixel(&mut self, worker: i32) -> Option<PixelInfo> {
+        // Pinned pixels leave the queue here rather than when pinned
+        while let Some(pixel) = self.queue.pop(worker) {
+            // Unlike pinned ones these still count as remaining
+            if self.dropped.contains(&(pixel.x, pixel.y)) {
+                if let Some(target) = self.target.get_mut(&(pixel.x, pixel.y)) {
+                    target.queued = false;
+                }
+                continue;
+            }
+            if !self.pinned.contains(&(pixel.x, pixel.y))
+                && !self.given_up.contains(&(pixel.x, pixel.y))
+            {
+                self.leases
+                    .insert((pixel.x, pixel.y), (worker, Instant::now()));
+                return Some(pixel);
+            }
+            if let Some(target) = self.target.get_mut(&(pixel.x, pixel.y)) {
+                target.queued = false;
+                if !target.painted {
+                    self.stats[target.color_id as usize].queued -= 1;
+                }
+            }
+        }
+        // Done ahead of the estimate, so the deadline has room for more after all
+        if !self.dropped.is_empty() && self.queue.is_empty() {
+            let mut readmitted = self.dropped.drain().collect::<Vec<_>>();
+            readmitted.sort_by_key(|&(x, y)| (y, x));
+            info!(
+                "Queue ran out before the deadline; taking back {} left out pixels",
+                readmitted.len()
+            );
+            for (x, y) in readmitted.into_iter().rev() {
+                if let Some(target) = self.target.get_mut(&(x, y)) {
+                    target.queued = true;
+                    self.queue.push_front(PixelInfo {
+                        x,
+                        y,
+                        color_id: target.color_id,
+                    });
+                }
+            }
+            return self.get_pixel(worker);
+        }
+        while let Some(pixel) = self.low_priority.pop_front() {
+            if self.given_up.contains(&(pixel.x, pixel.y)) {
+                if let Some(target) = self.target.get_mut(&(pixel.x, pixel.y)) {
+                    target.queued = false;
+                }
+                continue;
+            }
+            self.leases
+                .insert((pixel.x, pixel.y), (worker, Instant::now()));
+            return Some(pixel);
+        }
+        None
+    }
+
+    // Leaves out the least important queued pixels past the first `budget`
+    fn trim(&mut self, budget: usize, priority: &DeadlinePriority) {
+        let mut queued = self
+            .queue
+            .iter()
+            .filter(|p| !self.pinned.contains(&(p.x, p.y)) && !self.dropped.contains(&(p.x, p.y)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if queued.len() <= budget {
+            return;
+        }
+        let target = &self.target;
+        let palette = self.context.palette();
+        priority.rank(&mut queued, &palette, |x, y| {
+            target.get(&(x, y)).map(|t| t.color_id)
+        });
+        let mut colors = HashMap::<u8, u32>::new();
+        for pixel in &queued[budget..] {
+            self.dropped.insert((pixel.x, pixel.y));
+            *colors.entry(pixel.color_id).or_default() += 1;
+        }
+        let mut colors = colors.into_iter().collect::<Vec<_>>();
+        colors.sort_by_key(|&(id, count)| (cmp::Reverse(count), id));
+        let colors = colors
+            .iter()
+            .map(|&(id, count)| {
+                let (r, g, b) = palette.rgb_of(id).unwrap_or_default();
+                format!("{count} #{r:02X}{g:02X}{b:02X}")
+            })
+            .collect::<Vec<_>>();
+        warn!(
+            "Only about {budget} more paints fit before the deadline; leaving out {} of {} queued pixels: {}",
+            queued.len() - budget,
+            queued.len(),
+            colors.join(", ")
+        );
+    }
+
+    // Share of the target pixels with a known color that do not show it; None before the board is known
+    fn mismatch(&self) -> Option<f64> {
+        let (mut known, mut wrong) = (0, 0);
+        for (&(x, y), target) in &self.target {
+            let color_id = self.canvas.get(x, y);
+            if color_id == Canvas::UNKNOWN || self.pinned.contains(&(x, y)) {
+                continue;
+            }
+            known += 1;
+            if color_id != target.color_id {
+                wrong += 1;
+            }
+        }
+        (known > 0).then(|| wrong as f64 / known as f64)
+    }
+
+    // Whether painting got anywhere within `stall`, or has nothing left to do
+    fn progressing(&self, stall: Duration) -> bool {
+        self.queue.is_empty() || self.last_echo.max(self.stall_excused).elapsed() < stall
+    }
+
+    fn paused(&self) -> bool {
+        self.unconfirmed.is_some() || self.reset_guard.as_ref().is_some_and(ResetGuard::paused)
+    }
+
+    // Starts a run waiting for confirmation; false if none was awaited
+    fn confirm(&self) -> bool {
+        self.unconfirmed
+            .as_ref()
+            .is_some_and(|gate| gate.confirm("over the status server"))
+    }
+
+    // Lets painting go on after a suspected canvas reset; false if it was not paused
+    fn resume(&mut self) -> bool {
+        self.reset_guard.as_mut().is_some_and(ResetGuard::resume)
+    }
+
+    fn pin(&mut self, x: u32, y: u32) -> bool {
+        self.pinned.insert((x, y))
+    }
+
+    // Queues the pixel again if the canvas does not show the template there
+    fn unpin(&mut self, x: u32, y: u32) -> bool {
+        if !self.pinned.remove(&(x, y)) {
+            return false;
+        }
+        let Some(target) = self.target.get_mut(&(x, y)) else {
+            return true;
+        };
+        if !target.queued && self.canvas.get(x, y) != target.color_id {
+            target.queued = true;
+            if !target.painted {
+                self.stats[target.color_id as usize].queued += 1;
+            }
+            self.queue.push_front(PixelInfo {
+                x,
+                y,
+                color_id: target.color_id,
+            });
+        }
+        true
+    }
+
+    fn release(&mut self, pixel: PixelInfo) {
+        self.leases.remove(&(pixel.x, pixel.y));
+        self.queue.push_front(pixel);
+    }
+
+    fn failed(&mut self, pixel: PixelInfo) {
+        if self.failures.len() == Self::FAILURES_KEPT {
+            self.failures.pop_front();
+        }
+        self.failures
+            .push_back(((pixel.x, pixel.y), Instant::now()));
+        self.leases.remove(&(pixel.x, pixel.y));
+        // The planner decides whether and when to hand it out again
+        if self.planner.is_some() {
+            if let Some(target) = self.target.get_mut(&(pixel.x, pixel.y)) {
+                target.queued = false;
+            }
+            self.report_to_planner(Report::Failed(pixel));
+            return;
+        }
+        let failures = self.send_failures.entry((pixel.x, pixel.y)).or_default();
+        *failures += 1;
+        if self.max_retries.is_some_and(|max| *failures > max) {
+            warn!(
+                "Pixel {{{}:{}}} failed {} sends; no more retries.",
+                pixel.x, pixel.y, failures
+            );
+            self.abandoned.insert((pixel.x, pixel.y));
+            if let Some(target) = self.target.get_mut(&(pixel.x, pixel.y)) {
+                target.queued = false;
+            }
+            return;
+        }
+        self.queue.requeue(pixel);
+    }
+
+    fn painted(&mut self, pixel: &PixelInfo) {
+        let now = Instant::now();
+        self.leases.remove(&(pixel.x, pixel.y));
+        self.send_failures.remove(&(pixel.x, pixel.y));
+        let pending = self.pending.len();
+        self.pending
+            .retain(|_, (_, sent)| sent.elapsed() < Self::ECHO_TIMEOUT);
+        self.echo_timeouts += (pending - self.pending.len()) as u32;
+        self.pending
+            .insert((pixel.x, pixel.y), (pixel.color_id, now));
+        // Assume the server took it until an update says otherwise
+        self.canvas.set(pixel.x, pixel.y, pixel.color_id);
+        self.report_to_planner(Report::Done(pixel.clone()));
+        let Some(target) = self.target.get_mut(&(pixel.x, pixel.y)) else {
+            return;
+        };
+        if pixel.color_id == target.color_id {
+            self.queue.grow(pixel.x, pixel.y);
+        }
+        target.intact = true;
+        target.queued = false;
+        target.painted_at = Some(now);
+        if target.painted {
+            target.repaints += 1;
+        } else {
+            target.painted = true;
+            self.stats[pixel.color_id as usize].painted += 1;
+            let job = target.job;
+            self.job_painted(job);
+        }
+    }
+
+    // Marks a claimed pixel done if the canvas already has it in the target color
+    fn settle_if_correct(&mut self, pixel: &PixelInfo) -> bool {
+        if self.canvas.get(pixel.x, pixel.y) != pixel.color_id {
+            return false;
+        }
+        self.leases.remove(&(pixel.x, pixel.y));
+        self.already_correct += 1;
+        self.report_to_planner(Report::Done(pixel.clone()));
+        let Some(target) = self.target.get_mut(&(pixel.x, pixel.y)) else {
+            return true;
+        };
+        self.queue.grow(pixel.x, pixel.y);
+        target.intact = true;
+        target.queued = false;
+        if !target.painted {
+            target.painted = true;
+            self.stats[pixel.color_id as usize].painted += 1;
+            let job = target.job;
+            self.job_painted(job);
+        }
+        true
+    }
+
+    // Defending workers wait for damage instead of leaving when the queue drains
+    fn is_done(&self) -> bool {
+        !self.defend && self.queue.is_empty() && (self.planner.is_none() || self.planner_finished)
+    }
+
+    fn observe(&mut self, update: &PixelInfo) {
+        let update = &PixelInfo {
+            color_id: *self
+                .echo_remap
+                .get(&update.color_id)
+                .unwrap_or(&update.color_id),
+            ..update.clone()
+        };
+        self.canvas.set(update.x, update.y, update.color_id);
+        let echo = self
+            .pending
+            .get(&(update.x, update.y))
+            .filter(|&&(color_id, _)| {
+                self.ack_match == AckMatch::Position || color_id == update.color_id
+            })
+            .copied();
+        if let Some((color_id, sent)) = echo {
+            // Our own paint coming back, not activity of others
+            self.pending.remove(&(update.x, update.y));
+            self.last_echo = Instant::now();
+            if self.ack_match == AckMatch::Position {
+                if let Some(table) = self.remap_inference.record(color_id, update.color_id) {
+                    warn!(
+                        "Our paints are echoed with other color ids in a fixed pattern; add this to the config so the canvas is tracked in our numbering: {}",
+                        ack::format_remap(&table)
+                    );
+                }
+            }
+            let latency = sent.elapsed();
+            debug!(
+                "Paint {{{}:{}}} echoed after {}ms",
+                update.x,
+                update.y,
+                latency.as_millis()
+            );
+            self.echo_latencies.push(latency);
+            return;
+        }
+        let Some(target) = self.target.get_mut(&(update.x, update.y)) else {
+            return;
+        };
+        if update.color_id == target.color_id {
+            target.intact = true;
+            self.queue.grow(update.x, update.y);
+        } else if target.intact {
+            target.intact = false;
+            self.overwritten += 1;
+            let window = self.reverts.window;
+            let defended = self
+                .jobs
+                .get(target.job)
+                .map_or(self.defend, |job| job.defend);
+            if defended && target.painted_at.is_some_and(|at| at.elapsed() <= window) {
+                target.reverts += 1;
+                let reverts = target.reverts;
+                self.reverted(update.x, update.y, reverts);
+            }
+        }
+    }
+
+    fn reverted(&mut self, x: u32, y: u32, reverts: u32) {
+        let window = self.reverts.window.as_secs();
+        if self.reverts.give_up_after.is_some_and(|n| reverts >= n) {
+            if self.given_up.insert((x, y)) {
+                warn!(
+                    "Pixel {{{x}:{y}}} was reverted within {window}s of our paint {reverts} times; giving up on it"
+                );
+            }
+        } else if reverts >= self.reverts.demote_after && self.demoted.insert((x, y)) {
+            info!(
+                "Pixel {{{x}:{y}}} was reverted within {window}s of our paint {reverts} times; repainting it only when nothing else is pending"
+            );
+        }
+    }
+
+    // Whether the queue has been empty for `after`, logging when that starts
+    fn watching(&mut self, after: Duration) -> bool {
+        // More work may come from the planner any moment
+        let planned = self.planner.is_some() && !self.planner_finished;
+        if planned || !self.queue.is_empty() || !self.low_priority.is_empty() {
+            self.drained = None;
+            return false;
+        }
+        let drained = *self.drained.get_or_insert_with(Instant::now);
+        if !self.watching && drained.elapsed() >= after {
+            self.watching = true;
+            info!(
+                "Nothing to repaint for {}s; workers now wake only when damage shows up",
+                after.as_secs()
+            );
+        }
+        self.watching
+    }
+
+    // Requeues damaged target pixels, scanning only tiles changed since the last sweep; true if any were
+    fn sweep_damage(&mut self) -> bool {
+        if !self.defend {
+            return false;
+        }
+        let mut requeued = 0;
+        for area in self.canvas.sweep(self.area) {
+            for y in area.y0..area.y1 {
+                for x in area.x0..area.x1 {
+                    let Some(target) = self.target.get_mut(&(x, y)) else {
+                        continue;
+                    };
+                    if target.queued
+                        || !self
+                            .jobs
+                            .get(target.job)
+                            .map_or(self.defend, |job| job.defend)
+                        || self.canvas.get(x, y) == target.color_id
+                        || self.pinned.contains(&(x, y))
+                        || self.dropped.contains(&(x, y))
+                        || self.given_up.contains(&(x, y))
+                        || self.abandoned.contains(&(x, y))
+                    {
+                        continue;
+                    }
+                    target.queued = true;
+                    requeued += 1;
+                    let pixel = PixelInfo {
+                        x,
+                        y,
+                        color_id: target.color_id,
+                    };
+                    if self.demoted.contains(&(x, y)) {
+                        self.low_priority.push_back(pixel);
+                    } else {
+                        self.queue.push_front(pixel);
+                    }
+                }
+            }
+        }
+        if requeued > 0 && self.watching {
+            self.watching = false;
+            self.drained = None;
+            info!("{requeued} damaged pixels found while watching; workers back to painting");
+        }
+        requeued > 0
+    }
+
+    fn heatmap(&self) -> Option<RgbaImage> {
+        let min_x = self.target.keys().map(|&(x, _)| x).min()?;
+        let min_y = self.target.keys().map(|&(_, y)| y).min()?;
+        let max_x = self.target.keys().map(|&(x, _)| x).max()?;
+        let max_y = self.target.keys().map(|&(_, y)| y).max()?;
+        let hottest = self.target.values().map(|t| t.repaints).max()?.max(1);
+        let mut image = RgbaImage::new(max_x - min_x + 1, max_y - min_y + 1);
+        for (&(x, y), target) in &self.target {
+            // Black through red to yellow
+            let heat = target.repaints as f32 / hottest as f32;
+            let red = (heat * 2.0).min(1.0) * 255.0;
+            let green = (heat * 2.0 - 1.0).max(0.0) * 255.0;
+            image.put_pixel(
+                x - min_x,
+                y - min_y,
+                image::Rgba([red as u8, green as u8, 0, 255]),
+            );
+        }
+        Some(image)
+    }
+
+    fn report(&self) -> RunReport {
+        let colors = self
+            .stats
+            .iter()
+            .zip(self.context.palette().colors())
+            .filter(|(stats, _)| stats.queued > 0)
+            .map(|(stats, &(r, g, b))| ColorSummary {
+                color: format!("#{r:02X}{g:02X}{b:02X}"),
+                queued: stats.queued,
+                painted: stats.painted,
+                remaining: stats.queued - stats.painted,
+            })
+            .collect::<Vec<_>>();
+        RunReport {
+            queued: colors.iter().map(|c| c.queued).sum(),
+            painted: colors.iter().map(|c| c.painted).sum(),
+            colors,
+            overwritten: self.overwritten,
+            already_correct: self.already_correct,
+            demoted: self.demoted.len() as u32,
+            given_up: self.given_up.len() as u32,
+            failed: self.abandoned.len() as u32,
+            coverage: self.coverage.clone(),
+            jobs: self.jobs.iter().map(Job::report).collect(),
+            contested: self.contested(),
+            latency: self.latency(),
+            frame: self.frame(),
+            cumulative: None,
+            never_connected: Vec::new(),
+            template_versions: Vec::new(),
+            deadline: None,
+            stalled: false,
+        }
+    }
+
+    fn repaints(&self) -> u64 {
+        self.target.values().map(|t| t.repaints as u64).sum()
+    }
+
+    fn frame(&self) -> Option<FrameReport> {
+        let frame = self
+            .queue
+            .framed()
+            .iter()
+            .filter_map(|position| self.target.get(position))
+            .collect::<Vec<_>>();
+        (!frame.is_empty()).then(|| FrameReport {
+            queued: frame.len() as u32,
+            painted: frame.iter().filter(|target| target.painted).count() as u32,
+        })
+    }
+
+    fn latency(&self) -> Option<LatencyReport> {
+        let mut latencies = self.echo_latencies.clone();
+        latencies.sort();
+        let percentile = |p: usize| {
+            let index = (latencies.len() * p / 100).min(latencies.len() - 1);
+            latencies[index].as_millis() as u64
+        };
+        Some(LatencyReport {
+            p50_ms: (!latencies.is_empty()).then(|| percentile(50))?,
+            p95_ms: percentile(95),
+            p99_ms: percentile(99),
+            echoed: latencies.len() as u32,
+            timeouts: self.echo_timeouts,
+        })
+    }
+
+    fn contested(&self) -> Vec<Contested> {
+        let mut contested = self
+            .target
+            .iter()
+            .filter(|(_, target)| target.repaints > 0)
+            .map(|(&(x, y), target)| Contested {
+                x,
+                y,
+                repaints: target.repaints,
+            })
+            .collect::<Vec<_>>();
+        contested.sort_by_key(|c| (cmp::Reverse(c.repaints), c.y, c.x));
+        contested.truncate(Self::CONTESTED_REPORTED);
+        contested
+    }
+
+    #[allow(dead_code)]
+    fn get_packed_pixel(&mut self, worker: i32) -> Option<Vec<u8>> {
+        let info = self.get_pixel(worker)?;
+        self.context.pack(info).ok()
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct ColorStats {
+    queued: u32,
+    painted: u32,
+}
+
+#[derive(Serialize, Default)]
+pub struct RunReport {
+    pub queued: u32,
+    pub painted: u32,
+    pub colors: Vec<ColorSummary>,
+    pub overwritten: u32,
+    pub already_correct: u32,
+    // Reverted so often they were repainted last, or not at all
+    pub demoted: u32,
+    pub given_up: u32,
+    // Out of retries after failed sends
+    pub failed: u32,
+    // Where the brushes paint, for telling teammates
+    pub coverage: Coverage,
+    // One per brush, in config order
+    pub jobs: Vec<JobReport>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub contested: Vec<Contested>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<LatencyReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame: Option<FrameReport>,
+    // This run included
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cumulative: Option<Totals>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub never_connected: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub template_versions: Vec<TemplateVersion>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<DeadlineReport>,
+    // Gave up after stall.max_reconnects reconnects brought no paint back
+    pub stalled: bool,
+}
+
+#[derive(Serialize)]
+pub struct JobReport {
+    pub name: String,
+    pub priority: i32,
+    pub state: JobState,
+    pub queued: u32,
+    pub painted: u32,
+    // Unix seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct DeadlineReport {
+    pub at: String,
+    // The run was stopped by it rather than finishing or being interrupted
+    pub reached: bool,
+    // Pixels that did not fit and were never claimed
+    pub dropped: u32,
+}
+
+// Unix seconds the template was painted from and until
+#[derive(Serialize)]
+pub struct TemplateVersion {
+    pub version: String,
+    pub active_from: u64,
+    pub active_until: u64,
+}
+
+// Time from sending a paint to seeing it broadcast back
+#[derive(Serialize)]
+pub struct LatencyReport {
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub p99_ms: u64,
+    pub echoed: u32,
+    pub timeouts: u32,
+}
+
+// Part of the totals above
+#[derive(Serialize)]
+pub struct FrameReport {
+    pub queued: u32,
+    pub painted: u32,
+}
+
+#[derive(Serialize)]
+pub struct Contested {
+    pub x: u32,
+    pub y: u32,
+    pub repaints: u32,
+}
+
+#[derive(Serialize)]
+pub struct ColorSummary {
+    pub color: String,
+    pub queued: u32,
+    pub painted: u32,
+    pub remaining: u32,
+}
+
+impl std::fmt::Display for RunReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{} pixels painted", self.painted, self.queued)?;
+        if let Some(frame) = &self.frame {
+            write!(f, " ({}/{} of the frame)", frame.painted, frame.queued)?;
+        }
+        if self.jobs.len() > 1 {
+            let jobs = self
+                .jobs
+                .iter()
+                .map(|job| match job.state {
+                    JobState::Queued | JobState::Painting => {
+                        format!("{} {}/{}", job.name, job.painted, job.queued)
+                    }
+                    JobState::Complete => format!("{} complete", job.name),
+                    JobState::Defending => format!("{} defending", job.name),
+                })
+                .collect::<Vec<_>>();
+            write!(f, " [{}]", jobs.join(", "))?;
+        }
+        let mut remaining = self
+            .colors
+            .iter()
+            .filter(|c| c.remaining > 0)
+            .collect::<Vec<_>>();
+        remaining.sort_by_key(|c| cmp::Reverse(c.remaining));
+        for (i, color) in remaining.iter().take(5).enumerate() {
+            let separator = if i == 0 { "; remaining " } else { ", " };
+            write!(f, "{separator}{} {}", color.remaining, color.color)?;
+        }
+        if self.overwritten > 0 {
+            write!(f, "; {} overwritten by others", self.overwritten)?;
+        }
+        if self.already_correct > 0 {
+            write!(f, "; {} found already correct", self.already_correct)?;
+        }
+        if self.demoted > 0 || self.given_up > 0 {
+            write!(
+                f,
+                "; {} demoted and {} given up as contested",
+                self.demoted, self.given_up
+            )?;
+        }
+        if self.failed > 0 {
+            write!(f, "; {} failed after every retry", self.failed)?;
+        }
+        if let Some(deadline) = &self.deadline {
+            if deadline.reached {
+                write!(f, "; stopped at the deadline")?;
+            }
+            if deadline.dropped > 0 {
+                write!(f, "; {} left out to fit the deadline", deadline.dropped)?;
+            }
+        }
+        if let Some(latency) = &self.latency {
+            write!(
+                f,
+                "; echo p50/p95/p99 {}/{}/{}ms",
+                latency.p50_ms, latency.p95_ms, latency.p99_ms
+            )?;
+            if latency.timeouts > 0 {
+                write!(f, ", {} never echoed", latency.timeouts)?;
+            }
+        }
+        if let Some(totals) = &self.cumulative {
+            write!(f, "; {} painted over {} runs", totals.painted, totals.runs)?;
+        }
+        for (i, contested) in self.contested.iter().enumerate() {
+            let separator = if i == 0 { "; most contested " } else { ", " };
+            write!(
+                f,
+                "{separator}{{{}:{}}} {}x",
+                contested.x, contested.y, contested.repaints
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+pub struct PixelInfo {
+    pub x: u32,
+    pub y: u32,
+    pub color_id: u8,
+}
+
+impl PixelInfo {
+    pub fn position(&self) -> Point {
+        Point {
+            x: self.x,
+            y: self.y,
+        }
+    }
+
+    pub fn with_offset(self, dx: u32, dy: u32) -> Self {
+        Self {
+            x: self.x + dx,
+            y: self.y + dy,
+            ..self
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub struct Point {
+    pub x: u32,
+    pub y: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(width: u32, height: u32, seed: u64) -> RgbaImage {
+        let mut rng = StdRng::seed_from_u64(seed);
+        RgbaImage::from_fn(width, height, |_, _| {
+            image::Rgba([rng.gen(), rng.gen(), rng.gen(), 255])
+        })
+    }
+
+    fn quantize(dither: Dither, parallel: bool) -> (Vec<PixelInfo>, Sources) {
+        let options = QuantizeOptions {
+            metric: Metric::Lab,
+            dither,
+            ..Default::default()
+        };
+        let palette = Palette::default();
+        let image = noise(97, 61, 7);
+        PixelProvider::quantize(image, &palette, (3, 5), &options, 1.0, None, parallel).unwrap()
+    }
+
+    // One row of pixels from x = 0 in the given colors
+    pub(crate) fn provider(colors: &[u8]) -> PixelProvider {
+        let pixels = colors
+            .iter()
+            .enumerate()
+            .map(|(x, &color_id)| PixelInfo {
+                x: x as u32,
+                y: 0,
+                color_id,
+            })
+            .collect();
+        provider_of(pixels)
+    }
+
+    pub(crate) fn provider_of(pixels: Vec<PixelInfo>) -> PixelProvider {
+        PixelProvider::new(
+            pixels,
+            HashSet::new(),
+            Locality::default(),
+            false,
+            Arc::new(Context::default()),
+        )
+    }
+
+    // A path in the temp dir no other test or run uses, and nothing is at yet
+    pub(crate) fn scratch(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("pb-{}-{name}", std::process::id()));
+        drop(fs::remove_file(&path));
+        path
+    }
+
+    // The queue a config document builds, frame first
+    fn queue(document: &str) -> anyhow::Result<Vec<PixelInfo>> {
+        let config = parse_config(document)?;
+        build_queue(&config, &Context::new(&config))
+    }
+
+    #[test]
+    fn parallel_quantize_matches_serial_without_dither() {
+        assert_eq!(quantize(Dither::None, false), quantize(Dither::None, true));
+    }
+
+    #[test]
+    fn parallel_dither_covers_the_same_pixels() {
+        let position = |pixel: &PixelInfo| (pixel.x, pixel.y);
+        let (serial, _) = quantize(Dither::FloydSteinberg, false);
+        let (parallel, _) = quantize(Dither::FloydSteinberg, true);
+        assert_eq!(
+            serial.iter().map(position).collect::<Vec<_>>(),
+            parallel.iter().map(position).collect::<Vec<_>>()
+        );
+    }
+
+    // The bot goes through pb-core, so it has to land on the very goldens pb-core is pinned to
+    #[test]
+    fn quantize_matches_the_pb_core_goldens() {
+        let image = RgbaImage::from_raw(
+            fixture::WIDTH as u32,
+            fixture::HEIGHT as u32,
+            fixture::image(),
+        )
+        .unwrap();
+        let palette = Palette::default();
+        for (metric, dither, parallel, golden) in [
+            (Metric::Rgb, Dither::None, false, fixture::RGB),
+            (Metric::Lab, Dither::None, true, fixture::LAB),
+            (
+                Metric::Rgb,
+                Dither::FloydSteinberg,
+                false,
+                fixture::RGB_FLOYD_STEINBERG,
+            ),
+            (
+                Metric::Lab,
+                Dither::FloydSteinberg,
+                false,
+                fixture::LAB_FLOYD_STEINBERG,
+            ),
+        ] {
+            let options = QuantizeOptions {
+                metric,
+                dither,
+                alpha_threshold: PixelProvider::ALPHA_THRESHOLD,
+                ..Default::default()
+            };
+            let (pixels, _) = PixelProvider::quantize(
+                image.clone(),
+                &palette,
+                (0, 0),
+                &options,
+                1.0,
+                None,
+                parallel,
+            )
+            .unwrap();
+            let mut ids = vec![None; fixture::WIDTH * fixture::HEIGHT];
+            for pixel in pixels {
+                ids[pixel.y as usize * fixture::WIDTH + pixel.x as usize] = Some(pixel.color_id);
+            }
+            assert_eq!(ids, fixture::parse(golden), "{metric:?} {dither:?}");
+        }
+    }
+
+    #[test]
+    fn resolve_color_id_matches_the_pb_core_goldens() {
+        let context = |metric| Context {
+            metric,
+            ..Context::default()
+        };
+        let (rgb, lab) = (context(Metric::Rgb), context(Metric::Lab));
+        for ((r, g, b), (by_rgb, _), (by_lab, _)) in fixture::NEAREST {
+            assert_eq!(rgb.resolve_color_id(r, g, b).id, by_rgb, "{:?}", (r, g, b));
+            assert_eq!(lab.resolve_color_id(r, g, b).id, by_lab, "{:?}", (r, g, b));
+        }
+    }
+
+    // Two runs in one process must not see each other's palette or codec
+    #[test]
+    fn contexts_are_independent() {
+        let announced = Palette::new(vec![(0, 0, 0), (255, 255, 255)]).unwrap();
+        let (built_in, discovered) = (Context::default(), Context::default());
+        discovered.set_palette(announced.clone());
+        assert_eq!(*built_in.palette(), Palette::default());
+        assert_eq!(*discovered.palette(), announced);
+        let pixel = PixelInfo {
+            x: 5,
+            y: 3,
+            color_id: 1,
+        };
+        let wide = Context {
+            wire: Wire {
+                codec: Codec::Wide48,
+                ..Wire::default()
+            },
+            ..Context::default()
+        };
+        let (packed, wide_packed) = (
+            built_in.pack(pixel.clone()).unwrap(),
+            wide.pack(pixel.clone()).unwrap(),
+        );
+        assert_eq!((packed.len(), wide_packed.len()), (4, 6));
+        assert_eq!(built_in.decode(&packed), vec![pixel.clone()]);
+        assert_eq!(wide.decode(&wide_packed), vec![pixel]);
+    }
+
+    // Ten-minute cooldowns are waited out on the paused clock, so the rehearsal takes moments
+    #[test]
+    fn simulation_runs_on_virtual_time() {
+        let config = parse_config(
+            r##"{
+                "brush": {"rect": {"width": 3, "height": 2, "color": "#FFFFFF"}, "offset_x": 10},
+                "bots": ["ws://127.0.0.1:1/ws", "ws://127.0.0.1:1/ws"],
+                "cooldown": {"min": 600, "max": 600}
+            }"##,
+        )
+        .unwrap();
+        let out = scratch("simulation.png");
+        let started = std::time::Instant::now();
+        let report = simulate(config, 0.0, Duration::from_secs(6 * 3600), out.clone()).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(60));
+        assert_eq!((report.queued, report.painted), (6, 6));
+        let canvas = image::open(&out).unwrap().to_rgba8();
+        assert_eq!(canvas.get_pixel(12, 1).0, [255, 255, 255, 255]);
+        fs::remove_file(out).unwrap();
+    }
+
+    #[test]
+    fn echoes_match_by_position_or_exactly() {
+        let echo = PixelInfo {
+            x: 0,
+            y: 0,
+            color_id: 5,
+        };
+        for (ack_match, remap, echoed) in [
+            (AckMatch::Position, HashMap::new(), true),
+            (AckMatch::Exact, HashMap::new(), false),
+            // Taken back to our numbering before matching
+            (AckMatch::Exact, HashMap::from([(5, 2)]), true),
+        ] {
+            let mut pixel = provider(&[2]);
+            (pixel.ack_match, pixel.echo_remap) = (ack_match, remap);
+            let sent = pixel.get_pixel(0).unwrap();
+            pixel.painted(&sent);
+            pixel.observe(&echo);
+            assert_eq!(pixel.echo_latencies.len(), echoed as usize);
+            assert_eq!(pixel.overwritten, !echoed as u32);
+            assert_eq!(pixel.pending.is_empty(), echoed);
+        }
+    }
+
+    #[test]
+    fn echoes_interleaved_with_foreign_updates_are_told_apart() {
+        let at = |x, color_id| PixelInfo { x, y: 0, color_id };
+        // Foreign color on a pending pixel, both echoes, then foreign updates on and around ours
+        let updates = [at(1, 7), at(0, 2), at(1, 2), at(0, 7), at(2, 2), at(3, 7)];
+        for (ack_match, expected) in [
+            (
+                AckMatch::Exact,
+                ["overwrite", "echo", "echo", "overwrite", "other", "other"],
+            ),
+            // Any update on a pending position is taken as its echo
+            (
+                AckMatch::Position,
+                ["echo", "echo", "other", "overwrite", "other", "other"],
+            ),
+        ] {
+            let mut pixel = provider(&[2, 2, 2]);
+            pixel.ack_match = ack_match;
+            for _ in 0..2 {
+                let sent = pixel.get_pixel(0).unwrap();
+                pixel.painted(&sent);
+            }
+            let seen = updates
+                .iter()
+                .map(|update| {
+                    let (echoes, overwritten) = (pixel.echo_latencies.len(), pixel.overwritten);
+                    pixel.observe(update);
+                    if pixel.echo_latencies.len() > echoes {
+                        "echo"
+                    } else if pixel.overwritten > overwritten {
+                        "overwrite"
+                    } else {
+                        "other"
+                    }
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(seen, expected, "{ack_match:?}");
+            assert!(pixel.pending.is_empty());
+        }
+    }
+
+    #[test]
+    fn pixels_past_the_deadline_budget_wait_until_the_queue_runs_out() {
+        let mut pixel = provider(&[0, 1, 2, 3, 4]);
+        pixel.trim(2, &DeadlinePriority::Queue);
+        let mut next = || pixel.get_pixel(0).map(|next| next.x);
+        assert_eq!((next(), next()), (Some(0), Some(1)));
+        // Done ahead of the estimate, so the left out ones come back in canvas order
+        let rest = std::iter::from_fn(next).collect::<Vec<_>>();
+        assert_eq!(rest, [2, 3, 4]);
+        assert!(pixel.dropped.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_wiped_canvas_pauses_painting_and_refetches_the_board() {
+        let mut provider = provider(&[1, 1, 1, 1]);
+        provider.reset_guard = Some(ResetGuard::new(
+            serde_json::from_value(serde_json::json!({ "threshold": 0.9, "refetch_board": true }))
+                .unwrap(),
+        ));
+        let show = |provider: &mut PixelProvider, color_id| {
+            for x in 0..4 {
+                provider.observe(&PixelInfo { x, y: 0, color_id });
+            }
+        };
+        show(&mut provider, 1);
+        let pixel = Arc::new(Mutex::new(provider));
+        let (reconnect, refetched) = watch::channel(());
+        let (webhook, mut events) = webhook::tests::recorder();
+        let watcher = tokio::spawn(watch_resets(
+            pixel.clone(),
+            Some(Arc::new(reconnect)),
+            Some(webhook),
+        ));
+        tokio::time::sleep(RESET_CHECK_INTERVAL).await;
+        assert!(!pixel.lock().await.paused());
+        show(&mut *pixel.lock().await, 0);
+        tokio::time::sleep(RESET_CHECK_INTERVAL).await;
+        assert!(pixel.lock().await.paused());
+        assert!(refetched.has_changed().unwrap());
+        assert_eq!(
+            serde_json::json!(events.try_recv().unwrap()),
+            serde_json::json!({"event": "canvas_reset_suspected", "from": 0.0, "to": 1.0, "window": 60})
+        );
+        // Resumed from the wiped board, which is not taken for another reset
+        assert!(pixel.lock().await.resume());
+        tokio::time::sleep(RESET_CHECK_INTERVAL * 2).await;
+        assert!(!pixel.lock().await.paused());
+        watcher.abort();
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sustained_griefing_is_announced_once_per_attack() {
+        let mut provider = provider(&[1]);
+        let ours = provider.get_pixel(0).unwrap();
+        provider.painted(&ours);
+        provider.observe(&ours);
+        let pixel = Arc::new(Mutex::new(provider));
+        let (webhook, mut events) = webhook::tests::recorder();
+        let watcher = tokio::spawn(watch_griefing(pixel.clone(), 5, None, Some(webhook)));
+        // Half a window in, so each burst lands between two checks
+        tokio::time::sleep(GRIEFING_WINDOW / 2).await;
+        for hostile in [10, 10, 10, 0, 0, 8, 8, 0] {
+            let mut pixel = pixel.lock().await;
+            for _ in 0..hostile {
+                pixel.observe(&PixelInfo {
+                    color_id: 2,
+                    ..ours.clone()
+                });
+                pixel.observe(&ours);
+            }
+            drop(pixel);
+            tokio::time::sleep(GRIEFING_WINDOW).await;
+        }
+        watcher.abort();
+        assert_eq!(pixel.lock().await.overwritten, 46);
+        let mut announced = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            announced.push(serde_json::json!(event));
+        }
+        assert_eq!(
+            announced,
+            [
+                serde_json::json!({"event": "under_attack", "rate": 10, "threshold": 5}),
+                serde_json::json!({"event": "under_attack", "rate": 8, "threshold": 5}),
+            ]
+        );
+    }
+
+    #[test]
+    fn pins_must_lie_on_the_canvas() {
+        let pins = |pinned: &str| {
+            parse_config(&format!(
+                r##"{{
+                    "brush": {{"rect": {{"width": 1, "height": 1, "color": "#000000"}}}},
+                    "pinned": {pinned}
+                }}"##
+            ))
+            .map(|config| {
+                config
+                    .pinned
+                    .iter()
+                    .flat_map(Pin::positions)
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(
+            pins(r#"[[3, 4], {"x": 1589, "y": 0, "width": 1, "height": 2}]"#).unwrap(),
+            [(3, 4), (1589, 0), (1589, 1)]
+        );
+        for pinned in [
+            "[[1590, 0]]",
+            r#"[{"x": 1500, "y": 0, "width": 100, "height": 1}]"#,
+            r#"[{"x": 4294967295, "y": 0, "width": 2, "height": 1}]"#,
+            r#"[{"x": 0, "y": 1, "width": 1, "height": 4294967295}]"#,
+        ] {
+            let why = pins(pinned).err().unwrap();
+            assert!(why.to_string().contains("is outside the canvas"), "{why}");
+        }
+    }
+
+    #[test]
+    fn pixels_out_of_retries_are_given_up_and_reported() {
+        let mut pixel = provider(&[0, 1]);
+        pixel.max_retries = Some(2);
+        for _ in 0..3 {
+            let claim = pixel.get_pixel(0).unwrap();
+            assert_eq!(claim.x, 0);
+            pixel.failed(claim);
+        }
+        let next = pixel.get_pixel(0).unwrap();
+        assert_eq!(next.x, 1);
+        pixel.painted(&next);
+        assert!(pixel.get_pixel(0).is_none());
+        let report = pixel.report();
+        assert_eq!((report.painted, report.failed), (1, 1));
+    }
+
+    #[test]
+    fn remaining_counts_are_kept_per_color() {
+        let mut pixel = provider(&[0, 1, 2, 0, 1, 0]);
+        let remaining = |pixel: &PixelProvider| {
+            let report = pixel.report();
+            report
+                .colors
+                .iter()
+                .map(|color| color.remaining)
+                .collect::<Vec<_>>()
+        };
+        let claims = std::iter::from_fn(|| pixel.get_pixel(0)).collect::<Vec<_>>();
+        assert_eq!(claims.len(), 6);
+        assert_eq!(remaining(&pixel), [3, 2, 1]);
+        pixel.painted(&claims[0]);
+        assert_eq!(remaining(&pixel), [2, 2, 1]);
+        // Requeued, so still remaining
+        pixel.failed(claims[1].clone());
+        assert_eq!(remaining(&pixel), [2, 2, 1]);
+        // Skipped as the canvas already shows it
+        pixel.observe(&claims[2]);
+        assert!(pixel.settle_if_correct(&claims[2]));
+        assert_eq!(remaining(&pixel), [2, 2, 0]);
+        pixel.painted(&claims[3]);
+        pixel.painted(&claims[5]);
+        assert_eq!(remaining(&pixel), [0, 2, 0]);
+        let retry = pixel.get_pixel(1).unwrap();
+        assert_eq!(retry.x, 1);
+        pixel.painted(&retry);
+        pixel.painted(&claims[4]);
+        assert_eq!(remaining(&pixel), [0, 0, 0]);
+        let painted = pixel
+            .report()
+            .colors
+            .iter()
+            .map(|color| color.painted)
+            .collect::<Vec<_>>();
+        assert_eq!(painted, [3, 2, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pixels_claimed_by_a_cancelled_send_go_back_to_the_queue() {
+        let provider = Arc::new(Mutex::new(provider(&[3, 5])));
+        // A wire that never drains, so the send hangs until shutdown
+        let (wire, _unread) = mpsc::channel(1);
+        wire.send(PixelInfo {
+            x: 9,
+            y: 9,
+            color_id: 0,
+        })
+        .await
+        .unwrap();
+        let send = async {
+            let claim = Claim::take(&provider, 0).await.unwrap();
+            wire.send(claim.pixel().clone()).await.unwrap();
+            claim.confirm().await;
+        };
+        tokio::select! {
+            _ = send => panic!("the wire is full"),
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+        }
+        let claim = Claim::take(&provider, 0).await.unwrap();
+        assert_eq!((claim.pixel().x, claim.pixel().color_id), (0, 3));
+        claim.confirm().await;
+        let claim = Claim::take(&provider, 0).await.unwrap();
+        assert_eq!((claim.pixel().x, claim.pixel().color_id), (1, 5));
+        claim.confirm().await;
+        assert!(Claim::take(&provider, 0).await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn echo_percentiles_follow_the_server_delay() {
+        let mut pixel = provider(&[0; 101]);
+        // The n-th paint comes back n times 10ms later, and the last one never
+        for delay in 1..=100 {
+            let sent = pixel.get_pixel(0).unwrap();
+            pixel.painted(&sent);
+            tokio::time::advance(Duration::from_millis(delay * 10)).await;
+            pixel.observe(&sent);
+        }
+        let lost = pixel.get_pixel(0).unwrap();
+        pixel.painted(&lost);
+        tokio::time::advance(PixelProvider::ECHO_TIMEOUT).await;
+        let latency = pixel.latency().unwrap();
+        assert_eq!(
+            (latency.p50_ms, latency.p95_ms, latency.p99_ms),
+            (510, 960, 1000)
+        );
+        assert_eq!((latency.echoed, latency.timeouts), (100, 0));
+        // Counted once the next paint looks at what is pending
+        pixel.painted(&lost);
+        assert_eq!(pixel.latency().unwrap().timeouts, 1);
+    }
+
+    #[test]
+    fn palettes_must_fit_max_color_id() {
+        let spec = |max_color_id| CanvasSpec {
+            max_color_id,
+            ..CanvasSpec::default()
+        };
+        let palette = Context::default().palette();
+        assert!(spec(PixelProvider::MAX_COLOR_ID)
+            .check(&palette, Codec::Packed32)
+            .is_ok());
+        let why = spec(8).check(&palette, Codec::Wide48).err().unwrap();
+        assert_eq!(
+            why.to_string(),
+            format!(
+                "Palette has {} colors but canvas.max_color_id is 8",
+                palette.len()
+            )
+        );
+        let why = spec(300).check(&palette, Codec::Wide48).err().unwrap();
+        assert_eq!(
+            why.to_string(),
+            "canvas.max_color_id of 300 is more than the 256 colors supported"
+        );
+    }
+
+    #[tokio::test]
+    async fn received_bytes_are_counted_and_capped() {
+        // Streams 25 frames of 40 bytes and never reads, so no pong adds to them
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", server.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = server.accept().await {
+                tokio::spawn(async move {
+                    let connection = async_tungstenite::tokio::accept_async(stream).await?;
+                    let (mut sink, _source) = connection.split();
+                    for _ in 0..25 {
+                        sink.send(tungstenite::Message::Binary(vec![0; 40])).await?;
+                    }
+                    std::future::pending::<()>().await;
+                    anyhow::Ok(())
+                });
+            }
+        });
+        let status = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut config = parse_config(&format!(
+            r##"{{
+                "brush": {{"rect": {{"width": 1, "height": 1, "color": "#FFFFFF"}}, "defend": true}},
+                "bots": ["{url}"],
+                "verify_first_paint": false,
+                "max_rx_bytes_per_hour": 500,
+                "status": {{"address": "{status}"}}
+            }}"##
+        ))
+        .unwrap();
+        config.assume_yes();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(run(config, async {
+            drop(stopped.await);
+        }));
+        let url = Url::parse(&format!("http://{status}/status")).unwrap();
+        let mut worker = serde_json::Value::Null;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if let Ok(body) = fetch::get(&url).await {
+                worker =
+                    serde_json::from_str::<serde_json::Value>(&body).unwrap()["workers"][0].clone();
+                if worker["rx_bytes"] == 1000 {
+                    break;
+                }
+            }
+        }
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        assert_eq!(worker["rx_bytes"], 1000, "{worker}");
+        assert_eq!(worker["reduced"], true);
+        // Pings and paints alike
+        assert!(worker["tx_bytes"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn origins_mirror_the_canvas_and_come_back() {
+        let at = |x, y| PixelInfo { x, y, color_id: 3 };
+        let (right, bottom) = (PixelProvider::MAX_WIDTH - 1, PixelProvider::MAX_HEIGHT - 1);
+        for (origin, wire) in [
+            (Origin::TopLeft, at(2, 5)),
+            (Origin::TopRight, at(right - 2, 5)),
+            (Origin::BottomLeft, at(2, bottom - 5)),
+            (Origin::BottomRight, at(right - 2, bottom - 5)),
+        ] {
+            let spec = CanvasSpec {
+                origin,
+                ..Default::default()
+            };
+            assert_eq!(spec.transform(at(2, 5)), wire, "{origin:?}");
+            for swap_axes in [false, true] {
+                let spec = CanvasSpec { swap_axes, ..spec };
+                for pixel in [
+                    at(0, 0),
+                    at(2, 5),
+                    at(bottom, 17),
+                    at(right.min(bottom), bottom),
+                ] {
+                    assert_eq!(spec.untransform(spec.transform(pixel.clone())), pixel);
+                }
+            }
+        }
+        let (right, bottom) = (PixelProvider::MAX_WIDTH - 1, PixelProvider::MAX_HEIGHT - 1);
+        // Known packed values of a server counting positions column by column
+        let context = Context::default();
+        for (origin, pixel, position) in [
+            (Origin::TopLeft, at(2, 5), 5 + 2 * 400),
+            (Origin::TopLeft, at(right, bottom), 1590 * 400 - 1),
+            (Origin::TopLeft, at(right, 0), 1589 * 400),
+            (Origin::BottomLeft, at(2, 5), (bottom - 5) + 2 * 400),
+            (Origin::TopRight, at(0, 0), 1589 * 400),
+        ] {
+            let swapped = CanvasSpec {
+                origin,
+                swap_axes: true,
+                ..Default::default()
+            };
+            let packed = Codec::Packed32
+                .encode(&swapped.transform(pixel.clone()))
+                .unwrap();
+            let wire = position + PixelProvider::SIZE * 3;
+            assert_eq!(packed, wire.to_le_bytes(), "{origin:?} {pixel:?}");
+            let decoded = context.decode(&packed);
+            assert_eq!(decoded.len(), 1);
+            assert_eq!(swapped.untransform(decoded[0].clone()), pixel);
+        }
+    }
+
+    #[test]
+    fn overridden_colors_paint_as_their_target() {
+        let colors = |brush: &str, overrides: &str| {
+            queue(&format!(
+                r#"{{"brush": {{"rect": {brush}}}, "bots": [], "color_overrides": {overrides}}}"#
+            ))
+            .map(|pixels| {
+                pixels
+                    .iter()
+                    .map(|pixel| pixel.color_id)
+                    .collect::<Vec<_>>()
+            })
+        };
+        let grey = r##"{"width": 2, "height": 1, "color": "#C2C2C2"}"##;
+        assert_eq!(colors(grey, "{}").unwrap(), [1, 1]);
+        assert_eq!(colors(grey, r##"{"#C2C2C2": "#000000"}"##).unwrap(), [4, 4]);
+        assert_eq!(colors(grey, r##"{"#c2c2c2": 11}"##).unwrap(), [11, 11]);
+        // Other colors are left to the nearest match
+        assert_eq!(colors(grey, r##"{"#123456": 11}"##).unwrap(), [1, 1]);
+        let off = r##"{"width": 1, "height": 1, "color": "#123456"}"##;
+        assert_eq!(colors(off, r##"{"#123456": 20}"##).unwrap(), [20]);
+        for (overrides, why) in [
+            (r##"{"#C2C2C2": 99}"##, "Palette has no color with id 99"),
+            (
+                r##"{"#C2C2C2": "#123456"}"##,
+                "#123456 is not a palette color",
+            ),
+            (r##"{"grey": 4}"##, "grey is not a #RRGGBB color"),
+        ] {
+            let error = colors(grey, overrides).err().unwrap();
+            assert_eq!(error.to_string(), why);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reactions_are_spread_over_the_configured_delay() {
+        let humanize = |reaction_delay_ms, skip_probability| {
+            let mut sleep = SleepPerformer::new(&HumanizeConfig {
+                reaction_delay_ms,
+                skip_probability,
+            });
+            sleep.rng = Arc::new(Mutex::new(StdRng::seed_from_u64(3)));
+            sleep
+        };
+        let off = humanize(None, 0.0);
+        let started = Instant::now();
+        off.react().await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        assert_eq!(off.skip().await, None);
+        let sleep = humanize(
+            Some(Range {
+                min: 200,
+                max: 3000,
+            }),
+            0.0,
+        );
+        let mut delays = Vec::new();
+        for _ in 0..200 {
+            let started = Instant::now();
+            sleep.react().await;
+            delays.push(started.elapsed());
+        }
+        let (min, max) = (Duration::from_millis(200), Duration::from_millis(3000));
+        assert!(delays.iter().all(|delay| (min..=max).contains(delay)));
+        // Spread over the whole range rather than bunched at one end
+        let early = delays
+            .iter()
+            .filter(|&&delay| delay < Duration::from_millis(1600))
+            .count();
+        assert!((70..=130).contains(&early), "{early} of 200 early");
+        let mean = delays.iter().sum::<Duration>() / delays.len() as u32;
+        assert!(mean > Duration::from_millis(1300) && mean < Duration::from_millis(1900));
+        assert_eq!(sleep.skip().await, None);
+    }
+
+    #[tokio::test]
+    async fn skips_wait_a_reaction_unless_urgent() {
+        let sleep = SleepPerformer::new(&HumanizeConfig {
+            reaction_delay_ms: Some(Range { min: 200, max: 300 }),
+            skip_probability: 1.0,
+        });
+        for _ in 0..20 {
+            let delay = sleep.skip().await.unwrap();
+            assert!((Duration::from_millis(200)..=Duration::from_millis(300)).contains(&delay));
+        }
+        sleep.urgent.store(true, Ordering::Relaxed);
+        assert_eq!(sleep.skip().await, None);
+        // Without a reaction delay a skip waits SKIP_DELAY
+        let sleep = SleepPerformer::new(&HumanizeConfig {
+            reaction_delay_ms: None,
+            skip_probability: 1.0,
+        });
+        let (min, max) = SleepPerformer::SKIP_DELAY;
+        assert!((min..=max).contains(&sleep.skip().await.unwrap()));
+    }
+
+    #[test]
+    fn rect_brushes_fill_their_area_in_one_color() {
+        let rect = |width, height, x, y| {
+            queue(&format!(
+                r##"{{
+                    "brush": {{
+                        "rect": {{"width": {width}, "height": {height}, "color": "#000000"}},
+                        "offset_x": {x},
+                        "offset_y": {y}
+                    }},
+                    "bots": []
+                }}"##
+            ))
+        };
+        let pixels = rect(40, 25, 10, 20).unwrap();
+        assert_eq!(pixels.len(), 40 * 25);
+        assert!(pixels.iter().all(|pixel| pixel.color_id == 4));
+        let positions = pixels
+            .iter()
+            .map(|pixel| (pixel.x, pixel.y))
+            .collect::<HashSet<_>>();
+        let area = (20..45)
+            .flat_map(|y| (10..50).map(move |x| (x, y)))
+            .collect::<HashSet<_>>();
+        assert_eq!(positions, area);
+        // Clipped to the canvas like any other brush
+        let right = PixelProvider::MAX_WIDTH - 10;
+        assert_eq!(rect(40, 25, right, 0).unwrap().len(), 10 * 25);
+        let why = rect(0, 25, 0, 0).err().unwrap();
+        assert_eq!(why.to_string(), "Rect brush must not be empty");
+    }
+
+    #[test]
+    fn bots_sharing_an_account_need_an_opt_in() {
+        let check = |bots: &str, allow: bool| {
+            let config = parse_config(&format!(
+                r##"{{
+                    "brush": {{"rect": {{"width": 1, "height": 1, "color": "#000000"}}}},
+                    "bots": {bots},
+                    "allow_duplicate_bots": {allow}
+                }}"##
+            ))
+            .unwrap();
+            validate(&config).map_err(|why| why.to_string())
+        };
+        let exact = r#"[{"url": "wss://a.test/ws", "name": "first"}, {"url": "wss://a.test/ws", "name": "second"}]"#;
+        let token = r#"[
+            {"url": "wss://a.test/ws?token=abc&lang=en", "name": "first"},
+            {"url": "wss://b.test/ws?token=xyz", "name": "other"},
+            {"url": "wss://b.test/ws?lang=de&token=abc", "name": "second"}
+        ]"#;
+        for bots in [exact, token] {
+            assert_eq!(
+                check(bots, false),
+                Err("Bots share an account: first and second; set allow_duplicate_bots to run them anyway".into())
+            );
+            assert_eq!(check(bots, true), Ok(()));
+        }
+        let distinct =
+            r#"["wss://a.test/ws?token=abc", "wss://a.test/ws?token=xyz", "wss://a.test/ws"]"#;
+        assert_eq!(check(distinct, false), Ok(()));
+    }
+
+    #[test]
+    fn boosted_bots_scale_their_cooldown() {
+        let parse = |bots: &str| {
+            parse_config(&format!(
+                r##"{{
+                    "brush": {{"rect": {{"width": 1, "height": 1, "color": "#000000"}}}},
+                    "bots": {bots},
+                    "cooldown": {{"min": 60, "max": 90}}
+                }}"##
+            ))
+            .unwrap()
+        };
+        let config = parse(
+            r#"[
+                "wss://a.test/ws?token=plain",
+                {"url": "wss://a.test/ws?token=boosted", "cooldown_scale": 0.5},
+                {"url": "wss://a.test/ws?token=own", "cooldown": {"min": 10, "max": 20}, "cooldown_scale": 1.5}
+            ]"#,
+        );
+        let cooldowns = config
+            .bot_configs()
+            .iter()
+            .map(|bot| {
+                let (min, max) = bot.cooldown(config.cooldown);
+                (min.as_secs(), max.as_secs())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(cooldowns, [(60, 90), (30, 45), (15, 30)]);
+        for scale in ["0", "-1"] {
+            let bots = format!(r#"[{{"url": "wss://a.test/ws", "cooldown_scale": {scale}}}]"#);
+            let why = validate(&parse(&bots)).err().unwrap();
+            assert_eq!(why.to_string(), "bots.cooldown_scale must be positive");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn one_unreachable_bot_does_not_stop_the_others() {
+        let config = |bots: &str| {
+            let mut config = parse_config(&format!(
+                r##"{{
+                    "brush": {{"rect": {{"width": 3, "height": 2, "color": "#000000"}}}},
+                    "bots": {bots},
+                    "allow_duplicate_bots": true,
+                    "verify_first_paint": false,
+                    "cooldown": {{"min": 60, "max": 60}}
+                }}"##
+            ))
+            .unwrap();
+            config.assume_yes();
+            config
+        };
+        let dead = "ws://127.0.0.1:1/";
+        let (server, url) = simulate::Server::start(Duration::ZERO, Context::default())
+            .await
+            .unwrap();
+        let bots = format!(
+            r#"[
+                {{"url": "{url}", "name": "first"}},
+                {{"url": "{dead}", "name": "dead"}},
+                {{"url": "{url}", "name": "second"}},
+                {{"url": "{url}", "name": "third"}}
+            ]"#
+        );
+        let report = run(config(&bots), tokio::time::sleep(Duration::from_secs(3600)))
+            .await
+            .unwrap();
+        assert_eq!((report.queued, report.painted), (6, 6));
+        assert_eq!(report.never_connected, ["dead"]);
+        let canvas = server.snapshot().await;
+        for (x, y) in [(0, 0), (1, 0), (2, 0), (0, 1), (1, 1), (2, 1)] {
+            assert_eq!(canvas.get_pixel(x, y).0, [0, 0, 0, 255]);
+        }
+        let bots = format!(r#"["{dead}", "{dead}"]"#);
+        let why = run(config(&bots), std::future::pending())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            why.to_string(),
+            "Only 0 of 2 workers connected, at least 1 required"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn one_bot_entry_opens_several_connections() {
+        // Echoes paints, keeping what each connection painted
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", server.local_addr().unwrap());
+        let painted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sessions = painted.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = server.accept().await {
+                let session = {
+                    let mut sessions = sessions.lock().unwrap();
+                    sessions.push(Vec::new());
+                    sessions.len() - 1
+                };
+                let sessions = sessions.clone();
+                tokio::spawn(async move {
+                    let mut connection = async_tungstenite::tokio::accept_async(stream).await?;
+                    while let Some(msg) = connection.next().await {
+                        if let tungstenite::Message::Binary(frame) = msg? {
+                            let pixels = Context::default().decode(&frame);
+                            sessions.lock().unwrap()[session]
+                                .extend(pixels.iter().map(|pixel| (pixel.x, pixel.y)));
+                            connection.send(tungstenite::Message::Binary(frame)).await?;
+                        }
+                    }
+                    anyhow::Ok(())
+                });
+            }
+        });
+        let dead = "ws://127.0.0.1:1/";
+        let mut config = parse_config(&format!(
+            r##"{{
+                "brush": {{"rect": {{"width": 3, "height": 2, "color": "#000000"}}}},
+                "bots": [
+                    {{"url": "{url}", "name": "acct", "connections": 3}},
+                    {{"url": "{dead}", "name": "dead", "connections": 2}}
+                ],
+                "verify_first_paint": false,
+                "cooldown": {{"min": 60, "max": 60}}
+            }}"##
+        ))
+        .unwrap();
+        config.assume_yes();
+        let report = run(config, tokio::time::sleep(Duration::from_secs(3600)))
+            .await
+            .unwrap();
+        assert_eq!(report.painted, 6);
+        assert_eq!(report.never_connected, ["dead/1", "dead/2"]);
+        let painted = painted.lock().unwrap();
+        assert_eq!(painted.len(), 3);
+        assert!(painted.iter().all(|pixels| !pixels.is_empty()));
+        let mut all = painted.concat();
+        all.sort_unstable();
+        assert_eq!(all, [(0, 0), (0, 1), (1, 0), (1, 1), (2, 0), (2, 1)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bots_connect_concurrently_up_to_the_limit() {
+        // Takes a second per handshake, noting when each one began
+        async fn slow_gateway(connections: u32, concurrency: usize) -> Vec<Duration> {
+            let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}/", server.local_addr().unwrap());
+            let began = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let handshakes = began.clone();
+            let start = Instant::now();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = server.accept().await {
+                    handshakes.lock().unwrap().push(start.elapsed());
+                    tokio::spawn(async move {
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        let mut connection = async_tungstenite::tokio::accept_async(stream).await?;
+                        while let Some(msg) = connection.next().await {
+                            if let tungstenite::Message::Binary(frame) = msg? {
+                                connection.send(tungstenite::Message::Binary(frame)).await?;
+                            }
+                        }
+                        anyhow::Ok(())
+                    });
+                }
+            });
+            let mut config = parse_config(&format!(
+                r##"{{
+                    "brush": {{"rect": {{"width": {connections}, "height": 1, "color": "#000000"}}}},
+                    "bots": [{{"url": "{url}", "connections": {connections}}}],
+                    "connect_concurrency": {concurrency},
+                    "verify_first_paint": false,
+                    "cooldown": {{"min": 60, "max": 60}}
+                }}"##
+            ))
+            .unwrap();
+            config.assume_yes();
+            let report = run(config, tokio::time::sleep(Duration::from_secs(3600)))
+                .await
+                .unwrap();
+            assert_eq!(report.painted, connections);
+            let mut began = began.lock().unwrap().clone();
+            began.sort();
+            began
+        }
+        let concurrent = slow_gateway(4, 8).await;
+        assert_eq!(concurrent.len(), 4);
+        assert!(concurrent[3] < Duration::from_secs(1));
+        let limited = slow_gateway(4, 2).await;
+        assert!(limited[1] < Duration::from_secs(1));
+        assert!(limited[2] >= Duration::from_secs(1));
+        assert!(limited[3] < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn observers_snapshot_what_others_paint() {
+        let (_server, url) = simulate::Server::start(Duration::ZERO, Context::default())
+            .await
+            .unwrap();
+        let dir = scratch("snapshots");
+        drop(fs::remove_dir_all(&dir));
+        let config = |scouts: &str| {
+            let config = parse_config(&format!(
+                r##"{{
+                    "brush": {{"rect": {{"width": 1, "height": 1, "color": "#000000"}}}},
+                    "bots": [],
+                    "scouts": {scouts},
+                    "snapshot": {{"dir": {:?}, "interval": 3600}}
+                }}"##,
+                dir.to_str().unwrap()
+            ))
+            .unwrap();
+            config
+        };
+        let why = run(config("[]"), std::future::ready(()))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            why.to_string(),
+            "No bots to paint with; to only watch the canvas, list at least one scout or run with --observe"
+        );
+        // Someone else paints once the scout is listening, then the run is stopped
+        let painter = {
+            let url = Url::parse(&url).unwrap();
+            async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let mut connection = Bot::connect(&url, false, Limits::default()).await.unwrap();
+                let paint = Context::default()
+                    .pack(PixelInfo {
+                        x: 7,
+                        y: 3,
+                        color_id: 4,
+                    })
+                    .unwrap();
+                connection
+                    .send(tungstenite::Message::Binary(paint))
+                    .await
+                    .unwrap();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        };
+        let report = run(config(&format!(r#"["{url}"]"#)), painter)
+            .await
+            .unwrap();
+        assert_eq!(report.painted, 0);
+        let snapshots = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(snapshots.len(), 1);
+        let snapshot = image::open(&snapshots[0]).unwrap().to_rgba8();
+        assert_eq!(snapshot.get_pixel(7, 3).0, [0, 0, 0, 255]);
+        assert_eq!(snapshot.get_pixel(8, 3).0[3], 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batched_paints_go_out_in_one_frame() {
+        // Echoes paints, keeping how many records each frame held
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", server.local_addr().unwrap());
+        let frames = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received = frames.clone();
+        tokio::spawn(async move {
+            let (stream, _) = server.accept().await?;
+            let mut connection = async_tungstenite::tokio::accept_async(stream).await?;
+            while let Some(msg) = connection.next().await {
+                if let tungstenite::Message::Binary(frame) = msg? {
+                    let records = Context::default().decode(&frame).len();
+                    received.lock().unwrap().push(records);
+                    connection.send(tungstenite::Message::Binary(frame)).await?;
+                }
+            }
+            anyhow::Ok(())
+        });
+        let mut config = parse_config(&format!(
+            r##"{{
+                "brush": {{"rect": {{"width": 7, "height": 1, "color": "#000000"}}}},
+                "bots": ["{url}"],
+                "paints_per_cycle": 3,
+                "verify_first_paint": false,
+                "cooldown": {{"min": 60, "max": 60}}
+            }}"##
+        ))
+        .unwrap();
+        config.assume_yes();
+        let report = run(config, tokio::time::sleep(Duration::from_secs(3600)))
+            .await
+            .unwrap();
+        assert_eq!(report.painted, 7);
+        assert_eq!(*frames.lock().unwrap(), [3, 3, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_flood_of_broadcasts_does_not_hurry_the_cooldown() {
+        // Answers each paint with a burst of updates elsewhere on the canvas
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", server.local_addr().unwrap());
+        let paints = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received = paints.clone();
+        let start = Instant::now();
+        tokio::spawn(async move {
+            let (stream, _) = server.accept().await?;
+            let mut connection = async_tungstenite::tokio::accept_async(stream).await?;
+            let elsewhere = Context::default().pack(PixelInfo {
+                x: 500,
+                y: 500,
+                color_id: 0,
+            })?;
+            while let Some(msg) = connection.next().await {
+                if let tungstenite::Message::Binary(frame) = msg? {
+                    received.lock().unwrap().push(start.elapsed());
+                    connection.send(tungstenite::Message::Binary(frame)).await?;
+                    for _ in 0..500 {
+                        let update = tungstenite::Message::Binary(elsewhere.clone());
+                        connection.send(update).await?;
+                    }
+                }
+            }
+            anyhow::Ok(())
+        });
+        let mut config = parse_config(&format!(
+            r##"{{
+                "brush": {{"rect": {{"width": 3, "height": 1, "color": "#000000"}}}},
+                "bots": ["{url}"],
+                "verify_first_paint": false,
+                "cooldown": {{"min": 60, "max": 60}}
+            }}"##
+        ))
+        .unwrap();
+        config.assume_yes();
+        let report = run(config, tokio::time::sleep(Duration::from_secs(3600)))
+            .await
+            .unwrap();
+        assert_eq!(report.painted, 3);
+        let paints = paints.lock().unwrap();
+        assert_eq!(paints.len(), 3);
+        assert!(paints[1] >= Duration::from_secs(60));
+        assert!(paints[2] - paints[1] >= Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_stalled_run_reconnects_once_per_stall_then_ends() {
+        // Echoes the very first paint and nothing after, on any connection
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", server.local_addr().unwrap());
+        let connects = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let accepted = connects.clone();
+        let start = Instant::now();
+        tokio::spawn(async move {
+            let echoed = Arc::new(AtomicBool::new(false));
+            while let Ok((stream, _)) = server.accept().await {
+                accepted.lock().unwrap().push(start.elapsed());
+                let echoed = echoed.clone();
+                tokio::spawn(async move {
+                    let mut connection = async_tungstenite::tokio::accept_async(stream).await?;
+                    while let Some(msg) = connection.next().await {
+                        if let tungstenite::Message::Binary(frame) = msg? {
+                            if !echoed.swap(true, Ordering::Relaxed) {
+                                connection.send(tungstenite::Message::Binary(frame)).await?;
+                            }
+                        }
+                    }
+                    anyhow::Ok(())
+                });
+            }
+        });
+        let (hook, mut received) = webhook::tests::sink().await;
+        let mut config = parse_config(&format!(
+            r##"{{
+                "brush": {{"rect": {{"width": 100, "height": 100, "color": "#000000"}}}},
+                "bots": ["{url}"],
+                "verify_first_paint": false,
+                "cooldown": {{"min": 10, "max": 10}},
+                "stall": {{"timeout": 60, "max_reconnects": 2}},
+                "webhook": "{hook}"
+            }}"##
+        ))
+        .unwrap();
+        config.assume_yes();
+        let report = run(config, std::future::pending()).await.unwrap();
+        assert!(report.stalled);
+        let mut stalls = Vec::new();
+        while let Ok(event) = received.try_recv() {
+            if event["event"] == "stall" {
+                stalls.push(event["reconnect"].clone());
+            }
+        }
+        assert_eq!(stalls, [1, 2]);
+        // The first connect, then one per stall
+        let connects = connects.lock().unwrap();
+        assert_eq!(connects.len(), 3, "{connects:?}");
+    }
+
+    #[tokio::test]
+    async fn a_flood_of_broadcasts_takes_the_provider_lock_in_batches() {
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", server.local_addr().unwrap());
+        let (flooded, flood_sent) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (stream, _) = server.accept().await?;
+            let mut connection = async_tungstenite::tokio::accept_async(stream).await?;
+            let elsewhere = Context::default().pack(PixelInfo {
+                x: 500,
+                y: 500,
+                color_id: 0,
+            })?;
+            for _ in 0..1000 {
+                let update = tungstenite::Message::Binary(elsewhere.clone());
+                connection.send(update).await?;
+            }
+            flooded.send(()).unwrap();
+            std::future::pending::<()>().await;
+            anyhow::Ok(())
+        });
+        let status = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut config = parse_config(&format!(
+            r##"{{
+                "brush": {{"rect": {{"width": 1, "height": 1, "color": "#000000"}}, "defend": true}},
+                "bots": ["{url}"],
+                "verify_first_paint": false,
+                "status": {{"address": "{status}"}}
+            }}"##
+        ))
+        .unwrap();
+        config.assume_yes();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(run(config, async {
+            drop(stopped.await);
+        }));
+        flood_sent.await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let url = Url::parse(&format!("http://{status}/metrics")).unwrap();
+        let metrics = fetch::get(&url).await.unwrap();
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+        let batches = metrics
+            .lines()
+            .find_map(|line| line.strip_prefix("pb_canvas_update_batches_total "))
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        // A thousand frames, applied every 100ms at most
+        assert!((1..=10).contains(&batches), "{batches} batches");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_bot_whose_sends_always_fail_stays_benched() {
+        async fn paint(with_broken: bool) -> (u32, serde_json::Value) {
+            let good = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let good_url = format!("ws://{}/", good.local_addr().unwrap());
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = good.accept().await {
+                    tokio::spawn(async move {
+                        let mut connection = async_tungstenite::tokio::accept_async(stream).await?;
+                        while let Some(msg) = connection.next().await {
+                            if let tungstenite::Message::Binary(frame) = msg? {
+                                connection.send(tungstenite::Message::Binary(frame)).await?;
+                            }
+                        }
+                        anyhow::Ok(())
+                    });
+                }
+            });
+            // Resets every connection while the bot waits out its reaction delay, so the send after fails
+            let broken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let broken_url = format!("ws://{}/", broken.local_addr().unwrap());
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = broken.accept().await {
+                    stream.set_linger(Some(Duration::ZERO))?;
+                    tokio::spawn(async move {
+                        let connection = async_tungstenite::tokio::accept_async(stream).await?;
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        drop(connection);
+                        anyhow::Ok(())
+                    });
+                }
+                anyhow::Ok(())
+            });
+            let status = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let mut bots = vec![format!(r#"{{"url": "{good_url}", "name": "good"}}"#)];
+            if with_broken {
+                bots.push(format!(r#"{{"url": "{broken_url}", "name": "broken"}}"#));
+            }
+            let mut config = parse_config(&format!(
+                r##"{{
+                    "brush": {{"rect": {{"width": 100, "height": 1, "color": "#000000"}}}},
+                    "bots": [{}],
+                    "verify_first_paint": false,
+                    "cooldown": {{"min": 2, "max": 2}},
+                    "humanize": {{"reaction_delay_ms": {{"min": 10000, "max": 10000}}}},
+                    "failure_streak": 2,
+                    "status": {{"address": "{status}"}}
+                }}"##,
+                bots.join(", ")
+            ))
+            .unwrap();
+            config.assume_yes();
+            let running = tokio::spawn(run(config, tokio::time::sleep(Duration::from_secs(400))));
+            // Benched for 60s after the first two failed sends, then for 300s after the next two
+            tokio::time::sleep(Duration::from_secs(200)).await;
+            let url = Url::parse(&format!("http://{status}/status")).unwrap();
+            let body = fetch::get(&url).await.unwrap();
+            let workers =
+                serde_json::from_str::<serde_json::Value>(&body).unwrap()["workers"].clone();
+            let report = running.await.unwrap().unwrap();
+            (report.painted, workers)
+        }
+        let (alone, _) = paint(false).await;
+        let (painted, workers) = paint(true).await;
+        assert!(alone > 20);
+        assert_eq!(painted, alone);
+        assert_eq!(workers[0]["benched"], 0);
+        let broken = &workers[1];
+        assert_eq!(broken["state"], "benched", "{broken}");
+        assert_eq!(broken["benched"], 2);
+        assert_eq!(broken["sends"], 0);
+        assert_eq!(broken["failures"], 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_over_the_limit_reconnect_instead_of_ending_the_run() {
+        let paint = |max_message_size: usize| async move {
+            // Opens the first connection with a board-sized frame, then echoes paints
+            let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}/", server.local_addr().unwrap());
+            let connections = Arc::new(std::sync::atomic::AtomicU32::new(0));
+            let accepted = connections.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = server.accept().await {
+                    let first = accepted.fetch_add(1, Ordering::Relaxed) == 0;
+                    tokio::spawn(async move {
+                        let mut connection = async_tungstenite::tokio::accept_async(stream).await?;
+                        if first {
+                            let board = vec![0; 2 << 20];
+                            connection.send(tungstenite::Message::Binary(board)).await?;
+                        }
+                        while let Some(msg) = connection.next().await {
+                            if let tungstenite::Message::Binary(frame) = msg? {
+                                connection.send(tungstenite::Message::Binary(frame)).await?;
+                            }
+                        }
+                        anyhow::Ok(())
+                    });
+                }
+            });
+            let mut config = parse_config(&format!(
+                r##"{{
+                    "brush": {{"rect": {{"width": 3, "height": 1, "color": "#000000"}}, "offset_x": 10}},
+                    "bots": ["{url}"],
+                    "max_message_size": {max_message_size},
+                    "max_frame_size": {max_message_size},
+                    "verify_first_paint": false,
+                    "cooldown": {{"min": 1, "max": 1}}
+                }}"##
+            ))
+            .unwrap();
+            config.assume_yes();
+            let report = run(config, tokio::time::sleep(Duration::from_secs(3600)))
+                .await
+                .unwrap();
+            (report.painted, connections.load(Ordering::Relaxed))
+        };
+        assert_eq!(paint(4 << 20).await, (3, 1));
+        assert_eq!(paint(1024).await, (3, 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn strict_fairness_keeps_fast_bots_near_the_average() {
+        // Each bot paints on its own server, so the black pixels there are its share
+        let shares = |fairness: &'static str| async move {
+            let (fast, fast_url) = simulate::Server::start(Duration::ZERO, Context::default())
+                .await
+                .unwrap();
+            let (slow, slow_url) = simulate::Server::start(Duration::ZERO, Context::default())
+                .await
+                .unwrap();
+            let mut config = parse_config(&format!(
+                r##"{{
+                    "brush": {{"rect": {{"width": 10, "height": 4, "color": "#000000"}}}},
+                    "bots": [
+                        {{"url": "{fast_url}", "name": "fast"}},
+                        {{"url": "{slow_url}", "name": "slow", "cooldown_scale": 10}}
+                    ],
+                    "fairness": "{fairness}",
+                    "fairness_margin": 2,
+                    "verify_first_paint": false,
+                    "cooldown": {{"min": 1, "max": 1}}
+                }}"##
+            ))
+            .unwrap();
+            config.assume_yes();
+            let report = run(config, tokio::time::sleep(Duration::from_secs(3600)))
+                .await
+                .unwrap();
+            assert_eq!(report.painted, 40);
+            let mut shares = [0; 2];
+            for (share, server) in shares.iter_mut().zip([fast, slow]) {
+                *share = server
+                    .snapshot()
+                    .await
+                    .pixels()
+                    .filter(|pixel| pixel.0 == [0, 0, 0, 255])
+                    .count();
+            }
+            shares
+        };
+        let [fast, slow] = shares("none").await;
+        assert!(fast > 30, "{fast} against {slow}");
+        // Two sends above the average is four ahead of the other bot
+        let [fast, slow] = shares("strict").await;
+        assert_eq!(fast + slow, 40);
+        assert!(fast.abs_diff(slow) <= 6, "{fast} against {slow}");
+    }
+
+    #[test]
+    fn too_many_inexact_pixels_refuse_to_run() {
+        // Seven exact pixels, then three of two colors off the palette
+        let colors =
+            [[0, 0, 0]; 7]
+                .into_iter()
+                .chain([[0x12, 0x34, 0x56], [1, 2, 3], [0x12, 0x34, 0x56]]);
+        let mut image = RgbaImage::new(10, 1);
+        for (x, [r, g, b]) in colors.enumerate() {
+            image.put_pixel(x as u32, 0, image::Rgba([r, g, b, 255]));
+        }
+        let quantize = |max_inexact_ratio, max_color_distance| {
+            PixelProvider::quantize(
+                image.clone(),
+                &Palette::default(),
+                (0, 0),
+                &QuantizeOptions::default(),
+                max_inexact_ratio,
+                max_color_distance,
+                false,
+            )
+        };
+        let (pixels, sources) = quantize(0.3, None).unwrap();
+        assert_eq!(pixels.len(), 10);
+        assert_eq!(sources.len(), 3);
+        assert_eq!(sources[&(8, 0)], (1, 2, 3));
+        let why = quantize(0.25, None).err().unwrap();
+        assert_eq!(
+            why.to_string(),
+            "30.0% of pixels are inexact, more than max_inexact_ratio allows; \
+             worst source colors: #123456 (2), #010203 (1). \
+             Consider dithering the image to the palette first"
+        );
+        // Pixels too far from the palette are left out, so they do not count against the ratio
+        let (pixels, sources) = quantize(0.0, Some(0.0)).unwrap();
+        assert_eq!(pixels.len(), 7);
+        assert!(sources.is_empty());
+    }
+
+    #[test]
+    fn colors_far_from_the_palette_are_skipped() {
+        // No greens, so green is 160 from black and the red only 5 from red
+        let palette =
+            Palette::new(vec![(0, 0, 0), (255, 255, 255), (255, 0, 0), (0, 0, 255)]).unwrap();
+        let mut image = RgbaImage::new(3, 1);
+        for (x, [r, g, b]) in [[0, 0, 0], [0, 160, 0], [250, 0, 0]]
+            .into_iter()
+            .enumerate()
+        {
+            image.put_pixel(x as u32, 0, image::Rgba([r, g, b, 255]));
+        }
+        let painted = |metric, max_color_distance| {
+            let options = QuantizeOptions {
+                metric,
+                ..QuantizeOptions::default()
+            };
+            let (pixels, _) = PixelProvider::quantize(
+                image.clone(),
+                &palette,
+                (0, 0),
+                &options,
+                1.0,
+                max_color_distance,
+                false,
+            )
+            .unwrap();
+            pixels
+                .iter()
+                .map(|pixel| (pixel.x, pixel.color_id))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(painted(Metric::Rgb, None), [(0, 0), (1, 0), (2, 2)]);
+        assert_eq!(painted(Metric::Rgb, Some(200.0)), [(0, 0), (1, 0), (2, 2)]);
+        assert_eq!(painted(Metric::Rgb, Some(10.0)), [(0, 0), (2, 2)]);
+        assert_eq!(painted(Metric::Rgb, Some(0.0)), [(0, 0)]);
+        assert_eq!(painted(Metric::Lab, Some(10.0)), [(0, 0), (2, 2)]);
+    }
+
+    #[test]
+    fn frames_ring_the_artwork_and_stop_at_the_canvas_edge() {
+        let square = |x0: u32, y0: u32, size: u32| {
+            (y0..y0 + size)
+                .flat_map(|y| (x0..x0 + size).map(move |x| PixelInfo { x, y, color_id: 0 }))
+                .collect::<Vec<_>>()
+        };
+        let ring = |thickness, pixels: &[PixelInfo]| {
+            let frame = FrameConfig {
+                color: ColorTarget::Index(4),
+                thickness,
+            };
+            let ring = frame.around(pixels, &Palette::default()).unwrap();
+            assert!(ring.iter().all(|pixel| pixel.color_id == 4));
+            ring.iter()
+                .map(|pixel| (pixel.x, pixel.y))
+                .collect::<HashSet<_>>()
+        };
+        let middle = ring(1, &square(10, 20, 2));
+        let expected = (19..23)
+            .flat_map(|y| (9..13).map(move |x| (x, y)))
+            .filter(|&(x, y)| !(10..12).contains(&x) || !(20..22).contains(&y))
+            .collect::<HashSet<_>>();
+        assert_eq!(middle, expected);
+        // Nothing goes past the top left corner
+        let corner = ring(2, &square(0, 0, 2));
+        assert_eq!(corner.len(), 4 * 4 - 2 * 2);
+        assert!(corner.contains(&(3, 3)) && !corner.contains(&(0, 0)));
+        let (right, bottom) = (PixelProvider::MAX_WIDTH, PixelProvider::MAX_HEIGHT);
+        let far = ring(1, &square(right - 2, bottom - 2, 2));
+        assert_eq!(far.len(), 3 * 3 - 2 * 2);
+        assert!(far.iter().all(|&(x, y)| x < right && y < bottom));
+        // Along the top edge only the three other sides remain
+        let edge = ring(1, &square(100, 0, 3));
+        assert_eq!(edge.len(), 5 * 4 - 3 * 3);
+        assert!(ring(1, &[]).is_empty());
+    }
+
+    #[test]
+    fn frames_are_queued_before_the_artwork() {
+        let pixels = queue(
+            r##"{
+                "brush": {
+                    "rect": {"width": 2, "height": 2, "color": "#FFFFFF"},
+                    "offset_x": 5,
+                    "offset_y": 5,
+                    "frame": {"color": "#000000"}
+                },
+                "bots": []
+            }"##,
+        )
+        .unwrap();
+        let colors = pixels
+            .iter()
+            .map(|pixel| pixel.color_id)
+            .collect::<Vec<_>>();
+        assert_eq!(colors, [[4; 12].as_slice(), &[0; 4]].concat());
+    }
+
+    #[test]
+    fn the_run_plan_sums_up_brush_bots_and_timing() {
+        let config = parse_config(
+            r##"{
+                "brush": {"rect": {"width": 4, "height": 3, "color": "#000000"}, "offset_x": 10, "offset_y": 20},
+                "bots": [
+                    {"url": "wss://a.test/ws?token=abcdefghijkl", "name": "pair", "connections": 2},
+                    {"url": "wss://a.test/ws?token=mnopqrstuvwx", "cooldown": {"min": 30, "max": 90}}
+                ],
+                "cooldown": {"min": 60, "max": 60}
+            }"##,
+        )
+        .unwrap();
+        let Work {
+            mut frame,
+            pixels,
+            coverage,
+            ..
+        } = build_work(&config, &Context::new(&config)).unwrap();
+        frame.extend(pixels);
+        let version = template_version(&frame);
+        // Three connections painting once a minute on average take 4 minutes for 12 pixels
+        assert_eq!(
+            describe(&config, &frame, &version, &coverage),
+            format!(
+                "Run plan:
+  brush: rect #000000, 4x3 at {{10:20}}
+  covers: {{10:20}} to {{13:22}}, 12 pixels
+  canvas: origin TopLeft, axes as is, color ids up to 25
+  template: version {version}
+  traversal: None
+  pixels: 12
+  bots: 2 with 3 connections
+    pair x2: cooldown fixed 60.0s
+    wss://a.test/ws?token=mnop...uvwx x1: cooldown uniform 30.0-90.0s
+  estimated completion: 0h 04m 00s"
+            )
+        );
+    }
+
+    #[test]
+    fn images_are_decoded_by_content_when_the_extension_lies() {
+        let png = scratch("really-png.png");
+        RgbaImage::from_pixel(3, 2, image::Rgba([0, 0, 0, 255]))
+            .save(&png)
+            .unwrap();
+        let misnamed = scratch("really-png.jpg");
+        fs::rename(&png, &misnamed).unwrap();
+        assert_eq!(
+            open_image(&misnamed).unwrap().to_rgba8().dimensions(),
+            (3, 2)
+        );
+        fs::remove_file(misnamed).unwrap();
+        // Nothing recognizable, so the error shows what the file starts with
+        let garbage = scratch("garbage.png");
+        fs::write(&garbage, b"no image here").unwrap();
+        let why = open_image(&garbage).err().unwrap().to_string();
+        assert!(
+            why.starts_with(&format!("Cannot decode {} (", garbage.display())),
+            "{why}"
+        );
+        assert!(why.contains(&format!(
+            "it starts with {}, supported formats are Png, Jpeg, Gif",
+            protocol::hexdump(b"no image here")
+        )));
+        fs::remove_file(garbage).unwrap();
+        let why = open_image(&scratch("missing.png"))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(why.starts_with("Cannot read "), "{why}");
+    }
+
+    #[cfg(not(feature = "formats-extra"))]
+    #[test]
+    fn formats_left_out_of_the_build_name_their_feature() {
+        let bmp = scratch("left-out.png");
+        fs::write(&bmp, [b"BM".as_slice(), &[0; 64]].concat()).unwrap();
+        let why = open_image(&bmp).err().unwrap();
+        assert_eq!(
+            why.to_string(),
+            format!(
+                "{} is Bmp, which this build cannot read; rebuild with --features formats-extra",
+                bmp.display()
+            )
+        );
+        fs::remove_file(bmp).unwrap();
+    }
+
+    #[cfg(not(feature = "formats-extra"))]
+    #[test]
+    fn saving_to_a_format_left_out_of_the_build_names_its_feature() {
+        let image = RgbaImage::new(2, 2);
+        let png = scratch("saved.png");
+        save_image(&image, &png).unwrap();
+        fs::remove_file(png).unwrap();
+        let tiff = scratch("saved.tiff");
+        let why = save_image(&image, &tiff).err().unwrap().to_string();
+        assert!(why.starts_with(&format!("Cannot save {}: ", tiff.display())));
+        assert!(why.ends_with("; rebuild with --features formats-extra"));
+        drop(fs::remove_file(tiff));
+        assert_eq!(format_feature(::image::ImageFormat::Gif), "formats-basic");
+        assert!(readable(::image::ImageFormat::Jpeg));
+        assert!(!readable(::image::ImageFormat::Tiff));
+    }
+
+    #[test]
+    fn large_images_queue_only_what_lands_on_the_canvas() {
+        let image = scratch("large.png");
+        RgbaImage::from_pixel(2000, 1000, image::Rgba([0, 0, 0, 255]))
+            .save(&image)
+            .unwrap();
+        let pixels = queue(&format!(
+            r#"{{"brush": {{"image": {:?}, "offset_x": 1500, "offset_y": 350}}, "bots": []}}"#,
+            image.to_str().unwrap()
+        ))
+        .unwrap();
+        fs::remove_file(image).unwrap();
+        // 90x50 of the 2000x1000 are on the canvas
+        assert_eq!(pixels.len(), 90 * 50);
+        assert!(pixels
+            .iter()
+            .all(|pixel| (1500..1590).contains(&pixel.x) && (350..400).contains(&pixel.y)));
+    }
+
+    #[tokio::test]
+    async fn auto_canvas_quantizes_to_the_advertised_palette() {
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", server.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = server.accept().await?;
+            let mut connection = async_tungstenite::tokio::accept_async(stream).await?;
+            let metadata = r##"{"palette": ["#000000", "#FF0000", "#0000FF"], "width": 1590}"##;
+            connection
+                .send(tungstenite::Message::Text(metadata.into()))
+                .await?;
+            while connection.next().await.is_some() {}
+            anyhow::Ok(())
+        });
+        let image = scratch("advertised.png");
+        let mut source = RgbaImage::new(3, 1);
+        source.put_pixel(0, 0, image::Rgba([10, 10, 10, 255]));
+        source.put_pixel(1, 0, image::Rgba([240, 20, 10, 255]));
+        source.put_pixel(2, 0, image::Rgba([30, 10, 200, 255]));
+        source.save(&image).unwrap();
+        let config = parse_config(&format!(
+            r#"{{"brush": {{"image": {:?}}}, "bots": [{url:?}]}}"#,
+            image.to_str().unwrap()
+        ))
+        .unwrap();
+        let context = Context::new(&config);
+        discover_palette(&config.bots[0], config.limits(), &context)
+            .await
+            .unwrap();
+        let pixels = build_queue(&config, &context).unwrap();
+        fs::remove_file(image).unwrap();
+        assert_eq!(context.palette().len(), 3);
+        let mut ids = pixels
+            .iter()
+            .map(|pixel| (pixel.x, pixel.color_id))
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, [(0, 0), (1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn library_types_round_trip_through_serde() {
+        fn round_trip<T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug>(value: T) {
+            let json = serde_json::to_string(&value).unwrap();
+            assert_eq!(serde_json::from_str::<T>(&json).unwrap(), value);
+        }
+        let pixel = PixelInfo {
+            x: 3,
+            y: 4,
+            color_id: 5,
+        };
+        round_trip(pixel.clone());
+        round_trip(pixel.position());
+        round_trip(CanvasSpec::default());
+        round_trip(Palette::default());
+        round_trip(ColorId {
+            id: 2,
+            exact: false,
+            distance: 1.5,
+        });
+        assert_eq!(
+            serde_json::to_string(&Palette::new(vec![(1, 2, 3)]).unwrap()).unwrap(),
+            "[[1,2,3]]"
+        );
+        let crowded = serde_json::to_string(&vec![(0, 0, 0); Palette::MAX_LEN + 1]).unwrap();
+        assert!(serde_json::from_str::<Palette>(&crowded).is_err());
+        assert_eq!(
+            pixel.with_offset(10, 20),
+            PixelInfo {
+                x: 13,
+                y: 24,
+                color_id: 5,
+            }
+        );
+        let palette = Palette::new(vec![(1, 2, 3), (4, 5, 6)]).unwrap();
+        assert_eq!(palette.rgb_of(1), Some((4, 5, 6)));
+        assert_eq!(palette.rgb_of(2), None);
+    }
+
+    #[test]
+    fn coverage_boxes_leave_out_transparent_and_clipped_pixels() {
+        // A 3x2 patch in a mostly transparent 10x8 image
+        let image = scratch("margins.png");
+        RgbaImage::from_fn(10, 8, |x, y| {
+            let alpha = if (2..5).contains(&x) && (3..5).contains(&y) {
+                255
+            } else {
+                0
+            };
+            image::Rgba([0, 0, 0, alpha])
+        })
+        .save(&image)
+        .unwrap();
+        let config = parse_config(&format!(
+            r##"{{
+                "brush": {{"image": {:?}, "offset_x": 100, "offset_y": 50}},
+                "brushes": [{{
+                    "rect": {{"width": 20, "height": 2, "color": "#FFFFFF"}},
+                    "offset_x": 1580,
+                    "offset_y": 10
+                }}],
+                "bots": []
+            }}"##,
+            image.to_str().unwrap()
+        ))
+        .unwrap();
+        let work = build_work(&config, &Context::new(&config)).unwrap();
+        fs::remove_file(image).unwrap();
+        let bounds = |min_x, min_y, max_x, max_y, pixels| BoundingBox {
+            min_x,
+            min_y,
+            max_x,
+            max_y,
+            pixels,
+        };
+        assert_eq!(
+            work.coverage.brushes,
+            [
+                Some(bounds(102, 53, 104, 54, 6)),
+                Some(bounds(1580, 10, 1589, 11, 20))
+            ]
+        );
+        assert_eq!(work.coverage.union, Some(bounds(102, 10, 1589, 54, 26)));
+        assert_eq!(
+            work.coverage.union.unwrap().to_string(),
+            "{102:10} to {1589:54}, 26 pixels"
+        );
+    }
+
+    #[test]
+    fn palette_usage_counts_brush_pixels_per_color() {
+        let colors = [
+            [0, 0, 0],
+            [0, 0, 0],
+            [0, 0, 0],
+            [255, 255, 255],
+            [0x12, 0x34, 0x56],
+            [1, 2, 3],
+            [0x12, 0x34, 0x56],
+            [255, 255, 255],
+        ];
+        let image = scratch("usage.png");
+        let mut source = RgbaImage::new(4, 2);
+        for (i, [r, g, b]) in colors.into_iter().enumerate() {
+            source.put_pixel(i as u32 % 4, i as u32 / 4, image::Rgba([r, g, b, 255]));
+        }
+        source.save(&image).unwrap();
+        let config = parse_config(&format!(
+            r#"{{"brush": {{"image": {:?}}}, "bots": []}}"#,
+            image.to_str().unwrap()
+        ))
+        .unwrap();
+        let context = Context::new(&config);
+        let usage = PaletteUsage::of(build_work(&config, &context).unwrap(), &context);
+        fs::remove_file(image).unwrap();
+        let near = context.resolve_color_id(0x12, 0x34, 0x56).id;
+        let black = context.resolve_color_id(1, 2, 3).id;
+        let mut counts = vec![0; context.palette().len()];
+        counts[0] = 2;
+        counts[4] = 3 + 1;
+        counts[near as usize] += 2;
+        assert_eq!(black, 4);
+        assert_eq!(
+            usage,
+            PaletteUsage {
+                counts,
+                total: 8,
+                inexact: vec![((0x12, 0x34, 0x56), 2, near), ((1, 2, 3), 1, black)],
+            }
+        );
+        assert_eq!(usage.share(2), 25.0);
+        assert_eq!(swatch((1, 2, 3), false), "#010203");
+        assert_eq!(
+            swatch((1, 2, 3), true),
+            "\x1b[48;2;1;2;3m    \x1b[0m #010203"
+        );
+    }
+
+    #[test]
+    fn overlaps_go_to_the_brush_with_the_highest_priority() {
+        let squares = |second_x, allow_overlap| {
+            queue(&format!(
+                r##"{{
+                    "brush": {{"rect": {{"width": 4, "height": 4, "color": "#000000"}}}},
+                    "brushes": [{{
+                        "rect": {{"width": 4, "height": 4, "color": "#FFFFFF"}},
+                        "offset_x": {second_x},
+                        "offset_y": 2,
+                        "priority": 5
+                    }}],
+                    "bots": [],
+                    "allow_overlap": {allow_overlap}
+                }}"##
+            ))
+        };
+        let why = squares(2, false).err().unwrap();
+        assert_eq!(
+            why.to_string(),
+            "Brushes overlap on 4 pixels (brushes[0] over brush on 4); \
+             set allow_overlap to give each to the brush with the highest priority"
+        );
+        let pixels = squares(2, true).unwrap();
+        assert_eq!(pixels.len(), 16 + 12);
+        let owner = pixels
+            .iter()
+            .map(|pixel| ((pixel.x, pixel.y), pixel.color_id))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(owner.len(), pixels.len());
+        for position in [(2, 2), (3, 2), (2, 3), (3, 3)] {
+            assert_eq!(owner[&position], 0, "{position:?}");
+        }
+        assert_eq!((owner[&(1, 1)], owner[&(5, 5)]), (4, 0));
+        // The higher priority brush is also painted first
+        assert!(pixels[..16].iter().all(|pixel| pixel.color_id == 0));
+        // Side by side is no overlap
+        assert_eq!(squares(4, false).unwrap().len(), 32);
+    }
+
+    #[test]
+    fn pixels_reverted_again_and_again_wait_for_the_rest() {
+        let pixels = (0..6)
+            .map(|x| PixelInfo {
+                x,
+                y: 0,
+                color_id: 1,
+            })
+            .collect();
+        let mut pixel = PixelProvider::new(
+            pixels,
+            HashSet::new(),
+            Locality::default(),
+            true,
+            Arc::new(Context::default()),
+        );
+        pixel.reverts = RevertPolicy {
+            window: Duration::from_secs(10),
+            demote_after: 3,
+            give_up_after: Some(4),
+        };
+        let contest = |pixel: &mut PixelProvider, claim: PixelInfo| {
+            pixel.painted(&claim);
+            pixel.observe(&claim);
+            pixel.observe(&PixelInfo {
+                color_id: 2,
+                ..claim
+            });
+            assert!(pixel.sweep_damage());
+        };
+        // Reverted right after each paint, so it keeps coming back first
+        for _ in 0..3 {
+            let claim = pixel.get_pixel(0).unwrap();
+            assert_eq!(claim.x, 0);
+            contest(&mut pixel, claim);
+        }
+        assert_eq!(pixel.report().demoted, 1);
+        let order = std::iter::from_fn(|| {
+            let claim = pixel.get_pixel(0)?;
+            pixel.painted(&claim);
+            Some(claim.x)
+        })
+        .take(6)
+        .collect::<Vec<_>>();
+        assert_eq!(order, [1, 2, 3, 4, 5, 0]);
+        // Reverted once more after its echo, and it is not painted again
+        let last = PixelInfo {
+            x: 0,
+            y: 0,
+            color_id: 1,
+        };
+        pixel.observe(&last);
+        pixel.observe(&PixelInfo {
+            color_id: 2,
+            ..last
+        });
+        assert!(!pixel.sweep_damage());
+        assert_eq!(pixel.get_pixel(0), None);
+        let report = pixel.report();
+        assert_eq!((report.demoted, report.given_up), (1, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_defenders_watch_until_damage_shows_up() {
+        let pixels = (0..2)
+            .map(|x| PixelInfo {
+                x,
+                y: 0,
+                color_id: 1,
+            })
+            .collect();
+        let mut pixel = PixelProvider::new(
+            pixels,
+            HashSet::new(),
+            Locality::default(),
+            true,
+            Arc::new(Context::default()),
+        );
+        let after = Duration::from_secs(600);
+        let claim = pixel.get_pixel(0).unwrap();
+        pixel.painted(&claim);
+        assert!(!pixel.watching(after));
+        let claim = pixel.get_pixel(0).unwrap();
+        pixel.painted(&claim);
+        pixel.observe(&claim);
+        // Defenders never finish, but go quiet once nothing was left for a while
+        assert!(!pixel.is_done());
+        assert!(!pixel.watching(after));
+        tokio::time::advance(after - Duration::from_secs(1)).await;
+        assert!(!pixel.watching(after));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(pixel.watching(after));
+        // Hours later someone paints over a pixel, and it is queued right away
+        tokio::time::advance(Duration::from_secs(6 * 3600)).await;
+        pixel.observe(&PixelInfo {
+            x: 1,
+            y: 0,
+            color_id: 2,
+        });
+        assert!(pixel.sweep_damage());
+        assert!(!pixel.watching(after));
+        assert_eq!(
+            pixel.get_pixel(0),
+            Some(PixelInfo {
+                x: 1,
+                y: 0,
+                color_id: 1
+            })
+        );
+    }
+
+    #[test]
+    fn griefed_pixels_top_the_contested_report() {
+        let pixels = (0..3)
+            .map(|x| PixelInfo {
+                x,
+                y: 0,
+                color_id: 1,
+            })
+            .collect();
+        let mut pixel = PixelProvider::new(
+            pixels,
+            HashSet::new(),
+            Locality::default(),
+            true,
+            Arc::new(Context::default()),
+        );
+        while let Some(claim) = pixel.get_pixel(0) {
+            pixel.painted(&claim);
+            pixel.observe(&claim);
+        }
+        assert!(pixel.report().contested.is_empty());
+        // Griefed three times at x=2 and once at x=0, repainted after each
+        for x in [2, 0, 2, 2] {
+            pixel.observe(&PixelInfo {
+                x,
+                y: 0,
+                color_id: 2,
+            });
+            assert!(pixel.sweep_damage());
+            let claim = pixel.get_pixel(0).unwrap();
+            assert_eq!(claim.x, x);
+            pixel.painted(&claim);
+            pixel.observe(&claim);
+        }
+        let contested = pixel
+            .report()
+            .contested
+            .iter()
+            .map(|c| (c.x, c.y, c.repaints))
+            .collect::<Vec<_>>();
+        assert_eq!(contested, [(2, 0, 3), (0, 0, 1)]);
+        assert_eq!(pixel.repaints(), 4);
+        let heatmap = pixel.heatmap().unwrap();
+        assert_eq!(heatmap.dimensions(), (3, 1));
+        assert_eq!(heatmap.get_pixel(2, 0).0, [255, 255, 0, 255]);
+        assert_eq!(heatmap.get_pixel(1, 0).0, [0, 0, 0, 255]);
+        assert_eq!(heatmap.get_pixel(0, 0).0, [170, 0, 0, 255]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pixels_painted_right_by_others_are_skipped() {
+        // Echoes paints, and answers the first by showing the next three done by someone else
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", server.local_addr().unwrap());
+        let (sends, mut sent) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = server.accept().await {
+                let sends = sends.clone();
+                tokio::spawn(async move {
+                    let context = Context::default();
+                    let connection = async_tungstenite::tokio::accept_async(stream).await?;
+                    let (mut sink, mut source) = connection.split();
+                    while let Some(msg) = source.next().await {
+                        let tungstenite::Message::Binary(frame) = msg? else {
+                            continue;
+                        };
+                        sink.send(tungstenite::Message::Binary(frame.clone()))
+                            .await?;
+                        for pixel in context.decode(&frame) {
+                            if pixel.x == 0 {
+                                for x in 1..4 {
+                                    let done = context.pack(PixelInfo { x, ..pixel.clone() })?;
+                                    sink.send(tungstenite::Message::Binary(done)).await?;
+                                }
+                            }
+                            drop(sends.send((pixel.x, Instant::now())));
+                        }
+                    }
+                    anyhow::Ok(())
+                });
+            }
+        });
+        let mut config = parse_config(&format!(
+            r##"{{
+                "brush": {{"rect": {{"width": 5, "height": 1, "color": "#000000"}}}},
+                "bots": ["{url}"],
+                "verify_first_paint": false,
+                "cooldown": {{"min": 60, "max": 60}}
+            }}"##
+        ))
+        .unwrap();
+        config.assume_yes();
+        let report = run(config, std::future::pending()).await.unwrap();
+        assert_eq!((report.painted, report.already_correct), (5, 3));
+        let sends = std::iter::from_fn(|| sent.try_recv().ok()).collect::<Vec<_>>();
+        let painted = sends.iter().map(|&(x, _)| x).collect::<Vec<_>>();
+        assert_eq!(painted, [0, 4]);
+        // Skips spend no cooldown, so the last pixel goes in the next cycle or right after
+        let gap = sends[1].1 - sends[0].1;
+        assert!(gap < Duration::from_secs(2 * 60), "{gap:?}");
+    }
+
+    // Answers each upgrade request with the status line and headers listed for its path
+    async fn gateway(routes: HashMap<&'static str, String>) -> String {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = tokio::io::BufReader::new(stream);
+                let mut line = String::new();
+                stream.read_line(&mut line).await?;
+                let path = line
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap_or_default()
+                    .to_owned();
+                while line != "\r\n" {
+                    line.clear();
+                    if stream.read_line(&mut line).await? == 0 {
+                        break;
+                    }
+                }
+                let head = routes
+                    .get(path.as_str())
+                    .map_or("404 Not Found", String::as_str);
+                let response = format!("HTTP/1.1 {head}\r\nContent-Length: 0\r\n\r\n");
+                stream.get_mut().write_all(response.as_bytes()).await?;
+            }
+            std::io::Result::Ok(())
+        });
+        url
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn one_pixel_is_painted_and_watched_for() {
+        let (server, live) = simulate::Server::start(Duration::ZERO, Context::default())
+            .await
+            .unwrap();
+        let live = Url::parse(&live).unwrap();
+        let config =
+            parse_config(r##"{"brush": {"rect": {"width": 1, "height": 1, "color": "#FFFFFF"}}}"##)
+                .unwrap();
+        assert!(paint_one(&config, live.clone(), 7, 3, "#000000", false)
+            .await
+            .unwrap());
+        assert!(paint_one(&config, live.clone(), 8, 3, "11", false)
+            .await
+            .unwrap());
+        let canvas = server.snapshot().await;
+        assert_eq!(canvas.get_pixel(7, 3).0, [0, 0, 0, 255]);
+        assert_eq!(canvas.get_pixel(8, 3).0, [0xFE, 0x25, 0x00, 255]);
+        // Takes the paint and never says so
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}/", silent.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = silent.accept().await?;
+            let mut connection = async_tungstenite::tokio::accept_async(stream).await?;
+            while connection.next().await.is_some() {}
+            anyhow::Ok(())
+        });
+        assert!(!paint_one(&config, url, 7, 3, "#000000", false)
+            .await
+            .unwrap());
+        let why = paint_one(
+            &config,
+            live.clone(),
+            PixelProvider::MAX_WIDTH,
+            0,
+            "4",
+            false,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(why.to_string(), "Pixel {1590:0} is outside the canvas");
+        let why = paint_one(&config, live, 0, 0, "#123456", false)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(why.to_string(), "#123456 is not a palette color");
+        // A server on the wide encoding only echoes what was sent in it
+        let wide = parse_config(
+            r##"{
+                "brush": {"rect": {"width": 1, "height": 1, "color": "#FFFFFF"}},
+                "canvas": {"codec": "wide48"}
+            }"##,
+        )
+        .unwrap();
+        let (server, live) = simulate::Server::start(Duration::ZERO, Context::new(&wide))
+            .await
+            .unwrap();
+        let live = Url::parse(&live).unwrap();
+        assert!(paint_one(&wide, live, 5, 2, "#000000", false)
+            .await
+            .unwrap());
+        let canvas = server.snapshot().await;
+        assert_eq!(canvas.get_pixel(5, 2).0, [0, 0, 0, 255]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn validation_connects_each_bot_without_painting() {
+        let (_server, live) = simulate::Server::start(Duration::ZERO, Context::default())
+            .await
+            .unwrap();
+        // Accepts the upgrade and closes right away, as for a revoked token
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let revoked = format!("ws://{}/", server.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = server.accept().await {
+                let mut connection = async_tungstenite::tokio::accept_async(stream).await?;
+                let frame = CloseFrame {
+                    code: tungstenite::protocol::frame::coding::CloseCode::from(4001),
+                    reason: "token revoked".into(),
+                };
+                connection.close(Some(frame)).await?;
+            }
+            anyhow::Ok(())
+        });
+        let config = |bots: &str| {
+            parse_config(&format!(
+                r##"{{
+                    "brush": {{"rect": {{"width": 1, "height": 1, "color": "#000000"}}}},
+                    "bots": {bots}
+                }}"##
+            ))
+            .unwrap()
+        };
+        let bots = format!(
+            r#"[
+                {{"url": "{live}", "name": "live"}},
+                {{"url": "{revoked}", "name": "revoked"}},
+                {{"url": "ws://127.0.0.1:1/", "name": "dead"}}
+            ]"#
+        );
+        let config = config(&bots);
+        let mut results = Vec::new();
+        for bot in config.bot_configs() {
+            let result = validate_bot(&bot, config.limits()).await;
+            results.push((
+                result.name,
+                result.latency.is_some(),
+                result.first_frame,
+                result.passed,
+            ));
+        }
+        assert_eq!(
+            results,
+            [
+                ("live".into(), true, "none".into(), true),
+                ("revoked".into(), true, "close 4001".into(), false),
+                ("dead".into(), false, "-".into(), false),
+            ]
+        );
+        let why = validate_bots(config).await.err().unwrap();
+        assert_eq!(why.to_string(), "2 of 3 bots failed validation");
+    }
+
+    #[tokio::test]
+    async fn bot_urls_speak_ws_or_wss() {
+        let parse = |bots: &str| {
+            parse_config(&format!(
+                r##"{{
+                    "brush": {{"rect": {{"width": 1, "height": 1, "color": "#000000"}}}},
+                    "bots": {bots}
+                }}"##
+            ))
+            .unwrap()
+        };
+        let why = validate(&parse(r#"[{"url": "https://a.test/ws", "name": "typo"}]"#))
+            .err()
+            .unwrap();
+        assert_eq!(
+            why.to_string(),
+            "Bot typo has unsupported scheme https, expected ws or wss"
+        );
+        let local = r#"["ws://127.0.0.1:8080/ws", {"url": "wss://localhost:8443/ws", "allow_insecure": true}]"#;
+        assert!(validate(&parse(local)).is_ok());
+        // A wss URL pointing at a plain ws server fails in the TLS handshake, naming the host
+        let (_server, url) = simulate::Server::start(Duration::ZERO, Context::default())
+            .await
+            .unwrap();
+        let url = Url::parse(&url.replacen("ws://127.0.0.1", "wss://localhost", 1)).unwrap();
+        let why = Bot::connect(&url, true, Limits::default())
+            .await
+            .err()
+            .unwrap();
+        let failed = why.downcast_ref::<HandshakeFailed>().unwrap();
+        assert_eq!(failed.host, "localhost");
+        assert!(why
+            .to_string()
+            .starts_with("TLS handshake with localhost failed: "));
+    }
+
+    #[tokio::test]
+    async fn throttled_upgrades_carry_the_retry_after() {
+        let url = gateway(HashMap::from([
+            ("/busy", "429 Too Many Requests\r\nRetry-After: 7".into()),
+            ("/later", "429 Too Many Requests".into()),
+        ]))
+        .await;
+        let throttled = |path: &str| {
+            let url = Url::parse(&format!("{url}{path}")).unwrap();
+            async move {
+                let why = Bot::connect(&url, false, Limits::default())
+                    .await
+                    .err()
+                    .unwrap();
+                let throttled = why.downcast_ref::<Throttled>().unwrap();
+                (throttled.retry_after, throttled.to_string())
+            }
+        };
+        assert_eq!(
+            throttled("/busy").await,
+            (
+                Some(Duration::from_secs(7)),
+                "throttled, retry after 7s".into()
+            )
+        );
+        assert_eq!(throttled("/later").await, (None, "throttled".into()));
+        // Without a hint the default wait applies, with some jitter on top
+        let wait = Bot::throttle_wait(&Throttled { retry_after: None });
+        assert!(
+            wait >= Bot::DEFAULT_THROTTLE && wait <= Bot::DEFAULT_THROTTLE + Bot::THROTTLE_JITTER
+        );
+        // Other refusals are not throttling
+        let why = Bot::connect(
+            &Url::parse(&format!("{url}/gone")).unwrap(),
+            false,
+            Limits::default(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(why.downcast_ref::<Throttled>().is_none());
+        // Gateways closing right after the upgrade give the wait in the close reason
+        let frame = CloseFrame {
+            code: tungstenite::protocol::frame::coding::CloseCode::from(4429),
+            reason: "overloaded, retry in 45 seconds".into(),
+        };
+        assert!(matches!(
+            CloseAction::default_for(4429),
+            CloseAction::ReconnectAfter(30)
+        ));
+        assert_eq!(Bot::retry_hint(&frame), Some(Duration::from_secs(45)));
+        let frame = CloseFrame {
+            reason: "overloaded".into(),
+            ..frame
+        };
+        assert_eq!(Bot::retry_hint(&frame), None);
+    }
+
+    #[tokio::test]
+    async fn redirected_upgrades_are_followed_but_not_in_circles() {
+        let (_server, target) = simulate::Server::start(Duration::ZERO, Context::default())
+            .await
+            .unwrap();
+        // Gateways may point at the http form of the websocket URL
+        let target = target.replacen("ws://", "http://", 1);
+        let url = gateway(HashMap::from([
+            (
+                "/moved",
+                format!("301 Moved Permanently\r\nLocation: {target}"),
+            ),
+            ("/hop", "302 Found\r\nLocation: /moved".into()),
+            ("/loop", "302 Found\r\nLocation: /round".into()),
+            ("/round", "302 Found\r\nLocation: /loop".into()),
+            ("/nowhere", "302 Found".into()),
+            ("/ftp", "302 Found\r\nLocation: ftp://127.0.0.1/".into()),
+        ]))
+        .await;
+        let connect = |path: &str, max_redirects| {
+            let url = Url::parse(&format!("{url}{path}")).unwrap();
+            let limits = Limits {
+                max_redirects,
+                ..Limits::default()
+            };
+            async move {
+                Bot::connect(&url, false, limits)
+                    .await
+                    .map(drop)
+                    .map_err(|why| why.to_string())
+            }
+        };
+        assert_eq!(connect("/moved", 3).await, Ok(()));
+        assert_eq!(connect("/hop", 3).await, Ok(()));
+        let why = connect("/hop", 1).await.unwrap_err();
+        assert_eq!(why, format!("{url}/hop redirected more than 1 times"));
+        let why = connect("/loop", 3).await.unwrap_err();
+        assert_eq!(
+            why,
+            format!("{url}/round redirected back to {url}/loop; giving up on the loop")
+        );
+        let why = connect("/nowhere", 3).await.unwrap_err();
+        assert_eq!(
+            why,
+            format!("{url}/nowhere answered 302 Found without a Location")
+        );
+        let why = connect("/ftp", 3).await.unwrap_err();
+        assert_eq!(
+            why,
+            format!("{url}/ftp redirected to unsupported ftp://127.0.0.1/")
+        );
+    }
+
+    #[test]
+    fn close_codes_map_to_actions() {
+        let config = parse_config(
+            r##"{
+                "brush": {"rect": {"width": 1, "height": 1, "color": "#000000"}},
+                "bots": ["wss://a.test/ws"],
+                "close_codes": {"1008": "reconnect", "4000": {"reconnect_after": 5}, "4100": "quarantine"}
+            }"##,
+        )
+        .unwrap();
+        let action = |code| {
+            config
+                .close_codes
+                .get(&code)
+                .copied()
+                .unwrap_or_else(|| CloseAction::default_for(code))
+        };
+        for code in [4001, 4003, 4100] {
+            assert!(matches!(action(code), CloseAction::Quarantine), "{code}");
+        }
+        for code in [1000, 1001, 1006, 1008, 4999] {
+            assert!(matches!(action(code), CloseAction::Reconnect), "{code}");
+        }
+        for (code, secs) in [(1012, 10), (1013, 30), (4029, 30), (4429, 30), (4000, 5)] {
+            assert!(
+                matches!(action(code), CloseAction::ReconnectAfter(after) if after == secs),
+                "{code}"
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_first_paint_must_come_back_as_sent() {
+        let endpoint = |url: &str| Endpoint {
+            url: Url::parse(url).unwrap(),
+            insecure: false,
+            proxies: Vec::new(),
+            limits: Limits::default(),
+        };
+        let timeout = Duration::from_secs(5);
+        let (_server, url) = simulate::Server::start(Duration::ZERO, Context::default())
+            .await
+            .unwrap();
+        let pixel = Mutex::new(provider(&[4, 4]));
+        check_first_paint(&endpoint(&url), &pixel, CanvasSpec::default(), timeout)
+            .await
+            .unwrap();
+        assert_eq!(pixel.lock().await.report().painted, 1);
+        // Echoes every paint one pixel to the right, as a wrong codec might
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", server.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = server.accept().await?;
+            let context = Context::default();
+            let mut connection = async_tungstenite::tokio::accept_async(stream).await?;
+            while let Some(msg) = connection.next().await {
+                if let tungstenite::Message::Binary(frame) = msg? {
+                    for pixel in context.decode(&frame) {
+                        let moved = context.pack(PixelInfo {
+                            x: pixel.x + 1,
+                            ..pixel
+                        })?;
+                        connection.send(tungstenite::Message::Binary(moved)).await?;
+                    }
+                }
+            }
+            anyhow::Ok(())
+        });
+        let pixel = Mutex::new(provider(&[4, 4]));
+        let why = check_first_paint(&endpoint(&url), &pixel, CanvasSpec::default(), timeout)
+            .await
+            .err()
+            .unwrap();
+        let packed = Context::default()
+            .pack(PixelInfo {
+                x: 0,
+                y: 0,
+                color_id: 4,
+            })
+            .unwrap();
+        assert_eq!(
+            why.to_string(),
+            format!(
+                "first paint {{0:0}} color 4 was sent as {{0:0}} color 4 ({}) but did not come back within 5s; \
+                 the canvas changed at {{1:0}} color 4. \
+                 Check canvas.codec, canvas.update_offset and the canvas spec, or set verify_first_paint to false",
+                protocol::hex(&packed)
+            )
+        );
+        // The probe goes back to the queue
+        let provider = pixel.lock().await;
+        assert_eq!(provider.report().painted, 0);
+        assert_eq!(provider.queue.iter().count(), 2);
+    }
+
+    #[test]
+    fn a_new_palette_color_retargets_the_pixels_it_matches() {
+        let source = (0x12, 0x34, 0x56);
+        let near = Context::default().resolve_color_id(0x12, 0x34, 0x56).id;
+        let grown = Palette::new([Palette::default().colors(), &[source]].concat()).unwrap();
+        let added = grown.id_of(source).unwrap();
+        // Three pixels of a color the palette lacks, then one it has
+        let pixels = [near, near, near, 4]
+            .into_iter()
+            .enumerate()
+            .map(|(x, color_id)| PixelInfo {
+                x: x as u32,
+                y: 0,
+                color_id,
+            })
+            .collect::<Vec<_>>();
+        for requantize_completed in [false, true] {
+            let mut pixel = provider_of(pixels.clone());
+            pixel.sources = (0..3).map(|x| ((x, 0), source)).collect();
+            let first = pixel.get_pixel(0).unwrap();
+            pixel.painted(&first);
+            pixel.repalette(grown.clone(), requantize_completed);
+            let rest = std::iter::from_fn(|| pixel.get_pixel(0))
+                .map(|pixel| (pixel.x, pixel.color_id))
+                .collect::<Vec<_>>();
+            let mut expected = vec![(1, added), (2, added), (3, 4)];
+            if requantize_completed {
+                expected.insert(0, (0, added));
+            }
+            assert_eq!(rest, expected);
+            assert_eq!(pixel.report().painted, u32::from(!requantize_completed));
+        }
+    }
+
+    // On the real clock: the paused one runs ahead of the probes still in the socket
+    #[tokio::test]
+    async fn calibration_finds_the_server_cooldown() {
+        let (server, url) = simulate::Server::start(Duration::from_secs(3), Context::default())
+            .await
+            .unwrap();
+        let endpoint = Endpoint {
+            url: Url::parse(&url).unwrap(),
+            insecure: false,
+            proxies: Vec::new(),
+            limits: Limits::default(),
+        };
+        let calibration = CalibrationConfig {
+            max_paints: 8,
+            margin: 0,
+            ack_timeout: 2,
+        };
+        let pixel = Mutex::new(provider(&[4; 8]));
+        // Waiting out the rejected 1s probe takes the next one to 3s, not the 2s planned
+        let found = calibrate_cooldown(&endpoint, &pixel, CanvasSpec::default(), calibration, 1)
+            .await
+            .unwrap();
+        assert_eq!(found, Some(3));
+        assert_eq!(server.rejected.load(Ordering::Relaxed), 2);
+        // Rejected probes go back to the queue
+        let provider = pixel.lock().await;
+        let painted = provider.report().painted as usize;
+        assert_eq!((painted, provider.queue.iter().count()), (2, 6));
+    }
+
+    #[test]
+    fn overlays_list_the_pixels_to_paint_in_canvas_coordinates() {
+        let (state, out) = (scratch("overlay.state"), scratch("overlay.json"));
+        let document = format!(
+            r##"{{
+                "brush": {{
+                    "rect": {{"width": 2, "height": 1, "color": "#000000"}},
+                    "offset_x": 3,
+                    "offset_y": 4
+                }},
+                "brushes": [{{
+                    "rect": {{"width": 1, "height": 1, "color": "#FE2500"}},
+                    "offset_x": 10,
+                    "offset_y": 10
+                }}],
+                "bots": [],
+                "state_file": {state:?}
+            }}"##
+        );
+        let export = |remaining, pretty| {
+            export_overlay(
+                parse_config(&document).unwrap(),
+                out.clone(),
+                remaining,
+                pretty,
+            )?;
+            anyhow::Ok(fs::read_to_string(&out)?)
+        };
+        assert_eq!(
+            export(false, false).unwrap(),
+            r##"[{"x":3,"y":4,"color":"#000000"},{"x":4,"y":4,"color":"#000000"},{"x":10,"y":10,"color":"#FE2500"}]"##
+        );
+        let pretty = export(false, true).unwrap();
+        assert!(
+            pretty.contains("\n    \"color\": \"#FE2500\"\n"),
+            "{pretty}"
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&pretty).unwrap(),
+            serde_json::from_str::<serde_json::Value>(&export(false, false).unwrap()).unwrap()
+        );
+        // With the first pixel painted in an earlier run only the other two remain
+        let pixels = queue(&document).unwrap();
+        let (mut saved, _) =
+            StateFile::resume(&state, pixels.clone(), CanvasSpec::default()).unwrap();
+        let mut pixel = provider_of(pixels);
+        let first = pixel.get_pixel(0).unwrap();
+        pixel.painted(&first);
+        saved.record(&pixel);
+        saved.save(&state).unwrap();
+        assert_eq!(
+            export(true, false).unwrap(),
+            r##"[{"x":4,"y":4,"color":"#000000"},{"x":10,"y":10,"color":"#FE2500"}]"##
+        );
+        fs::remove_file(state).unwrap();
+        fs::remove_file(out).unwrap();
+    }
+
+    #[test]
+    fn plan_slices_add_up_to_the_whole_queue() {
+        let work = scratch("slices.plan");
+        let planned = parse_config(
+            r##"{
+                "brush": {
+                    "rect": {"width": 4, "height": 3, "color": "#000000"},
+                    "offset_x": 7,
+                    "frame": {"color": "#FE2500"}
+                },
+                "bots": []
+            }"##,
+        )
+        .unwrap();
+        let full = build_queue(&planned, &Context::new(&planned)).unwrap();
+        // The frame is cut off above the top row
+        assert_eq!(full.len(), 6 * 4);
+        plan(planned, work.clone(), None).unwrap();
+        let slice = |slice: &str, canvas: &str| {
+            queue(&format!(
+                r#"{{
+                    "brush": {{"plan_file": {work:?}, "slice": "{slice}"}},
+                    "bots": [],
+                    "canvas": {canvas}
+                }}"#
+            ))
+        };
+        let first = slice("0..20", "{}").unwrap();
+        let second = slice(&format!("20..{}", full.len()), "{}").unwrap();
+        assert_eq!([first, second].concat(), full);
+        let why = slice("0..99", "{}").err().unwrap();
+        assert_eq!(
+            why.to_string(),
+            format!("Slice 0..99 is out of the queue of {} pixels", full.len())
+        );
+        let why = slice("0..1", r#"{"origin": "bottom-left"}"#).err().unwrap();
+        assert_eq!(
+            why.to_string(),
+            format!("{} was planned for a different canvas spec", work.display())
+        );
+        fs::remove_file(work).unwrap();
+    }
+
+    #[test]
+    fn candidates_are_counted_against_the_board() {
+        let template = (0..2)
+            .flat_map(|y| (0..3).map(move |x| PixelInfo { x, y, color_id: 4 }))
+            .collect::<Vec<_>>();
+        let context = Context::default();
+        let mut board = Canvas::new(PixelProvider::MAX_WIDTH, PixelProvider::MAX_HEIGHT);
+        for (x, y, color_id) in [(0, 0, 4), (1, 0, 4), (2, 0, 0), (1589, 1, 4)] {
+            board.set(x, y, color_id);
+        }
+        let frame = FrameConfig {
+            color: ColorTarget::Index(11),
+            thickness: 1,
+        };
+        let right = PixelProvider::MAX_WIDTH - 2;
+        for (offset, frame, expected) in [
+            ((0, 0), None, (6, 0, 2)),
+            // Only the first two columns fit
+            ((right, 0), None, (4, 2, 1)),
+            ((10, 10), Some(&frame), (6 + 14, 0, 0)),
+        ] {
+            let (pixels, clipped, matching) = expected;
+            let candidate =
+                Candidate::at(&template, offset, frame, Some(&board), &context).unwrap();
+            assert_eq!(
+                candidate,
+                Candidate {
+                    pixels,
+                    clipped,
+                    matching: Some(matching),
+                    to_paint: pixels - matching,
+                },
+                "{offset:?}"
+            );
+        }
+        // Without a board everything is to paint
+        let blind = Candidate::at(&template, (0, 0), None, None, &context).unwrap();
+        assert_eq!((blind.matching, blind.to_paint), (None, 6));
+    }
+
+    #[test]
+    fn configs_come_from_stdin_or_inline() {
+        // Relative to the working directory, as there is no config file to be relative to
+        let image = PathBuf::from("target").join(format!("pb-{}-relative.png", std::process::id()));
+        fs::create_dir_all("target").unwrap();
+        RgbaImage::from_pixel(2, 1, image::Rgba([0, 0, 0, 255]))
+            .save(&image)
+            .unwrap();
+        let document = format!(
+            r#"{{"brush": {{"image": {:?}, "offset_x": 5}}, "bots": []}}"#,
+            image.to_str().unwrap()
+        );
+        let positions = |config: Config| {
+            build_queue(&config, &Context::new(&config))
+                .unwrap()
+                .iter()
+                .map(|pixel| (pixel.x, pixel.color_id))
+                .collect::<Vec<_>>()
+        };
+        let config = read_config(Some("-".into()), document.as_bytes()).unwrap();
+        assert_eq!(positions(config), [(5, 4), (6, 4)]);
+        // Inline wins over any path, and may be TOML as well
+        let toml = format!(
+            "bots = []\n[brush]\nimage = {:?}\noffset_x = 7\n",
+            image.to_str().unwrap()
+        );
+        env::set_var("PB_CONFIG_INLINE", &toml);
+        let config = read_config(Some("-".into()), io::empty());
+        env::remove_var("PB_CONFIG_INLINE");
+        assert_eq!(positions(config.unwrap()), [(7, 4), (8, 4)]);
+        fs::remove_file(image).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    env,
+    io::{self, IsTerminal, Write},
+    sync::OnceLock,
+};
+
+use pretty_env_logger::env_logger::{fmt::WriteStyle, Builder};
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    // Colored, for a terminal
+    Pretty,
+    // Timestamped lines without escape codes
+    Plain,
+    // One object per line
+    Json,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+pub struct LogConfig {
+    #[serde(default)]
+    pub(crate) paint_level: PaintLevel,
+    // At most one paint line per worker in this many seconds, the rest go to debug
+    #[serde(default)]
+    pub(crate) paint_interval: u64,
+    // Format and progress left out pick what suits where pb is running
+    pub format: Option<LogFormat>,
+    // The worker table with every progress report
+    pub progress: Option<bool>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PaintLevel {
+    #[default]
+    Info,
+    Debug,
+    Off,
+}
+
+// What the defaults are picked from
+pub struct Surroundings {
+    pub stderr_tty: bool,
+    // Started by systemd, which sets these for its units
+    pub journal: bool,
+}
+
+impl Surroundings {
+    pub fn detect() -> Self {
+        Self {
+            stderr_tty: io::stderr().is_terminal(),
+            journal: env::var_os("INVOCATION_ID").is_some()
+                || env::var_os("JOURNAL_STREAM").is_some(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Logging {
+    pub format: LogFormat,
+    pub progress: bool,
+}
+
+impl Logging {
+    // Anything set explicitly wins; a terminal gets colors and progress, a service plain lines
+    pub fn decide(config: &LogConfig, surroundings: &Surroundings) -> Self {
+        let interactive = surroundings.stderr_tty && !surroundings.journal;
+        Self {
+            format: config.format.unwrap_or(if interactive {
+                LogFormat::Pretty
+            } else {
+                LogFormat::Plain
+            }),
+            progress: config.progress.unwrap_or(interactive),
+        }
+    }
+}
+
+static CHOSEN: OnceLock<Logging> = OnceLock::new();
+
+/// Installs the logger, filtered by RUST_LOG, in the format `config` asks for or the
+/// surroundings suggest.
+pub fn init(config: &LogConfig) -> Logging {
+    let logging = Logging::decide(config, &Surroundings::detect());
+    let mut builder = match logging.format {
+        LogFormat::Pretty => pretty_env_logger::formatted_builder(),
+        LogFormat::Plain => {
+            let mut builder = Builder::new();
+            builder
+                .write_style(WriteStyle::Never)
+                .format(|buf, record| {
+                    writeln!(
+                        buf,
+                        "{} {:<5} {} > {}",
+                        buf.timestamp_millis(),
+                        record.level(),
+                        record.target(),
+                        record.args()
+                    )
+                });
+            builder
+        }
+        LogFormat::Json => {
+            let mut builder = Builder::new();
+            builder
+                .write_style(WriteStyle::Never)
+                .format(|buf, record| {
+                    let line = serde_json::json!({
+                        "timestamp": buf.timestamp_millis().to_string(),
+                        "level": record.level().as_str(),
+                        "target": record.target(),
+                        "message": record.args().to_string(),
+                    });
+                    writeln!(buf, "{line}")
+                });
+            builder
+        }
+    };
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    builder.init();
+    CHOSEN.set(logging).ok();
+    logging
+}
+
+// Whether to print the worker table; on when init was never called, as before
+pub fn progress() -> bool {
+    CHOSEN.get().is_none_or(|logging| logging.progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decide(
+        format: Option<LogFormat>,
+        progress: Option<bool>,
+        tty: bool,
+        journal: bool,
+    ) -> (LogFormat, bool) {
+        let config = LogConfig {
+            format,
+            progress,
+            ..LogConfig::default()
+        };
+        let surroundings = Surroundings {
+            stderr_tty: tty,
+            journal,
+        };
+        let logging = Logging::decide(&config, &surroundings);
+        (logging.format, logging.progress)
+    }
+
+    #[test]
+    fn defaults_follow_the_surroundings() {
+        assert_eq!(decide(None, None, true, false), (LogFormat::Pretty, true));
+        assert_eq!(decide(None, None, false, false), (LogFormat::Plain, false));
+        assert_eq!(decide(None, None, false, true), (LogFormat::Plain, false));
+        // journalctl -f in a terminal still gets no escape codes
+        assert_eq!(decide(None, None, true, true), (LogFormat::Plain, false));
+    }
+
+    #[test]
+    fn explicit_settings_win() {
+        for (tty, journal) in [(true, false), (false, false), (false, true), (true, true)] {
+            assert_eq!(
+                decide(Some(LogFormat::Json), Some(true), tty, journal),
+                (LogFormat::Json, true)
+            );
+            let pretty = decide(Some(LogFormat::Pretty), None, tty, journal);
+            assert_eq!(pretty.0, LogFormat::Pretty);
+            let quiet = decide(None, Some(false), tty, journal);
+            assert!(!quiet.1);
+        }
+    }
+}
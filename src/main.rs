@@ -1,321 +1,286 @@
-use std::sync::Arc;
-
-use std::{cmp, env, fs::File, path::PathBuf, time::Duration};
+use std::{ops::Range as IndexRange, path::PathBuf, time::Duration};
 
 use anyhow::anyhow;
-use async_tungstenite::tungstenite;
-
-use async_tungstenite::{
-    stream::Stream,
-    tokio::{connect_async, TokioAdapter},
-    WebSocketStream,
-};
-use futures::{stream::FuturesUnordered, SinkExt, StreamExt};
-use image::DynamicImage;
-use lazy_static::lazy_static;
+use clap::{Parser, Subcommand};
 use log::*;
-
-use rand::distributions::uniform::{UniformDuration, UniformSampler};
-use rand::rngs::StdRng;
-use rand::SeedableRng;
-use serde::Deserialize;
-use tokio::sync::Mutex;
-use tokio::task::JoinHandle;
 use url::Url;
 
-#[derive(Deserialize)]
-struct Config {
-    brush: Brush,
-    bots: Vec<Url>,
-}
+// Exit code of a verify under the threshold or a run the deadline cut short
+const INCOMPLETE: i32 = 3;
+// Exit code of a run that gave up on a stall, for systemd to restart it
+const STALLED: i32 = 75;
 
-#[derive(Deserialize)]
-struct Brush {
-    image: PathBuf,
-    offset_x: u32,
-    offset_y: u32,
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Config file to use instead of PB_CONFIG
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Record every websocket frame to this file, zstd compressed if it ends in .zst
+    #[arg(long)]
+    capture: Option<PathBuf>,
+    /// Print the run plan summary and exit
+    #[arg(long)]
+    print_plan: bool,
+    /// Print how the brush maps onto the palette and exit
+    #[arg(long)]
+    palette_report: bool,
+    /// Only watch the canvas, using the bots as listen-only connections
+    #[arg(long)]
+    observe: bool,
+    /// Print the worker table with each progress report, overriding log.progress
+    #[arg(long, global = true, overrides_with = "no_progress")]
+    progress: bool,
+    /// Leave the worker table out of progress reports
+    #[arg(long, global = true)]
+    no_progress: bool,
+    /// Start painting without asking, even above confirm_above_pixels
+    #[arg(long)]
+    yes: bool,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    drop(dotenvy::dotenv());
-    pretty_env_logger::init();
-    let config_file = File::open(env::var("PB_CONFIG").unwrap_or_else(|_| {
-        info!("PB_CONFIG var were not present, using default path (pb.json)");
-        "pb.json".into()
-    }))?;
-    let config = serde_json::from_reader::<_, Config>(config_file)?;
-    let pixel = Arc::new(Mutex::new(PixelProvider::new(
-        config.brush.image,
-        config.brush.offset_x,
-        config.brush.offset_y,
-    )?));
-    let sleep = SleepPerformer::new(UniformDuration::new_inclusive(
-        Duration::from_secs(65),
-        Duration::from_secs(180),
-    ));
-    let handles = FuturesUnordered::new();
-    for (i, url) in config.bots.into_iter().enumerate() {
-        let bot = Bot::new(i as i32, url, pixel.clone(), sleep.clone()).await?;
-        handles.push(tokio::spawn(async move { bot.run().await }));
-    }
-    handles.collect::<Vec<_>>().await;
-    Ok(())
+#[derive(Subcommand)]
+enum Command {
+    /// Build the pixel queue and save it as a work file instead of painting
+    Plan {
+        #[arg(long)]
+        out: PathBuf,
+        /// Only keep queue entries in the given index range, e.g. 0..5000
+        #[arg(long, value_parser = pb::parse_slice)]
+        slice: Option<IndexRange<usize>>,
+    },
+    /// Save the pixels to paint as {x, y, color} JSON for overlay userscripts
+    ExportOverlay {
+        #[arg(long)]
+        out: PathBuf,
+        /// Leave out pixels the state file has as painted
+        #[arg(long)]
+        remaining: bool,
+        #[arg(long)]
+        pretty: bool,
+    },
+    /// Send one pixel over a single connection and print every frame received
+    PaintOne {
+        #[arg(long)]
+        url: Url,
+        #[arg(long)]
+        x: u32,
+        #[arg(long)]
+        y: u32,
+        /// Palette index or #RRGGBB color
+        #[arg(long)]
+        color: String,
+        #[arg(long)]
+        allow_insecure: bool,
+    },
+    /// Connect every configured bot once without painting and report which work
+    ValidateBots,
+    /// Rehearse a run against an in-process server
+    Simulate {
+        /// Foreign overwrites of our pixels, e.g. 5/min
+        #[arg(long, value_parser = parse_rate, default_value = "0/min")]
+        griefer_rate: f64,
+        /// Simulated run length, e.g. 6h; it passes on a paused clock, as fast as the bots paint
+        #[arg(long, value_parser = parse_duration)]
+        duration: Duration,
+        /// Where to save the final canvas
+        #[arg(long, default_value = "simulation.png")]
+        out: PathBuf,
+    },
+    /// Compare the live canvas with the template; exits with 3 below the threshold
+    Verify {
+        /// How long to collect canvas updates, e.g. 30s
+        #[arg(long, value_parser = parse_duration, default_value = "10s")]
+        listen: Duration,
+        /// Share of template pixels that must be intact, from 0 to 1
+        #[arg(long, default_value_t = 1.0)]
+        threshold: f64,
+        /// Save the canvas with wrong pixels in red here
+        #[arg(long)]
+        diff: Option<PathBuf>,
+    },
+    /// Compare candidate brush offsets by clipped, matching and remaining pixels
+    Estimate {
+        /// Candidates as x,y separated by semicolons, e.g. "100,50;400,10"
+        #[arg(long, value_parser = parse_offsets)]
+        offsets: Offsets,
+        /// Also diff against the live canvas watched this long, e.g. 30s
+        #[arg(long, value_parser = parse_duration)]
+        listen: Option<Duration>,
+    },
+    /// Summarize a capture: paints per worker and hour, never echoed paints, repaints
+    Stats {
+        /// Capture file written with --capture
+        #[arg(long)]
+        audit: PathBuf,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Inspect recorded websocket traffic
+    Capture {
+        #[command(subcommand)]
+        command: CaptureCommand,
+    },
 }
 
-#[derive(Clone)]
-struct SleepPerformer {
-    rng: Arc<Mutex<StdRng>>,
-    uniform: UniformDuration,
+#[derive(Subcommand)]
+enum CaptureCommand {
+    /// Print a capture file, decoding canvas updates
+    Dump { file: PathBuf },
+    /// Rebuild the canvas from a capture file's inbound updates
+    Replay {
+        file: PathBuf,
+        /// Where to save the reconstructed canvas
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
 }
 
-impl SleepPerformer {
-    fn new(uniform: UniformDuration) -> Self {
-        Self {
-            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
-            uniform,
-        }
-    }
-
-    fn exact(&self, secs: u64) -> JoinHandle<()> {
-        let duration = Duration::from_secs(secs);
-        tokio::spawn(async move {
-            tokio::time::sleep(duration).await;
-        })
-    }
+type Offsets = Vec<(u32, u32)>;
 
-    async fn perform(&mut self) -> JoinHandle<()> {
-        let duration = self.uniform.sample(&mut *self.rng.lock().await);
-        tokio::spawn(async move {
-            tokio::time::sleep(duration).await;
+fn parse_offsets(offsets: &str) -> Result<Offsets, String> {
+    offsets
+        .split(';')
+        .map(|offset| {
+            let (x, y) = offset
+                .trim()
+                .split_once(',')
+                .ok_or_else(|| format!("{offset:?} is not x,y"))?;
+            let parse = |n: &str| {
+                n.trim()
+                    .parse::<u32>()
+                    .map_err(|e| format!("{offset:?}: {e}"))
+            };
+            Ok((parse(x)?, parse(y)?))
         })
-    }
+        .collect()
 }
 
-type WStream = WebSocketStream<
-    Stream<
-        TokioAdapter<tokio::net::TcpStream>,
-        TokioAdapter<tokio_native_tls::TlsStream<tokio::net::TcpStream>>,
-    >,
->;
-
-struct Bot {
-    id: i32,
-    url: Url,
-    pixel: Arc<Mutex<PixelProvider>>,
-    sleep: SleepPerformer,
-    connection: WStream,
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let split = duration.len() - duration.ends_with(['s', 'm', 'h']) as usize;
+    let (amount, unit) = duration.split_at(split);
+    let amount = amount.parse::<f64>().map_err(|e| e.to_string())?;
+    let secs = match unit {
+        "h" => amount * 3600.0,
+        "m" => amount * 60.0,
+        _ => amount,
+    };
+    Duration::try_from_secs_f64(secs).map_err(|e| e.to_string())
 }
 
-impl Bot {
-    async fn new(
-        id: i32,
-        url: Url,
-        pixel: Arc<Mutex<PixelProvider>>,
-        sleep: SleepPerformer,
-    ) -> anyhow::Result<Self> {
-        Ok(Self {
-            id,
-            url: url.clone(),
-            pixel,
-            sleep,
-            connection: Self::connect(&url).await?,
-        })
-    }
-
-    async fn connect(url: &Url) -> anyhow::Result<WStream> {
-        Ok(connect_async(url).await.map(|x| x.0)?)
+// Events per minute
+fn parse_rate(rate: &str) -> Result<f64, String> {
+    let (count, unit) = rate
+        .split_once('/')
+        .ok_or_else(|| format!("{rate} is not a COUNT/UNIT rate"))?;
+    let count = count.parse::<f64>().map_err(|e| e.to_string())?;
+    match unit {
+        "s" | "sec" => Ok(count * 60.0),
+        "m" | "min" => Ok(count),
+        "h" | "hour" => Ok(count / 60.0),
+        _ => Err(format!("{unit} is not one of s, min, h")),
     }
-
-    async fn run(mut self) {
-        info!("Worker #{} started.", self.id);
-        let mut ping_timer = tokio::spawn(async {});
-        let mut timer = tokio::spawn(async {});
-        while let Some(msg) = self.connection.next().await {
-            match msg {
-                Ok(tungstenite::Message::Close(..))
-                | Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
-                    info!(
-                        "Worker #{} connection was closed; trying to reconnect.",
-                        self.id,
-                    );
-                    self.connection = Self::connect(&self.url).await.unwrap();
-                    info!("Worker #{} reconnected", self.id);
-                    continue;
-                }
-                Ok(msg) => info!("Message {msg}"),
-                // Idk how to deal. C'mon, just ignore
-                Err(why) => {
-                    error!(
-                        "Worker #{} received unexpected error: {}; exiting.",
-                        self.id, why
-                    );
-                    break;
-                }
-            }
-            if ping_timer.is_finished() {
-                self.connection
-                    .send(tungstenite::Message::Ping("ping".into()))
-                    .await
-                    .unwrap();
-                ping_timer = self.sleep.exact(30);
-            } else if timer.is_finished() {
-                match self.pixel.lock().await.get_pixel() {
-                    Some(pixel) => {
-                        info!(
-                            "Worker #{} painting {{{}:{}}} to {}",
-                            self.id, pixel.x, pixel.y, pixel.color_id
-                        );
-                        self.connection
-                            .send(PixelProvider::pack(pixel.clone()).into())
-                            .await
-                            .unwrap();
-                    }
-                    None => return,
-                }
-                timer = self.sleep.perform().await;
-            }
-        }
-    }
-}
-
-lazy_static! {
-    static ref COLORS: Vec<(u8, u8, u8)> = [
-        "#FFFFFF", "#C2C2C2", "#858585", "#474747", "#000000", "#3AAFFF", "#71AAEB", "#4A76A8",
-        "#074BF3", "#5E30EB", "#FF6C5B", "#FE2500", "#FF218B", "#99244F", "#4D2C9C", "#FFCF4A",
-        "#FEB43F", "#FE8648", "#FF5B36", "#DA5100", "#94E044", "#5CBF0D", "#C3D117", "#FCC700",
-        "#D38301",
-    ]
-    .into_iter()
-    .map(|x| (
-        u8::from_str_radix(&x[1..3], 16).unwrap(),
-        u8::from_str_radix(&x[3..5], 16).unwrap(),
-        u8::from_str_radix(&x[5..], 16).unwrap()
-    ))
-    .collect::<Vec<_>>();
-}
-
-struct PixelProvider {
-    image: DynamicImage,
-    initial: (u32, u32),
-    current: (u32, u32),
-    end: bool,
 }
 
-impl PixelProvider {
-    const MAX_COLOR_ID: u32 = 25;
-    const MAX_HEIGHT: u32 = 400;
-    const MAX_WIDTH: u32 = 1590;
-    const SIZE: u32 = 636000;
-
-    #[allow(clippy::new_ret_no_self)]
-    fn new(image: PathBuf, x: u32, y: u32) -> anyhow::Result<Self> {
-        if x >= Self::MAX_WIDTH {
-            Err(anyhow!("X axis is out of range"))?
-        }
-        if y >= Self::MAX_HEIGHT {
-            Err(anyhow!("Y axis is out of range"))?
-        }
-        Ok(Self {
-            image: ::image::open(image)?,
-            initial: (x, y),
-            current: (x, y),
-            end: false,
-        })
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    drop(dotenvy::dotenv());
+    let cli = Cli::parse();
+    let log = pb::log_config(cli.config.as_ref());
+    let mut logging = log.as_ref().ok().copied().flatten().unwrap_or_default();
+    if cli.progress || cli.no_progress {
+        logging.progress = Some(cli.progress);
     }
-
-    #[allow(clippy::erasing_op)]
-    fn pack(info: PixelInfo) -> Vec<u8> {
-        let PixelInfo { x, y, color_id } = info;
-        let value = x as i32
-            + y as i32 * Self::MAX_WIDTH as i32
-            + Self::SIZE as i32 * (color_id as i32 + 0 * Self::MAX_COLOR_ID as i32);
-        value.to_le_bytes().into()
+    pb::init_logging(&logging);
+    if let Err(why) = log {
+        warn!("Logging with defaults, the config did not parse: {why}");
     }
-
-    fn resolve_color_id(r: u8, g: u8, b: u8) -> ColorId {
-        let mut nearest = None;
-        for (index, (r1, g1, b1)) in COLORS.iter().enumerate() {
-            let temp = ((cmp::max(r, *r1) - cmp::min(r, *r1)) as u32).pow(2)
-                + ((cmp::max(g, *g1) - cmp::min(g, *g1)) as u32).pow(2)
-                + ((cmp::max(b, *b1) - cmp::min(b, *b1)) as u32).pow(2);
-            if temp == 0 {
-                return ColorId {
-                    id: index as u8,
-                    exact: true,
-                };
+    match cli.command {
+        Some(Command::Plan { out, slice }) => pb::plan(pb::load_config(cli.config)?, out, slice),
+        Some(Command::ExportOverlay {
+            out,
+            remaining,
+            pretty,
+        }) => pb::export_overlay(pb::load_config(cli.config)?, out, remaining, pretty),
+        Some(Command::PaintOne {
+            url,
+            x,
+            y,
+            color,
+            allow_insecure,
+        }) => {
+            let config = pb::load_config(cli.config)?;
+            if !pb::paint_one(&config, url, x, y, &color, allow_insecure).await? {
+                Err(anyhow!("No echo of the pixel was observed"))?
             }
-            nearest = nearest.map_or(Some((index, temp)), |(c, t)| {
-                if temp < t {
-                    Some((index, temp))
-                } else {
-                    Some((c, t))
-                }
-            });
-        }
-        ColorId {
-            id: nearest.unwrap().0 as u8,
-            exact: false,
+            Ok(())
         }
-    }
-
-    fn get_pixel(&mut self) -> Option<PixelInfo> {
-        if self.end {
-            return None;
+        Some(Command::ValidateBots) => pb::validate_bots(pb::load_config(cli.config)?).await,
+        Some(Command::Simulate {
+            griefer_rate,
+            duration,
+            out,
+        }) => {
+            let config = pb::load_config(cli.config)?;
+            tokio::task::spawn_blocking(move || pb::simulate(config, griefer_rate, duration, out))
+                .await??;
+            Ok(())
         }
-        if self.current.0 >= Self::MAX_WIDTH || self.current.1 >= Self::MAX_WIDTH {
-            self.end = true;
-            return None;
+        Some(Command::Estimate { offsets, listen }) => {
+            pb::estimate(pb::load_config(cli.config)?, offsets, listen).await
         }
-        let (dx, dy) = (
-            self.current.0 - self.initial.0,
-            self.current.1 - self.initial.1,
-        );
-        let rgb = self
-            .image
-            .as_rgb8()
-            .expect("Cannot represent given image as rgb8");
-        let (width, height) = rgb.dimensions();
-        if dx >= width {
-            self.current.0 = 0;
-            self.current.1 += 1;
-        }
-        if dy >= height {
-            self.end = true;
-            return None;
+        Some(Command::Verify {
+            listen,
+            threshold,
+            diff,
+        }) => {
+            let ratio = pb::verify(pb::load_config(cli.config)?, listen, diff).await?;
+            if ratio < threshold {
+                std::process::exit(INCOMPLETE);
+            }
+            Ok(())
         }
-        let [r, g, b] = rgb.get_pixel(dx, dy).0;
-        let ColorId { id, exact } = Self::resolve_color_id(r, g, b);
-        if !exact {
-            warn!("Pixel {{{dx}:{dy}}} is not exactly match allowed colors. Converted to {id:x}");
+        Some(Command::Stats { audit, json }) => pb::audit_stats(&audit, json),
+        Some(Command::Capture {
+            command: CaptureCommand::Dump { file },
+        }) => pb::dump_capture(&file),
+        Some(Command::Capture {
+            command: CaptureCommand::Replay { file, out },
+        }) => pb::replay_capture(&file, out),
+        None => {
+            let mut config = pb::load_config(cli.config)?;
+            if cli.capture.is_some() {
+                config.capture_path = cli.capture;
+            }
+            if cli.observe {
+                config.observe_only();
+            }
+            if cli.yes {
+                config.assume_yes();
+            }
+            if cli.print_plan {
+                return pb::print_plan(config);
+            }
+            if cli.palette_report {
+                return pb::palette_report(config);
+            }
+            let report = pb::run(config, async {
+                if tokio::signal::ctrl_c().await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+                info!("Interrupted.");
+            })
+            .await?;
+            if report.stalled {
+                std::process::exit(STALLED);
+            }
+            if report.deadline.is_some_and(|deadline| deadline.reached)
+                && report.painted < report.queued
+            {
+                std::process::exit(INCOMPLETE);
+            }
+            Ok(())
         }
-        self.current.0 += 1;
-        Some(PixelInfo {
-            x: self.current.0 - 1,
-            y: self.current.1,
-            color_id: id,
-        })
-    }
-
-    #[allow(dead_code)]
-    fn get_packed_pixel(&mut self) -> Option<Vec<u8>> {
-        let info = match self.get_pixel() {
-            Some(pixel) => pixel,
-            None => return None,
-        };
-        Some(Self::pack(info))
     }
 }
-
-#[derive(Debug)]
-struct ColorId {
-    id: u8,
-    exact: bool,
-}
-
-#[derive(Clone)]
-struct PixelInfo {
-    x: u32,
-    y: u32,
-    color_id: u8,
-}
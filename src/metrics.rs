@@ -0,0 +1,369 @@
+use std::fmt::{Display, Write};
+
+use crate::stats::{PoolSnapshot, State};
+use crate::{protocol, PixelProvider};
+
+// Prometheus text exposition, served on /metrics
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+// Upper bounds in seconds; echoes time out after 30s
+const LATENCY_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.0, "# HELP {name} {help}\n# TYPE {name} {kind}").unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let labels = labels
+            .iter()
+            .map(|(key, value)| format!("{key}=\"{}\"", escape(value)))
+            .collect::<Vec<_>>();
+        if labels.is_empty() {
+            writeln!(self.0, "{name} {value}").unwrap();
+        } else {
+            writeln!(self.0, "{name}{{{}}} {value}", labels.join(",")).unwrap();
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+pub fn render(pool: &PoolSnapshot, pixel: &PixelProvider) -> String {
+    let mut out = Exposition::default();
+    let report = pixel.report();
+    out.family("pb_queued_pixels", "gauge", "Pixels of the template");
+    out.sample("pb_queued_pixels", &[], report.queued);
+    out.family("pb_painted_pixels", "gauge", "Template pixels painted");
+    out.sample("pb_painted_pixels", &[], report.painted);
+    out.family(
+        "pb_watching",
+        "gauge",
+        "1 while nothing is left to repaint and workers wake only on damage",
+    );
+    out.sample("pb_watching", &[], u8::from(pixel.watching));
+    let per_color = [
+        ("pb_color_queued_pixels", "Pixels of the template by color"),
+        (
+            "pb_color_painted_pixels",
+            "Template pixels painted by color",
+        ),
+        (
+            "pb_color_remaining_pixels",
+            "Template pixels left to paint by color",
+        ),
+    ];
+    for (i, (name, help)) in per_color.into_iter().enumerate() {
+        out.family(name, "gauge", help);
+        for color in &report.colors {
+            let value = [color.queued, color.painted, color.remaining][i];
+            out.sample(name, &[("color", &color.color)], value);
+        }
+    }
+    let latency = "pb_echo_latency_seconds";
+    out.family(
+        latency,
+        "histogram",
+        "Time from sending a paint to its echo",
+    );
+    let latencies = pixel
+        .echo_latencies
+        .iter()
+        .map(|latency| latency.as_secs_f64())
+        .collect::<Vec<_>>();
+    for bound in LATENCY_BUCKETS {
+        let count = latencies.iter().filter(|&&secs| secs <= bound).count();
+        let bucket = format!("{latency}_bucket");
+        out.sample(&bucket, &[("le", &bound.to_string())], count);
+    }
+    out.sample(
+        &format!("{latency}_bucket"),
+        &[("le", "+Inf")],
+        latencies.len(),
+    );
+    out.sample(
+        &format!("{latency}_sum"),
+        &[],
+        latencies.iter().sum::<f64>(),
+    );
+    out.sample(&format!("{latency}_count"), &[], latencies.len());
+    out.family(
+        "pb_echo_timeouts_total",
+        "counter",
+        "Paints the server never echoed",
+    );
+    out.sample("pb_echo_timeouts_total", &[], pixel.echo_timeouts);
+    out.family(
+        "pb_protocol_parse_errors_total",
+        "counter",
+        "Incoming frames or records that could not be parsed",
+    );
+    out.sample(
+        "pb_protocol_parse_errors_total",
+        &[],
+        protocol::parse_errors(),
+    );
+    out.family(
+        "pb_overwritten_pixels_total",
+        "counter",
+        "Times one of our painted pixels was overwritten by someone else",
+    );
+    out.sample("pb_overwritten_pixels_total", &[], pixel.overwritten);
+    out.family(
+        "pb_demoted_pixels_total",
+        "counter",
+        "Pixels reverted so often they are painted only when nothing else is pending",
+    );
+    out.sample("pb_demoted_pixels_total", &[], pixel.demoted.len());
+    out.family(
+        "pb_given_up_pixels_total",
+        "counter",
+        "Pixels reverted so often they are not painted again",
+    );
+    out.sample("pb_given_up_pixels_total", &[], pixel.given_up.len());
+    out.family(
+        "pb_canvas_update_batches_total",
+        "counter",
+        "Times workers applied their buffered canvas updates",
+    );
+    out.sample("pb_canvas_update_batches_total", &[], pixel.update_batches);
+    let traffic = [
+        (
+            "pb_bot_received_bytes_total",
+            "Frame payload bytes received by worker",
+        ),
+        (
+            "pb_bot_sent_bytes_total",
+            "Frame payload bytes sent by worker",
+        ),
+    ];
+    for (i, (name, help)) in traffic.into_iter().enumerate() {
+        out.family(name, "counter", help);
+        for worker in &pool.workers {
+            let value = [worker.rx_bytes, worker.tx_bytes][i];
+            out.sample(name, &[("bot", &worker.name)], value);
+        }
+    }
+    out.family(
+        "pb_bot_reduced",
+        "gauge",
+        "1 while a worker ignores broadcasts after max_rx_bytes_per_hour",
+    );
+    for worker in &pool.workers {
+        out.sample(
+            "pb_bot_reduced",
+            &[("bot", &worker.name)],
+            worker.reduced as u8,
+        );
+    }
+    out.family(
+        "pb_bot_benched_total",
+        "counter",
+        "Times a worker was benched after failed sends in a row",
+    );
+    for worker in &pool.workers {
+        out.sample(
+            "pb_bot_benched_total",
+            &[("bot", &worker.name)],
+            worker.benched,
+        );
+    }
+    out.family(
+        "pb_bot_benched",
+        "gauge",
+        "1 while a worker sits out a bench cooloff",
+    );
+    for worker in &pool.workers {
+        out.sample(
+            "pb_bot_benched",
+            &[("bot", &worker.name)],
+            (worker.state == State::Benched) as u8,
+        );
+    }
+    // Left out until the server first reports it
+    if let Some(online) = &pool.online {
+        out.family(
+            "pb_online_users",
+            "gauge",
+            "Users online as the server last reported",
+        );
+        out.sample("pb_online_users", &[], online.count);
+        out.family(
+            "pb_online_users_age_seconds",
+            "gauge",
+            "Time since the server last reported the users online",
+        );
+        out.sample("pb_online_users_age_seconds", &[], online.age_secs);
+    }
+    out.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::stats::StatsRegistry;
+    use crate::tests::provider;
+    use crate::{Context, PixelInfo};
+
+    #[test]
+    fn colors_are_labeled_by_hex() {
+        let mut pixel = provider(&[0, 0, 1]);
+        let first = pixel.get_pixel(0).unwrap();
+        assert_eq!(first.color_id, 0);
+        pixel.painted(&first);
+        let metrics = render(&StatsRegistry::default().snapshot(), &pixel);
+        let (first, second) = {
+            let palette = Context::default().palette();
+            let hex = |id| {
+                let (r, g, b) = palette.rgb_of(id).unwrap();
+                format!("#{r:02X}{g:02X}{b:02X}")
+            };
+            (hex(0), hex(1))
+        };
+        for line in [
+            "pb_queued_pixels 3".to_string(),
+            "pb_painted_pixels 1".to_string(),
+            format!("pb_color_queued_pixels{{color=\"{first}\"}} 2"),
+            format!("pb_color_painted_pixels{{color=\"{first}\"}} 1"),
+            format!("pb_color_remaining_pixels{{color=\"{first}\"}} 1"),
+            format!("pb_color_remaining_pixels{{color=\"{second}\"}} 1"),
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "{line} in\n{metrics}");
+        }
+    }
+
+    #[test]
+    fn echo_latencies_fill_cumulative_buckets() {
+        let mut pixel = provider(&[0]);
+        pixel.echo_latencies = [80, 300, 300, 4000].map(Duration::from_millis).to_vec();
+        pixel.echo_timeouts = 2;
+        let metrics = render(&StatsRegistry::default().snapshot(), &pixel);
+        for line in [
+            "pb_echo_latency_seconds_bucket{le=\"0.05\"} 0",
+            "pb_echo_latency_seconds_bucket{le=\"0.1\"} 1",
+            "pb_echo_latency_seconds_bucket{le=\"0.5\"} 3",
+            "pb_echo_latency_seconds_bucket{le=\"5\"} 4",
+            "pb_echo_latency_seconds_bucket{le=\"+Inf\"} 4",
+            "pb_echo_latency_seconds_sum 4.68",
+            "pb_echo_latency_seconds_count 4",
+            "pb_echo_timeouts_total 2",
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "{line} in\n{metrics}");
+        }
+    }
+
+    #[test]
+    fn watching_is_a_gauge() {
+        let mut pixel = provider(&[0]);
+        let metrics = render(&StatsRegistry::default().snapshot(), &pixel);
+        assert!(metrics.lines().any(|l| l == "pb_watching 0"), "{metrics}");
+        pixel.watching = true;
+        let metrics = render(&StatsRegistry::default().snapshot(), &pixel);
+        assert!(metrics.lines().any(|l| l == "pb_watching 1"), "{metrics}");
+    }
+
+    #[test]
+    fn demotions_and_give_ups_are_counted() {
+        let mut pixel = provider(&[0, 0, 0]);
+        pixel.demoted.extend([(0, 0), (1, 0)]);
+        pixel.given_up.insert((1, 0));
+        let metrics = render(&StatsRegistry::default().snapshot(), &pixel);
+        for line in ["pb_demoted_pixels_total 2", "pb_given_up_pixels_total 1"] {
+            assert!(metrics.lines().any(|l| l == line), "{line} in\n{metrics}");
+        }
+    }
+
+    #[test]
+    fn parse_errors_are_counted() {
+        let errors = protocol::parse_errors();
+        protocol::parse_failed();
+        let metrics = render(&StatsRegistry::default().snapshot(), &provider(&[0]));
+        let counted = metrics
+            .lines()
+            .find_map(|line| line.strip_prefix("pb_protocol_parse_errors_total "))
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        // Other tests fail parses concurrently
+        assert!(counted > errors, "{counted} after {errors}");
+    }
+
+    #[test]
+    fn overwrites_are_counted() {
+        let mut pixel = provider(&[1]);
+        let ours = pixel.get_pixel(0).unwrap();
+        pixel.painted(&ours);
+        pixel.observe(&ours);
+        for _ in 0..3 {
+            pixel.observe(&PixelInfo {
+                color_id: 2,
+                ..ours.clone()
+            });
+            pixel.observe(&ours);
+        }
+        let metrics = render(&StatsRegistry::default().snapshot(), &pixel);
+        assert!(metrics
+            .lines()
+            .any(|l| l == "pb_overwritten_pixels_total 3"));
+    }
+
+    #[test]
+    fn online_users_appear_once_reported() {
+        let (stats, pixel) = (StatsRegistry::default(), provider(&[0]));
+        let metrics = render(&stats.snapshot(), &pixel);
+        assert!(!metrics.contains("pb_online_users"));
+        stats.set_online(42);
+        let metrics = render(&stats.snapshot(), &pixel);
+        assert!(metrics.lines().any(|l| l == "pb_online_users 42"));
+        assert!(metrics
+            .lines()
+            .any(|l| l == "pb_online_users_age_seconds 0"));
+    }
+
+    #[test]
+    fn traffic_is_counted_per_bot() {
+        let stats = StatsRegistry::default();
+        let (first, second) = (
+            stats.register("first".into(), String::new()),
+            stats.register("second".into(), String::new()),
+        );
+        first.received(1000);
+        first.received(24);
+        first.transmitted(4);
+        second.received(7);
+        second.set_reduced(true);
+        second.benched();
+        second.set(State::Benched);
+        let metrics = render(&stats.snapshot(), &provider(&[0]));
+        for line in [
+            "pb_bot_received_bytes_total{bot=\"first\"} 1024",
+            "pb_bot_sent_bytes_total{bot=\"first\"} 4",
+            "pb_bot_received_bytes_total{bot=\"second\"} 7",
+            "pb_bot_sent_bytes_total{bot=\"second\"} 0",
+            "pb_bot_reduced{bot=\"first\"} 0",
+            "pb_bot_reduced{bot=\"second\"} 1",
+            "pb_bot_benched_total{bot=\"first\"} 0",
+            "pb_bot_benched_total{bot=\"second\"} 1",
+            "pb_bot_benched{bot=\"first\"} 0",
+            "pb_bot_benched{bot=\"second\"} 1",
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "{line} in\n{metrics}");
+        }
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let mut out = Exposition::default();
+        out.sample("pb_x", &[("bot", "a\"b\\c\nd")], 1);
+        assert_eq!(out.0, "pb_x{bot=\"a\\\"b\\\\c\\nd\"} 1\n");
+    }
+}
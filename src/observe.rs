@@ -0,0 +1,176 @@
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_tungstenite::tungstenite::{self, Message};
+use futures::StreamExt;
+use log::*;
+use serde::Deserialize;
+
+use crate::canvas::Canvas;
+use crate::capture::{Capture, Direction};
+use crate::retry::{ConnectRetries, RetryConfig};
+use crate::{protocol, redact, save_image, Bot, CanvasSpec, Context, Endpoint, PixelProvider};
+
+#[derive(Deserialize)]
+pub struct SnapshotConfig {
+    // A canvas-<unix time>.png is written here every interval
+    pub dir: PathBuf,
+    #[serde(default = "SnapshotConfig::default_interval")]
+    pub interval: u64,
+}
+
+impl SnapshotConfig {
+    fn default_interval() -> u64 {
+        60
+    }
+}
+
+// Listen-only connection that keeps `canvas` up to date
+async fn scout(
+    id: i32,
+    endpoint: Endpoint,
+    context: Arc<Context>,
+    spec: CanvasSpec,
+    canvas: Arc<StdMutex<Canvas>>,
+    capture: Option<Capture>,
+    retry: RetryConfig,
+) {
+    let mut retries = ConnectRetries::new(&retry);
+    loop {
+        let why = match Bot::connect_through(&endpoint).await {
+            Ok(mut connection) => {
+                info!("Scout {} connected.", redact(&endpoint.url));
+                retries.reset();
+                while let Some(msg) = connection.next().await {
+                    let msg = match msg {
+                        Ok(msg) => msg,
+                        Err(tungstenite::Error::Utf8) => {
+                            protocol::parse_failed();
+                            continue;
+                        }
+                        Err(_) => break,
+                    };
+                    if let Some(capture) = &capture {
+                        capture.record(id, Direction::Inbound, &msg);
+                    }
+                    if let Message::Binary(frame) = &msg {
+                        let updates = context.decode_updates(frame);
+                        let mut canvas = canvas.lock().unwrap();
+                        for pixel in updates {
+                            let pixel = spec.untransform(pixel);
+                            canvas.set(pixel.x, pixel.y, pixel.color_id);
+                        }
+                    }
+                }
+                anyhow::anyhow!("connection lost")
+            }
+            Err(why) => why,
+        };
+        let backoff = retries.pick(&why);
+        let Some(wait) = backoff.next() else {
+            error!(
+                "Scout {}: {why}; giving up ({backoff}).",
+                redact(&endpoint.url)
+            );
+            return;
+        };
+        warn!(
+            "Scout {}: {why}; reconnecting in {}s ({backoff}).",
+            redact(&endpoint.url),
+            wait.as_secs()
+        );
+        tokio::time::sleep(wait).await;
+    }
+}
+
+fn save_snapshot(canvas: &StdMutex<Canvas>, dir: &Path, context: &Context) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = dir.join(format!("canvas-{timestamp}.png"));
+    let image = canvas.lock().unwrap().to_image(&context.palette());
+    match save_image(&image, &path) {
+        Ok(()) => info!("Saved a canvas snapshot to {}", path.display()),
+        Err(why) => warn!("Cannot save a snapshot to {}: {why}", path.display()),
+    }
+}
+
+// Tracks the canvas without painting until `shutdown_signal` resolves
+pub async fn run(
+    scouts: Vec<Endpoint>,
+    context: Arc<Context>,
+    spec: CanvasSpec,
+    snapshot: Option<SnapshotConfig>,
+    capture_path: Option<PathBuf>,
+    retry: RetryConfig,
+    shutdown_signal: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    if let Some(snapshot) = &snapshot {
+        std::fs::create_dir_all(&snapshot.dir)?;
+        if snapshot.interval == 0 {
+            Err(anyhow::anyhow!("snapshot.interval must be at least 1"))?
+        }
+    }
+    let (capture, capture_writer) = match &capture_path {
+        Some(path) => {
+            let (capture, writer) = Capture::start(path)?;
+            (Some(capture), Some(writer))
+        }
+        None => (None, None),
+    };
+    info!(
+        "Observing with {} scouts; nothing will be painted.",
+        scouts.len()
+    );
+    let canvas = Arc::new(StdMutex::new(Canvas::new(
+        PixelProvider::MAX_WIDTH,
+        PixelProvider::MAX_HEIGHT,
+    )));
+    let tasks = scouts
+        .into_iter()
+        .enumerate()
+        .map(|(id, endpoint)| {
+            tokio::spawn(scout(
+                id as i32,
+                endpoint,
+                context.clone(),
+                spec,
+                canvas.clone(),
+                capture.clone(),
+                retry,
+            ))
+        })
+        .collect::<Vec<_>>();
+    let snapshots = snapshot.as_ref().map(|snapshot| {
+        let (canvas, dir, context) = (canvas.clone(), snapshot.dir.clone(), context.clone());
+        let interval = Duration::from_secs(snapshot.interval);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                save_snapshot(&canvas, &dir, &context);
+            }
+        })
+    });
+    shutdown_signal.await;
+    for task in tasks {
+        task.abort();
+    }
+    if let Some(snapshots) = snapshots {
+        snapshots.abort();
+    }
+    if let Some(snapshot) = &snapshot {
+        save_snapshot(&canvas, &snapshot.dir, &context);
+    }
+    if let (Some(capture), Some(writer)) = (capture, capture_writer) {
+        drop(capture);
+        drop(writer.await);
+    }
+    Ok(())
+}
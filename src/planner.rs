@@ -0,0 +1,336 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::{mpsc, Mutex},
+    time,
+};
+
+use pb_core::Palette;
+
+use crate::{parse_hex, PixelInfo, PixelProvider};
+
+// Work handed out one pixel at a time by an external planner over JSON lines
+#[derive(Deserialize, Clone)]
+pub struct PlannerConfig {
+    // host:port, or unix:/path/to/socket
+    pub address: String,
+    // Seconds to wait for the answer to a claim before reconnecting
+    #[serde(default = "PlannerConfig::default_timeout")]
+    pub timeout: u64,
+    // Pixels claimed ahead of the bots; one per connection and paint slot when left out
+    pub prefetch: Option<usize>,
+}
+
+impl PlannerConfig {
+    fn default_timeout() -> u64 {
+        10
+    }
+}
+
+// What the provider tells the planner about the pixels it handed out
+pub enum Report {
+    Done(PixelInfo),
+    Failed(PixelInfo),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    Claim,
+    Done { x: u32, y: u32, color: String },
+    Failed { x: u32, y: u32, color: String },
+}
+
+impl Request {
+    fn report(report: Report, palette: &Palette) -> Self {
+        let color = |pixel: &PixelInfo| {
+            let (r, g, b) = palette.rgb_of(pixel.color_id).unwrap_or_default();
+            format!("#{r:02X}{g:02X}{b:02X}")
+        };
+        match report {
+            Report::Done(pixel) => Self::Done {
+                x: pixel.x,
+                y: pixel.y,
+                color: color(&pixel),
+            },
+            Report::Failed(pixel) => Self::Failed {
+                x: pixel.x,
+                y: pixel.y,
+                color: color(&pixel),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Answer {
+    Pixel { x: u32, y: u32, color: String },
+    // Nothing for us right now; ask again in this many seconds
+    Wait { wait: f64 },
+    // Nothing for us ever again
+    Finished { finished: bool },
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// How often to look again whether the bots need more pixels
+const PREFETCH_POLL: Duration = Duration::from_millis(500);
+
+// Keeps the queue topped up from the planner until it is finished or the provider lets go of `reports`
+pub async fn feed(
+    config: PlannerConfig,
+    prefetch: usize,
+    pixel: Arc<Mutex<PixelProvider>>,
+    mut reports: mpsc::UnboundedReceiver<Report>,
+) {
+    let prefetch = config.prefetch.unwrap_or(prefetch).max(1);
+    loop {
+        let ended = match connect(&config.address).await {
+            Ok(stream) => {
+                info!("Connected to the planner at {}", config.address);
+                session(stream, &config, prefetch, &pixel, &mut reports).await
+            }
+            Err(why) => Err(why.into()),
+        };
+        match ended {
+            // The run is over and every report went out
+            Ok(()) => return,
+            // Bots keep painting what is queued and idle once it runs out
+            Err(why) => warn!(
+                "Planner at {} unavailable: {why}; retrying in {}s",
+                config.address,
+                RECONNECT_DELAY.as_secs()
+            ),
+        }
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn connect(address: &str) -> io::Result<Box<dyn Stream>> {
+    #[cfg(unix)]
+    if let Some(path) = address.strip_prefix("unix:") {
+        return Ok(Box::new(tokio::net::UnixStream::connect(path).await?));
+    }
+    Ok(Box::new(TcpStream::connect(address).await?))
+}
+
+// Claims handed out on a connection that drops are the planner's to reassign
+async fn session(
+    stream: Box<dyn Stream>,
+    config: &PlannerConfig,
+    prefetch: usize,
+    pixel: &Mutex<PixelProvider>,
+    reports: &mut mpsc::UnboundedReceiver<Report>,
+) -> anyhow::Result<()> {
+    let context = pixel.lock().await.context.clone();
+    let (read, mut write) = io::split(stream);
+    let mut lines = BufReader::new(read).lines();
+    let timeout = Duration::from_secs(config.timeout);
+    loop {
+        // Reports first, so the planner knows what is done before handing out more
+        loop {
+            match reports.try_recv() {
+                Ok(report) => {
+                    send(&mut write, &Request::report(report, &context.palette())).await?
+                }
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+        let wait = if pixel.lock().await.outstanding() >= prefetch {
+            PREFETCH_POLL
+        } else {
+            send(&mut write, &Request::Claim).await?;
+            let line = time::timeout(timeout, lines.next_line())
+                .await
+                .map_err(|_| anyhow!("no answer to a claim within {}s", config.timeout))??
+                .ok_or_else(|| anyhow!("the planner closed the connection"))?;
+            match serde_json::from_str(&line)
+                .map_err(|why| anyhow!("cannot read {line:?} from the planner: {why}"))?
+            {
+                Answer::Pixel { x, y, color } => {
+                    let added = match parse_hex(&color) {
+                        Ok((r, g, b)) => {
+                            let color_id = context.resolve_color_id(r, g, b).id;
+                            pixel.lock().await.add_remote(PixelInfo { x, y, color_id })
+                        }
+                        Err(why) => Err(why),
+                    };
+                    if let Err(why) = added {
+                        warn!("Ignoring {{{x}:{y}}} from the planner: {why}");
+                    }
+                    continue;
+                }
+                Answer::Wait { wait } => Duration::try_from_secs_f64(wait).unwrap_or_default(),
+                Answer::Finished { finished: true } => {
+                    info!("The planner has no more work; finishing what is queued");
+                    pixel.lock().await.planner_finished = true;
+                    // It still hears how the last claims went
+                    while let Some(report) = reports.recv().await {
+                        send(&mut write, &Request::report(report, &context.palette())).await?;
+                    }
+                    return Ok(());
+                }
+                Answer::Finished { finished: false } => PREFETCH_POLL,
+            }
+        };
+        tokio::select! {
+            report = reports.recv() => match report {
+                Some(report) => send(&mut write, &Request::report(report, &context.palette())).await?,
+                None => return Ok(()),
+            },
+            _ = time::sleep(wait) => {}
+        }
+    }
+}
+
+async fn send(write: &mut (impl AsyncWrite + Unpin), request: &Request) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    write.write_all(&line).await?;
+    write.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashSet, VecDeque};
+
+    use serde_json::{json, Value};
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::tests::provider;
+    use crate::Context;
+
+    fn config() -> PlannerConfig {
+        PlannerConfig {
+            address: "mock".into(),
+            timeout: PlannerConfig::default_timeout(),
+            prefetch: None,
+        }
+    }
+
+    // Hands out a row of `pixels`, again when they fail, and what it was told about them
+    async fn plan(stream: DuplexStream, pixels: u32) -> Vec<Value> {
+        let (read, mut write) = io::split(stream);
+        let mut lines = BufReader::new(read).lines();
+        let mut todo = (0..pixels).collect::<VecDeque<_>>();
+        let (mut reports, mut done) = (Vec::new(), HashSet::new());
+        while let Ok(Some(line)) = lines.next_line().await {
+            let request = serde_json::from_str::<Value>(&line).unwrap();
+            let answer = match request["type"].as_str().unwrap() {
+                "claim" => match todo.pop_front() {
+                    Some(x) => json!({ "x": x, "y": 0, "color": "#FFFFFF" }),
+                    None if done.len() == pixels as usize => json!({ "finished": true }),
+                    None => json!({ "wait": 0.5 }),
+                },
+                kind => {
+                    let x = request["x"].as_u64().unwrap() as u32;
+                    if kind == "done" {
+                        done.insert(x);
+                    } else {
+                        todo.push_back(x);
+                    }
+                    reports.push(request);
+                    continue;
+                }
+            };
+            write
+                .write_all(format!("{answer}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+        reports
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn two_bots_paint_what_the_planner_hands_out() {
+        let (ours, theirs) = io::duplex(4096);
+        let planner = tokio::spawn(plan(theirs, 10));
+        let (sender, mut reports) = mpsc::unbounded_channel();
+        let mut provider = provider(&[]);
+        provider.planner = Some(sender);
+        let pixel = Arc::new(Mutex::new(provider));
+        let session = tokio::spawn({
+            let pixel = pixel.clone();
+            async move { session(Box::new(ours), &config(), 2, &pixel, &mut reports).await }
+        });
+        let (mut painted, mut failed_once) = ([0; 2], false);
+        for worker in [0, 1].into_iter().cycle() {
+            let mut pixel = pixel.lock().await;
+            match pixel.get_pixel(worker) {
+                // The bots lose one paint, which the planner hands out again
+                Some(claim) if claim.x == 3 && !failed_once => {
+                    failed_once = true;
+                    pixel.failed(claim);
+                }
+                Some(claim) => {
+                    assert_eq!(
+                        claim.color_id,
+                        Context::default().resolve_color_id(255, 255, 255).id
+                    );
+                    pixel.painted(&claim);
+                    painted[worker as usize] += 1;
+                }
+                None if pixel.is_done() => break,
+                None => {
+                    drop(pixel);
+                    time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+        assert_eq!(painted.iter().sum::<u32>(), 10);
+        assert!(painted.iter().all(|&paints| paints > 0), "{painted:?}");
+        // Letting go of the reports ends the session once the last of them went out
+        pixel.lock().await.planner = None;
+        session.await.unwrap().unwrap();
+        let reports = planner.await.unwrap();
+        let failed = reports
+            .iter()
+            .filter(|r| r["type"] == "failed")
+            .collect::<Vec<_>>();
+        assert_eq!(
+            failed,
+            [&json!({ "type": "failed", "x": 3, "y": 0, "color": "#FFFFFF" })]
+        );
+        assert_eq!(reports.iter().filter(|r| r["type"] == "done").count(), 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_silent_or_gone_planner_ends_the_session() {
+        let pixel = Mutex::new(provider(&[]));
+        let (_sender, mut reports) = mpsc::unbounded_channel();
+        let (ours, _theirs) = io::duplex(4096);
+        let why = session(Box::new(ours), &config(), 2, &pixel, &mut reports)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(why.to_string(), "no answer to a claim within 10s");
+        let (ours, theirs) = io::duplex(4096);
+        drop(theirs);
+        assert!(session(Box::new(ours), &config(), 2, &pixel, &mut reports)
+            .await
+            .is_err());
+        let (ours, mut theirs) = io::duplex(4096);
+        theirs.write_all(b"<html>\n").await.unwrap();
+        let why = session(Box::new(ours), &config(), 2, &pixel, &mut reports)
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            why.to_string().starts_with("cannot read \"<html>\""),
+            "{why}"
+        );
+        assert!(pixel.lock().await.queue.is_empty());
+    }
+}
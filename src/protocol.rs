@@ -0,0 +1,538 @@
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::anyhow;
+use flate2::read::ZlibDecoder;
+use log::*;
+use serde::Deserialize;
+
+use crate::{PixelInfo, PixelProvider};
+
+const HEXDUMP_PREFIX: usize = 16;
+// Every pixel of the canvas at the widest record size
+const MAX_INFLATED: usize = PixelProvider::SIZE as usize * 8;
+
+// Incoming frames or records we could not make sense of, over all connections
+static PARSE_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+pub fn parse_failed() {
+    PARSE_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn parse_errors() -> u64 {
+    PARSE_ERRORS.load(Ordering::Relaxed)
+}
+
+// Added to the coordinates of canvas updates from servers that send them chunk-relative
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct UpdateOffset {
+    #[serde(default)]
+    pub x: i32,
+    #[serde(default)]
+    pub y: i32,
+}
+
+// Record layouts of the servers we know, picked with canvas.codec
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Codec {
+    #[default]
+    Packed32,
+    Wide48,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    // position + canvas size * color
+    Packed,
+    Color,
+    // x + y * canvas width
+    Position,
+}
+
+impl Codec {
+    // Little-endian fields in wire order with their widths in bytes
+    fn layout(self) -> &'static [(Field, usize)] {
+        match self {
+            Self::Packed32 => &[(Field::Packed, 4)],
+            Self::Wide48 => &[(Field::Color, 2), (Field::Position, 4)],
+        }
+    }
+
+    fn record_size(self) -> usize {
+        self.layout().iter().map(|&(_, width)| width).sum()
+    }
+
+    fn limit(field: Field, width: usize) -> u64 {
+        match field {
+            // Read back as a signed integer
+            Field::Packed => (1 << (8 * width - 1)) - 1,
+            _ => (1 << (8 * width)) - 1,
+        }
+    }
+
+    pub fn max_color_id(self) -> u64 {
+        let size = PixelProvider::SIZE as u64;
+        self.layout()
+            .iter()
+            .filter_map(|&(field, width)| match field {
+                Field::Packed => Some((Self::limit(field, width) - (size - 1)) / size),
+                Field::Color => Some(Self::limit(field, width)),
+                Field::Position => None,
+            })
+            .min()
+            .unwrap_or_default()
+    }
+
+    pub fn encode(self, pixel: &PixelInfo) -> anyhow::Result<Vec<u8>> {
+        let PixelInfo { x, y, color_id } = *pixel;
+        let position = x as u64 + y as u64 * PixelProvider::MAX_WIDTH as u64;
+        let mut record = Vec::with_capacity(self.record_size());
+        for &(field, width) in self.layout() {
+            let value = match field {
+                Field::Packed => position + PixelProvider::SIZE as u64 * color_id as u64,
+                Field::Color => color_id as u64,
+                Field::Position => position,
+            };
+            if value > Self::limit(field, width) {
+                Err(anyhow!(
+                    "Pixel {{{x}:{y}}} of color {color_id} overflows the {self:?} encoding"
+                ))?
+            }
+            record.extend_from_slice(&value.to_le_bytes()[..width]);
+        }
+        Ok(record)
+    }
+
+    // Colors past the `colors` of the palette are out of range like positions past the canvas
+    fn decode_record(self, record: &[u8], colors: usize) -> Option<PixelInfo> {
+        let (mut position, mut color_id, mut offset) = (0, 0, 0);
+        for &(field, width) in self.layout() {
+            let mut bytes = [0; 8];
+            bytes[..width].copy_from_slice(&record[offset..offset + width]);
+            offset += width;
+            let value = u64::from_le_bytes(bytes);
+            if value > Self::limit(field, width) {
+                return None;
+            }
+            match field {
+                Field::Packed => {
+                    position = value % PixelProvider::SIZE as u64;
+                    color_id = value / PixelProvider::SIZE as u64;
+                }
+                Field::Color => color_id = value,
+                Field::Position => position = value,
+            }
+        }
+        if position >= PixelProvider::SIZE as u64 || color_id as usize >= colors {
+            return None;
+        }
+        Some(PixelInfo {
+            x: (position % PixelProvider::MAX_WIDTH as u64) as u32,
+            y: (position / PixelProvider::MAX_WIDTH as u64) as u32,
+            color_id: color_id as u8,
+        })
+    }
+}
+
+// How one server lays out its frames, from canvas.codec and canvas.update_offset
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Wire {
+    pub codec: Codec,
+    pub update_offset: UpdateOffset,
+}
+
+impl Wire {
+    // Canvas updates broadcast by the server, shifted by canvas.update_offset; what we send is never shifted
+    pub fn decode_updates(self, frame: &[u8], colors: usize) -> Vec<PixelInfo> {
+        let offset = self.update_offset;
+        if offset == UpdateOffset::default() {
+            return self.decode(frame, colors);
+        }
+        self.decode(frame, colors)
+            .into_iter()
+            .filter_map(|pixel| {
+                let x = pixel.x.checked_add_signed(offset.x)?;
+                let y = pixel.y.checked_add_signed(offset.y)?;
+                (x < PixelProvider::MAX_WIDTH && y < PixelProvider::MAX_HEIGHT)
+                    .then_some(PixelInfo { x, y, ..pixel })
+            })
+            .collect()
+    }
+
+    pub fn decode(self, frame: &[u8], colors: usize) -> Vec<PixelInfo> {
+        let inflated = inflate(frame);
+        let payload = inflated.as_deref().unwrap_or(frame);
+        let record_size = self.codec.record_size();
+        if payload.is_empty() || !payload.len().is_multiple_of(record_size) {
+            parse_failed();
+            debug!(
+                "Skipping malformed frame of {} bytes: {}",
+                payload.len(),
+                hexdump(payload)
+            );
+            return Vec::new();
+        }
+        let mut pixels = Vec::with_capacity(payload.len() / record_size);
+        for record in payload.chunks_exact(record_size) {
+            match self.codec.decode_record(record, colors) {
+                Some(pixel) => pixels.push(pixel),
+                None => {
+                    parse_failed();
+                    debug!("Skipping record out of canvas range: {}", hexdump(record));
+                }
+            }
+        }
+        pixels
+    }
+}
+
+fn inflate(frame: &[u8]) -> Option<Vec<u8>> {
+    // zlib header: deflate method with a 0x78 CMF byte and a FLG byte making the pair divisible by 31
+    let [0x78, flag, ..] = *frame else {
+        return None;
+    };
+    if !u16::from_be_bytes([0x78, flag]).is_multiple_of(31) {
+        return None;
+    }
+    let mut inflated = Vec::new();
+    ZlibDecoder::new(frame)
+        .take(MAX_INFLATED as u64 + 1)
+        .read_to_end(&mut inflated)
+        .ok()?;
+    // Nothing legitimate inflates past a full canvas, so a bomb decodes to an empty, malformed payload
+    if inflated.len() > MAX_INFLATED {
+        inflated.clear();
+    }
+    Some(inflated)
+}
+
+// Sent as a text frame by servers that advertise their canvas on connect
+#[derive(Deserialize)]
+pub struct Metadata {
+    pub palette: Vec<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+pub fn parse_metadata(text: &str) -> Option<Metadata> {
+    serde_json::from_str(text).ok()
+}
+
+// From periodic server text frames, either JSON like {"online": 42} or text like "Online: 42"
+pub fn parse_online(text: &str) -> Option<u32> {
+    if let Ok(serde_json::Value::Object(fields)) = serde_json::from_str(text) {
+        return ["online", "online_users", "users_online", "onlineCount"]
+            .iter()
+            .find_map(|key| fields.get(*key)?.as_u64())
+            .and_then(|count| u32::try_from(count).ok());
+    }
+    let lower = text.to_ascii_lowercase();
+    let at = lower.find("online")?;
+    // The number closest to the word, looking after it first
+    let after = lower[at..]
+        .split(|c: char| !c.is_ascii_digit())
+        .find(|digits| !digits.is_empty());
+    let before = lower[..at]
+        .rsplit(|c: char| !c.is_ascii_digit())
+        .find(|digits| !digits.is_empty());
+    after.or(before)?.parse().ok()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut dump = hex(&bytes[..bytes.len().min(HEXDUMP_PREFIX)]);
+    if bytes.len() > HEXDUMP_PREFIX {
+        dump.push_str(" ...");
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::ZlibEncoder, Compression};
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const CODECS: [Codec; 2] = [Codec::Packed32, Codec::Wide48];
+
+    fn pixels() -> impl Strategy<Value = Vec<PixelInfo>> {
+        prop::collection::vec(
+            (
+                0..PixelProvider::MAX_WIDTH,
+                0..PixelProvider::MAX_HEIGHT,
+                any::<u8>(),
+            ),
+            1..100,
+        )
+        .prop_map(|pixels| {
+            pixels
+                .into_iter()
+                .map(|(x, y, color_id)| PixelInfo { x, y, color_id })
+                .collect()
+        })
+    }
+
+    fn encode(codec: Codec, pixels: &[PixelInfo]) -> Vec<u8> {
+        pixels
+            .iter()
+            .flat_map(|pixel| codec.encode(pixel).unwrap())
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn frames_round_trip(pixels in pixels()) {
+            for codec in CODECS {
+                let wire = Wire { codec, ..Wire::default() };
+                let pixels = pixels
+                    .iter()
+                    .filter(|pixel| (pixel.color_id as u64) <= codec.max_color_id())
+                    .cloned()
+                    .collect::<Vec<_>>();
+                prop_assume!(!pixels.is_empty());
+                prop_assert_eq!(wire.decode(&encode(codec, &pixels), 256), pixels);
+            }
+        }
+
+        #[test]
+        fn distinct_pixels_never_pack_alike(
+            pixels in pixels(),
+            // Which of x, y and color the second pixel takes from the first, so near misses come up
+            shared in 0..4usize,
+        ) {
+            let a = &pixels[0];
+            let mut b = pixels[pixels.len() - 1].clone();
+            match shared {
+                0 => b.x = a.x,
+                1 => b.y = a.y,
+                2 => b.color_id = a.color_id,
+                _ => {}
+            }
+            prop_assume!(*a != b);
+            for codec in CODECS {
+                prop_assert_ne!(codec.encode(a).unwrap(), codec.encode(&b).unwrap());
+            }
+        }
+
+        #[test]
+        fn compressed_frames_decode_like_plain_ones(pixels in pixels()) {
+            let frame = encode(Codec::Wide48, &pixels);
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&frame).unwrap();
+            let compressed = encoder.finish().unwrap();
+            let wire = Wire { codec: Codec::Wide48, ..Wire::default() };
+            prop_assert_eq!(wire.decode(&compressed, 256), wire.decode(&frame, 256));
+        }
+
+        #[test]
+        fn garbage_decodes_to_pixels_on_the_canvas(
+            frame in prop::collection::vec(any::<u8>(), 0..256),
+            colors in 1..256usize,
+        ) {
+            for codec in CODECS {
+                for pixel in (Wire { codec, ..Wire::default() }).decode(&frame, colors) {
+                    prop_assert!(pixel.x < PixelProvider::MAX_WIDTH);
+                    prop_assert!(pixel.y < PixelProvider::MAX_HEIGHT);
+                    prop_assert!((pixel.color_id as usize) < colors);
+                }
+            }
+        }
+
+        #[test]
+        fn text_parsers_take_any_bytes(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+            let text = String::from_utf8_lossy(&bytes);
+            parse_online(&text);
+            parse_metadata(&text);
+            prop_assert!(hexdump(&bytes).len() <= HEXDUMP_PREFIX * 3 + 3);
+            let wire = Wire {
+                codec: Codec::Wide48,
+                update_offset: UpdateOffset { x: -1, y: 1 },
+            };
+            for pixel in wire.decode_updates(&bytes, 256) {
+                prop_assert!(pixel.x < PixelProvider::MAX_WIDTH && pixel.y < PixelProvider::MAX_HEIGHT);
+            }
+        }
+
+        #[test]
+        fn online_counts_are_found_next_to_the_word(
+            prefix in "[^0-9]{0,20}",
+            count in any::<u32>(),
+            suffix in "[^0-9]{0,20}",
+        ) {
+            prop_assert_eq!(parse_online(&format!("{prefix}online: {count}{suffix}")), Some(count));
+        }
+
+        #[test]
+        fn updates_shift_by_the_offset_and_stay_on_the_canvas(
+            pixels in pixels(),
+            x in -2000..2000i32,
+            y in -500..500i32,
+        ) {
+            let wire = Wire {
+                codec: Codec::Wide48,
+                update_offset: UpdateOffset { x, y },
+            };
+            let frame = encode(Codec::Wide48, &pixels);
+            let expected = pixels
+                .iter()
+                .filter_map(|pixel| {
+                    let (x, y) = (pixel.x as i32 + x, pixel.y as i32 + y);
+                    ((0..PixelProvider::MAX_WIDTH as i32).contains(&x)
+                        && (0..PixelProvider::MAX_HEIGHT as i32).contains(&y))
+                        .then_some(PixelInfo { x: x as u32, y: y as u32, ..pixel.clone() })
+                })
+                .collect::<Vec<_>>();
+            prop_assert_eq!(wire.decode_updates(&frame, 256), expected);
+        }
+    }
+
+    fn compress(frame: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(frame).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn batched_frames_decode_every_record() {
+        // {1:0} in color 2, {0:1} in color 0 and {1589:399} in color 1
+        let frame = [
+            0xc1, 0x68, 0x13, 0x00, 0x36, 0x06, 0x00, 0x00, 0xbf, 0x68, 0x13, 0x00,
+        ];
+        let expected = [(1, 0, 2), (0, 1, 0), (1589, 399, 1)].map(|(x, y, color_id)| PixelInfo {
+            x,
+            y,
+            color_id,
+        });
+        let wire = Wire::default();
+        assert_eq!(wire.decode(&frame, 32), expected);
+        assert_eq!(wire.decode(&compress(&frame), 32), expected);
+        assert_eq!(encode(Codec::Packed32, &expected), frame);
+    }
+
+    #[test]
+    fn malformed_frames_are_skipped_and_counted() {
+        let wire = Wire::default();
+        let errors = parse_errors();
+        assert!(wire.decode(&[1, 2, 3], 32).is_empty());
+        assert!(wire.decode(&[], 32).is_empty());
+        // A zlib header over a broken stream is taken as plain records
+        assert_eq!(wire.decode(&[0x78, 0x9c, 0, 0], 32).len(), 1);
+        // Counted per record out of range
+        assert!(wire.decode(&[0xff; 8], 32).is_empty());
+        assert!(parse_errors() >= errors + 4);
+        assert_eq!(
+            hexdump(&[0xab; 17]),
+            format!("{} ...", ["ab"; 16].join(" "))
+        );
+    }
+
+    #[test]
+    fn zlib_bombs_decode_to_nothing() {
+        let bomb = compress(&vec![0; MAX_INFLATED + 4]);
+        assert!(bomb.len() < 100_000);
+        assert!(Wire::default().decode(&bomb, 32).is_empty());
+        let full = compress(&vec![0; MAX_INFLATED]);
+        assert_eq!(Wire::default().decode(&full, 32).len(), MAX_INFLATED / 4);
+    }
+
+    #[test]
+    fn each_codec_has_its_own_layout_and_color_range() {
+        let pixel = PixelInfo {
+            x: 1,
+            y: 1,
+            color_id: 2,
+        };
+        assert_eq!(
+            Codec::Packed32.encode(&pixel).unwrap(),
+            [0xf7, 0x6e, 0x13, 0x00]
+        );
+        assert_eq!(
+            Codec::Wide48.encode(&pixel).unwrap(),
+            [0x02, 0x00, 0x37, 0x06, 0x00, 0x00]
+        );
+        assert_eq!(Codec::Packed32.max_color_id(), 3375);
+        assert_eq!(Codec::Wide48.max_color_id(), 65535);
+        // Past the 2-byte color field of the wider codec, which the 1-byte palette never reaches
+        let wide = Wire {
+            codec: Codec::Wide48,
+            ..Wire::default()
+        };
+        assert!(wide
+            .decode(&[0x02, 0x01, 0x37, 0x06, 0x00, 0x00], 256)
+            .is_empty());
+    }
+
+    #[test]
+    fn online_counts_come_in_many_shapes() {
+        for (payload, count) in [
+            (r#"{"online": 42}"#, Some(42)),
+            (
+                r#"{"type": "stats", "onlineCount": 17, "pixels": 90210}"#,
+                Some(17),
+            ),
+            (r#"{"users_online": 3}"#, Some(3)),
+            ("Online: 42", Some(42)),
+            ("ONLINE 7 users", Some(7)),
+            ("128 users online", Some(128)),
+            // Malformed or absent counts keep the last one
+            (r#"{"online": "many"}"#, None),
+            (r#"{"online": -1}"#, None),
+            (r#"{"online": 4294967296}"#, None),
+            (r#"{"pixels": 5}"#, None),
+            ("nobody is online", None),
+            ("hello", None),
+        ] {
+            assert_eq!(parse_online(payload), count, "{payload}");
+        }
+    }
+
+    #[test]
+    fn chunk_relative_updates_land_on_absolute_coordinates() {
+        let wire = Wire {
+            codec: Codec::Packed32,
+            update_offset: UpdateOffset { x: 512, y: 256 },
+        };
+        let relative = PixelInfo {
+            x: 3,
+            y: 4,
+            color_id: 5,
+        };
+        let frame = Codec::Packed32.encode(&relative).unwrap();
+        let absolute = PixelInfo {
+            x: 515,
+            y: 260,
+            color_id: 5,
+        };
+        assert_eq!(
+            wire.decode_updates(&frame, 32),
+            std::slice::from_ref(&absolute)
+        );
+        // What we send, and decode back from a capture, is never shifted
+        assert_eq!(wire.decode(&frame, 32), [relative]);
+        let config = crate::parse_config(
+            r##"{
+                "brush": {"rect": {"width": 1, "height": 1, "color": "#FFFFFF"}},
+                "canvas": {"update_offset": {"x": 512, "y": 256}},
+                "bots": []
+            }"##,
+        )
+        .unwrap();
+        let context = crate::Context::new(&config);
+        assert_eq!(
+            context.pack(absolute.clone()).unwrap(),
+            Codec::Packed32.encode(&absolute).unwrap()
+        );
+        assert_eq!(context.decode_updates(&frame), [absolute]);
+    }
+}
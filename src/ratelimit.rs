@@ -0,0 +1,133 @@
+use std::{sync::Mutex, time::Duration};
+
+use serde::Deserialize;
+use tokio::time::Instant;
+
+// Lets one log line through per window and counts the ones held back
+pub struct LogThrottle {
+    window: Duration,
+    last: Option<Instant>,
+    suppressed: u32,
+}
+
+impl LogThrottle {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last: None,
+            suppressed: 0,
+        }
+    }
+
+    // The number of events suppressed since the previous line, if this one may be logged
+    pub fn admit(&mut self) -> Option<u32> {
+        let now = Instant::now();
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < self.window)
+        {
+            self.suppressed += 1;
+            return None;
+        }
+        self.last = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+pub struct ReconnectLimit {
+    #[serde(default = "ReconnectLimit::default_attempts")]
+    pub attempts: u32,
+    #[serde(default = "ReconnectLimit::default_per")]
+    pub per: u64,
+}
+
+impl Default for ReconnectLimit {
+    fn default() -> Self {
+        Self {
+            attempts: Self::default_attempts(),
+            per: Self::default_per(),
+        }
+    }
+}
+
+impl ReconnectLimit {
+    fn default_attempts() -> u32 {
+        5
+    }
+
+    fn default_per() -> u64 {
+        10
+    }
+}
+
+// Token bucket refilled at `attempts` per `per` seconds, shared by every worker
+pub struct Bucket {
+    capacity: f64,
+    per_sec: f64,
+    // Tokens left and when they were counted
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    pub fn new(limit: ReconnectLimit) -> Self {
+        let capacity = limit.attempts as f64;
+        Self {
+            capacity,
+            per_sec: capacity / limit.per as f64,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let (tokens, counted) = &mut *state;
+                *tokens =
+                    (*tokens + counted.elapsed().as_secs_f64() * self.per_sec).min(self.capacity);
+                *counted = Instant::now();
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - *tokens) / self.per_sec)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn one_line_per_window_with_the_count_held_back() {
+        let mut throttle = LogThrottle::new(Duration::from_secs(10));
+        assert_eq!(throttle.admit(), Some(0));
+        assert_eq!(throttle.admit(), None);
+        tokio::time::advance(Duration::from_secs(9)).await;
+        assert_eq!(throttle.admit(), None);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(throttle.admit(), Some(2));
+        assert_eq!(throttle.admit(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnects_past_the_burst_are_spread_out() {
+        let bucket = Bucket::new(ReconnectLimit {
+            attempts: 5,
+            per: 10,
+        });
+        let started = Instant::now();
+        for _ in 0..5 {
+            bucket.acquire().await;
+        }
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        bucket.acquire().await;
+        assert_eq!(started.elapsed().as_secs_f64().round(), 2.0);
+        bucket.acquire().await;
+        assert_eq!(started.elapsed().as_secs_f64().round(), 4.0);
+    }
+}
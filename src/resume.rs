@@ -0,0 +1,370 @@
+use std::{fs, io, path::PathBuf};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::compress;
+use crate::{fnv1a, template_version, CanvasSpec, PixelInfo, PixelProvider, Point};
+
+// Which pixels of the template are already on the canvas, kept across runs
+#[derive(Serialize, Deserialize)]
+pub struct StateFile {
+    canvas: CanvasSpec,
+    // Top left corner of the template's bounding box
+    offset: Point,
+    hash: u64,
+    width: u32,
+    height: u32,
+    colors: Vec<u8>,
+    done: Vec<u8>,
+    // Written after the rest, so files from before pinning still load
+    #[serde(skip)]
+    pinned: Vec<Point>,
+    // Likewise after the pins; empty in files from before versions
+    #[serde(skip)]
+    version: String,
+}
+
+impl StateFile {
+    const NONE: u8 = u8::MAX;
+
+    fn new(pixels: &[PixelInfo], canvas: CanvasSpec) -> Self {
+        let min_x = pixels.iter().map(|p| p.x).min().unwrap_or_default();
+        let min_y = pixels.iter().map(|p| p.y).min().unwrap_or_default();
+        let width = pixels
+            .iter()
+            .map(|p| p.x - min_x + 1)
+            .max()
+            .unwrap_or_default();
+        let height = pixels
+            .iter()
+            .map(|p| p.y - min_y + 1)
+            .max()
+            .unwrap_or_default();
+        let cells = (width * height) as usize;
+        let mut colors = vec![Self::NONE; cells];
+        for pixel in pixels {
+            colors[((pixel.y - min_y) * width + pixel.x - min_x) as usize] = pixel.color_id;
+        }
+        let hash = fnv1a(
+            width
+                .to_le_bytes()
+                .into_iter()
+                .chain(colors.iter().copied()),
+        );
+        Self {
+            canvas,
+            offset: Point { x: min_x, y: min_y },
+            hash,
+            width,
+            height,
+            colors,
+            done: vec![0; cells.div_ceil(8)],
+            pinned: Vec::new(),
+            version: template_version(pixels),
+        }
+    }
+
+    fn index(&self, x: u32, y: u32) -> Option<usize> {
+        let dx = x.checked_sub(self.offset.x)?;
+        let dy = y.checked_sub(self.offset.y)?;
+        (dx < self.width && dy < self.height).then(|| (dy * self.width + dx) as usize)
+    }
+
+    fn is_done(&self, index: usize) -> bool {
+        self.done[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    fn set_done(&mut self, index: usize, done: bool) {
+        if done {
+            self.done[index / 8] |= 1 << (index % 8);
+        } else {
+            self.done[index / 8] &= !(1 << (index % 8));
+        }
+    }
+
+    // Whether `old` is the top left corner of this template, unchanged
+    fn extends(&self, old: &Self) -> bool {
+        old.width <= self.width
+            && old.height <= self.height
+            && (old.width, old.height) != (self.width, self.height)
+            && (0..old.colors.len()).all(|index| {
+                let (dx, dy) = (index as u32 % old.width, index as u32 / old.width);
+                old.colors[index] == self.colors[(dy * self.width + dx) as usize]
+            })
+    }
+
+    fn load(path: &PathBuf) -> anyhow::Result<Option<Self>> {
+        match compress::open(path) {
+            Ok(mut reader) => {
+                let mut state: Self = bincode::deserialize_from(&mut reader)?;
+                state.pinned = bincode::deserialize_from(&mut reader).unwrap_or_default();
+                state.version = bincode::deserialize_from(&mut reader).unwrap_or_default();
+                Ok(Some(state))
+            }
+            Err(why) if why.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(why) => Err(why.into()),
+        }
+    }
+
+    // Carries over progress from `path` and returns the pixels still to paint
+    pub fn resume(
+        path: &PathBuf,
+        pixels: Vec<PixelInfo>,
+        canvas: CanvasSpec,
+    ) -> anyhow::Result<(Self, Vec<PixelInfo>)> {
+        let mut state = Self::new(&pixels, canvas);
+        let old = Self::load(path)?;
+        if let Some(old) = &old {
+            state.pinned.clone_from(&old.pinned);
+        }
+        match old {
+            None => info!("No state at {}; starting fresh", path.display()),
+            Some(old) if old.canvas != canvas => warn!(
+                "{} was saved for a different canvas spec; starting over",
+                path.display()
+            ),
+            Some(old) if state.extends(&old) => {
+                // Rows or columns were appended, so every old bit still applies as is
+                let mut kept = 0;
+                for previous in 0..old.colors.len() {
+                    if old.colors[previous] == Self::NONE || !old.is_done(previous) {
+                        continue;
+                    }
+                    let (dx, dy) = (previous as u32 % old.width, previous as u32 / old.width);
+                    state.set_done((dy * state.width + dx) as usize, true);
+                    kept += 1;
+                }
+                info!(
+                    "Template grew from {}x{} to {}x{} (version {} to {}); keeping progress and queueing only the new area",
+                    old.width,
+                    old.height,
+                    state.width,
+                    state.height,
+                    old.version(),
+                    state.version
+                );
+                info!("Resuming with {kept} pixels already painted");
+            }
+            Some(old) => {
+                let (mut kept, mut changed) = (0, 0);
+                for index in 0..state.colors.len() {
+                    let color = state.colors[index];
+                    if color == Self::NONE {
+                        continue;
+                    }
+                    // Matching relative positions makes a moved template keep its progress
+                    let (dx, dy) = (index as u32 % state.width, index as u32 / state.width);
+                    let Some(previous) = old.index(old.offset.x + dx, old.offset.y + dy) else {
+                        continue;
+                    };
+                    if !old.is_done(previous) {
+                        continue;
+                    }
+                    if old.colors[previous] == color {
+                        state.set_done(index, true);
+                        kept += 1;
+                    } else {
+                        changed += 1;
+                    }
+                }
+                if old.offset != state.offset {
+                    info!(
+                        "Template moved from {},{} to {},{}; translating progress",
+                        old.offset.x, old.offset.y, state.offset.x, state.offset.y
+                    );
+                }
+                if old.hash != state.hash {
+                    info!(
+                        "Template changed since the last run (version {} to {}); diffed pixel by pixel, {changed} painted pixels differ now",
+                        old.version(),
+                        state.version
+                    );
+                }
+                info!("Resuming with {kept} pixels already painted");
+            }
+        }
+        let remaining = pixels
+            .into_iter()
+            .filter(|pixel| {
+                state
+                    .index(pixel.x, pixel.y)
+                    .is_none_or(|index| !state.is_done(index))
+            })
+            .collect();
+        Ok((state, remaining))
+    }
+
+    fn version(&self) -> &str {
+        if self.version.is_empty() {
+            "unknown"
+        } else {
+            &self.version
+        }
+    }
+
+    pub fn pinned(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.pinned.iter().map(|point| (point.x, point.y))
+    }
+
+    // Takes in what the workers painted, forgetting pixels seen overwritten since
+    pub fn record(&mut self, pixel: &PixelProvider) {
+        self.pinned = pixel.pinned.iter().map(|&(x, y)| Point { x, y }).collect();
+        self.pinned.sort_by_key(|point| (point.y, point.x));
+        for (&(x, y), target) in &pixel.target {
+            let Some(index) = self.index(x, y) else {
+                continue;
+            };
+            if target.painted && self.colors[index] == target.color_id {
+                self.set_done(index, target.intact);
+            }
+        }
+    }
+
+    pub fn save(&self, path: &PathBuf) -> anyhow::Result<()> {
+        // Write aside and rename so a crash never leaves a torn file
+        let partial = path.with_extension("partial");
+        let mut writer = compress::create(&partial, compress::wanted(path))?;
+        bincode::serialize_into(&mut writer, self)?;
+        bincode::serialize_into(&mut writer, &self.pinned)?;
+        bincode::serialize_into(&mut writer, &self.version)?;
+        io::Write::flush(&mut writer)?;
+        drop(writer);
+        fs::rename(partial, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{provider_of, scratch};
+
+    // Rows of color ids with the top left corner at `(x, y)`; NONE leaves a hole
+    fn template((x, y): (u32, u32), rows: &[&[u8]]) -> Vec<PixelInfo> {
+        rows.iter()
+            .enumerate()
+            .flat_map(|(dy, row)| {
+                row.iter()
+                    .enumerate()
+                    .filter(|&(_, &color_id)| color_id != StateFile::NONE)
+                    .map(move |(dx, &color_id)| PixelInfo {
+                        x: x + dx as u32,
+                        y: y + dy as u32,
+                        color_id,
+                    })
+            })
+            .collect()
+    }
+
+    // Runs `pixels` with the first `painted` of them painted and saves the state
+    fn run(path: &PathBuf, pixels: Vec<PixelInfo>, painted: usize) {
+        let (mut state, remaining) =
+            StateFile::resume(path, pixels, CanvasSpec::default()).unwrap();
+        let mut pixel = provider_of(remaining);
+        for _ in 0..painted {
+            let next = pixel.get_pixel(0).unwrap();
+            pixel.painted(&next);
+        }
+        state.record(&pixel);
+        state.save(path).unwrap();
+    }
+
+    fn remaining(path: &PathBuf, pixels: Vec<PixelInfo>) -> Vec<(u32, u32)> {
+        let (_, remaining) = StateFile::resume(path, pixels, CanvasSpec::default()).unwrap();
+        remaining.iter().map(|pixel| (pixel.x, pixel.y)).collect()
+    }
+
+    #[test]
+    fn painted_pixels_are_not_queued_again() {
+        let path = scratch("resume-painted.state");
+        let pixels = template((10, 5), &[&[1, 2, 3, 4]]);
+        assert_eq!(remaining(&path, pixels.clone()).len(), 4);
+        run(&path, pixels.clone(), 3);
+        assert_eq!(remaining(&path, pixels.clone()), [(13, 5)]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn changed_pixels_are_queued_again() {
+        let path = scratch("resume-changed.state");
+        run(&path, template((10, 5), &[&[1, 2, 3, 4]]), 4);
+        let changed = template((10, 5), &[&[1, 9, 3, 4]]);
+        assert_eq!(remaining(&path, changed), [(11, 5)]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn moved_templates_keep_their_progress() {
+        let path = scratch("resume-moved.state");
+        run(&path, template((10, 5), &[&[1, 2], &[3, 4]]), 2);
+        let moved = template((100, 50), &[&[1, 2], &[3, 4]]);
+        assert_eq!(remaining(&path, moved), [(100, 51), (101, 51)]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn grown_templates_queue_only_the_new_area() {
+        let path = scratch("resume-grown.state");
+        run(&path, template((10, 5), &[&[1, 2], &[3, 4]]), 4);
+        let grown = template((10, 5), &[&[1, 2, 5], &[3, 4, 6], &[7, 8, 9]]);
+        let mut left = remaining(&path, grown);
+        left.sort_unstable();
+        assert_eq!(left, [(10, 7), (11, 7), (12, 5), (12, 6), (12, 7)]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn another_canvas_spec_starts_over() {
+        let path = scratch("resume-spec.state");
+        let pixels = template((10, 5), &[&[1, 2]]);
+        run(&path, pixels.clone(), 2);
+        let spec = CanvasSpec {
+            swap_axes: true,
+            ..CanvasSpec::default()
+        };
+        let (_, remaining) = StateFile::resume(&path, pixels, spec).unwrap();
+        assert_eq!(remaining.len(), 2);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn pins_are_kept_across_runs() {
+        let path = scratch("resume-pins.state");
+        let pixels = template((10, 5), &[&[1, 2]]);
+        let (mut state, remaining) =
+            StateFile::resume(&path, pixels.clone(), CanvasSpec::default()).unwrap();
+        let mut pixel = provider_of(remaining);
+        pixel.pin(11, 5);
+        pixel.pin(3, 3);
+        state.record(&pixel);
+        state.save(&path).unwrap();
+        let (state, _) = StateFile::resume(&path, pixels, CanvasSpec::default()).unwrap();
+        assert_eq!(state.pinned().collect::<Vec<_>>(), [(3, 3), (11, 5)]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn versions_follow_the_template_into_the_state_file() {
+        let path = scratch("resume-versions.state");
+        let first = template((10, 5), &[&[1, 2]]);
+        let second = template((10, 5), &[&[1, 3]]);
+        assert_eq!(template_version(&first), template_version(&first.clone()));
+        assert_ne!(template_version(&first), template_version(&second));
+        run(&path, first.clone(), 2);
+        let saved = StateFile::load(&path).unwrap().unwrap();
+        assert_eq!(saved.version(), template_version(&first));
+        // The changed template is saved as the new version
+        run(&path, second.clone(), 1);
+        let saved = StateFile::load(&path).unwrap().unwrap();
+        assert_eq!(saved.version(), template_version(&second));
+        // Files from before versions still load, naming theirs unknown
+        let (old, _) = StateFile::resume(&path, first, CanvasSpec::default()).unwrap();
+        let mut writer = fs::File::create(&path).unwrap();
+        bincode::serialize_into(&mut writer, &old).unwrap();
+        drop(writer);
+        let saved = StateFile::load(&path).unwrap().unwrap();
+        assert_eq!(saved.version(), "unknown");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::proxy::{Stage, StageFailed};
+
+// Per category overrides of the built-in retry policies
+#[derive(Deserialize, Default, Clone, Copy)]
+pub struct RetryConfig {
+    #[serde(default)]
+    connect: Override,
+    #[serde(default)]
+    handshake: Override,
+    // Once called send, when it was the only policy for failed sends
+    #[serde(default, alias = "send")]
+    bench: Override,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+struct Override {
+    // 0 retries forever
+    max_attempts: Option<u32>,
+    base_backoff: Option<u64>,
+    max_backoff: Option<u64>,
+}
+
+#[derive(Clone, Copy)]
+pub enum Category {
+    // The server could not be reached or refused the upgrade
+    Connect,
+    // TLS failed, usually a bad proxy or certificate that retrying won't fix
+    Handshake,
+    // Benchings after failed sends in a row
+    Bench,
+}
+
+impl Category {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Connect => "connect",
+            Self::Handshake => "handshake",
+            Self::Bench => "bench",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Policy {
+    category: Category,
+    max_attempts: u32,
+    base_backoff: Duration,
+    max_backoff: Duration,
+    growth: u32,
+}
+
+impl RetryConfig {
+    pub fn policy(&self, category: Category) -> Policy {
+        let (tuned, max_attempts, base_backoff, max_backoff, growth) = match category {
+            Category::Connect => (self.connect, 0, 5, 60, 2),
+            Category::Handshake => (self.handshake, 5, 5, 60, 2),
+            Category::Bench => (self.bench, 0, 60, 25 * 60, 5),
+        };
+        Policy {
+            category,
+            max_attempts: tuned.max_attempts.unwrap_or(max_attempts),
+            base_backoff: Duration::from_secs(tuned.base_backoff.unwrap_or(base_backoff)),
+            max_backoff: Duration::from_secs(tuned.max_backoff.unwrap_or(max_backoff)),
+            growth,
+        }
+    }
+
+    pub fn check(&self) -> anyhow::Result<()> {
+        for category in [Category::Connect, Category::Handshake, Category::Bench] {
+            let policy = self.policy(category);
+            if policy.base_backoff.is_zero() || policy.base_backoff > policy.max_backoff {
+                Err(anyhow::anyhow!(
+                    "retry_policy.{} needs 0 < base_backoff <= max_backoff",
+                    category.name()
+                ))?
+            }
+        }
+        Ok(())
+    }
+}
+
+// Waits growing from base_backoff by `growth` each attempt, capped at max_backoff
+pub struct Backoff {
+    policy: Policy,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: Policy) -> Self {
+        Self { policy, attempt: 0 }
+    }
+
+    // The wait before the next attempt, or None once the policy gives up
+    pub fn next(&mut self) -> Option<Duration> {
+        let policy = &self.policy;
+        if policy.max_attempts > 0 && self.attempt >= policy.max_attempts {
+            return None;
+        }
+        let wait = policy
+            .base_backoff
+            .saturating_mul(policy.growth.saturating_pow(self.attempt))
+            .min(policy.max_backoff);
+        self.attempt += 1;
+        Some(wait)
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+impl std::fmt::Display for Backoff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} policy, attempt {}",
+            self.policy.category.name(),
+            self.attempt
+        )?;
+        if self.policy.max_attempts > 0 {
+            write!(f, " of {}", self.policy.max_attempts)?;
+        }
+        Ok(())
+    }
+}
+
+// Connection attempts keep one backoff per way they can fail
+pub struct ConnectRetries {
+    connect: Backoff,
+    handshake: Backoff,
+}
+
+impl ConnectRetries {
+    pub fn new(config: &RetryConfig) -> Self {
+        Self {
+            connect: Backoff::new(config.policy(Category::Connect)),
+            handshake: Backoff::new(config.policy(Category::Handshake)),
+        }
+    }
+
+    pub fn pick(&mut self, why: &anyhow::Error) -> &mut Backoff {
+        let proxied_tls = why
+            .downcast_ref::<StageFailed>()
+            .is_some_and(|failed| failed.stage == Stage::TlsHandshake);
+        if why.is::<HandshakeFailed>() || proxied_tls {
+            &mut self.handshake
+        } else {
+            &mut self.connect
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.connect.reset();
+        self.handshake.reset();
+    }
+}
+
+#[derive(Debug)]
+pub struct HandshakeFailed {
+    pub host: String,
+    pub why: String,
+}
+
+impl std::fmt::Display for HandshakeFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TLS handshake with {} failed: {}", self.host, self.why)
+    }
+}
+
+impl std::error::Error for HandshakeFailed {}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+
+    fn waits(policy: Policy, n: usize) -> Vec<Option<u64>> {
+        let mut backoff = Backoff::new(policy);
+        (0..n)
+            .map(|_| backoff.next().map(|wait| wait.as_secs()))
+            .collect()
+    }
+
+    #[test]
+    fn built_in_policies_grow_to_their_cap() {
+        let config = RetryConfig::default();
+        assert_eq!(
+            waits(config.policy(Category::Connect), 6),
+            [5, 10, 20, 40, 60, 60].map(Some)
+        );
+        assert_eq!(
+            waits(config.policy(Category::Handshake), 6),
+            [Some(5), Some(10), Some(20), Some(40), Some(60), None]
+        );
+        assert_eq!(
+            waits(config.policy(Category::Bench), 5),
+            [60, 300, 1500, 1500, 1500].map(Some)
+        );
+    }
+
+    #[test]
+    fn categories_are_tuned_on_their_own() {
+        let config: RetryConfig = serde_json::from_str(
+            r#"{"connect": {"max_attempts": 2, "base_backoff": 1}, "bench": {"max_backoff": 120}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            waits(config.policy(Category::Connect), 3),
+            [Some(1), Some(2), None]
+        );
+        assert_eq!(
+            waits(config.policy(Category::Handshake), 2),
+            [Some(5), Some(10)]
+        );
+        assert_eq!(
+            waits(config.policy(Category::Bench), 3),
+            [60, 120, 120].map(Some)
+        );
+        let renamed: RetryConfig =
+            serde_json::from_str(r#"{"send": {"max_backoff": 120}}"#).unwrap();
+        assert_eq!(
+            waits(renamed.policy(Category::Bench), 3),
+            [60, 120, 120].map(Some)
+        );
+    }
+
+    #[test]
+    fn a_reset_starts_over() {
+        let mut backoff = Backoff::new(RetryConfig::default().policy(Category::Handshake));
+        for _ in 0..5 {
+            backoff.next();
+        }
+        assert_eq!(backoff.to_string(), "handshake policy, attempt 5 of 5");
+        assert_eq!(backoff.next(), None);
+        backoff.reset();
+        assert_eq!(backoff.next(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn backoffs_have_to_fit_their_cap() {
+        for (document, valid) in [
+            (r#"{}"#, true),
+            (r#"{"bench": {"base_backoff": 0}}"#, false),
+            (r#"{"connect": {"base_backoff": 90}}"#, false),
+            (
+                r#"{"connect": {"base_backoff": 90, "max_backoff": 90}}"#,
+                true,
+            ),
+        ] {
+            let config: RetryConfig = serde_json::from_str(document).unwrap();
+            assert_eq!(config.check().is_ok(), valid, "{document}");
+        }
+    }
+
+    #[test]
+    fn tls_failures_use_the_handshake_policy() {
+        let mut retries = ConnectRetries::new(&RetryConfig::default());
+        let proxy = Url::parse("socks5://127.0.0.1:1080").unwrap();
+        for (why, category) in [
+            (anyhow::anyhow!("connection refused"), "connect"),
+            (
+                anyhow::Error::new(HandshakeFailed {
+                    host: "example.com".into(),
+                    why: "bad certificate".into(),
+                }),
+                "handshake",
+            ),
+            (
+                anyhow::Error::new(StageFailed::new(Stage::TlsHandshake, &proxy, "eof")),
+                "handshake",
+            ),
+            (
+                anyhow::Error::new(StageFailed::new(Stage::ProxyConnect, &proxy, "refused")),
+                "connect",
+            ),
+        ] {
+            let picked = retries.pick(&why).to_string();
+            assert!(picked.starts_with(category), "{why}: {picked}");
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use serde::Deserialize;
+
+use crate::clock::{SystemClock, WallClock};
+
+const DAY: u64 = 24 * 60 * 60;
+
+// Daily windows in UTC during which bots are allowed to paint
+#[derive(Deserialize)]
+pub struct Schedule {
+    windows: Vec<Window>,
+    #[serde(default)]
+    pub disconnect_when_idle: bool,
+}
+
+#[derive(Deserialize)]
+struct Window {
+    #[serde(deserialize_with = "deserialize_time")]
+    start: u64,
+    #[serde(deserialize_with = "deserialize_time")]
+    end: u64,
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let time = String::deserialize(deserializer)?;
+    parse_time(&time).map_err(serde::de::Error::custom)
+}
+
+fn parse_time(time: &str) -> anyhow::Result<u64> {
+    let (hours, minutes) = time
+        .split_once(':')
+        .ok_or_else(|| anyhow!("{time} is not a HH:MM time"))?;
+    let (hours, minutes) = (hours.parse::<u64>()?, minutes.parse::<u64>()?);
+    if hours > 24 || minutes > 59 || hours * 60 + minutes > 24 * 60 {
+        Err(anyhow!("{time} is not a time of day"))?
+    }
+    Ok((hours * 60 + minutes) * 60)
+}
+
+impl Schedule {
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.windows.is_empty() {
+            Err(anyhow!("schedule.windows must not be empty"))?
+        }
+        Ok(())
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.until_active().is_zero()
+    }
+
+    pub fn until_active(&self) -> Duration {
+        self.until_active_on(&SystemClock)
+    }
+
+    pub fn until_active_on(&self, clock: &dyn WallClock) -> Duration {
+        let since_epoch = clock.now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let now = since_epoch.as_secs() % DAY;
+        let wait = self
+            .windows
+            .iter()
+            .map(|Window { start, end }| {
+                let inside = if start <= end {
+                    (*start..*end).contains(&now)
+                } else {
+                    // Window wraps around midnight
+                    now >= *start || now < *end
+                };
+                if inside {
+                    0
+                } else {
+                    (start + DAY - now) % DAY
+                }
+            })
+            .min()
+            .unwrap_or_default();
+        Duration::from_secs(wait)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    struct At(u64);
+
+    impl WallClock for At {
+        fn now(&self) -> SystemTime {
+            // Some day at midnight UTC, plus the seconds given
+            UNIX_EPOCH + Duration::from_secs(19_000 * DAY + self.0)
+        }
+    }
+
+    fn schedule(windows: &[(&str, &str)]) -> Schedule {
+        let windows = windows
+            .iter()
+            .map(|(start, end)| serde_json::json!({ "start": start, "end": end }))
+            .collect::<Vec<_>>();
+        serde_json::from_value(serde_json::json!({ "windows": windows })).unwrap()
+    }
+
+    fn wait_at(schedule: &Schedule, hours: u64, minutes: u64) -> u64 {
+        schedule
+            .until_active_on(&At((hours * 60 + minutes) * 60))
+            .as_secs()
+    }
+
+    #[test]
+    fn times_are_hours_and_minutes() {
+        assert_eq!(parse_time("00:00").unwrap(), 0);
+        assert_eq!(parse_time("18:30").unwrap(), (18 * 60 + 30) * 60);
+        assert_eq!(parse_time("24:00").unwrap(), DAY);
+        for invalid in ["24:01", "12:60", "1230", "noon", "-1:00"] {
+            assert!(parse_time(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn waits_until_the_next_window() {
+        let schedule = schedule(&[("08:00", "12:00"), ("18:00", "22:00")]);
+        assert_eq!(wait_at(&schedule, 9, 0), 0);
+        assert_eq!(wait_at(&schedule, 12, 0), 6 * 3600);
+        assert_eq!(wait_at(&schedule, 17, 30), 30 * 60);
+        assert_eq!(wait_at(&schedule, 23, 0), 9 * 3600);
+    }
+
+    #[test]
+    fn windows_may_wrap_around_midnight() {
+        let schedule = schedule(&[("22:00", "02:00")]);
+        assert_eq!(wait_at(&schedule, 23, 0), 0);
+        assert_eq!(wait_at(&schedule, 1, 59), 0);
+        assert_eq!(wait_at(&schedule, 2, 0), 20 * 3600);
+    }
+
+    #[test]
+    fn an_empty_schedule_is_refused() {
+        assert!(schedule(&[]).check().is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use log::*;
+use serde::Deserialize;
+
+// The part of the config read before `${NAME}` references are expanded
+#[derive(Deserialize)]
+pub struct Prelude {
+    // key=value lines, kept out of the main config so it can be shared
+    secrets_file: Option<PathBuf>,
+    #[serde(default)]
+    secrets_permissions: Permissions,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Permissions {
+    // Refuse to start if other users may read the secrets file
+    #[default]
+    Strict,
+    Warn,
+}
+
+pub struct Variables {
+    secrets: HashMap<String, String>,
+    secrets_file: Option<PathBuf>,
+    dotenv: bool,
+}
+
+impl Variables {
+    pub fn load(prelude: Prelude) -> anyhow::Result<Self> {
+        let secrets = match &prelude.secrets_file {
+            Some(path) => {
+                check_permissions(path, prelude.secrets_permissions)?;
+                let text = fs::read_to_string(path)
+                    .map_err(|why| anyhow!("Cannot read {}: {why}", path.display()))?;
+                parse(&text)
+            }
+            None => HashMap::new(),
+        };
+        Ok(Self {
+            secrets,
+            secrets_file: prelude.secrets_file,
+            dotenv: dotenvy::dotenv_iter().is_ok(),
+        })
+    }
+
+    // The environment, which .env was loaded into, wins over the secrets file
+    fn get(&self, name: &str) -> Option<String> {
+        env::var(name)
+            .ok()
+            .or_else(|| self.secrets.get(name).cloned())
+    }
+
+    fn searched(&self) -> String {
+        let mut sources = vec!["the environment".to_string()];
+        if self.dotenv {
+            sources.push(".env".into());
+        }
+        if let Some(path) = &self.secrets_file {
+            sources.push(format!("secrets file {}", path.display()));
+        }
+        let last = sources.pop().unwrap_or_default();
+        if sources.is_empty() {
+            last
+        } else {
+            format!("{} or {last}", sources.join(", "))
+        }
+    }
+
+    // Replaces every ${NAME} in `document`
+    pub fn expand(&self, document: &str) -> anyhow::Result<String> {
+        let mut expanded = String::with_capacity(document.len());
+        let mut rest = document;
+        while let Some(start) = rest.find("${") {
+            expanded.push_str(&rest[..start]);
+            let Some(end) = rest[start..].find('}') else {
+                Err(anyhow!("Unterminated ${{ in config"))?
+            };
+            let name = &rest[start + 2..start + end];
+            match self.get(name) {
+                Some(value) => expanded.push_str(&value),
+                None => Err(anyhow!(
+                    "Config refers to ${{{name}}}, which is not set in {}",
+                    self.searched()
+                ))?,
+            }
+            rest = &rest[start + end + 1..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+}
+
+fn parse(text: &str) -> HashMap<String, String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            (key.trim().to_string(), value.to_string())
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path, permissions: Permissions) -> anyhow::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mode = fs::metadata(path)
+        .map_err(|why| anyhow!("Cannot read {}: {why}", path.display()))?
+        .mode();
+    if mode & 0o004 == 0 {
+        return Ok(());
+    }
+    let problem = format!(
+        "{} is readable by every user (mode {:o}); chmod o-r it",
+        path.display(),
+        mode & 0o777
+    );
+    match permissions {
+        Permissions::Strict => Err(anyhow!("{problem}, or set secrets_permissions to warn")),
+        Permissions::Warn => {
+            warn!("{problem}");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_: &Path, _: Permissions) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(secrets: &str) -> Variables {
+        Variables {
+            secrets: parse(secrets),
+            secrets_file: Some("secrets.env".into()),
+            dotenv: false,
+        }
+    }
+
+    #[test]
+    fn secrets_files_are_key_value_lines() {
+        let secrets =
+            parse("# proxies\nPROXY = socks5://a:b@host \n\nHOOK=\"https://x/y\"\nnonsense\n");
+        assert_eq!(secrets.len(), 2);
+        assert_eq!(secrets["PROXY"], "socks5://a:b@host");
+        assert_eq!(secrets["HOOK"], "https://x/y");
+    }
+
+    #[test]
+    fn the_environment_wins_over_the_secrets_file() {
+        env::set_var("PB_TEST_SHADOWED", "from env");
+        let variables = variables("PB_TEST_SHADOWED=from file\nPB_TEST_SECRET=hunter2");
+        let expanded = variables
+            .expand("a = \"${PB_TEST_SHADOWED}\"\nb = \"${PB_TEST_SECRET}\"")
+            .unwrap();
+        assert_eq!(expanded, "a = \"from env\"\nb = \"hunter2\"");
+    }
+
+    #[test]
+    fn missing_variables_name_where_they_were_looked_up() {
+        let why = variables("").expand("${PB_TEST_UNSET}").err().unwrap();
+        assert_eq!(
+            why.to_string(),
+            "Config refers to ${PB_TEST_UNSET}, which is not set in the environment or secrets file secrets.env"
+        );
+        let variables = Variables {
+            dotenv: true,
+            ..variables("")
+        };
+        assert!(variables
+            .expand("${PB_TEST_UNSET}")
+            .err()
+            .unwrap()
+            .to_string()
+            .ends_with("not set in the environment, .env or secrets file secrets.env"));
+        assert!(variables.expand("x = ${PB_TEST_UNSET").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn world_readable_secrets_are_refused_unless_warned_about() {
+        use std::os::unix::fs::PermissionsExt;
+
+        use crate::tests::scratch;
+
+        let path = scratch("secrets.env");
+        fs::write(&path, "TOKEN=abc").unwrap();
+        let chmod = |mode| fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        let load = |permissions| {
+            Variables::load(Prelude {
+                secrets_file: Some(path.clone()),
+                secrets_permissions: permissions,
+            })
+        };
+        chmod(0o644);
+        let why = load(Permissions::Strict).err().unwrap().to_string();
+        assert!(why.contains("(mode 644); chmod o-r it"), "{why}");
+        assert_eq!(load(Permissions::Warn).unwrap().secrets["TOKEN"], "abc");
+        chmod(0o600);
+        assert_eq!(load(Permissions::Strict).unwrap().secrets["TOKEN"], "abc");
+    }
+}
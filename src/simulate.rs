@@ -0,0 +1,132 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_tungstenite::tungstenite::Message;
+use futures::{SinkExt, StreamExt};
+use rand::Rng;
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, Mutex},
+    time::Instant,
+};
+
+use crate::{Context, PixelInfo, PixelProvider};
+
+// Stand-in for the game server on a loopback socket
+pub struct Server {
+    canvas: Arc<Mutex<Vec<u8>>>,
+    updates: broadcast::Sender<Vec<u8>>,
+    // Speaks the codec and palette of the run it stands in for
+    context: Arc<Context>,
+    pub rejected: Arc<AtomicU32>,
+}
+
+impl Server {
+    const BACKLOG: usize = 4096;
+
+    pub async fn start(cooldown: Duration, context: Context) -> anyhow::Result<(Self, String)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}/", listener.local_addr()?);
+        let server = Self {
+            canvas: Arc::new(Mutex::new(vec![0; PixelProvider::SIZE as usize])),
+            updates: broadcast::channel(Self::BACKLOG).0,
+            context: Arc::new(context),
+            rejected: Arc::new(AtomicU32::new(0)),
+        };
+        let (canvas, updates) = (server.canvas.clone(), server.updates.clone());
+        let (context, rejected) = (server.context.clone(), server.rejected.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (canvas, updates) = (canvas.clone(), updates.clone());
+                let (context, rejected) = (context.clone(), rejected.clone());
+                tokio::spawn(async move {
+                    let Ok(connection) = async_tungstenite::tokio::accept_async(stream).await
+                    else {
+                        return;
+                    };
+                    let (mut sink, mut source) = connection.split();
+                    let mut receiver = updates.subscribe();
+                    let mut last_paint: Option<Instant> = None;
+                    loop {
+                        tokio::select! {
+                            msg = source.next() => match msg {
+                                Some(Ok(Message::Binary(frame))) => {
+                                    if last_paint.is_some_and(|last| last.elapsed() < cooldown) {
+                                        rejected.fetch_add(1, Ordering::Relaxed);
+                                        continue;
+                                    }
+                                    last_paint = Some(Instant::now());
+                                    Self::apply(&canvas, &context, &frame).await;
+                                    drop(updates.send(frame));
+                                }
+                                Some(Ok(Message::Ping(payload))) => {
+                                    drop(sink.send(Message::Pong(payload)).await);
+                                }
+                                Some(Ok(_)) => {}
+                                _ => return,
+                            },
+                            Ok(frame) = receiver.recv() => {
+                                drop(sink.send(Message::Binary(frame)).await);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        Ok((server, url))
+    }
+
+    async fn apply(canvas: &Mutex<Vec<u8>>, context: &Context, frame: &[u8]) {
+        let mut canvas = canvas.lock().await;
+        for pixel in context.decode(frame) {
+            canvas[(pixel.y * PixelProvider::MAX_WIDTH + pixel.x) as usize] = pixel.color_id;
+        }
+    }
+
+    // Overwrites a random target pixel with a wrong color `per_second` times a second
+    pub fn grief(&self, targets: Vec<PixelInfo>, per_second: f64) -> impl Future<Output = ()> {
+        let (canvas, updates) = (self.canvas.clone(), self.updates.clone());
+        let context = self.context.clone();
+        async move {
+            if targets.is_empty() || per_second <= 0.0 {
+                return;
+            }
+            let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / per_second));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let target = &targets[rand::thread_rng().gen_range(0..targets.len())];
+                let hostile = PixelInfo {
+                    x: target.x,
+                    y: target.y,
+                    color_id: ((target.color_id as usize + 1) % context.palette().len()) as u8,
+                };
+                let Ok(frame) = context.pack(hostile) else {
+                    continue;
+                };
+                Self::apply(&canvas, &context, &frame).await;
+                drop(updates.send(frame));
+            }
+        }
+    }
+
+    pub async fn snapshot(&self) -> image::RgbaImage {
+        let canvas = self.canvas.lock().await;
+        let palette = self.context.palette();
+        image::RgbaImage::from_fn(
+            PixelProvider::MAX_WIDTH,
+            PixelProvider::MAX_HEIGHT,
+            |x, y| {
+                let color_id = canvas[(y * PixelProvider::MAX_WIDTH + x) as usize];
+                let (r, g, b) = palette.rgb_of(color_id).unwrap_or_default();
+                image::Rgba([r, g, b, 255])
+            },
+        )
+    }
+}
@@ -0,0 +1,422 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicU8, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::cooldown::RampUp;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum State {
+    Connecting,
+    Connected,
+    Disconnected,
+    Reconnecting,
+    Throttled,
+    Benched,
+    Idle,
+    // Defending with nothing to repaint
+    Watching,
+    Quarantined,
+    // Held back after a suspected canvas reset
+    Paused,
+    Stopped,
+}
+
+impl State {
+    const ALL: [State; 11] = [
+        Self::Connecting,
+        Self::Connected,
+        Self::Disconnected,
+        Self::Reconnecting,
+        Self::Throttled,
+        Self::Benched,
+        Self::Idle,
+        Self::Watching,
+        Self::Quarantined,
+        Self::Paused,
+        Self::Stopped,
+    ];
+
+    fn from_u8(value: u8) -> Self {
+        Self::ALL[value as usize]
+    }
+}
+
+// Instants are kept as milliseconds since the registry started; this one means never
+const NEVER: i64 = -1;
+
+// Counters of one worker, written by its own bot loop and read by anyone without a lock
+pub struct BotStats {
+    name: String,
+    url: String,
+    epoch: Instant,
+    state: AtomicU8,
+    since: AtomicI64,
+    last_paint: AtomicI64,
+    next_paint: AtomicI64,
+    sends: AtomicU64,
+    failures: AtomicU64,
+    reconnects: AtomicU64,
+    refreshes: AtomicU64,
+    refresh_failures: AtomicU64,
+    benched: AtomicU64,
+    rx_bytes: AtomicU64,
+    tx_bytes: AtomicU64,
+    // Broadcasts are ignored after max_rx_bytes_per_hour
+    reduced: AtomicBool,
+}
+
+impl BotStats {
+    fn new(name: String, url: String, epoch: Instant) -> Self {
+        let stats = Self {
+            name,
+            url,
+            epoch,
+            state: AtomicU8::new(State::Connecting as u8),
+            since: AtomicI64::new(NEVER),
+            last_paint: AtomicI64::new(NEVER),
+            next_paint: AtomicI64::new(NEVER),
+            sends: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            refreshes: AtomicU64::new(0),
+            refresh_failures: AtomicU64::new(0),
+            benched: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            reduced: AtomicBool::new(false),
+        };
+        stats
+            .since
+            .store(stats.stamp(Instant::now()), Ordering::Relaxed);
+        stats
+    }
+
+    fn stamp(&self, at: Instant) -> i64 {
+        at.saturating_duration_since(self.epoch).as_millis() as i64
+    }
+
+    fn instant(&self, stamp: &AtomicI64) -> Option<Instant> {
+        match stamp.load(Ordering::Relaxed) {
+            NEVER => None,
+            millis => Some(self.epoch + Duration::from_millis(millis as u64)),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        State::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, state: State) {
+        if self.state.swap(state as u8, Ordering::Relaxed) != state as u8 {
+            self.since
+                .store(self.stamp(Instant::now()), Ordering::Relaxed);
+        }
+    }
+
+    // Quarantined workers stay reported as such
+    pub fn finish(&self) {
+        let stopped = self
+            .state
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |state| {
+                (state != State::Quarantined as u8).then_some(State::Stopped as u8)
+            });
+        if stopped.is_ok_and(|previous| previous != State::Stopped as u8) {
+            self.since
+                .store(self.stamp(Instant::now()), Ordering::Relaxed);
+        }
+    }
+
+    pub fn sent(&self, succeeded: bool) {
+        if succeeded {
+            self.sends.fetch_add(1, Ordering::Relaxed);
+            self.last_paint
+                .store(self.stamp(Instant::now()), Ordering::Relaxed);
+        } else {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn refreshed(&self, succeeded: bool) {
+        if succeeded {
+            self.refreshes.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.refresh_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn benched(&self) {
+        self.benched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn scheduled(&self, wait: Duration) {
+        self.next_paint
+            .store(self.stamp(Instant::now() + wait), Ordering::Relaxed);
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.rx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn transmitted(&self, bytes: usize) {
+        self.tx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn reduced(&self) -> bool {
+        self.reduced.load(Ordering::Relaxed)
+    }
+
+    pub fn set_reduced(&self, reduced: bool) {
+        self.reduced.store(reduced, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, id: usize) -> BotSnapshot {
+        let secs_ago = |at: Instant| at.elapsed().as_secs();
+        BotSnapshot {
+            id,
+            name: self.name.clone(),
+            url: self.url.clone(),
+            state: self.state(),
+            for_secs: self.instant(&self.since).map(secs_ago).unwrap_or_default(),
+            last_paint_secs: self.instant(&self.last_paint).map(secs_ago),
+            next_paint_secs: self
+                .instant(&self.next_paint)
+                .map(|at| at.saturating_duration_since(Instant::now()).as_secs()),
+            sends: self.sends.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            refreshes: self.refreshes.load(Ordering::Relaxed),
+            refresh_failures: self.refresh_failures.load(Ordering::Relaxed),
+            benched: self.benched.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            reduced: self.reduced(),
+        }
+    }
+}
+
+// Every worker's stats; workers register before the run starts and update their own entry after
+#[derive(Clone)]
+pub struct StatsRegistry {
+    epoch: Instant,
+    bots: Arc<RwLock<Vec<Arc<BotStats>>>>,
+    // Users online as last reported by the server, and when
+    online: Arc<(AtomicI64, AtomicI64)>,
+    ramp: Arc<OnceLock<Arc<RampUp>>>,
+}
+
+impl Default for StatsRegistry {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            bots: Arc::default(),
+            online: Arc::new((AtomicI64::new(NEVER), AtomicI64::new(NEVER))),
+            ramp: Arc::default(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BotSnapshot {
+    pub id: usize,
+    pub name: String,
+    pub url: String,
+    pub state: State,
+    pub for_secs: u64,
+    pub last_paint_secs: Option<u64>,
+    pub next_paint_secs: Option<u64>,
+    pub sends: u64,
+    pub failures: u64,
+    pub reconnects: u64,
+    pub refreshes: u64,
+    pub refresh_failures: u64,
+    // Times the worker was benched after failed sends in a row
+    pub benched: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub reduced: bool,
+}
+
+#[derive(Serialize)]
+pub struct Online {
+    pub count: u32,
+    pub age_secs: u64,
+}
+
+// Every worker's counters at one moment; totals are worked out from these when asked
+#[derive(Serialize)]
+pub struct PoolSnapshot {
+    pub workers: Vec<BotSnapshot>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub online: Option<Online>,
+    // Cooldowns are multiplied by this while ramping up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ramp_factor: Option<f64>,
+}
+
+impl PoolSnapshot {
+    pub fn connected(&self) -> usize {
+        self.workers
+            .iter()
+            .filter(|worker| worker.state == State::Connected)
+            .count()
+    }
+
+    // Paused workers are still connected, just held back
+    pub fn connected_or_paused(&self) -> usize {
+        self.workers
+            .iter()
+            .filter(|worker| matches!(worker.state, State::Connected | State::Paused))
+            .count()
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.workers.iter().map(|worker| worker.reconnects).sum()
+    }
+
+    // How many sends `id` is ahead of the connected workers' average; None unless others can catch up
+    pub fn lead(&self, id: i32) -> Option<f64> {
+        let own = self.workers.get(id as usize)?;
+        let connected = self
+            .workers
+            .iter()
+            .filter(|worker| worker.state == State::Connected)
+            .collect::<Vec<_>>();
+        if own.state != State::Connected || connected.len() < 2 {
+            return None;
+        }
+        let total = connected
+            .iter()
+            .map(|worker| worker.sends as f64)
+            .sum::<f64>();
+        Some(own.sends as f64 - total / connected.len() as f64)
+    }
+}
+
+impl StatsRegistry {
+    // Ids are handed out in registration order
+    pub fn register(&self, name: String, url: String) -> Arc<BotStats> {
+        let stats = Arc::new(BotStats::new(name, url, self.epoch));
+        self.bots.write().unwrap().push(stats.clone());
+        stats
+    }
+
+    // Stats of an unknown id go nowhere
+    pub fn bot(&self, id: i32) -> Arc<BotStats> {
+        let bots = self.bots.read().unwrap();
+        bots.get(id as usize)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(BotStats::new(String::new(), String::new(), self.epoch)))
+    }
+
+    pub fn set_online(&self, count: u32) {
+        let (online, at) = &*self.online;
+        online.store(count as i64, Ordering::Relaxed);
+        at.store(self.epoch.elapsed().as_millis() as i64, Ordering::Relaxed);
+    }
+
+    pub fn set_ramp(&self, ramp: Arc<RampUp>) {
+        drop(self.ramp.set(ramp));
+    }
+
+    fn online(&self) -> Option<Online> {
+        let (count, at) = &*self.online;
+        let (count, at) = (count.load(Ordering::Relaxed), at.load(Ordering::Relaxed));
+        (count != NEVER && at != NEVER).then(|| Online {
+            count: count as u32,
+            age_secs: self
+                .epoch
+                .elapsed()
+                .saturating_sub(Duration::from_millis(at as u64))
+                .as_secs(),
+        })
+    }
+
+    // None once the ramp-up is over
+    fn ramp_factor(&self) -> Option<f64> {
+        let factor = self.ramp.get()?.factor();
+        (factor != 1.0).then_some(factor)
+    }
+
+    pub fn snapshot(&self) -> PoolSnapshot {
+        let bots = self.bots.read().unwrap().clone();
+        PoolSnapshot {
+            workers: bots
+                .iter()
+                .enumerate()
+                .map(|(id, bot)| bot.snapshot(id))
+                .collect(),
+            online: self.online(),
+            ramp_factor: self.ramp_factor(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn snapshots_never_lose_or_invent_counts() {
+        let stats = StatsRegistry::default();
+        let bots = (0..8)
+            .map(|id| stats.register(format!("bot{id}"), String::new()))
+            .collect::<Vec<_>>();
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = thread::spawn({
+            let (stats, done) = (stats.clone(), done.clone());
+            move || {
+                let mut last = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let snapshot = stats.snapshot();
+                    let sends = snapshot.workers.iter().map(|w| w.sends).sum::<u64>();
+                    assert!(sends >= last, "{sends} after {last}");
+                    last = sends;
+                }
+            }
+        });
+        let writers = bots
+            .into_iter()
+            .map(|bot| {
+                thread::spawn(move || {
+                    for i in 0..10_000 {
+                        bot.sent(true);
+                        bot.transmitted(1);
+                        bot.sent(i % 10 != 0);
+                        bot.set(if i % 2 == 0 {
+                            State::Connected
+                        } else {
+                            State::Throttled
+                        });
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        reader.join().unwrap();
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.workers.len(), 8);
+        for worker in &snapshot.workers {
+            assert_eq!((worker.sends, worker.failures), (19_000, 1_000));
+            assert_eq!(worker.tx_bytes, 10_000);
+            assert_eq!(worker.state, State::Throttled);
+        }
+    }
+}
@@ -0,0 +1,561 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+
+use crate::schedule::Schedule;
+use crate::stats::{PoolSnapshot, State, StatsRegistry};
+use crate::{metrics, protocol, PixelProvider, RunReport};
+
+#[derive(Deserialize)]
+pub struct StatusConfig {
+    pub address: SocketAddr,
+    #[serde(default = "StatusConfig::default_min_healthy_bots")]
+    pub min_healthy_bots: usize,
+    // Entries per list in /queue before it is truncated
+    #[serde(default = "StatusConfig::default_max_queue_entries")]
+    pub max_queue_entries: usize,
+}
+
+impl StatusConfig {
+    fn default_min_healthy_bots() -> usize {
+        1
+    }
+
+    fn default_max_queue_entries() -> usize {
+        10000
+    }
+}
+
+#[derive(Serialize)]
+struct Status {
+    #[serde(flatten)]
+    pool: PoolSnapshot,
+    protocol_parse_errors: u64,
+    progress: RunReport,
+}
+
+#[derive(Serialize)]
+struct QueueSnapshot {
+    remaining: Vec<[u32; 2]>,
+    pending: Vec<Lease>,
+    failed: Vec<Failure>,
+    totals: QueueTotals,
+    truncated: bool,
+}
+
+#[derive(Serialize)]
+struct Lease {
+    x: u32,
+    y: u32,
+    worker: i32,
+    age_secs: u64,
+}
+
+#[derive(Serialize)]
+struct Failure {
+    x: u32,
+    y: u32,
+    age_secs: u64,
+}
+
+#[derive(Serialize)]
+struct QueueTotals {
+    remaining: usize,
+    pending: usize,
+    failed: usize,
+}
+
+impl QueueSnapshot {
+    // Copies at most `max` entries of each list so the lock is not held while encoding
+    fn take(pixel: &PixelProvider, max: usize) -> Self {
+        let failures = pixel.failures.iter().rev();
+        let totals = QueueTotals {
+            remaining: pixel.queue.iter().count(),
+            pending: pixel.leases.len(),
+            failed: pixel.failures.len(),
+        };
+        Self {
+            remaining: pixel
+                .queue
+                .iter()
+                .take(max)
+                .map(|pixel| [pixel.x, pixel.y])
+                .collect(),
+            pending: pixel
+                .leases
+                .iter()
+                .take(max)
+                .map(|(&(x, y), &(worker, since))| Lease {
+                    x,
+                    y,
+                    worker,
+                    age_secs: since.elapsed().as_secs(),
+                })
+                .collect(),
+            failed: failures
+                .take(max)
+                .map(|&((x, y), at)| Failure {
+                    x,
+                    y,
+                    age_secs: at.elapsed().as_secs(),
+                })
+                .collect(),
+            truncated: totals.remaining > max || totals.pending > max || totals.failed > max,
+            totals,
+        }
+    }
+}
+
+// One line per worker for the progress log
+pub fn table(pool: &PoolSnapshot) -> String {
+    let width = pool
+        .workers
+        .iter()
+        .map(|worker| worker.name.len())
+        .max()
+        .unwrap_or_default()
+        .max(4);
+    let mut lines = vec![format!(
+        "  {:width$}  {:12}  {:>10}  {:>10}  {:>6}  {:>8}  {:>9}  {:>9}",
+        "name", "state", "last paint", "next paint", "sends", "failures", "received", "sent"
+    )];
+    for worker in &pool.workers {
+        let age = |secs: Option<u64>| secs.map_or("-".into(), |secs| format!("{secs}s"));
+        let next_paint = match worker.state {
+            State::Connected => age(worker.next_paint_secs),
+            _ => "-".into(),
+        };
+        let mut state = format!("{:?}", worker.state).to_lowercase();
+        if worker.reduced {
+            state.push('*');
+        }
+        lines.push(format!(
+            "  {:width$}  {:12}  {:>10}  {:>10}  {:>6}  {:>8}  {:>9}  {:>9}",
+            worker.name,
+            state,
+            age(worker.last_paint_secs),
+            next_paint,
+            worker.sends,
+            worker.failures,
+            size(worker.rx_bytes),
+            size(worker.tx_bytes)
+        ));
+    }
+    if let Some(factor) = pool.ramp_factor {
+        lines.push(format!("  ramping up: cooldowns x{factor:.2}"));
+    }
+    lines.join("\n")
+}
+
+fn size(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{bytes}B"),
+        1024..1048576 => format!("{:.1}KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1}MiB", bytes as f64 / 1048576.0),
+    }
+}
+
+pub async fn serve(
+    config: StatusConfig,
+    stats: StatsRegistry,
+    pixel: Arc<Mutex<PixelProvider>>,
+    stall: Duration,
+    schedule: Option<Arc<Schedule>>,
+) {
+    let listener = match TcpListener::bind(config.address).await {
+        Ok(listener) => listener,
+        Err(why) => {
+            error!("Cannot serve status on {}: {why}", config.address);
+            return;
+        }
+    };
+    info!(
+        "Serving /healthz, /status and /metrics on {}",
+        config.address
+    );
+    while let Ok((stream, _)) = listener.accept().await {
+        let (stats, pixel, schedule) = (stats.clone(), pixel.clone(), schedule.clone());
+        let limits = (config.min_healthy_bots, config.max_queue_entries);
+        tokio::spawn(async move {
+            let health = (stall, schedule.as_deref());
+            if let Err(why) = respond(stream, &stats, &pixel, limits, health).await {
+                debug!("Status request failed: {why}");
+            }
+        });
+    }
+}
+
+// What is wrong with the run, if anything; waiting on the schedule, a confirmation or a resume is not
+pub async fn health(
+    stats: &StatsRegistry,
+    pixel: &Mutex<PixelProvider>,
+    min_healthy_bots: usize,
+    stall: Duration,
+    schedule: Option<&Schedule>,
+) -> Option<String> {
+    // Workers may well disconnect off-hours
+    if schedule.is_some_and(|schedule| !schedule.is_active()) {
+        return None;
+    }
+    let connected = stats.snapshot().connected_or_paused();
+    let progressing = {
+        let pixel = pixel.lock().await;
+        pixel.paused() || pixel.progressing(stall)
+    };
+    if connected < min_healthy_bots {
+        Some(format!(
+            "{connected} workers connected, at least {min_healthy_bots} required"
+        ))
+    } else if !progressing {
+        Some(format!("No paint was echoed for {}s", stall.as_secs()))
+    } else {
+        None
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    stats: &StatsRegistry,
+    pixel: &Mutex<PixelProvider>,
+    (min_healthy_bots, max_queue_entries): (usize, usize),
+    (stall, schedule): (Duration, Option<&Schedule>),
+) -> anyhow::Result<()> {
+    let mut request = String::new();
+    BufReader::new(&mut stream).read_line(&mut request).await?;
+    let mut words = request.split_whitespace();
+    let (method, path) = (
+        words.next().unwrap_or_default(),
+        words.next().unwrap_or_default(),
+    );
+    let command = path.split_once('?').map_or(path, |(command, _)| command);
+    let (code, body) = match path {
+        // Whatever changes the run wants a POST, so a crawler or a prefetching browser cannot
+        _ if changes_state(command) && method != "POST" => (405, r#"{"reason":"use POST"}"#.into()),
+        "/healthz" => {
+            let connected = stats.snapshot().connected();
+            let problem = health(stats, pixel, min_healthy_bots, stall, schedule).await;
+            let code = if problem.is_some() { 503 } else { 200 };
+            let body = serde_json::json!({
+                "healthy": problem.is_none(),
+                "connected": connected,
+                "reason": problem,
+            });
+            (code, body.to_string())
+        }
+        "/status" => {
+            let status = Status {
+                pool: stats.snapshot(),
+                protocol_parse_errors: protocol::parse_errors(),
+                progress: pixel.lock().await.report(),
+            };
+            (200, serde_json::to_string(&status)?)
+        }
+        "/metrics" => {
+            let pool = stats.snapshot();
+            (200, metrics::render(&pool, &*pixel.lock().await))
+        }
+        "/queue" => {
+            let snapshot = QueueSnapshot::take(&*pixel.lock().await, max_queue_entries);
+            (200, serde_json::to_string(&snapshot)?)
+        }
+        "/confirm" => {
+            let confirmed = pixel.lock().await.confirm();
+            (
+                200,
+                serde_json::json!({ "confirmed": confirmed }).to_string(),
+            )
+        }
+        "/resume" => {
+            let resumed = pixel.lock().await.resume();
+            if resumed {
+                info!("Painting resumed over the status server");
+            }
+            (200, serde_json::json!({ "resumed": resumed }).to_string())
+        }
+        command if command.starts_with("/pin?") || command.starts_with("/unpin?") => {
+            let (command, query) = command.split_once('?').unwrap_or_default();
+            let coordinate = |name: &str| {
+                query
+                    .split('&')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|&(key, _)| key == name)
+                    .and_then(|(_, value)| value.parse::<u32>().ok())
+            };
+            match (coordinate("x"), coordinate("y")) {
+                (Some(x), Some(y)) => {
+                    let mut pixel = pixel.lock().await;
+                    let pin = command == "/pin";
+                    let changed = if pin {
+                        pixel.pin(x, y)
+                    } else {
+                        pixel.unpin(x, y)
+                    };
+                    let verb = if pin { "Pinned" } else { "Unpinned" };
+                    info!("{verb} {{{x}:{y}}} over the status server");
+                    let body = serde_json::json!({
+                        "x": x,
+                        "y": y,
+                        "pinned": pin,
+                        "changed": changed,
+                    });
+                    (200, body.to_string())
+                }
+                _ => (400, r#"{"reason":"x and y are required"}"#.into()),
+            }
+        }
+        _ => (404, r#"{"reason":"not found"}"#.into()),
+    };
+    let reason = match code {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Service Unavailable",
+    };
+    let allow = if code == 405 { "Allow: POST\r\n" } else { "" };
+    let content_type = match path {
+        "/metrics" => metrics::CONTENT_TYPE,
+        _ => "application/json",
+    };
+    let response = format!(
+        "HTTP/1.1 {code} {reason}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n{allow}Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn changes_state(command: &str) -> bool {
+    matches!(command, "/confirm" | "/resume" | "/pin" | "/unpin")
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::tests::provider;
+
+    const STALL: Duration = Duration::from_secs(60);
+
+    // Sends one request line to `respond` and splits what comes back into status and body
+    async fn request(
+        line: &str,
+        stats: &StatsRegistry,
+        pixel: &Mutex<PixelProvider>,
+        max_queue_entries: usize,
+    ) -> (u16, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        client
+            .write_all(format!("{line} HTTP/1.1\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        respond(server, stats, pixel, (1, max_queue_entries), (STALL, None))
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let code = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (code, body.into())
+    }
+
+    fn json(body: &str) -> serde_json::Value {
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn health_and_status_follow_the_workers() {
+        let (stats, pixel) = (StatsRegistry::default(), Mutex::new(provider(&[0, 1])));
+        let worker = stats.register("first".into(), "ws://server/".into());
+        let (code, body) = request("GET /healthz", &stats, &pixel, 10).await;
+        assert_eq!(code, 503);
+        assert_eq!(
+            json(&body),
+            serde_json::json!({
+                "healthy": false,
+                "connected": 0,
+                "reason": "0 workers connected, at least 1 required",
+            })
+        );
+        worker.set(State::Connected);
+        worker.sent(true);
+        let (code, body) = request("GET /healthz", &stats, &pixel, 10).await;
+        assert_eq!((code, json(&body)["connected"].as_u64()), (200, Some(1)));
+        let (code, body) = request("GET /status", &stats, &pixel, 10).await;
+        let status = json(&body);
+        assert_eq!(code, 200);
+        assert_eq!(status["workers"][0]["name"], "first");
+        assert_eq!(status["workers"][0]["state"], "connected");
+        assert_eq!(status["workers"][0]["sends"], 1);
+        assert_eq!(status["progress"]["queued"], 2);
+        let (code, _) = request("GET /nothing", &stats, &pixel, 10).await;
+        assert_eq!(code, 404);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn runs_are_unhealthy_once_echoes_stop() {
+        let (stats, pixel) = (StatsRegistry::default(), Mutex::new(provider(&[0])));
+        stats
+            .register("first".into(), String::new())
+            .set(State::Connected);
+        assert_eq!(health(&stats, &pixel, 1, STALL, None).await, None);
+        tokio::time::advance(STALL).await;
+        assert_eq!(
+            health(&stats, &pixel, 1, STALL, None).await.as_deref(),
+            Some("No paint was echoed for 60s")
+        );
+        assert_eq!(
+            health(&stats, &pixel, 2, STALL, None).await.as_deref(),
+            Some("1 workers connected, at least 2 required")
+        );
+        // A finished queue has nothing left to echo
+        let mut provider = pixel.lock().await;
+        let last = provider.get_pixel(0).unwrap();
+        provider.painted(&last);
+        drop(provider);
+        assert_eq!(health(&stats, &pixel, 1, STALL, None).await, None);
+    }
+
+    #[tokio::test]
+    async fn off_hours_are_healthy() {
+        let (stats, pixel) = (StatsRegistry::default(), Mutex::new(provider(&[0])));
+        // Never active, short of the one second at noon
+        let schedule: Schedule = serde_json::from_value(serde_json::json!({
+            "windows": [{ "start": "12:00", "end": "12:00" }]
+        }))
+        .unwrap();
+        assert!(health(&stats, &pixel, 1, STALL, None).await.is_some());
+        assert_eq!(
+            health(&stats, &pixel, 1, STALL, Some(&schedule)).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn queue_lists_remaining_leased_and_failed_pixels() {
+        let mut provider = provider(&[0, 1, 2, 3]);
+        let leased = provider.get_pixel(2).unwrap();
+        let failed = provider.get_pixel(0).unwrap();
+        provider.failed(failed.clone());
+        let (stats, pixel) = (StatsRegistry::default(), Mutex::new(provider));
+        let (code, body) = request("GET /queue", &stats, &pixel, 10).await;
+        assert_eq!(code, 200);
+        let queue = json(&body);
+        assert_eq!(
+            queue["totals"],
+            serde_json::json!({ "remaining": 3, "pending": 1, "failed": 1 })
+        );
+        assert_eq!(queue["remaining"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            queue["pending"],
+            serde_json::json!([{ "x": leased.x, "y": 0, "worker": 2, "age_secs": 0 }])
+        );
+        assert_eq!(
+            queue["failed"],
+            serde_json::json!([{ "x": failed.x, "y": 0, "age_secs": 0 }])
+        );
+        assert_eq!(queue["truncated"], false);
+        let (_, body) = request("GET /queue", &stats, &pixel, 2).await;
+        let queue = json(&body);
+        assert_eq!(queue["remaining"].as_array().unwrap().len(), 2);
+        assert_eq!(queue["totals"]["remaining"], 3);
+        assert_eq!(queue["truncated"], true);
+    }
+
+    #[tokio::test]
+    async fn pins_change_only_over_post() {
+        let (stats, pixel) = (StatsRegistry::default(), Mutex::new(provider(&[0, 1])));
+        let (code, body) = request("GET /pin?x=1&y=0", &stats, &pixel, 10).await;
+        assert_eq!((code, body.as_str()), (405, r#"{"reason":"use POST"}"#));
+        assert!(pixel.lock().await.pinned.is_empty());
+        let (code, body) = request("POST /pin?x=1&y=0", &stats, &pixel, 10).await;
+        assert_eq!(code, 200);
+        assert_eq!(
+            json(&body),
+            serde_json::json!({ "x": 1, "y": 0, "pinned": true, "changed": true })
+        );
+        assert!(pixel.lock().await.pinned.contains(&(1, 0)));
+        let (code, _) = request("POST /pin?x=1", &stats, &pixel, 10).await;
+        assert_eq!(code, 400);
+        let (code, body) = request("POST /unpin?y=0&x=1", &stats, &pixel, 10).await;
+        assert_eq!((code, json(&body)["changed"].as_bool()), (200, Some(true)));
+        assert!(pixel.lock().await.pinned.is_empty());
+    }
+
+    #[tokio::test]
+    async fn painting_resumes_only_over_post() {
+        let mut provider = provider(&[0]);
+        let mut guard = crate::guard::ResetGuard::new(
+            serde_json::from_value(serde_json::json!({ "threshold": 0.9 })).unwrap(),
+        );
+        let now = tokio::time::Instant::now();
+        guard.observe(0.0, now);
+        guard.observe(1.0, now);
+        provider.reset_guard = Some(guard);
+        let (stats, pixel) = (StatsRegistry::default(), Mutex::new(provider));
+        let (code, _) = request("GET /resume", &stats, &pixel, 10).await;
+        assert_eq!(code, 405);
+        assert!(pixel.lock().await.paused());
+        let (code, body) = request("POST /resume", &stats, &pixel, 10).await;
+        assert_eq!((code, body.as_str()), (200, r#"{"resumed":true}"#));
+        assert!(!pixel.lock().await.paused());
+        let (_, body) = request("POST /resume", &stats, &pixel, 10).await;
+        assert_eq!(body, r#"{"resumed":false}"#);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_table_follows_paints_and_disconnects() {
+        let stats = StatsRegistry::default();
+        let (first, second) = (
+            stats.register("first".into(), String::new()),
+            stats.register("second-bot".into(), String::new()),
+        );
+        for worker in [&first, &second] {
+            worker.set(State::Connected);
+        }
+        first.sent(true);
+        first.scheduled(Duration::from_secs(30));
+        second.sent(false);
+        second.received(2048);
+        tokio::time::advance(Duration::from_secs(10)).await;
+        second.set(State::Disconnected);
+        second.set_reduced(true);
+        let table = table(&stats.snapshot());
+        let rows = table
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                vec![
+                    "name", "state", "last", "paint", "next", "paint", "sends", "failures",
+                    "received", "sent"
+                ],
+                vec!["first", "connected", "10s", "20s", "1", "0", "0B", "0B"],
+                vec![
+                    "second-bot",
+                    "disconnected*",
+                    "-",
+                    "-",
+                    "0",
+                    "1",
+                    "2.0KiB",
+                    "0B"
+                ],
+            ]
+        );
+    }
+}
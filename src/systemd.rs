@@ -0,0 +1,81 @@
+use std::{env, io, os::unix::net::UnixDatagram, process, time::Duration};
+
+use log::*;
+
+// sd_notify(3) without libsystemd; a silent no-op outside a Type=notify unit
+pub fn notify(state: &str) {
+    let Some(socket) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(why) = send(&socket.to_string_lossy(), state) {
+        debug!("Cannot notify systemd of {state}: {why}");
+    }
+}
+
+fn send(socket: &str, state: &str) -> io::Result<()> {
+    let sender = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            sender.send_to_addr(state.as_bytes(), &address)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "abstract sockets need Linux",
+        ))?,
+        None => {
+            sender.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+// Half the watchdog timeout, as sd_watchdog_enabled(3) recommends; None when not asked for us
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != process::id() {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::scratch;
+
+    #[test]
+    fn states_go_out_as_datagrams() {
+        let path = scratch("notify.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        send(path.to_str().unwrap(), "READY=1").unwrap();
+        send(path.to_str().unwrap(), "WATCHDOG=1").unwrap();
+        let mut buffer = [0; 64];
+        for expected in ["READY=1", "WATCHDOG=1"] {
+            let length = receiver.recv(&mut buffer).unwrap();
+            assert_eq!(&buffer[..length], expected.as_bytes());
+        }
+        assert!(send(path.with_extension("gone").to_str().unwrap(), "READY=1").is_err());
+    }
+
+    #[test]
+    fn watchdog_pings_twice_per_timeout_and_only_for_us() {
+        env::remove_var("WATCHDOG_PID");
+        env::remove_var("WATCHDOG_USEC");
+        assert_eq!(watchdog_interval(), None);
+        env::set_var("WATCHDOG_USEC", "30000000");
+        assert_eq!(watchdog_interval(), Some(Duration::from_secs(15)));
+        env::set_var("WATCHDOG_PID", process::id().to_string());
+        assert_eq!(watchdog_interval(), Some(Duration::from_secs(15)));
+        env::set_var("WATCHDOG_PID", (process::id() + 1).to_string());
+        assert_eq!(watchdog_interval(), None);
+        env::remove_var("WATCHDOG_PID");
+        env::set_var("WATCHDOG_USEC", "0");
+        assert_eq!(watchdog_interval(), None);
+    }
+}
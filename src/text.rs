@@ -0,0 +1,117 @@
+use std::{fs, path::PathBuf};
+
+use ab_glyph::{point, Font, FontVec, Glyph, PxScale, ScaleFont};
+use anyhow::anyhow;
+use image::{Rgba, RgbaImage};
+use serde::Deserialize;
+
+use crate::parse_hex;
+
+#[derive(Deserialize)]
+pub struct TextBrush {
+    content: String,
+    font: PathBuf,
+    px_height: f32,
+    color: String,
+    background: Option<String>,
+}
+
+impl TextBrush {
+    // Glyph edges are anti-aliased; anything below this coverage is treated as background
+    const COVERAGE_THRESHOLD: f32 = 0.5;
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    pub fn render(&self) -> anyhow::Result<RgbaImage> {
+        let (r, g, b) = parse_hex(&self.color)?;
+        let foreground = Rgba([r, g, b, 255]);
+        let background = match &self.background {
+            Some(hex) => {
+                let (r, g, b) = parse_hex(hex)?;
+                Rgba([r, g, b, 255])
+            }
+            None => Rgba([0, 0, 0, 0]),
+        };
+        let font = FontVec::try_from_vec(fs::read(&self.font)?)
+            .map_err(|_| anyhow!("Cannot parse font {}", self.font.display()))?;
+        let font = font.as_scaled(PxScale::from(self.px_height));
+        let line_height = font.height() + font.line_gap();
+
+        let mut glyphs = Vec::new();
+        let mut width = 0f32;
+        let lines = self.content.split('\n').collect::<Vec<_>>();
+        for (row, line) in lines.iter().enumerate() {
+            let baseline = font.ascent() + row as f32 * line_height;
+            let mut caret = 0f32;
+            let mut previous: Option<Glyph> = None;
+            for c in line.chars() {
+                let mut glyph = font.scaled_glyph(c);
+                if let Some(previous) = previous.take() {
+                    caret += font.kern(previous.id, glyph.id);
+                }
+                glyph.position = point(caret, baseline);
+                caret += font.h_advance(glyph.id);
+                previous = Some(glyph.clone());
+                glyphs.push(glyph);
+            }
+            width = width.max(caret);
+        }
+        let height = lines.len() as f32 * line_height - font.line_gap();
+
+        let (width, height) = (width.ceil() as u32, height.ceil() as u32);
+        if width == 0 || height == 0 {
+            Err(anyhow!("Text brush renders to an empty image"))?
+        }
+        let mut image = RgbaImage::from_pixel(width, height, background);
+        for glyph in glyphs {
+            let Some(outlined) = font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outlined.px_bounds();
+            outlined.draw(|x, y, coverage| {
+                let (x, y) = (
+                    bounds.min.x as i32 + x as i32,
+                    bounds.min.y as i32 + y as i32,
+                );
+                if coverage >= Self::COVERAGE_THRESHOLD
+                    && (0..width as i32).contains(&x)
+                    && (0..height as i32).contains(&y)
+                {
+                    image.put_pixel(x as u32, y as u32, foreground);
+                }
+            });
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brush(font: PathBuf, color: &str) -> TextBrush {
+        TextBrush {
+            content: "pb".into(),
+            font,
+            px_height: 16.0,
+            color: color.into(),
+            background: None,
+        }
+    }
+
+    #[test]
+    fn colors_are_checked_before_the_font_is_read() {
+        let why = brush("missing.ttf".into(), "red").render().unwrap_err();
+        assert_eq!(why.to_string(), "red is not a #RRGGBB color");
+    }
+
+    #[test]
+    fn unreadable_fonts_are_named() {
+        let path = env!("CARGO_MANIFEST_DIR").to_owned() + "/Cargo.toml";
+        let why = brush(path.clone().into(), "#FFFFFF").render().unwrap_err();
+        assert_eq!(why.to_string(), format!("Cannot parse font {path}"));
+        assert!(brush("missing.ttf".into(), "#FFFFFF").render().is_err());
+    }
+}
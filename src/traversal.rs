@@ -0,0 +1,104 @@
+use crate::PixelInfo;
+
+// The order pixels enter the queue in; dispatch may still hand them out differently
+pub trait TraversalStrategy {
+    fn order(&self, pixels: &[PixelInfo], ctx: &TraversalCtx) -> Vec<PixelInfo>;
+}
+
+pub struct TraversalCtx {
+    // Side of the square blocks clustered workers own
+    pub block_size: u32,
+}
+
+// As built: brush by brush, each in image order
+pub struct Sequential;
+
+impl TraversalStrategy for Sequential {
+    fn order(&self, pixels: &[PixelInfo], _: &TraversalCtx) -> Vec<PixelInfo> {
+        pixels.to_vec()
+    }
+}
+
+// Block by block in rows of blocks, keeping the built order inside each
+pub struct BlockMajor;
+
+impl TraversalStrategy for BlockMajor {
+    fn order(&self, pixels: &[PixelInfo], ctx: &TraversalCtx) -> Vec<PixelInfo> {
+        let positions = pixels
+            .iter()
+            .map(|pixel| (pixel.x, pixel.y))
+            .collect::<Vec<_>>();
+        pb_core::block_major(&positions, ctx.block_size)
+            .into_iter()
+            .map(|i| pixels[i].clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn pixels() -> impl Strategy<Value = Vec<PixelInfo>> {
+        prop::collection::hash_set((0..1590u32, 0..400u32), 0..300).prop_map(|positions| {
+            positions
+                .into_iter()
+                .enumerate()
+                .map(|(i, (x, y))| PixelInfo {
+                    x,
+                    y,
+                    color_id: (i % 32) as u8,
+                })
+                .collect()
+        })
+    }
+
+    fn strategies() -> [&'static dyn TraversalStrategy; 2] {
+        [&Sequential, &BlockMajor]
+    }
+
+    fn sorted(pixels: &[PixelInfo]) -> Vec<(u32, u32, u8)> {
+        let mut pixels = pixels
+            .iter()
+            .map(|pixel| (pixel.x, pixel.y, pixel.color_id))
+            .collect::<Vec<_>>();
+        pixels.sort_unstable();
+        pixels
+    }
+
+    proptest! {
+        #[test]
+        fn every_strategy_permutes_its_input(pixels in pixels(), block_size in 1..64u32) {
+            let ctx = TraversalCtx { block_size };
+            for strategy in strategies() {
+                prop_assert_eq!(sorted(&strategy.order(&pixels, &ctx)), sorted(&pixels));
+            }
+        }
+
+        #[test]
+        fn every_strategy_is_deterministic(pixels in pixels(), block_size in 1..64u32) {
+            let ctx = TraversalCtx { block_size };
+            for strategy in strategies() {
+                prop_assert_eq!(strategy.order(&pixels, &ctx), strategy.order(&pixels, &ctx));
+            }
+        }
+
+        #[test]
+        fn block_major_keeps_the_built_order_inside_blocks(
+            pixels in pixels(),
+            block_size in 1..64u32,
+        ) {
+            let ordered = BlockMajor.order(&pixels, &TraversalCtx { block_size });
+            let block = |pixel: &PixelInfo| (pixel.y / block_size, pixel.x / block_size);
+            let built = |pixel: &PixelInfo| pixels.iter().position(|p| p == pixel).unwrap();
+            for pair in ordered.windows(2) {
+                prop_assert!(block(&pair[0]) <= block(&pair[1]));
+                if block(&pair[0]) == block(&pair[1]) {
+                    prop_assert!(built(&pair[0]) < built(&pair[1]));
+                }
+            }
+        }
+    }
+}
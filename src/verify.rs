@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use image::RgbaImage;
+use pb_core::Palette;
+
+use crate::canvas::Canvas;
+use crate::PixelInfo;
+
+// How much of the template the canvas shows right now
+pub struct Verification {
+    pub total: u32,
+    pub matching: u32,
+    // Never seen while listening, so neither right nor wrong
+    pub unseen: u32,
+    // Wrong pixels by the color they should have
+    pub wrong: BTreeMap<u8, u32>,
+    // Names the wrong colors
+    palette: Arc<Palette>,
+}
+
+impl Verification {
+    pub fn compare(canvas: &Canvas, pixels: &[PixelInfo], palette: Arc<Palette>) -> Self {
+        let mut verification = Self {
+            total: pixels.len() as u32,
+            matching: 0,
+            unseen: 0,
+            wrong: BTreeMap::new(),
+            palette,
+        };
+        for pixel in pixels {
+            match canvas.get(pixel.x, pixel.y) {
+                Canvas::UNKNOWN => verification.unseen += 1,
+                color_id if color_id == pixel.color_id => verification.matching += 1,
+                _ => *verification.wrong.entry(pixel.color_id).or_default() += 1,
+            }
+        }
+        verification
+    }
+
+    pub fn ratio(&self) -> f64 {
+        if self.total == 0 {
+            return 1.0;
+        }
+        self.matching as f64 / self.total as f64
+    }
+
+    // The canvas with wrong pixels in red and unseen ones in gray
+    pub fn diff(canvas: &Canvas, pixels: &[PixelInfo], palette: &Palette) -> RgbaImage {
+        let mut image = canvas.to_image(palette);
+        for pixel in pixels {
+            let marker = match canvas.get(pixel.x, pixel.y) {
+                Canvas::UNKNOWN => [128, 128, 128, 255],
+                color_id if color_id == pixel.color_id => continue,
+                _ => [255, 0, 0, 255],
+            };
+            if let Some(target) = image.get_pixel_mut_checked(pixel.x, pixel.y) {
+                *target = image::Rgba(marker);
+            }
+        }
+        image
+    }
+}
+
+impl std::fmt::Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{} pixels intact ({:.1}%)",
+            self.matching,
+            self.total,
+            self.ratio() * 100.0
+        )?;
+        if self.unseen > 0 {
+            write!(f, ", {} not seen", self.unseen)?;
+        }
+        for (i, (&color_id, count)) in self.wrong.iter().enumerate() {
+            let separator = if i == 0 { "; wrong " } else { ", " };
+            let (r, g, b) = self.palette.rgb_of(color_id).unwrap_or_default();
+            write!(f, "{separator}{count} #{r:02X}{g:02X}{b:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(x: u32, color_id: u8) -> PixelInfo {
+        PixelInfo { x, y: 0, color_id }
+    }
+
+    // Template of four pixels: one right, two wrong, one never seen
+    fn setup() -> (Canvas, Vec<PixelInfo>) {
+        let mut canvas = Canvas::new(8, 1);
+        canvas.set(0, 0, 1);
+        canvas.set(1, 0, 0);
+        canvas.set(2, 0, 0);
+        (
+            canvas,
+            vec![pixel(0, 1), pixel(1, 2), pixel(2, 2), pixel(3, 1)],
+        )
+    }
+
+    #[test]
+    fn pixels_are_right_wrong_or_unseen() {
+        let (canvas, pixels) = setup();
+        let palette = Arc::new(Palette::default());
+        let verification = Verification::compare(&canvas, &pixels, palette.clone());
+        assert_eq!(
+            (
+                verification.total,
+                verification.matching,
+                verification.unseen
+            ),
+            (4, 1, 1)
+        );
+        assert_eq!(verification.wrong, BTreeMap::from([(2, 2)]));
+        assert_eq!(verification.ratio(), 0.25);
+        let (r, g, b) = palette.rgb_of(2).unwrap();
+        assert_eq!(
+            verification.to_string(),
+            format!("1/4 pixels intact (25.0%), 1 not seen; wrong 2 #{r:02X}{g:02X}{b:02X}")
+        );
+    }
+
+    #[test]
+    fn an_empty_template_is_complete() {
+        let verification = Verification::compare(&Canvas::new(1, 1), &[], Arc::default());
+        assert_eq!(verification.ratio(), 1.0);
+    }
+
+    #[test]
+    fn diffs_mark_wrong_pixels_red_and_unseen_gray() {
+        let (canvas, pixels) = setup();
+        let palette = Palette::default();
+        let diff = Verification::diff(&canvas, &pixels, &palette);
+        let (r, g, b) = palette.rgb_of(1).unwrap();
+        assert_eq!(diff.get_pixel(0, 0).0, [r, g, b, 255]);
+        assert_eq!(diff.get_pixel(1, 0).0, [255, 0, 0, 255]);
+        assert_eq!(diff.get_pixel(3, 0).0, [128, 128, 128, 255]);
+        // Off the template the canvas shows as it is
+        assert_eq!(diff.get_pixel(5, 0).0, [0, 0, 0, 0]);
+    }
+}
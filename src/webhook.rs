@@ -0,0 +1,254 @@
+use std::time::Duration;
+
+use log::*;
+use serde::Serialize;
+use tokio::{sync::mpsc, task::JoinHandle};
+use url::Url;
+
+use crate::{fetch, Coverage};
+
+// What the webhook is told about, as {"event": "stall", ...}
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    // Where on the canvas the run paints, once its work is built
+    RunStart {
+        coverage: Coverage,
+    },
+    // No paint was echoed for `idle_secs`, so every worker reconnects
+    Stall {
+        idle_secs: u64,
+        reconnect: u32,
+        max_reconnects: u32,
+    },
+    // Every pixel of the job got its first paint
+    JobComplete {
+        job: String,
+        priority: i32,
+        painted: u32,
+        defending: bool,
+    },
+    // The mismatching share of the target climbed from `from` to `to` within `window` seconds, so painting paused
+    CanvasResetSuspected {
+        from: f64,
+        to: f64,
+        window: u64,
+    },
+    // More of our pixels were overwritten in the last minute than griefing.threshold allows
+    UnderAttack {
+        rate: u32,
+        threshold: u32,
+    },
+}
+
+// POSTs events one at a time and in order, off the painting path
+#[derive(Clone)]
+pub struct Webhook {
+    events: mpsc::UnboundedSender<Event>,
+}
+
+impl Webhook {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    // The task ends once every Webhook is dropped and the queued events went out
+    pub fn start(url: Url) -> (Self, JoinHandle<()>) {
+        let (events, mut queued) = mpsc::unbounded_channel::<Event>();
+        let delivery = tokio::spawn(async move {
+            while let Some(event) = queued.recv().await {
+                let body = serde_json::json!(event).to_string();
+                match tokio::time::timeout(Self::TIMEOUT, fetch::post_json(&url, &body)).await {
+                    Ok(Ok(())) => debug!("Webhook took {body}"),
+                    Ok(Err(why)) => warn!("Webhook did not take {body}: {why}"),
+                    Err(_) => warn!("Webhook timed out on {body}"),
+                }
+            }
+        });
+        (Self { events }, delivery)
+    }
+
+    pub fn send(&self, event: Event) {
+        drop(self.events.send(event));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        build_work, dispatch::Locality, parse_config, BoundingBox, Context, PixelProvider,
+    };
+
+    // A webhook keeping its events for the test instead of posting them
+    pub(crate) fn recorder() -> (Webhook, mpsc::UnboundedReceiver<Event>) {
+        let (events, recorded) = mpsc::unbounded_channel();
+        (Webhook { events }, recorded)
+    }
+
+    // An HTTP endpoint handing over the JSON body of every request it takes
+    pub(crate) async fn sink() -> (Url, mpsc::UnboundedReceiver<serde_json::Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+        let (bodies, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await.unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).await.unwrap();
+                let stream = stream.get_mut();
+                stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await.unwrap();
+                stream.shutdown().await.unwrap();
+                drop(bodies.send(serde_json::from_slice(&body).unwrap()));
+            }
+        });
+        (url, received)
+    }
+
+    async fn delivered(
+        delivery: JoinHandle<()>,
+        mut received: mpsc::UnboundedReceiver<serde_json::Value>,
+    ) -> Vec<serde_json::Value> {
+        delivery.await.unwrap();
+        let mut events = Vec::new();
+        while let Ok(event) = received.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn events_are_posted_in_order() {
+        let (url, received) = sink().await;
+        let (webhook, delivery) = Webhook::start(url);
+        let logo = BoundingBox {
+            min_x: 10,
+            min_y: 20,
+            max_x: 13,
+            max_y: 21,
+            pixels: 8,
+        };
+        webhook.send(Event::RunStart {
+            coverage: Coverage {
+                brushes: vec![Some(logo), None],
+                union: Some(logo),
+            },
+        });
+        webhook.send(Event::Stall {
+            idle_secs: 600,
+            reconnect: 1,
+            max_reconnects: 3,
+        });
+        webhook.send(Event::JobComplete {
+            job: "brush".into(),
+            priority: 0,
+            painted: 12,
+            defending: false,
+        });
+        webhook.send(Event::UnderAttack {
+            rate: 40,
+            threshold: 25,
+        });
+        drop(webhook);
+        assert_eq!(
+            delivered(delivery, received).await,
+            [
+                serde_json::json!({
+                    "event": "run_start",
+                    "coverage": {
+                        "brushes": [
+                            {"min_x": 10, "min_y": 20, "max_x": 13, "max_y": 21, "pixels": 8},
+                            null,
+                        ],
+                        "union": {"min_x": 10, "min_y": 20, "max_x": 13, "max_y": 21, "pixels": 8},
+                    },
+                }),
+                serde_json::json!({
+                    "event": "stall",
+                    "idle_secs": 600,
+                    "reconnect": 1,
+                    "max_reconnects": 3,
+                }),
+                serde_json::json!({
+                    "event": "job_complete",
+                    "job": "brush",
+                    "priority": 0,
+                    "painted": 12,
+                    "defending": false,
+                }),
+                serde_json::json!({
+                    "event": "under_attack",
+                    "rate": 40,
+                    "threshold": 25,
+                }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn jobs_complete_one_by_one_in_priority_order() {
+        let (url, received) = sink().await;
+        let config = parse_config(
+            r##"{
+                "brush": {"rect": {"width": 2, "height": 1, "color": "#FFFFFF"}, "name": "backdrop"},
+                "brushes": [{
+                    "rect": {"width": 1, "height": 1, "color": "#FFFFFF"},
+                    "offset_y": 5,
+                    "priority": 10,
+                    "name": "logo",
+                    "defend": true
+                }],
+                "bots": []
+            }"##,
+        )
+        .unwrap();
+        let context = Arc::new(Context::new(&config));
+        let work = build_work(&config, &context).unwrap();
+        let mut pixel = PixelProvider::new(
+            work.pixels,
+            HashSet::new(),
+            Locality::default(),
+            false,
+            context,
+        );
+        pixel.assign_jobs(config.jobs(), &work.owners);
+        let (webhook, delivery) = Webhook::start(url);
+        pixel.webhook = Some(webhook);
+        while let Some(next) = pixel.get_pixel(0) {
+            pixel.painted(&next);
+        }
+        drop(pixel);
+        let events = delivered(delivery, received).await;
+        let completed = events
+            .iter()
+            .map(|event| {
+                (
+                    event["job"].as_str().unwrap(),
+                    event["painted"].as_u64().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(completed, [("logo", 1), ("backdrop", 2)]);
+        assert_eq!(events[0]["defending"], true);
+        assert_eq!(events[1]["defending"], false);
+    }
+}